@@ -1,7 +1,9 @@
-use serde::Deserialize;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, sync::Arc};
 
-use crate::file_system::{FileSystem, Local, SSH};
+use crate::file_system::{
+    Compressed, Encrypted, FileSystem, Local, Memory, Mirror, Mounts, S3, SSH, Tiered, WebDAV,
+};
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
@@ -15,20 +17,42 @@ pub struct Config {
     pub storage_limit: usize,
     pub upload_timeout: u64,
     pub cookie_domain: Option<String>,
+    pub dedup: Option<bool>,
 
     pub ssh: Option<SSHConfig>,
     pub local: Option<LocalConfig>,
+    pub s3: Option<S3Config>,
+    pub webdav: Option<WebDAVConfig>,
+    pub mirror: Option<MirrorConfig>,
+    pub tiered: Option<TieredConfig>,
+    pub mounts: Option<Vec<MountConfig>>,
+
+    pub encryption: Option<EncryptionConfig>,
+    pub compression: Option<CompressionConfig>,
+    pub scrub: Option<ScrubConfig>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub enum WhichFileSystem {
     #[serde(rename = "local")]
     Local,
     #[serde(rename = "ssh")]
     SSH,
+    #[serde(rename = "s3")]
+    S3,
+    #[serde(rename = "webdav")]
+    WebDAV,
+    #[serde(rename = "memory")]
+    Memory,
+    #[serde(rename = "mirror")]
+    Mirror,
+    #[serde(rename = "tiered")]
+    Tiered,
+    #[serde(rename = "mounts")]
+    Mounts,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct SSHConfig {
     pub host: String,
     pub port: u16,
@@ -37,14 +61,16 @@ pub struct SSHConfig {
 
     pub password: Option<SSHPassword>,
     pub public_key: Option<SSHPublicKey>,
+    /// How many SFTP sessions can be open at once, defaults to 4
+    pub pool_size: Option<usize>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct SSHPassword {
     pub password: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct SSHPublicKey {
     pub public_key: Option<PathBuf>,
     pub private_key: PathBuf,
@@ -54,6 +80,90 @@ pub struct SSHPublicKey {
 #[derive(Debug, Deserialize)]
 pub struct LocalConfig {
     pub root: String,
+    /// Picks up changes other programs make in `root` while running, see `crate::watcher`
+    pub watch: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScrubConfig {
+    /// Seconds between checking every file, see `crate::scrub`
+    pub interval: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+
+    pub prefix: Option<String>,
+    pub path_style: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebDAVConfig {
+    pub url: String,
+
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub partial_update: Option<PartialUpdate>,
+}
+
+/// Every file is written to the primary and all replicas,
+/// each of them is configured in their own section as usual
+#[derive(Debug, Deserialize)]
+pub struct MirrorConfig {
+    pub primary: WhichFileSystem,
+    pub replicas: Vec<WhichFileSystem>,
+}
+
+/// New files go to the hot file system and are moved to the cold one
+/// once they haven't been downloaded for `max_age` seconds
+#[derive(Debug, Deserialize)]
+pub struct TieredConfig {
+    pub hot: WhichFileSystem,
+    pub cold: WhichFileSystem,
+    pub max_age: u64,
+
+    /// How often (in seconds) to look for files to move, defaults to an hour
+    pub interval: Option<u64>,
+}
+
+/// A file system that holds everything below `path`,
+/// configured in its own section as usual
+#[derive(Debug, Deserialize)]
+pub struct MountConfig {
+    pub path: String,
+    pub file_system: WhichFileSystem,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EncryptionConfig {
+    /// Hex encoded 256-bit key
+    pub key: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompressionConfig {
+    /// zstd compression level
+    pub level: Option<i32>,
+}
+
+/// How a WebDAV server lets us write into the middle of a file
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq, Default)]
+pub enum PartialUpdate {
+    /// `PUT` with a `Content-Range` header (Apache mod_dav)
+    #[default]
+    #[serde(rename = "content-range")]
+    ContentRange,
+    /// `PATCH` with a `X-Update-Range` header (SabreDAV based servers)
+    #[serde(rename = "sabredav")]
+    SabreDAV,
+    /// No partial updates, uploads are spooled locally and sent in one go once complete
+    #[serde(rename = "none")]
+    None,
 }
 
 impl WhichFileSystem {
@@ -62,6 +172,12 @@ impl WhichFileSystem {
         (match self {
             &Self::Local => "Local",
             &Self::SSH => "SSH",
+            &Self::S3 => "S3",
+            &Self::WebDAV => "WebDAV",
+            &Self::Memory => "Memory",
+            &Self::Mirror => "Mirror",
+            &Self::Tiered => "Tiered",
+            &Self::Mounts => "Mounts",
         })
         .to_string()
     }
@@ -70,6 +186,16 @@ impl WhichFileSystem {
 impl Config {
     const CONFIG_FILE: &'static str = "config.toml";
 
+    /// If files with the same contents should only be stored once, see [`crate::dedup`]
+    pub fn dedup(&self) -> bool {
+        self.dedup.unwrap_or(false)
+    }
+
+    /// If the database only lives in memory (`sqlite::memory:`)
+    pub fn in_memory_db(&self) -> bool {
+        self.db.contains(":memory:")
+    }
+
     pub fn read_config() -> Self {
         let str = std::fs::read_to_string(Config::CONFIG_FILE)
             .expect("Failed to read config file (config.toml)");
@@ -79,9 +205,157 @@ impl Config {
         toml::from_str(&str).expect("Invalid toml in config")
     }
 
+    /// Points `file_system` in the config file to another file system,
+    /// leaving the rest of the file (comments included) as it is
+    pub fn save_file_system(which: &WhichFileSystem) -> std::io::Result<()> {
+        let str = std::fs::read_to_string(Config::CONFIG_FILE)?;
+        std::fs::write(Config::CONFIG_FILE, with_file_system(&str, which)?)
+    }
+
+    /// The single file systems a file system is made up of
+    pub fn members(&self, which: &WhichFileSystem) -> Vec<WhichFileSystem> {
+        match which {
+            WhichFileSystem::Mirror => self
+                .mirror
+                .iter()
+                .flat_map(|mirror| std::iter::once(&mirror.primary).chain(&mirror.replicas))
+                .cloned()
+                .collect(),
+            WhichFileSystem::Tiered => self
+                .tiered
+                .iter()
+                .flat_map(|tiered| [&tiered.hot, &tiered.cold])
+                .cloned()
+                .collect(),
+            WhichFileSystem::Mounts => self
+                .mounts
+                .iter()
+                .flatten()
+                .flat_map(|mount| self.members(&mount.file_system))
+                .collect(),
+            which => vec![which.clone()],
+        }
+    }
+
+    /// If the config has everything needed to create the file system
+    pub fn is_configured(&self, which: &WhichFileSystem) -> bool {
+        // mirrors and tiers are made up of single file systems
+        let single = |member: &WhichFileSystem| {
+            !matches!(
+                member,
+                WhichFileSystem::Mirror | WhichFileSystem::Tiered | WhichFileSystem::Mounts
+            ) && self.is_configured(member)
+        };
+
+        match which {
+            WhichFileSystem::Local => self.local.is_some(),
+            WhichFileSystem::SSH => self.ssh.is_some(),
+            WhichFileSystem::S3 => self.s3.is_some(),
+            WhichFileSystem::WebDAV => self.webdav.is_some(),
+            WhichFileSystem::Memory => true,
+            WhichFileSystem::Mirror => self.mirror.as_ref().is_some_and(|mirror| {
+                single(&mirror.primary) && mirror.replicas.iter().all(single)
+            }),
+            WhichFileSystem::Tiered => self
+                .tiered
+                .as_ref()
+                .is_some_and(|tiered| single(&tiered.hot) && single(&tiered.cold)),
+            WhichFileSystem::Mounts => self.mounts.as_ref().is_some_and(|mounts| {
+                mounts.iter().all(|mount| {
+                    mount.file_system != WhichFileSystem::Mounts
+                        && self.is_configured(&mount.file_system)
+                })
+            }),
+        }
+    }
+
+    /// Creates the file system that stores the files, without encryption or compression
     #[tracing::instrument(skip(self))]
-    pub async fn get_file_system(&self) -> Box<dyn FileSystem> {
-        match self.file_system {
+    pub async fn get_file_system(&self, which: &WhichFileSystem) -> Box<dyn FileSystem> {
+        match which {
+            WhichFileSystem::Mirror => {
+                let sub_config = self.mirror.as_ref().expect("No mirror config");
+                tracing::info!("Creating a 'Mirror' file system");
+
+                let mut used = vec![&sub_config.primary];
+                for replica in &sub_config.replicas {
+                    if used.contains(&replica) {
+                        panic!("A mirror can't use the same file system twice");
+                    }
+                    used.push(replica);
+                }
+
+                let primary = Arc::from(self.backend(&sub_config.primary).await);
+                let mut replicas = vec![];
+                for replica in &sub_config.replicas {
+                    replicas.push(Arc::from(self.backend(replica).await));
+                }
+                Box::new(Mirror::new(primary, replicas).await)
+            }
+            WhichFileSystem::Tiered => {
+                let sub_config = self.tiered.as_ref().expect("No tiered config");
+                tracing::info!("Creating a 'Tiered' file system");
+
+                // every `memory` file system is a separate one
+                if sub_config.hot == sub_config.cold && sub_config.hot != WhichFileSystem::Memory {
+                    panic!("The hot and cold tier can't be the same file system");
+                }
+                Box::new(Tiered::new(
+                    self.backend(&sub_config.hot).await,
+                    self.backend(&sub_config.cold).await,
+                ))
+            }
+            WhichFileSystem::Mounts => {
+                let sub_config = self.mounts.as_ref().expect("No mounts config");
+                tracing::info!("Creating a 'Mounts' file system");
+
+                let mut used = vec![];
+                for mount in sub_config {
+                    if mount.file_system == WhichFileSystem::Mounts {
+                        panic!("Mounts can't be mounted");
+                    }
+                    for member in self.members(&mount.file_system) {
+                        if used.contains(&member) {
+                            panic!("Two mounts can't use the same file system");
+                        }
+                        used.push(member);
+                    }
+                }
+
+                let mut mounts = vec![];
+                for mount in sub_config {
+                    let fs = Box::pin(self.get_file_system(&mount.file_system)).await;
+                    mounts.push((mount.path.clone(), fs));
+                }
+                Box::new(Mounts::new(mounts).expect("Invalid mounts config"))
+            }
+            which => self.backend(which).await,
+        }
+    }
+
+    /// Puts encryption and compression on top of a file system, if enabled
+    pub fn wrap_file_system(&self, fs: Box<dyn FileSystem>) -> Box<dyn FileSystem> {
+        let fs: Box<dyn FileSystem> = match &self.encryption {
+            Some(encryption) => {
+                tracing::info!("Encrypting every file at rest");
+                Box::new(Encrypted::new(fs, &encryption.key).expect("Invalid encryption key"))
+            }
+            None => fs,
+        };
+
+        // compression has to come before encryption, ciphertext doesn't compress
+        match &self.compression {
+            Some(compression) => {
+                tracing::info!("Compressing files before storing them");
+                Box::new(Compressed::new(fs, compression.level))
+            }
+            None => fs,
+        }
+    }
+
+    /// Creates a single file system from its config section
+    async fn backend(&self, which: &WhichFileSystem) -> Box<dyn FileSystem> {
+        match which {
             WhichFileSystem::Local => {
                 let sub_config = self.local.as_ref().expect("No local config");
                 tracing::info!("Creating a 'Local' file system");
@@ -93,18 +367,92 @@ impl Config {
                 let sub_config = self.ssh.as_ref().expect("No ssh config");
                 tracing::info!("Creating a 'SSH' file system (SFTP)");
                 Box::new(
-                    SSH::connect(
-                        &sub_config.host,
-                        sub_config.port,
-                        &sub_config.username,
-                        &sub_config.password,
-                        &sub_config.public_key,
-                        &sub_config.root,
-                    )
-                    .await
-                    .expect("Failed to connect to SSH host"),
+                    SSH::connect(sub_config)
+                        .await
+                        .expect("Failed to connect to SSH host"),
                 )
             }
+            WhichFileSystem::S3 => {
+                let sub_config = self.s3.as_ref().expect("No s3 config");
+                tracing::info!("Creating a 'S3' file system");
+                Box::new(S3::new(sub_config).expect("Invalid s3 config"))
+            }
+            WhichFileSystem::WebDAV => {
+                let sub_config = self.webdav.as_ref().expect("No webdav config");
+                tracing::info!("Creating a 'WebDAV' file system");
+                Box::new(WebDAV::new(sub_config).expect("Invalid webdav config"))
+            }
+            WhichFileSystem::Memory => {
+                tracing::warn!("Creating a 'Memory' file system, every file is lost on restart");
+                Box::new(Memory::new())
+            }
+            WhichFileSystem::Mirror | WhichFileSystem::Tiered | WhichFileSystem::Mounts => {
+                panic!("Mirrored & tiered file systems can only contain regular file systems")
+            }
         }
     }
 }
+
+/// `config` with `file_system` set to `which`, see [`Config::save_file_system`]
+fn with_file_system(config: &str, which: &WhichFileSystem) -> std::io::Result<String> {
+    let value = toml::Value::try_from(which).map_err(std::io::Error::other)?;
+
+    let mut in_table = false;
+    let mut saved = false;
+    let mut lines = vec![];
+    for line in config.lines() {
+        let trimmed = line.trim_start();
+        in_table |= trimmed.starts_with('[');
+
+        let key = trimmed.split('=').next().unwrap_or_default().trim();
+        if !in_table && !saved && key == "file_system" {
+            let comment = line.find('#').map(|i| &line[i..]).unwrap_or_default();
+            lines.push(
+                format!("file_system = {value} {comment}")
+                    .trim_end()
+                    .to_string(),
+            );
+            saved = true;
+        } else {
+            lines.push(line.to_string());
+        }
+    }
+
+    if !saved {
+        return Err(std::io::Error::other("No file_system in the config file"));
+    }
+    Ok(lines.join("\n") + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saving_the_file_system() {
+        let config = r#"# where files are stored
+file_system = "local" # for now
+addr = "0.0.0.0:4000"
+
+[mirror]
+primary = "local"
+file_system = "local"
+"#;
+
+        let saved = with_file_system(config, &WhichFileSystem::S3).unwrap();
+        assert_eq!(
+            saved,
+            config.replace(
+                r#"file_system = "local" # for now"#,
+                r#"file_system = "s3" # for now"#
+            )
+        );
+        let parsed = toml::from_str::<toml::Table>(&saved).unwrap();
+        assert_eq!(parsed["file_system"].as_str(), Some("s3"));
+        assert_eq!(parsed["mirror"]["file_system"].as_str(), Some("local"));
+
+        assert!(
+            with_file_system("[mirror]\nfile_system = \"local\"", &WhichFileSystem::S3).is_err()
+        );
+    }
+}
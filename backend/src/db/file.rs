@@ -1,5 +1,7 @@
-use sf_core::{File, FileAccess};
-use sqlx::{Result, SqlitePool, query, query_as, query_scalar};
+use sf_core::{File, FileAccess, Tier};
+use sqlx::{FromRow, Result, SqlitePool, query, query_as, query_scalar};
+
+use crate::{db::add_column, generate_id};
 
 #[tracing::instrument(skip(db))]
 pub async fn init(db: &SqlitePool) -> Result<()> {
@@ -15,16 +17,52 @@ pub async fn init(db: &SqlitePool) -> Result<()> {
                     updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
                     access INTEGER DEFAULT 0,
                     chunk_index INTEGER DEFAULT 0,
-                    total_chunks INTEGER
+                    total_chunks INTEGER,
+                    stored_size INTEGER,
+                    blob TEXT,
+                    tier INTEGER DEFAULT 0,
+                    hash TEXT,
+                    password TEXT,
+                    unlock_nonce TEXT
                 );
             "#,
     )
     .execute(db)
     .await?;
 
+    add_column(db, "files", "stored_size", "INTEGER").await?;
+    add_column(db, "files", "blob", "TEXT").await?;
+    add_column(db, "files", "tier", "INTEGER DEFAULT 0").await?;
+    add_column(db, "files", "hash", "TEXT").await?;
+    add_column(db, "files", "password", "TEXT").await?;
+    add_column(db, "files", "unlock_nonce", "TEXT").await?;
+
+    // a blob is named after the digest of its contents
+    query(r#"UPDATE files SET hash = blob WHERE hash IS NULL AND blob IS NOT NULL;"#)
+        .execute(db)
+        .await?;
+
     query(r#"CREATE INDEX IF NOT EXISTS idx_files_path ON files (path);"#)
         .execute(db)
         .await?;
+    query(r#"CREATE INDEX IF NOT EXISTS idx_files_blob ON files (blob);"#)
+        .execute(db)
+        .await?;
+
+    // contents shared between files when deduplicating, see `crate::dedup`
+    query(
+        r#"
+                CREATE TABLE IF NOT EXISTS blobs (
+                    digest TEXT PRIMARY KEY,
+                    size INTEGER NOT NULL,
+                    stored_size INTEGER NOT NULL,
+                    ref_count INTEGER NOT NULL DEFAULT 0,
+                    created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+                );
+            "#,
+    )
+    .execute(db)
+    .await?;
 
     Ok(())
 }
@@ -57,16 +95,146 @@ pub async fn new(db: &SqlitePool, id: &str, path: &str, total_chunks: i64) -> Re
     Ok(file)
 }
 
+/// Adds a copy of `file` at `path` with all of its columns in one go,
+/// along with another reference to its blob if it's deduplicated.
+/// The copy is private and without a password unless `keep_access` is set
+#[tracing::instrument(skip(db, file))]
+pub async fn insert_copy(
+    db: &SqlitePool,
+    file: &File,
+    id: &str,
+    path: &str,
+    stored_size: i64,
+    keep_access: bool,
+) -> Result<File> {
+    let mut tx = db.begin().await?;
+
+    if let Some(digest) = &file.blob {
+        query(
+            r#"
+                INSERT INTO blobs (digest, size, stored_size, ref_count) VALUES (?, ?, ?, 1)
+                    ON CONFLICT (digest) DO UPDATE SET ref_count = ref_count + 1;
+            "#,
+        )
+        .bind(digest)
+        .bind(file.size)
+        .bind(stored_size)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    let (access, password) = match keep_access {
+        true => (file.get_access(), file.password.as_deref()),
+        false => (FileAccess::Private, None),
+    };
+    let copy: File = query_as(
+        r#"
+            INSERT INTO files (id, path, size, access, chunk_index, total_chunks,
+                               stored_size, blob, tier, hash, password)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) RETURNING *;
+        "#,
+    )
+    .bind(id)
+    .bind(path)
+    .bind(file.size)
+    .bind(access as i64)
+    .bind(file.chunk_index)
+    .bind(file.total_chunks)
+    .bind(stored_size)
+    .bind(&file.blob)
+    .bind(file.get_tier() as i64)
+    .bind(&file.hash)
+    .bind(password)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(copy)
+}
+
+/// Also releases the file's reference to its blob,
+/// returns the digest of the blob if nothing references it anymore
 #[tracing::instrument(skip(db))]
-pub async fn delete(db: &SqlitePool, id: &str) -> Result<()> {
-    query(r#"DELETE FROM files WHERE id = ?;"#)
+pub async fn delete(db: &SqlitePool, id: &str) -> Result<Option<String>> {
+    let mut tx = db.begin().await?;
+
+    let blob: Option<String> = query_scalar(r#"DELETE FROM files WHERE id = ? RETURNING blob;"#)
         .bind(id)
-        .execute(db)
+        .fetch_optional(&mut *tx)
+        .await?
+        .flatten();
+
+    let mut orphaned = None;
+    if let Some(digest) = blob {
+        let ref_count: i64 = query_scalar(
+            r#"UPDATE blobs SET ref_count = ref_count - 1 WHERE digest = ? RETURNING ref_count;"#,
+        )
+        .bind(&digest)
+        .fetch_optional(&mut *tx)
+        .await?
+        .unwrap_or(0);
+
+        if ref_count <= 0 {
+            query(r#"DELETE FROM blobs WHERE digest = ?;"#)
+                .bind(&digest)
+                .execute(&mut *tx)
+                .await?;
+            orphaned = Some(digest);
+        }
+    }
+
+    tx.commit().await?;
+    Ok(orphaned)
+}
+
+/// Points the file to a blob, adding a reference to it (and the blob if it's new)
+#[tracing::instrument(skip(file, db))]
+pub async fn set_blob(
+    file: &mut File,
+    db: &SqlitePool,
+    digest: &str,
+    stored_size: i64,
+) -> Result<()> {
+    let mut tx = db.begin().await?;
+
+    query(
+        r#"
+            INSERT INTO blobs (digest, size, stored_size, ref_count) VALUES (?, ?, ?, 1)
+                ON CONFLICT (digest) DO UPDATE SET ref_count = ref_count + 1;
+        "#,
+    )
+    .bind(digest)
+    .bind(file.size)
+    .bind(stored_size)
+    .execute(&mut *tx)
+    .await?;
+
+    query(r#"UPDATE files SET blob = ?1, hash = ?1, stored_size = ?2 WHERE id = ?3;"#)
+        .bind(digest)
+        .bind(stored_size)
+        .bind(&file.id)
+        .execute(&mut *tx)
         .await?;
 
+    tx.commit().await?;
+
+    file.blob = Some(digest.to_string());
+    file.hash = Some(digest.to_string());
+    file.stored_size = Some(stored_size);
+
     Ok(())
 }
 
+#[tracing::instrument(skip(db))]
+pub async fn blob_is_referenced(db: &SqlitePool, digest: &str) -> Result<bool> {
+    Ok(
+        query_scalar(r#"SELECT EXISTS (SELECT 1 FROM files WHERE blob = ?);"#)
+            .bind(digest)
+            .fetch_one(db)
+            .await?,
+    )
+}
+
 #[tracing::instrument(skip(file, db))]
 pub async fn successful_upload(file: &mut File, db: &SqlitePool, size: i64) -> Result<()> {
     query(r#"UPDATE files SET size = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ? "#)
@@ -82,19 +250,137 @@ pub async fn successful_upload(file: &mut File, db: &SqlitePool, size: i64) -> R
     Ok(())
 }
 
+/// How many bytes the file actually takes up in the file system,
+/// which can differ from its size if the file system compresses it
 #[tracing::instrument(skip(file, db))]
-pub async fn change_access(file: &mut File, db: &SqlitePool, access: FileAccess) -> Result<()> {
-    query(r#"UPDATE files SET access = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ?;"#)
-        .bind(access.clone() as i64)
+pub async fn set_stored_size(file: &mut File, db: &SqlitePool, stored_size: i64) -> Result<()> {
+    query(r#"UPDATE files SET stored_size = ? WHERE id = ?;"#)
+        .bind(stored_size)
         .bind(&file.id)
         .execute(db)
         .await?;
 
+    file.stored_size = Some(stored_size);
+
+    Ok(())
+}
+
+/// Hex encoded SHA-256 digest of the contents
+#[tracing::instrument(skip(file, db))]
+pub async fn set_hash(file: &mut File, db: &SqlitePool, hash: &str) -> Result<()> {
+    query(r#"UPDATE files SET hash = ? WHERE id = ?;"#)
+        .bind(hash)
+        .bind(&file.id)
+        .execute(db)
+        .await?;
+
+    file.hash = Some(hash.to_string());
+
+    Ok(())
+}
+
+/// Argon2 hash of the password a protected file needs, revokes unlocks with the old one
+#[tracing::instrument(skip(file, db, password))]
+pub async fn set_password(file: &mut File, db: &SqlitePool, password: &str) -> Result<()> {
+    let nonce = generate_id(Some(16));
+    query(r#"UPDATE files SET password = ?, unlock_nonce = ? WHERE id = ?;"#)
+        .bind(password)
+        .bind(&nonce)
+        .bind(&file.id)
+        .execute(db)
+        .await?;
+
+    file.password = Some(password.to_string());
+    file.unlock_nonce = Some(nonce);
+
+    Ok(())
+}
+
+/// Revokes unlocks from before, so protecting the file again doesn't bring them back
+#[tracing::instrument(skip(file, db))]
+pub async fn change_access(file: &mut File, db: &SqlitePool, access: FileAccess) -> Result<()> {
+    let nonce = generate_id(Some(16));
+    query(
+        r#"UPDATE files SET access = ?, unlock_nonce = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ?;"#,
+    )
+    .bind(access.clone() as i64)
+    .bind(&nonce)
+    .bind(&file.id)
+    .execute(db)
+    .await?;
+
     file.set_access(access);
+    file.unlock_nonce = Some(nonce);
+
+    Ok(())
+}
+
+/// Files sharing a blob always share their tier as well
+#[tracing::instrument(skip(file, db))]
+pub async fn set_tier(file: &mut File, db: &SqlitePool, tier: Tier) -> Result<()> {
+    match &file.blob {
+        Some(digest) => {
+            set_tier_for_blob(db, digest, tier).await?;
+        }
+        None => {
+            query(r#"UPDATE files SET tier = ? WHERE id = ?;"#)
+                .bind(tier as i64)
+                .bind(&file.id)
+                .execute(db)
+                .await?;
+        }
+    }
+
+    file.set_tier(tier);
+
+    Ok(())
+}
+
+/// Every file stored in the blob, returns how many there are
+#[tracing::instrument(skip(db))]
+pub async fn set_tier_for_blob(db: &SqlitePool, digest: &str, tier: Tier) -> Result<u64> {
+    Ok(query(r#"UPDATE files SET tier = ? WHERE blob = ?;"#)
+        .bind(tier as i64)
+        .bind(digest)
+        .execute(db)
+        .await?
+        .rows_affected())
+}
 
+/// Puts every file back on the hot tier, after a migration wrote them all anew
+#[tracing::instrument(skip(db))]
+pub async fn reset_tiers(db: &SqlitePool) -> Result<()> {
+    query(r#"UPDATE files SET tier = ?;"#)
+        .bind(Tier::Hot as i64)
+        .execute(db)
+        .await?;
     Ok(())
 }
 
+/// Fully uploaded files on the hot tier that haven't been downloaded
+/// (or created, if never downloaded) in the last `max_age` seconds.
+/// Deduplicated files are only included if every file sharing their blob is that old
+#[tracing::instrument(skip(db))]
+pub async fn get_files_to_demote(db: &SqlitePool, max_age: u64) -> Result<Vec<File>> {
+    Ok(query_as(
+        r#"
+            SELECT * FROM files
+                WHERE tier = ?1
+                AND (total_chunks < 0 OR chunk_index >= total_chunks)
+                AND COALESCE(last_downloaded_at, created_at) < datetime('now', ?2)
+                AND (blob IS NULL OR NOT EXISTS (
+                    SELECT 1 FROM files AS other
+                        WHERE other.blob = files.blob
+                        AND COALESCE(other.last_downloaded_at, other.created_at) >= datetime('now', ?2)
+                ));
+        "#,
+    )
+    .bind(Tier::Hot as i64)
+    .bind(format!("-{max_age} seconds"))
+    .fetch_all(db)
+    .await?)
+}
+
 #[tracing::instrument(skip(file, db))]
 pub async fn rename(file: &mut File, db: &SqlitePool, new_path: &str) -> Result<()> {
     query(r#"UPDATE files SET path = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ?;"#)
@@ -108,6 +394,26 @@ pub async fn rename(file: &mut File, db: &SqlitePool, new_path: &str) -> Result<
     Ok(())
 }
 
+/// Moves every file below the directory `from` into `to`, keeping their ids.
+/// It's a single statement, so either all of them are moved or none are
+#[tracing::instrument(skip(db))]
+pub async fn rename_directory(db: &SqlitePool, from: &str, to: &str) -> Result<u64> {
+    let from = format!("{from}/");
+    let result = query(
+        r#"
+            UPDATE files SET path = ?1 || substr(path, ?2 + 1), updated_at = CURRENT_TIMESTAMP
+                WHERE substr(path, 1, ?2) = ?3;
+        "#,
+    )
+    .bind(format!("{to}/"))
+    .bind(from.chars().count() as i64)
+    .bind(&from)
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
 /// Also updates the latest download_time
 #[tracing::instrument(skip(file, db))]
 pub async fn increment_download_count(
@@ -122,12 +428,27 @@ pub async fn increment_download_count(
     Ok(())
 }
 
+/// Bytes stored by all files, `logical` is the sum of their original sizes
+/// and `physical` what they actually take up in the file system,
+/// where files sharing a blob only count once
+#[derive(Debug, Clone, Copy, FromRow)]
+pub struct BytesStored {
+    pub logical: i64,
+    pub physical: i64,
+}
+
 #[tracing::instrument(skip(db))]
-pub async fn get_bytes_stored(db: &SqlitePool) -> Result<u64> {
-    let bytes: u64 = query_scalar(r#"SELECT SUM(size) FROM files"#)
-        .fetch_one(db)
-        .await?;
-    Ok(bytes)
+pub async fn get_bytes_stored(db: &SqlitePool) -> Result<BytesStored> {
+    Ok(query_as(
+        r#"
+            SELECT
+                (SELECT COALESCE(SUM(size), 0) FROM files) AS logical,
+                (SELECT COALESCE(SUM(COALESCE(stored_size, size)), 0) FROM files WHERE blob IS NULL)
+                    + (SELECT COALESCE(SUM(stored_size), 0) FROM blobs) AS physical
+        "#,
+    )
+    .fetch_one(db)
+    .await?)
 }
 
 #[tracing::instrument(skip(db))]
@@ -153,16 +474,20 @@ pub async fn get_files_in_directory(db: &SqlitePool, path: &str) -> Result<Vec<F
     Ok(files)
 }
 
+/// Every file anywhere below the directory, nested directories included
 #[tracing::instrument(skip(db))]
-pub async fn get_all_files(db: &SqlitePool) -> Result<Vec<File>> {
-    Ok(query_as(r#"SELECT * FROM files"#).fetch_all(db).await?)
+pub async fn get_files_below(db: &SqlitePool, path: &str) -> Result<Vec<File>> {
+    let prefix = format!("{path}/");
+    query_as(r#"SELECT * FROM files WHERE substr(path, 1, ?) = ?;"#)
+        .bind(prefix.chars().count() as i64)
+        .bind(&prefix)
+        .fetch_all(db)
+        .await
 }
 
 #[tracing::instrument(skip(db))]
-pub async fn get_total_amount_of_files(db: &SqlitePool) -> Result<i64> {
-    Ok(query_scalar(r#"SELECT COUNT(*) FROM files"#)
-        .fetch_one(db)
-        .await?)
+pub async fn get_all_files(db: &SqlitePool) -> Result<Vec<File>> {
+    Ok(query_as(r#"SELECT * FROM files"#).fetch_all(db).await?)
 }
 
 #[tracing::instrument(skip(file, db))]
@@ -73,6 +73,15 @@ impl FileLink {
         )
     }
 
+    /// Links that were used to upload the file
+    #[tracing::instrument(skip(db))]
+    pub async fn get_via_uploaded_file(db: &SqlitePool, file_id: &str) -> Result<Vec<FileLink>> {
+        query_as(r#"SELECT * FROM links WHERE uploaded_file = ?;"#)
+            .bind(file_id)
+            .fetch_all(db)
+            .await
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn is_valid_to_use(&self) -> bool {
         if self.uploaded_file.is_some() && self.uploaded_at.is_some() {
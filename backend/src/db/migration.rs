@@ -0,0 +1,56 @@
+use sqlx::{Result, SqlitePool, query, query_scalar};
+
+/// Files that were already copied by a migration, see `crate::migration`
+#[tracing::instrument(skip(db))]
+pub async fn init(db: &SqlitePool) -> Result<()> {
+    query(
+        r#"
+                CREATE TABLE IF NOT EXISTS migrated_files (
+                    path TEXT NOT NULL,
+                    target TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    hash TEXT NOT NULL,
+                    migrated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    PRIMARY KEY (path, target)
+                );
+            "#,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Size of the file when it was copied to `target`, if it was
+pub async fn get_migrated_size(db: &SqlitePool, path: &str, target: &str) -> Result<Option<i64>> {
+    query_scalar(r#"SELECT size FROM migrated_files WHERE path = ? AND target = ?;"#)
+        .bind(path)
+        .bind(target)
+        .fetch_optional(db)
+        .await
+}
+
+pub async fn set_migrated(
+    db: &SqlitePool,
+    path: &str,
+    target: &str,
+    size: i64,
+    hash: &str,
+) -> Result<()> {
+    query(
+        r#"INSERT OR REPLACE INTO migrated_files (path, target, size, hash) VALUES (?, ?, ?, ?);"#,
+    )
+    .bind(path)
+    .bind(target)
+    .bind(size)
+    .bind(hash)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Forgets every copied file once a migration is done
+pub async fn clear(db: &SqlitePool) -> Result<()> {
+    query(r#"DELETE FROM migrated_files;"#).execute(db).await?;
+    Ok(())
+}
@@ -1,10 +1,39 @@
-use sqlx::{Result, SqlitePool};
+use sqlx::{Result, SqlitePool, query, query_scalar};
 
 pub mod file;
 pub mod links;
+pub mod migration;
 
 pub async fn init(db: &SqlitePool) -> Result<()> {
     file::init(db).await?;
     links::FileLink::init(db).await?;
+    migration::init(db).await?;
+    Ok(())
+}
+
+/// Adds a column to an existing table if it isn't there yet,
+/// since `CREATE TABLE IF NOT EXISTS` won't touch databases made by older versions
+pub(crate) async fn add_column(
+    db: &SqlitePool,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    let exists: bool =
+        query_scalar(r#"SELECT COUNT(*) > 0 FROM pragma_table_info(?) WHERE name = ?;"#)
+            .bind(table)
+            .bind(column)
+            .fetch_one(db)
+            .await?;
+
+    if !exists {
+        tracing::info!("Adding column '{column}' to '{table}'");
+        query(&format!(
+            "ALTER TABLE {table} ADD COLUMN {column} {definition};"
+        ))
+        .execute(db)
+        .await?;
+    }
+
     Ok(())
 }
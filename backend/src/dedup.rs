@@ -0,0 +1,281 @@
+//! Content-addressed storage, enabled with `dedup = true` in the config.
+//!
+//! Once a file has been uploaded its contents are moved to a blob named after
+//! their SHA-256 digest under [`BLOB_DIR`], or dropped if that blob already exists.
+//! The `files` table then points to the blob and every blob keeps count of how many
+//! files reference it, so the same contents are only ever stored once.
+//! Files without a blob (uploaded before dedup was enabled, or still uploading)
+//! are stored at their own path just like before.
+
+use futures_util::StreamExt;
+use sf_core::File;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    sync::{Arc, LazyLock, Mutex},
+};
+use tokio::sync::OwnedMutexGuard;
+
+use crate::{AppState, db, file_system::FileSystem, upload::partial_path};
+
+/// Where every blob is stored, relative to the file system root
+pub const BLOB_DIR: &str = ".blobs";
+
+/// Storing a duplicate & deleting the last file of a blob must not interleave,
+/// otherwise the duplicate could end up pointing to a deleted blob
+static BLOB_LOCKS: LazyLock<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Waits until nothing else stores or deletes the blob
+async fn lock_blob(digest: &str) -> OwnedMutexGuard<()> {
+    let lock = {
+        let mut locks = BLOB_LOCKS.lock().unwrap();
+        // only the map itself still holds these
+        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        locks.entry(digest.to_string()).or_default().clone()
+    };
+    lock.lock_owned().await
+}
+
+/// `.blobs/ab/abcdef...`, split up by the first byte so no directory gets too big
+pub fn blob_path(digest: &str) -> String {
+    format!("{BLOB_DIR}/{}/{digest}", &digest[..2])
+}
+
+/// Where the contents of a file are actually stored in the file system
+pub fn storage_path(file: &File) -> String {
+    match &file.blob {
+        Some(digest) => blob_path(digest),
+        None if !is_uploaded(file) => partial_path(&file.id, &file.path),
+        None => file.path.clone(),
+    }
+}
+
+/// If the file has been fully uploaded, and not just partially
+pub fn is_uploaded(file: &File) -> bool {
+    // synced files have no chunks
+    file.total_chunks < 0 || file.chunk_index >= file.total_chunks
+}
+
+/// Moves a fully uploaded file from its path into its blob
+/// and points the database entry to it
+#[tracing::instrument(skip(state))]
+pub async fn store(state: &AppState, file: &mut File) -> Result<(), DedupError> {
+    if file.blob.is_some() {
+        return Ok(());
+    }
+
+    let digest = hash(state.fs.as_ref(), &file.path).await?;
+    store_as(state, file, &digest).await
+}
+
+/// Like [`store`] for contents whose digest is already known,
+/// so they don't have to be read again
+#[tracing::instrument(skip(state))]
+pub async fn store_as(state: &AppState, file: &mut File, digest: &str) -> Result<(), DedupError> {
+    if file.blob.is_some() {
+        return Ok(());
+    }
+
+    let blob = blob_path(digest);
+    let _lock = lock_blob(digest).await;
+
+    let duplicate = state.fs.exists(&blob).await?;
+    if !duplicate {
+        if let Some(parent) = PathBuf::from(&blob).parent() {
+            state
+                .fs
+                .create_dir_all(&parent.to_string_lossy().replace("\\", "/"))
+                .await?;
+        }
+        state.fs.rename(&file.path, &blob).await?;
+    }
+
+    let stored_size = state.fs.stored_size(&blob).await? as i64;
+    db::file::set_blob(file, &state.db, digest, stored_size).await?;
+
+    // only dropped once the reference keeps the blob around
+    if duplicate {
+        tracing::debug!("{:?} is a duplicate of {}", file.path, digest);
+        state.fs.delete(&file.path).await?;
+    }
+
+    Ok(())
+}
+
+/// Deletes a file from the database and its contents from the file system,
+/// blobs are only deleted once nothing references them anymore
+#[tracing::instrument(skip(state))]
+pub async fn delete_file(state: &AppState, file: &File) -> Result<(), DedupError> {
+    let _lock = match &file.blob {
+        Some(digest) => Some(lock_blob(digest).await),
+        None => None,
+    };
+    let orphaned_blob = db::file::delete(&state.db, &file.id).await?;
+
+    let path = match (&file.blob, orphaned_blob) {
+        // unfinished uploads are still staged
+        (None, _) => storage_path(file),
+        (Some(_), Some(digest)) => blob_path(&digest),
+        // other files still use the blob
+        (Some(_), None) => return Ok(()),
+    };
+
+    if state.fs.exists(&path).await? {
+        state.fs.delete(&path).await?;
+    }
+
+    Ok(())
+}
+
+/// Deletes every blob in the file system that no file references,
+/// left behind by interrupted deletes or a lost database.
+/// With `dry_run` they're only counted
+#[tracing::instrument(skip(state))]
+pub async fn remove_orphaned_blobs(state: &AppState, dry_run: bool) -> Result<u64, DedupError> {
+    if !state.fs.exists(BLOB_DIR).await? {
+        return Ok(0);
+    }
+
+    let mut count = 0;
+    for dir in state.fs.list_dir(BLOB_DIR).await? {
+        if !dir.is_dir {
+            continue;
+        }
+
+        let dir = format!("{BLOB_DIR}/{}", dir.path);
+        for blob in state.fs.list_dir(&dir).await? {
+            if blob.is_dir {
+                continue;
+            }
+            let _lock = lock_blob(&blob.path).await;
+            if db::file::blob_is_referenced(&state.db, &blob.path).await? {
+                continue;
+            }
+
+            count += 1;
+            if dry_run {
+                continue;
+            }
+            state.fs.delete(&format!("{dir}/{}", blob.path)).await?;
+            tracing::info!("Deleted orphaned blob {}", blob.path);
+        }
+    }
+
+    Ok(count)
+}
+
+/// Hex encoded SHA-256 digest of a file
+pub(crate) async fn hash(fs: &dyn FileSystem, path: &str) -> io::Result<String> {
+    let mut stream = fs.read_stream(path).await?;
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = stream.next().await {
+        hasher.update(chunk?);
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum DedupError {
+    IO(io::Error),
+    DB(sqlx::Error),
+}
+
+impl From<io::Error> for DedupError {
+    fn from(value: io::Error) -> Self {
+        Self::IO(value)
+    }
+}
+
+impl From<sqlx::Error> for DedupError {
+    fn from(value: sqlx::Error) -> Self {
+        Self::DB(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_system::{FSStream, FileHandler, FileSystem, Memory};
+    use async_trait::async_trait;
+    use sf_core::FileMetadata;
+    use std::time::Duration;
+
+    /// Takes its time deleting blobs, so other requests can run in between
+    #[derive(Debug, Default)]
+    struct SlowBlobDeletes(Memory);
+
+    #[async_trait]
+    impl FileSystem for SlowBlobDeletes {
+        async fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+            self.0.read(path).await
+        }
+        async fn read_stream(&self, path: &str) -> io::Result<FSStream> {
+            self.0.read_stream(path).await
+        }
+        async fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+            self.0.write(path, data).await
+        }
+        async fn delete(&self, path: &str) -> io::Result<()> {
+            if path.starts_with(BLOB_DIR) {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+            self.0.delete(path).await
+        }
+        async fn exists(&self, path: &str) -> io::Result<bool> {
+            self.0.exists(path).await
+        }
+        async fn metadata(&self, path: &str) -> io::Result<FileMetadata> {
+            self.0.metadata(path).await
+        }
+        async fn get_file_handler(&self, path: &str) -> io::Result<FileHandler> {
+            self.0.get_file_handler(path).await
+        }
+        async fn list_dir(&self, path: &str) -> io::Result<Vec<FileMetadata>> {
+            self.0.list_dir(path).await
+        }
+        async fn create_dir_all(&self, path: &str) -> io::Result<()> {
+            self.0.create_dir_all(path).await
+        }
+        async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+            self.0.rename(from, to).await
+        }
+        async fn delete_empty_dir(&self, path: &str) -> io::Result<()> {
+            self.0.delete_empty_dir(path).await
+        }
+        async fn root_directory(&self) -> PathBuf {
+            self.0.root_directory().await
+        }
+    }
+
+    /// Storing a duplicate while the only other file with the same contents
+    /// is deleted must still leave a blob behind for the duplicate
+    #[tokio::test(flavor = "multi_thread")]
+    async fn store_while_deleting() {
+        let mut state = AppState::in_memory().await;
+        Arc::get_mut(&mut state).unwrap().fs = Box::new(SlowBlobDeletes::default());
+
+        let mut old = db::file::new(&state.db, "old", "old", -1).await.unwrap();
+        state.fs.write("old", b"contents").await.unwrap();
+        store(&state, &mut old).await.unwrap();
+        let mut new = db::file::new(&state.db, "new", "new", -1).await.unwrap();
+        state.fs.write("new", b"contents").await.unwrap();
+
+        let deleting = tokio::spawn({
+            let state = state.clone();
+            async move { delete_file(&state, &old).await }
+        });
+        // until the blob is about to be deleted
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        store(&state, &mut new).await.unwrap();
+        deleting.await.unwrap().unwrap();
+
+        let blob = blob_path(new.blob.as_ref().unwrap());
+        assert_eq!(state.fs.read(&blob).await.unwrap(), b"contents");
+        assert!(!state.fs.exists("new").await.unwrap());
+    }
+}
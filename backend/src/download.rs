@@ -1,11 +1,22 @@
-use std::{ffi::OsString, io::Cursor, path::PathBuf, sync::Arc};
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    io::Cursor,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use axum::{
     body::Body,
-    extract::{Path, Query, State},
+    extract::{ConnectInfo, Path, Query, State},
     http::{
-        HeaderMap, HeaderValue,
-        header::{self, CONTENT_DISPOSITION, CONTENT_TYPE, TRANSFER_ENCODING},
+        HeaderMap, HeaderValue, Method, StatusCode,
+        header::{
+            ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+            IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE,
+        },
     },
     response::{IntoResponse, Response, Result},
 };
@@ -14,14 +25,22 @@ use image::{ImageFormat, Luma};
 use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
 use qrcode::QrCode;
 use serde::Deserialize;
-use sf_core::FileAccess;
+use sf_core::{File, FileAccess};
+use time::OffsetDateTime;
 
 use crate::{
     AppState, db,
+    dedup::{is_uploaded, storage_path},
     download_stream::DownloadStream,
     error::{SimplyError, err},
-    preview::PREVIEW_FILE_LIMIT,
+    generate_id,
+    password::{self, Access, PasswordQuery},
+    preview::can_preview,
     protected::standalone_auth,
+    range::{self, ByteRange, Multipart, Ranges},
+    signed::Signature,
+    tiering,
+    zip::{self, Zip},
 };
 
 #[derive(Debug, Deserialize)]
@@ -30,6 +49,8 @@ pub struct DownloadQuery {
     r: Option<String>,
     // preview
     p: Option<String>,
+    #[serde(flatten)]
+    password: PasswordQuery,
 }
 
 /// The one and only: Download
@@ -39,13 +60,22 @@ pub struct DownloadQuery {
 /// If the request has access to the specified file
 /// If it exists. And if so streams it properly to the client
 /// With content_disposition & mime_type
+///
+/// Only the parts asked for in a `Range` header are sent,
+/// see [`range`] and [`DownloadStream`] for how those are counted.
+/// Clients can revalidate their copy with the `ETag` & `Last-Modified`,
+/// and `HEAD` requests get the headers without the file being read.
+/// Private files can also be downloaded with a [`crate::signed`] link,
+/// protected ones with their [`password`] too
 pub async fn download(
-    jar: CookieJar,
     headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    method: Method,
     Path(id): Path<String>,
     Query(query): Query<DownloadQuery>,
+    Query(signature): Query<Signature>,
     State(state): State<Arc<AppState>>,
-) -> Result<Response<DownloadStream>, SimplyError> {
+) -> Result<Response, SimplyError> {
     let file = match db::file::get_via_id(&state.db, &id).await {
         Ok(f) => f,
         Err(err) => match err {
@@ -54,42 +84,242 @@ pub async fn download(
         },
     };
 
-    if !state.fs.exists(&file.path).await.unwrap_or(false) {
+    if !state.fs.exists(&storage_path(&file)).await.unwrap_or(false) {
         err!("No actual file found", NOT_FOUND);
     }
 
-    if file.get_access() == FileAccess::Private
-        && !standalone_auth(&jar, &headers, &state.config.token)
+    let (jar, ip) = (CookieJar::from_headers(&headers), addr.ip());
+    match password::check(
+        &state,
+        &jar,
+        &headers,
+        ip,
+        &file,
+        &signature,
+        &query.password,
+    )
+    .await
     {
-        err!("You can't access this file", UNAUTHORIZED);
+        Access::Granted => (),
+        Access::PasswordRequired => err!("This file needs a password", UNAUTHORIZED),
+        Access::TooManyAttempts => {
+            err!(
+                "Too many wrong passwords, try again later",
+                TOO_MANY_REQUESTS
+            )
+        }
+        Access::Denied => err!("You can't access this file", UNAUTHORIZED),
     }
 
-    if query.p.unwrap_or(String::from("nuh_uh")) == "t" && file.size > PREVIEW_FILE_LIMIT {
+    let mime = get_mime_type(&file.path);
+    let mime_str = mime
+        .as_ref()
+        .and_then(|mime| mime.to_str().ok())
+        .unwrap_or("application/octet-stream");
+    if query.p.unwrap_or(String::from("nuh_uh")) == "t" && !can_preview(file.size, mime_str) {
         err!(
             "Can't preview this file, it's above the preview size limit",
             IM_A_TEAPOT
         );
     }
 
-    let body = match state.fs.read_stream(&file.path).await {
-        Ok(s) => DownloadStream::new(s, file.id.clone(), state.clone()),
-        Err(err) => return Err(SimplyError::from(err)),
+    let size = file.size as u64;
+    let etag = etag(&file);
+    let mut res = Response::builder()
+        .header(ACCEPT_RANGES, HeaderValue::from_static("bytes"))
+        .header(ETAG, &etag)
+        .header(
+            LAST_MODIFIED,
+            httpdate::fmt_http_date(file.updated_at.into()),
+        );
+    if query.r.unwrap_or(String::from("nuh_uh")) != "t" {
+        res = res.header(CONTENT_DISPOSITION, content_disposition(&file.path));
+    }
+
+    if not_modified(&headers, &etag, file.updated_at) {
+        return Ok(res.status(StatusCode::NOT_MODIFIED).body(Body::empty())?);
+    }
+
+    // the part a client already has is useless if the file changed since
+    let ranges = match if_range(&headers, &etag, file.updated_at) {
+        true => range::parse(headers.get(RANGE), size),
+        false => Ranges::Full,
     };
 
-    let mut res = Response::builder()
-        .header(TRANSFER_ENCODING, HeaderValue::from_static("chunked"))
-        .body(body)?;
+    let (content, len, whole_file) = match ranges {
+        Ranges::Full => (Content::Full, size, true),
+        Ranges::Partial(ranges) if ranges.len() == 1 => {
+            let range = ranges[0];
+            res = res
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_RANGE, range.content_range(size));
+            (Content::Range(range), range.len(), range.len() == size)
+        }
+        Ranges::Partial(ranges) => {
+            let multipart = Multipart {
+                ranges,
+                size,
+                content_type: mime_str.to_string(),
+                boundary: generate_id(Some(32)),
+            };
+            res = res.status(StatusCode::PARTIAL_CONTENT).header(
+                CONTENT_TYPE,
+                format!("multipart/byteranges; boundary={}", multipart.boundary),
+            );
+            let len = multipart.len();
+            (Content::Multipart(multipart), len, false)
+        }
+        Ranges::Unsatisfiable => {
+            return Ok(res
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(CONTENT_RANGE, format!("bytes */{size}"))
+                .body(Body::empty())?);
+        }
+    };
 
-    if query.r.unwrap_or(String::from("nuh_uh")) != "t" {
-        res.headers_mut()
-            .insert(CONTENT_DISPOSITION, content_disposition(&file.path));
+    res = res.header(CONTENT_LENGTH, len);
+    // multipart responses say the type of each part instead
+    if let Some(mime) = mime
+        && !matches!(content, Content::Multipart(_))
+    {
+        res = res.header(CONTENT_TYPE, mime);
+    }
+
+    if method == Method::HEAD {
+        return Ok(res.body(Body::empty())?);
+    }
+
+    let path = storage_path(&file);
+    let stream = match content {
+        Content::Full => state.fs.read_stream(&path).await?,
+        Content::Range(range) => {
+            state
+                .fs
+                .read_range(&path, range.start, Some(range.len()))
+                .await?
+        }
+        Content::Multipart(multipart) => multipart.stream(state.clone(), path),
+    };
+    tiering::promote(state.clone(), file.clone());
+
+    let body = DownloadStream::new(stream, file.id.clone(), len, whole_file, state.clone());
+    Ok(res.body(body)?.into_response())
+}
+
+/// What of the file is sent
+enum Content {
+    Full,
+    Range(ByteRange),
+    Multipart(Multipart),
+}
+
+/// The digest changes with the contents, files without one yet
+/// use their size & when they were last changed instead
+fn etag(file: &File) -> String {
+    match &file.hash {
+        Some(hash) => format!("\"{hash}\""),
+        None => format!(
+            "\"{}-{}-{}\"",
+            file.id,
+            file.size,
+            file.updated_at.unix_timestamp()
+        ),
+    }
+}
+
+fn unix_timestamp(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// If the copy the client has cached is still the current one,
+/// `If-Modified-Since` is ignored if there's an `If-None-Match`, see RFC 9110 section 13.2.2
+fn not_modified(headers: &HeaderMap, etag: &str, modified: OffsetDateTime) -> bool {
+    if let Some(if_none_match) = headers.get(IF_NONE_MATCH) {
+        let etag = etag.trim_start_matches("W/");
+        return if_none_match.to_str().is_ok_and(|tags| {
+            tags.trim() == "*"
+                || tags
+                    .split(',')
+                    .any(|tag| tag.trim().trim_start_matches("W/") == etag)
+        });
+    }
+
+    headers
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|since| since.to_str().ok())
+        .and_then(|since| httpdate::parse_http_date(since).ok())
+        .is_some_and(|since| modified.unix_timestamp() <= unix_timestamp(since))
+}
+
+/// If the `Range` should be used, which is only the case
+/// if the file is still the one named in `If-Range`
+fn if_range(headers: &HeaderMap, etag: &str, modified: OffsetDateTime) -> bool {
+    let Some(if_range) = headers.get(IF_RANGE) else {
+        return true;
+    };
+    let Ok(if_range) = if_range.to_str() else {
+        return false;
+    };
+
+    match httpdate::parse_http_date(if_range) {
+        Ok(date) => modified.unix_timestamp() == unix_timestamp(date),
+        Err(_) => if_range.trim() == etag,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ZipQuery {
+    /// Comma separated
+    ids: String,
+}
+
+/// Every file in `ids` as one ZIP archive, named by their file names.
+/// Anyone can download it if every file is public
+pub async fn download_zip(
+    jar: CookieJar,
+    headers: HeaderMap,
+    Query(query): Query<ZipQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Response, SimplyError> {
+    let authorized = standalone_auth(&jar, &headers, &state.config.token);
+
+    let mut zip = Zip::default();
+    let mut ids = HashSet::new();
+    for id in query.ids.split(',').map(str::trim) {
+        if id.is_empty() || !ids.insert(id) {
+            continue;
+        }
+
+        let file = match db::file::get_via_id(&state.db, id).await {
+            Ok(f) => f,
+            Err(err) => match err {
+                sqlx::Error::RowNotFound => err!("No file with this id found", NOT_FOUND),
+                _ => return Err(SimplyError::from(err)),
+            },
+        };
+
+        if file.get_access() != FileAccess::Public && !authorized {
+            err!("You can't access this file", UNAUTHORIZED);
+        }
+        if !is_uploaded(&file) {
+            err!("This file isn't fully uploaded yet", CONFLICT);
+        }
+
+        let name = PathBuf::from(&file.path)
+            .file_name()
+            .unwrap_or(&OsString::from("unknown"))
+            .to_string_lossy()
+            .to_string();
+        zip.add(&name, file);
     }
 
-    if let Some(mime) = get_mime_type(&file.path) {
-        res.headers_mut().insert(header::CONTENT_TYPE, mime);
+    if zip.is_empty() {
+        err!("No files to download", BAD_REQUEST);
     }
 
-    Ok(res)
+    zip::response(state, zip, "files.zip")
 }
 
 fn get_mime_type(path: &str) -> Option<HeaderValue> {
@@ -102,7 +332,7 @@ fn get_mime_type(path: &str) -> Option<HeaderValue> {
     }
 }
 
-fn content_disposition(path: &str) -> HeaderValue {
+pub fn content_disposition(path: &str) -> HeaderValue {
     let path = PathBuf::from(path);
     let name = path
         .file_name()
@@ -161,7 +391,7 @@ pub async fn qr_code(
         },
     };
 
-    if !state.fs.exists(&file.path).await.unwrap_or(false) {
+    if !state.fs.exists(&storage_path(&file)).await.unwrap_or(false) {
         err!("No actual file found", NOT_FOUND);
     }
 
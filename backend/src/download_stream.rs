@@ -13,18 +13,29 @@ use tokio::spawn;
 use crate::{AppState, db, file_system::FSStream};
 
 pin_project! {
+    /// Counts a download once the whole file was sent.
+    ///
+    /// Responses to `Range` requests only count if the range covered the whole file,
+    /// a video player seeking around would otherwise count dozens of downloads,
+    /// and a download resumed in parts wouldn't be counted at all instead of once
     pub struct DownloadStream {
         #[pin]
         stream: FSStream,
         state: Arc<AppState>,
         file_id: String,
+        // bytes the stream will yield
+        len: u64,
+        sent: u64,
+        whole_file: bool,
         completed: bool
     }
 
     impl PinnedDrop for DownloadStream {
         fn drop(this: Pin<&mut Self>) {
             let this = this.project();
-            if *this.completed {
+            if *this.completed && !*this.whole_file {
+                tracing::debug!("sent part of a file, not counted as a download: {}", *this.file_id);
+            } else if *this.completed {
                 let (state, id) = (this.state.clone(), this.file_id.clone());
 
                 spawn(async move {
@@ -65,12 +76,18 @@ impl Body for DownloadStream {
 
         match this.stream.as_mut().poll_next(cx) {
             Poll::Ready(Some(Ok(chunk))) => {
+                *this.sent += chunk.len() as u64;
+                // the body isn't polled again once all of it was sent
+                if *this.sent >= *this.len {
+                    *this.completed = true;
+                }
                 let bytes = Bytes::from(chunk);
                 Poll::Ready(Some(Ok(Frame::data(bytes))))
             }
             Poll::Ready(Some(Err(_e))) => Poll::Ready(None),
             Poll::Ready(None) => {
-                *this.completed = true;
+                // it ended early if the file got smaller
+                *this.completed = *this.sent >= *this.len;
                 Poll::Ready(None)
             }
             Poll::Pending => Poll::Pending,
@@ -82,17 +99,29 @@ impl Body for DownloadStream {
     }
 
     fn size_hint(&self) -> SizeHint {
-        SizeHint::default()
+        SizeHint::with_exact(self.len)
     }
 }
 
 impl DownloadStream {
-    pub fn new(stream: FSStream, file_id: String, state: Arc<AppState>) -> Self {
+    /// `len` has to be exactly what the stream yields,
+    /// `whole_file` is false if only part of the file is sent
+    pub fn new(
+        stream: FSStream,
+        file_id: String,
+        len: u64,
+        whole_file: bool,
+        state: Arc<AppState>,
+    ) -> Self {
         DownloadStream {
             stream,
             state,
             file_id,
-            completed: false,
+            len,
+            sent: 0,
+            whole_file,
+            // empty bodies are never polled
+            completed: len == 0,
         }
     }
 }
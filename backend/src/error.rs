@@ -79,6 +79,41 @@ impl From<sqlx::Error> for SimplyError {
     }
 }
 
+impl From<crate::dedup::DedupError> for SimplyError {
+    fn from(value: crate::dedup::DedupError) -> Self {
+        match value {
+            crate::dedup::DedupError::IO(err) => err.into(),
+            crate::dedup::DedupError::DB(err) => err.into(),
+        }
+    }
+}
+
+impl From<crate::sync::SyncError> for SimplyError {
+    fn from(value: crate::sync::SyncError) -> Self {
+        match value {
+            crate::sync::SyncError::IO(err) => err.into(),
+            crate::sync::SyncError::DB(err) => err.into(),
+        }
+    }
+}
+
+impl From<crate::migration::MigrationError> for SimplyError {
+    fn from(value: crate::migration::MigrationError) -> Self {
+        match value {
+            crate::migration::MigrationError::IO(err) => err.into(),
+            crate::migration::MigrationError::DB(err) => err.into(),
+            crate::migration::MigrationError::Invalid(reason) => {
+                SimplyError::construct(StatusCode::CONFLICT, reason, None)
+            }
+            err => SimplyError::construct(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Migration failed: {err:?}"),
+                None,
+            ),
+        }
+    }
+}
+
 impl From<axum::http::Error> for SimplyError {
     fn from(value: axum::http::Error) -> Self {
         SimplyError {
@@ -0,0 +1,703 @@
+//! Transparent zstd compression, wrapping any other file system.
+//!
+//! Compressed files start with [`MAGIC`] followed by independent frames that each
+//! hold up to [`BLOCK_SIZE`] bytes of the original file, so finding a position
+//! only means hopping over frame headers instead of decompressing everything before it.
+//! Frames that don't get any smaller are stored raw, and files that are already
+//! compressed by their format (see [`is_incompressible`]) are stored as is without
+//! any framing. Anything without the magic is read back untouched, which also means
+//! that files stored before compression was enabled keep working.
+//!
+//! Sizes reported by [`FileSystem::metadata`] and [`FileSystem::list_dir`] are the
+//! stored (physical) sizes, the original size of every file is kept in the database.
+
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use sf_core::Tier;
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io::{Error, ErrorKind, Result, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::file_system::{FSStream, FileHandler, FileMetadata, FileSystem, block_on, slice_stream};
+
+const MAGIC: &[u8; 8] = b"SFZSTD01";
+/// Bytes of the original file in every frame
+const BLOCK_SIZE: usize = 1024 * 1024;
+const HEADER_SIZE: usize = 16;
+/// The frame data is zstd compressed, otherwise it's raw
+const FLAG_COMPRESSED: u32 = 1;
+
+/// Extensions of formats that are compressed already
+const INCOMPRESSIBLE: &[&str] = &[
+    "7z", "avif", "br", "bz2", "flac", "gif", "gz", "heic", "jpeg", "jpg", "lz4", "m4a", "m4v",
+    "mkv", "mov", "mp3", "mp4", "ogg", "opus", "png", "rar", "webm", "webp", "xz", "zip", "zst",
+];
+
+pub struct Compressed {
+    inner: Arc<dyn FileSystem>,
+    level: i32,
+    /// Where the handlers of unfinished uploads stopped, by path,
+    /// so the next one can continue without reading the file back
+    resumable: Arc<Mutex<HashMap<String, Resumable>>>,
+}
+
+/// What a [`CompressedHandler`] needs to continue where another one stopped
+struct Resumable {
+    frame_start: u64,
+    frame_offset: u64,
+    stored_frame_len: u64,
+    pending: Vec<u8>,
+}
+
+impl Debug for Compressed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Compressed({:?})", self.inner)
+    }
+}
+
+impl Compressed {
+    pub fn new(inner: Box<dyn FileSystem>, level: Option<i32>) -> Self {
+        Self {
+            inner: Arc::from(inner),
+            level: level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL),
+            resumable: Arc::default(),
+        }
+    }
+
+    /// The file at `path` was replaced or is gone, so its upload can't be continued
+    fn forget(&self, path: &str) {
+        self.resumable.lock().unwrap().remove(path);
+    }
+}
+
+#[async_trait]
+impl FileSystem for Compressed {
+    #[tracing::instrument]
+    async fn read(&self, path: &str) -> Result<Vec<u8>> {
+        let data = self.inner.read(path).await?;
+        if !data.starts_with(MAGIC) {
+            return Ok(data);
+        }
+
+        let mut buffer = data[MAGIC.len()..].to_vec();
+        let mut result = vec![];
+        while let Some(frame) = take_frame(&mut buffer, true)? {
+            result.extend(frame);
+        }
+        Ok(result)
+    }
+
+    #[tracing::instrument]
+    async fn read_stream(&self, path: &str) -> Result<FSStream> {
+        let stream = self.inner.read_stream(path).await?;
+        decompress_stream(stream).await
+    }
+
+    /// Hops over the frame headers up to the frame `start` is in
+    /// and only decompresses from there on
+    #[tracing::instrument]
+    async fn read_range(&self, path: &str, start: u64, len: Option<u64>) -> Result<FSStream> {
+        if read_bytes(self.inner.as_ref(), path, 0, MAGIC.len()).await? != MAGIC {
+            return self.inner.read_range(path, start, len).await;
+        }
+
+        let (mut frame_start, mut offset) = (0, MAGIC.len() as u64);
+        loop {
+            let header = read_bytes(self.inner.as_ref(), path, offset, HEADER_SIZE).await?;
+            // past the last frame, anything that's left is read as a truncated frame
+            let Some(header) = FrameHeader::parse(&header) else {
+                break;
+            };
+            if frame_start + header.plain_len as u64 > start {
+                break;
+            }
+            frame_start += header.plain_len as u64;
+            offset += (HEADER_SIZE + header.frame_len as usize) as u64;
+        }
+
+        let stream = self.inner.read_range(path, offset, None).await?;
+        Ok(slice_stream(
+            decompress_frames(stream, vec![]),
+            start - frame_start,
+            len,
+        ))
+    }
+
+    #[tracing::instrument(skip(data))]
+    async fn write(&self, path: &str, data: &[u8]) -> Result<()> {
+        self.forget(path);
+        if is_incompressible(path) {
+            return self.inner.write(path, data).await;
+        }
+
+        let mut stored = MAGIC.to_vec();
+        for block in data.chunks(BLOCK_SIZE) {
+            stored.extend(encode_frame(block, self.level, 0)?);
+        }
+        self.inner.write(path, &stored).await
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        self.forget(path);
+        self.inner.delete(path).await
+    }
+
+    async fn exists(&self, path: &str) -> Result<bool> {
+        self.inner.exists(path).await
+    }
+
+    async fn metadata(&self, path: &str) -> Result<FileMetadata> {
+        self.inner.metadata(path).await
+    }
+
+    /// Adds up the original sizes in the frame headers
+    #[tracing::instrument]
+    async fn content_size(&self, path: &str) -> Result<u64> {
+        if read_bytes(self.inner.as_ref(), path, 0, MAGIC.len()).await? != MAGIC {
+            return self.inner.content_size(path).await;
+        }
+
+        let (mut size, mut offset) = (0, MAGIC.len() as u64);
+        while let Some(header) =
+            FrameHeader::parse(&read_bytes(self.inner.as_ref(), path, offset, HEADER_SIZE).await?)
+        {
+            size += header.plain_len as u64;
+            offset += (HEADER_SIZE + header.frame_len as usize) as u64;
+        }
+        Ok(size)
+    }
+
+    /// Continues where the last handler of `path` stopped, if it's known
+    #[tracing::instrument]
+    async fn get_file_handler(&self, path: &str) -> Result<FileHandler> {
+        let inner = self.inner.get_file_handler(path).await?;
+        if is_incompressible(path) {
+            return Ok(inner);
+        }
+
+        let resumable = self.resumable.lock().unwrap().remove(path);
+        let resumable = resumable.unwrap_or(Resumable {
+            frame_start: 0,
+            frame_offset: MAGIC.len() as u64,
+            stored_frame_len: 0,
+            pending: Vec::with_capacity(BLOCK_SIZE),
+        });
+
+        Ok(Box::new(CompressedHandler {
+            inner,
+            fs: self.inner.clone(),
+            resumable: self.resumable.clone(),
+            path: path.to_string(),
+            level: self.level,
+            frame_start: resumable.frame_start,
+            frame_offset: resumable.frame_offset,
+            stored_frame_len: resumable.stored_frame_len,
+            pending: resumable.pending,
+            dirty: false,
+        }))
+    }
+
+    async fn finish_upload(&self, path: &str) -> Result<()> {
+        self.forget(path);
+        self.inner.finish_upload(path).await
+    }
+
+    async fn stored_size(&self, path: &str) -> Result<u64> {
+        self.inner.stored_size(path).await
+    }
+
+    async fn list_dir(&self, path: &str) -> Result<Vec<FileMetadata>> {
+        self.inner.list_dir(path).await
+    }
+
+    async fn create_dir_all(&self, path: &str) -> Result<()> {
+        self.inner.create_dir_all(path).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.forget(from);
+        self.forget(to);
+        self.inner.rename(from, to).await
+    }
+
+    async fn delete_empty_dir(&self, path: &str) -> Result<()> {
+        self.inner.delete_empty_dir(path).await
+    }
+
+    /// What's stored doesn't depend on the path, so it's copied as is
+    async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        self.forget(to);
+        self.inner.copy(from, to).await
+    }
+
+    async fn repair(&self) -> Result<u64> {
+        self.inner.repair().await
+    }
+
+    async fn move_to_tier(&self, path: &str, tier: Tier) -> Result<()> {
+        self.inner.move_to_tier(path, tier).await
+    }
+
+    fn contains_mount_point(&self, path: &str) -> bool {
+        self.inner.contains_mount_point(path)
+    }
+
+    async fn root_directory(&self) -> PathBuf {
+        self.inner.root_directory().await
+    }
+}
+
+/// Compresses everything written to it into frames.
+///
+/// The frame currently being written is kept in `pending` until it holds a whole
+/// block, flushing writes it early and it's then rewritten in place when more data
+/// arrives. A rewritten frame never shrinks on disk (it's padded instead), so it
+/// can't leave stale bytes behind that would be read as the next frame.
+/// Only appending is supported, seeking anywhere but the end (or the very start)
+/// of what's already stored fails.
+/// When dropped after a flush, where it stopped is kept for the next handler of the file
+struct CompressedHandler {
+    inner: FileHandler,
+    fs: Arc<dyn FileSystem>,
+    resumable: Arc<Mutex<HashMap<String, Resumable>>>,
+    path: String,
+    level: i32,
+    /// Where the current frame starts in the original file
+    frame_start: u64,
+    /// Where the current frame starts on disk
+    frame_offset: u64,
+    /// How much room the current frame already takes up on disk
+    stored_frame_len: u64,
+    pending: Vec<u8>,
+    dirty: bool,
+}
+
+impl CompressedHandler {
+    fn position(&self) -> u64 {
+        self.frame_start + self.pending.len() as u64
+    }
+
+    fn write_pending(&mut self) -> Result<()> {
+        if !self.dirty || self.pending.is_empty() {
+            return Ok(());
+        }
+
+        if self.frame_start == 0 {
+            self.inner.seek(SeekFrom::Start(0))?;
+            self.inner.write_all(MAGIC)?;
+        }
+
+        let frame = encode_frame(&self.pending, self.level, self.stored_frame_len)?;
+        self.inner.seek(SeekFrom::Start(self.frame_offset))?;
+        self.inner.write_all(&frame)?;
+
+        self.stored_frame_len = frame.len() as u64;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// The last complete frame of what's already stored, found by hopping over
+    /// the frame headers so only that one frame has to be read
+    fn last_frame(&self) -> Result<Option<StoredFrame>> {
+        block_on(async {
+            let fs = self.fs.as_ref();
+            let size = match fs.metadata(&self.path).await {
+                Ok(metadata) => metadata.size,
+                Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(err),
+            };
+            if size == 0 {
+                return Ok(None);
+            }
+            if read_bytes(fs, &self.path, 0, MAGIC.len()).await? != MAGIC {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Can't resume writing to a file that isn't compressed",
+                ));
+            }
+
+            let mut last = None;
+            let (mut start, mut offset) = (0, MAGIC.len() as u64);
+            while offset + HEADER_SIZE as u64 <= size {
+                let header = read_bytes(fs, &self.path, offset, HEADER_SIZE).await?;
+                let Some(header) = FrameHeader::parse(&header) else {
+                    break;
+                };
+                let stored_len = (HEADER_SIZE + header.frame_len as usize) as u64;
+                // a frame that was cut off while being written
+                if offset + stored_len > size {
+                    break;
+                }
+
+                last = Some((start, offset, stored_len));
+                start += header.plain_len as u64;
+                offset += stored_len;
+            }
+
+            let Some((start, offset, stored_len)) = last else {
+                return Ok(None);
+            };
+            Ok(Some(StoredFrame {
+                start,
+                offset,
+                stored_len,
+                data: read_bytes(fs, &self.path, offset, stored_len as usize).await?,
+            }))
+        })
+    }
+}
+
+impl Write for CompressedHandler {
+    fn write(&mut self, mut buf: &[u8]) -> Result<usize> {
+        let written = buf.len();
+
+        while !buf.is_empty() {
+            let space = BLOCK_SIZE - self.pending.len();
+            let (now, rest) = buf.split_at(space.min(buf.len()));
+            self.pending.extend_from_slice(now);
+            self.dirty = true;
+            buf = rest;
+
+            if self.pending.len() == BLOCK_SIZE {
+                self.write_pending()?;
+                self.frame_start += BLOCK_SIZE as u64;
+                self.frame_offset += self.stored_frame_len;
+                self.stored_frame_len = 0;
+                self.pending.clear();
+            }
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.write_pending()?;
+        self.inner.flush()
+    }
+}
+
+impl Seek for CompressedHandler {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(p) => p,
+            SeekFrom::Current(0) => return Ok(self.position()),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "Compressed files only support seeking from the start",
+                ));
+            }
+        };
+
+        if target == self.position() {
+            return Ok(target);
+        }
+
+        if target == 0 {
+            self.frame_start = 0;
+            self.frame_offset = MAGIC.len() as u64;
+            self.stored_frame_len = 0;
+            self.pending.clear();
+            self.dirty = false;
+            return Ok(0);
+        }
+
+        self.write_pending()?;
+        let last = self.last_frame()?;
+        let end = match &last {
+            Some(last) => (
+                last.start + last.plain_len()?,
+                last.offset + last.stored_len,
+            ),
+            None => (0, MAGIC.len() as u64),
+        };
+
+        match last {
+            // continue in a new frame after the last (full) one
+            _ if target == end.0 && (end.0 % BLOCK_SIZE as u64) == 0 => {
+                self.frame_start = end.0;
+                self.frame_offset = end.1;
+                self.stored_frame_len = 0;
+                self.pending.clear();
+            }
+            // continue inside the last frame
+            Some(last) if target >= last.start && target <= end.0 => {
+                let mut plaintext = decode_frame(&last.data)?;
+                plaintext.truncate((target - last.start) as usize);
+                self.frame_start = last.start;
+                self.frame_offset = last.offset;
+                self.stored_frame_len = last.stored_len;
+                self.pending = plaintext;
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!(
+                        "Can't seek to {target} in a compressed file that ends at {}",
+                        end.0
+                    ),
+                ));
+            }
+        }
+        self.dirty = false;
+
+        Ok(target)
+    }
+}
+
+impl Drop for CompressedHandler {
+    fn drop(&mut self) {
+        // unflushed data never made it into the file
+        if self.dirty || (self.frame_start == 0 && self.pending.is_empty()) {
+            return;
+        }
+
+        self.resumable.lock().unwrap().insert(
+            self.path.clone(),
+            Resumable {
+                frame_start: self.frame_start,
+                frame_offset: self.frame_offset,
+                stored_frame_len: self.stored_frame_len,
+                pending: std::mem::take(&mut self.pending),
+            },
+        );
+    }
+}
+
+struct StoredFrame {
+    start: u64,
+    offset: u64,
+    stored_len: u64,
+    /// Header and data
+    data: Vec<u8>,
+}
+
+impl StoredFrame {
+    fn plain_len(&self) -> Result<u64> {
+        match FrameHeader::parse(&self.data) {
+            Some(header) => Ok(header.plain_len as u64),
+            None => Err(Error::new(ErrorKind::InvalidData, "Invalid frame header")),
+        }
+    }
+}
+
+struct FrameHeader {
+    plain_len: u32,
+    data_len: u32,
+    /// Data and padding
+    frame_len: u32,
+    flags: u32,
+}
+
+impl FrameHeader {
+    fn parse(buffer: &[u8]) -> Option<Self> {
+        if buffer.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let field = |i: usize| u32::from_le_bytes(buffer[i * 4..i * 4 + 4].try_into().unwrap());
+        Some(Self {
+            plain_len: field(0),
+            data_len: field(1),
+            frame_len: field(2),
+            flags: field(3),
+        })
+    }
+}
+
+/// Compresses a block into a frame that takes up at least `min_len` bytes on disk
+fn encode_frame(block: &[u8], level: i32, min_len: u64) -> Result<Vec<u8>> {
+    let compressed = zstd::bulk::compress(block, level)?;
+    let (data, flags) = if compressed.len() < block.len() {
+        (compressed, FLAG_COMPRESSED)
+    } else {
+        (block.to_vec(), 0)
+    };
+
+    let frame_len = (data.len() as u64).max(min_len.saturating_sub(HEADER_SIZE as u64));
+    let mut frame = Vec::with_capacity(HEADER_SIZE + frame_len as usize);
+    frame.extend((block.len() as u32).to_le_bytes());
+    frame.extend((data.len() as u32).to_le_bytes());
+    frame.extend((frame_len as u32).to_le_bytes());
+    frame.extend(flags.to_le_bytes());
+    frame.extend(&data);
+    frame.resize(HEADER_SIZE + frame_len as usize, 0);
+    Ok(frame)
+}
+
+/// The original data of a whole frame (header included)
+fn decode_frame(frame: &[u8]) -> Result<Vec<u8>> {
+    let header = match FrameHeader::parse(frame) {
+        Some(h) if frame.len() >= HEADER_SIZE + h.data_len as usize => h,
+        _ => return Err(Error::new(ErrorKind::InvalidData, "Truncated frame")),
+    };
+
+    let data = &frame[HEADER_SIZE..HEADER_SIZE + header.data_len as usize];
+    if header.flags & FLAG_COMPRESSED == 0 {
+        return Ok(data.to_vec());
+    }
+    zstd::bulk::decompress(data, header.plain_len as usize)
+}
+
+/// Removes and decodes the first frame of `buffer` if it's complete,
+/// `at_end` turns a trailing partial frame into an error
+fn take_frame(buffer: &mut Vec<u8>, at_end: bool) -> Result<Option<Vec<u8>>> {
+    let frame_len = match FrameHeader::parse(buffer) {
+        Some(header) => HEADER_SIZE + header.frame_len as usize,
+        None if buffer.is_empty() || !at_end => return Ok(None),
+        None => return Err(Error::new(ErrorKind::InvalidData, "Truncated frame")),
+    };
+
+    if buffer.len() < frame_len {
+        if at_end {
+            return Err(Error::new(ErrorKind::InvalidData, "Truncated frame"));
+        }
+        return Ok(None);
+    }
+
+    let frame = buffer.drain(..frame_len).collect::<Vec<u8>>();
+    decode_frame(&frame).map(Some)
+}
+
+/// Decompresses a stored stream, passing it through as is if it isn't compressed
+async fn decompress_stream(mut stream: FSStream) -> Result<FSStream> {
+    // peek far enough to see if it starts with the magic
+    let mut buffer = vec![];
+    while buffer.len() < MAGIC.len() {
+        match stream.next().await {
+            Some(chunk) => buffer.extend(chunk?),
+            None => break,
+        }
+    }
+
+    if !buffer.starts_with(MAGIC) {
+        let first = futures_util::stream::iter([Ok(buffer)]);
+        return Ok(Box::pin(first.chain(stream)));
+    }
+    buffer.drain(..MAGIC.len());
+    Ok(decompress_frames(stream, buffer))
+}
+
+/// Decompresses the frames of a stored stream that starts at a frame header,
+/// `buffer` is what was already taken from the stream
+fn decompress_frames(stream: FSStream, buffer: Vec<u8>) -> FSStream {
+    let state = (stream, buffer, false);
+    Box::pin(futures_util::stream::unfold(
+        state,
+        |(mut stream, mut buffer, done)| async move {
+            if done {
+                return None;
+            }
+
+            loop {
+                match take_frame(&mut buffer, false) {
+                    Ok(Some(frame)) => return Some((Ok(frame), (stream, buffer, false))),
+                    Ok(None) => (),
+                    Err(err) => return Some((Err(err), (stream, buffer, true))),
+                }
+
+                match stream.next().await {
+                    Some(Ok(chunk)) => buffer.extend(chunk),
+                    Some(Err(err)) => return Some((Err(err), (stream, buffer, true))),
+                    None => {
+                        return match take_frame(&mut buffer, true) {
+                            Ok(Some(frame)) => Some((Ok(frame), (stream, buffer, true))),
+                            Ok(None) => None,
+                            Err(err) => Some((Err(err), (stream, buffer, true))),
+                        };
+                    }
+                }
+            }
+        },
+    ))
+}
+
+/// Reads up to `len` bytes at `start` as a whole
+async fn read_bytes(fs: &dyn FileSystem, path: &str, start: u64, len: usize) -> Result<Vec<u8>> {
+    let mut stream = fs.read_range(path, start, Some(len as u64)).await?;
+    let mut bytes = Vec::with_capacity(len);
+    while let Some(chunk) = stream.next().await {
+        bytes.extend(chunk?);
+    }
+    Ok(bytes)
+}
+
+/// If a file is already compressed by its format and not worth compressing again
+fn is_incompressible(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .is_some_and(|e| INCOMPRESSIBLE.contains(&e.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_system::{Encrypted, Memory};
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 7 * (i % 13)) as u8).collect()
+    }
+
+    /// Writes `data[from..to]` with a new handler like a resumed upload does
+    async fn write_with_handler(fs: &Compressed, path: &str, data: &[u8], from: usize, to: usize) {
+        let mut handler = fs.get_file_handler(path).await.unwrap();
+        tokio::task::block_in_place(|| {
+            handler.seek(SeekFrom::Start(from as u64)).unwrap();
+            handler.write_all(&data[from..to]).unwrap();
+            handler.flush().unwrap();
+        });
+    }
+
+    async fn resume(fs: Compressed) {
+        let data = data(2 * BLOCK_SIZE + 12345);
+        let (first, second) = (BLOCK_SIZE / 2, BLOCK_SIZE + 99);
+
+        write_with_handler(&fs, "upload", &data, 0, first).await;
+        // where the handler stopped is still known
+        write_with_handler(&fs, "upload", &data, first, second).await;
+        // and where it's not, only the last frame is read back
+        fs.forget("upload");
+        write_with_handler(&fs, "upload", &data, second, data.len()).await;
+        fs.finish_upload("upload").await.unwrap();
+
+        assert_eq!(fs.read("upload").await.unwrap(), data);
+        let range = fs.read_range("upload", 1000, Some(BLOCK_SIZE as u64)).await;
+        let mut range = range.unwrap();
+        let mut bytes = vec![];
+        while let Some(chunk) = range.next().await {
+            bytes.extend(chunk.unwrap());
+        }
+        assert_eq!(bytes, data[1000..1000 + BLOCK_SIZE]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn resuming_uploads() {
+        resume(Compressed::new(Box::new(Memory::new()), None)).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn resuming_encrypted_uploads() {
+        let key = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        let encrypted = Encrypted::new(Box::new(Memory::new()), key).unwrap();
+        resume(Compressed::new(Box::new(encrypted), None)).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn seeking_past_the_end_fails() {
+        let fs = Compressed::new(Box::new(Memory::new()), None);
+        let data = data(1000);
+        write_with_handler(&fs, "upload", &data, 0, data.len()).await;
+        fs.forget("upload");
+
+        let mut handler = fs.get_file_handler("upload").await.unwrap();
+        tokio::task::block_in_place(|| {
+            assert!(handler.seek(SeekFrom::Start(5000)).is_err());
+            assert_eq!(handler.seek(SeekFrom::Start(500)).unwrap(), 500);
+        });
+    }
+}
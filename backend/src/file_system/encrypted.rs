@@ -0,0 +1,854 @@
+//! Encryption at rest, wrapping any other file system.
+//!
+//! Every file starts with a random file id of [`FILE_ID_SIZE`] bytes, followed by
+//! segments of [`SEGMENT_SIZE`] plaintext bytes that are each sealed with
+//! XChaCha20-Poly1305 under a random nonce. The associated data of a segment is
+//! the file id, its index and if it's the final segment (like the STREAM construction),
+//! so segments can't be reordered, moved between files or cut off at the end.
+//! Only the last segment is shorter than [`SEGMENT_SIZE`] and it's the only final one,
+//! files that end on a segment boundary get an empty final segment.
+//! Every segment is stored as `nonce || ciphertext || tag` right after the previous one,
+//! which keeps the ciphertext offset of any plaintext position a simple calculation.
+//! The inner file system only ever sees ciphertext.
+
+use async_trait::async_trait;
+use chacha20poly1305::{
+    Key, KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, Payload},
+};
+use futures_util::StreamExt;
+use rand::RngCore;
+use sf_core::Tier;
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io::{Error, ErrorKind, Result, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use crate::file_system::{FSStream, FileHandler, FileMetadata, FileSystem, block_on, slice_stream};
+
+/// Plaintext bytes per segment
+const SEGMENT_SIZE: u64 = 64 * 1024;
+const FILE_ID_SIZE: u64 = 32;
+const NONCE_SIZE: u64 = 24;
+const TAG_SIZE: u64 = 16;
+/// Bytes every segment takes up on top of its plaintext
+const OVERHEAD: u64 = NONCE_SIZE + TAG_SIZE;
+const STORED_SEGMENT_SIZE: u64 = SEGMENT_SIZE + OVERHEAD;
+
+type FileId = [u8; FILE_ID_SIZE as usize];
+
+pub struct Encrypted {
+    inner: Arc<dyn FileSystem>,
+    cipher: XChaCha20Poly1305,
+    /// Where the handlers of unfinished uploads stopped, by path,
+    /// so the next one can continue without reading the file back
+    resumable: Arc<Mutex<HashMap<String, Resumable>>>,
+}
+
+/// What an [`EncryptedHandler`] needs to continue where another one stopped
+struct Resumable {
+    file_id: FileId,
+    segment: u64,
+    pending: Vec<u8>,
+}
+
+impl Debug for Encrypted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Encrypted({:?})", self.inner)
+    }
+}
+
+impl Encrypted {
+    /// `key` is the hex encoded 256-bit key
+    pub fn new(inner: Box<dyn FileSystem>, key: &str) -> Result<Self> {
+        let key = hex::decode(key.trim()).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        if key.len() != 32 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The encryption key has to be 32 bytes (64 hex characters)",
+            ));
+        }
+
+        Ok(Self {
+            inner: Arc::from(inner),
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&key)),
+            resumable: Arc::default(),
+        })
+    }
+
+    /// The file at `path` was replaced or is gone, so its upload can't be continued
+    fn forget(&self, path: &str) {
+        self.resumable.lock().unwrap().remove(path);
+    }
+
+    /// The id of an already stored file, [`None`] if there's nothing stored yet
+    async fn read_file_id(&self, path: &str) -> Result<Option<FileId>> {
+        let mut stream = match self.inner.read_range(path, 0, Some(FILE_ID_SIZE)).await {
+            Ok(stream) => stream,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let mut header = vec![];
+        while let Some(chunk) = stream.next().await {
+            header.extend(chunk?);
+        }
+        Ok(header.try_into().ok())
+    }
+}
+
+#[async_trait]
+impl FileSystem for Encrypted {
+    #[tracing::instrument]
+    async fn read(&self, path: &str) -> Result<Vec<u8>> {
+        let data = self.inner.read(path).await?;
+        if (data.len() as u64) < FILE_ID_SIZE {
+            return Err(missing_header());
+        }
+
+        let (file_id, segments) = data.split_at(FILE_ID_SIZE as usize);
+        let file_id = file_id.try_into().unwrap();
+        let mut plaintext = Vec::with_capacity(plaintext_size(data.len() as u64) as usize);
+        let mut finished = false;
+        for (index, segment) in segments.chunks(STORED_SEGMENT_SIZE as usize).enumerate() {
+            let is_final = segment.len() < STORED_SEGMENT_SIZE as usize;
+            plaintext.extend(open(
+                &self.cipher,
+                &file_id,
+                index as u64,
+                is_final,
+                segment,
+            )?);
+            finished = is_final;
+        }
+
+        if !finished {
+            return Err(cut_short());
+        }
+        Ok(plaintext)
+    }
+
+    #[tracing::instrument]
+    async fn read_stream(&self, path: &str) -> Result<FSStream> {
+        let stream = self.inner.read_stream(path).await?;
+        Ok(decrypt_stream(stream, self.cipher.clone(), None, 0, None))
+    }
+
+    /// Only reads and decrypts the segments the range is in
+    #[tracing::instrument]
+    async fn read_range(&self, path: &str, start: u64, len: Option<u64>) -> Result<FSStream> {
+        let file_id = self.read_file_id(path).await?.ok_or_else(missing_header)?;
+
+        let first_segment = start / SEGMENT_SIZE;
+        let stored_len = len.map(|len| {
+            let end_segment = start.saturating_add(len).div_ceil(SEGMENT_SIZE);
+            (end_segment - first_segment) * STORED_SEGMENT_SIZE
+        });
+
+        let stream = self
+            .inner
+            .read_range(
+                path,
+                FILE_ID_SIZE + first_segment * STORED_SEGMENT_SIZE,
+                stored_len,
+            )
+            .await?;
+        let plaintext = decrypt_stream(
+            stream,
+            self.cipher.clone(),
+            Some(file_id),
+            first_segment,
+            stored_len,
+        );
+        Ok(slice_stream(
+            plaintext,
+            start - first_segment * SEGMENT_SIZE,
+            len,
+        ))
+    }
+
+    #[tracing::instrument(skip(data))]
+    async fn write(&self, path: &str, data: &[u8]) -> Result<()> {
+        self.forget(path);
+
+        let file_id = new_file_id();
+        let mut ciphertext = Vec::with_capacity(stored_size(data.len() as u64) as usize);
+        ciphertext.extend(file_id);
+
+        let segments = data.len() as u64 / SEGMENT_SIZE + 1;
+        for index in 0..segments {
+            let start = (index * SEGMENT_SIZE) as usize;
+            let end = (start + SEGMENT_SIZE as usize).min(data.len());
+            let is_final = index == segments - 1;
+            ciphertext.extend(seal(
+                &self.cipher,
+                &file_id,
+                index,
+                is_final,
+                &data[start..end],
+            )?);
+        }
+        self.inner.write(path, &ciphertext).await
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        self.forget(path);
+        self.inner.delete(path).await
+    }
+
+    async fn exists(&self, path: &str) -> Result<bool> {
+        self.inner.exists(path).await
+    }
+
+    #[tracing::instrument]
+    async fn metadata(&self, path: &str) -> Result<FileMetadata> {
+        let mut metadata = self.inner.metadata(path).await?;
+        if !metadata.is_dir {
+            metadata.size = plaintext_size(metadata.size);
+        }
+        Ok(metadata)
+    }
+
+    async fn stored_size(&self, path: &str) -> Result<u64> {
+        self.inner.stored_size(path).await
+    }
+
+    /// Continues where the last handler of `path` stopped, if it's known.
+    /// Otherwise only the file id and the segment the handler seeks into are read back
+    #[tracing::instrument]
+    async fn get_file_handler(&self, path: &str) -> Result<FileHandler> {
+        let resumable = self.resumable.lock().unwrap().remove(path);
+        let (file_id, header_written, segment, pending) = match resumable {
+            Some(resumable) => (
+                resumable.file_id,
+                true,
+                resumable.segment,
+                resumable.pending,
+            ),
+            None => match self.read_file_id(path).await? {
+                Some(file_id) => (file_id, true, 0, vec![]),
+                None => (new_file_id(), false, 0, vec![]),
+            },
+        };
+
+        let inner = self.inner.get_file_handler(path).await?;
+        Ok(Box::new(EncryptedHandler {
+            inner,
+            fs: self.inner.clone(),
+            resumable: self.resumable.clone(),
+            path: path.to_string(),
+            cipher: self.cipher.clone(),
+            file_id,
+            header_written,
+            segment,
+            pending,
+            dirty: false,
+        }))
+    }
+
+    async fn finish_upload(&self, path: &str) -> Result<()> {
+        self.forget(path);
+        self.inner.finish_upload(path).await
+    }
+
+    #[tracing::instrument]
+    async fn list_dir(&self, path: &str) -> Result<Vec<FileMetadata>> {
+        let mut entries = self.inner.list_dir(path).await?;
+        for entry in entries.iter_mut().filter(|e| !e.is_dir) {
+            entry.size = plaintext_size(entry.size);
+        }
+        Ok(entries)
+    }
+
+    async fn create_dir_all(&self, path: &str) -> Result<()> {
+        self.inner.create_dir_all(path).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.forget(from);
+        self.forget(to);
+        self.inner.rename(from, to).await
+    }
+
+    async fn delete_empty_dir(&self, path: &str) -> Result<()> {
+        self.inner.delete_empty_dir(path).await
+    }
+
+    /// What's stored doesn't depend on the path, so it's copied as is
+    async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        self.forget(to);
+        self.inner.copy(from, to).await
+    }
+
+    async fn repair(&self) -> Result<u64> {
+        self.inner.repair().await
+    }
+
+    async fn move_to_tier(&self, path: &str, tier: Tier) -> Result<()> {
+        self.inner.move_to_tier(path, tier).await
+    }
+
+    fn contains_mount_point(&self, path: &str) -> bool {
+        self.inner.contains_mount_point(path)
+    }
+
+    async fn root_directory(&self) -> PathBuf {
+        self.inner.root_directory().await
+    }
+}
+
+/// Encrypts everything written to it before passing it on to the inner handler.
+///
+/// The segment currently being written is kept in `pending` until it's full,
+/// flushing writes it out early as a shorter (final) segment that gets overwritten
+/// once more data arrives. Seeking to the middle of a segment that isn't buffered
+/// has to read it back from the file system first, so uploads should use a
+/// chunk size that is a multiple of [`SEGMENT_SIZE`].
+/// When dropped after a flush, where it stopped is kept for the next handler of the file
+struct EncryptedHandler {
+    inner: FileHandler,
+    fs: Arc<dyn FileSystem>,
+    resumable: Arc<Mutex<HashMap<String, Resumable>>>,
+    path: String,
+    cipher: XChaCha20Poly1305,
+    file_id: FileId,
+    /// If the file id is stored at the start of the file
+    header_written: bool,
+    /// Index of the segment `pending` belongs to
+    segment: u64,
+    /// Plaintext from the start of the current segment
+    pending: Vec<u8>,
+    /// If `pending` has changed since it was last written
+    dirty: bool,
+}
+
+impl EncryptedHandler {
+    fn position(&self) -> u64 {
+        self.segment * SEGMENT_SIZE + self.pending.len() as u64
+    }
+
+    fn write_pending(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        if !self.header_written {
+            self.inner.seek(SeekFrom::Start(0))?;
+            self.inner.write_all(&self.file_id)?;
+            self.header_written = true;
+        }
+
+        let is_final = (self.pending.len() as u64) < SEGMENT_SIZE;
+        let sealed = seal(
+            &self.cipher,
+            &self.file_id,
+            self.segment,
+            is_final,
+            &self.pending,
+        )?;
+        self.inner.seek(SeekFrom::Start(
+            FILE_ID_SIZE + self.segment * STORED_SEGMENT_SIZE,
+        ))?;
+        self.inner.write_all(&sealed)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Reads back the plaintext of an already stored segment
+    fn load_segment(&self, segment: u64) -> Result<Vec<u8>> {
+        let start = FILE_ID_SIZE + segment * STORED_SEGMENT_SIZE;
+        let sealed = block_on(async {
+            let mut stream = self
+                .fs
+                .read_range(&self.path, start, Some(STORED_SEGMENT_SIZE))
+                .await?;
+            let mut sealed = vec![];
+            while let Some(chunk) = stream.next().await {
+                sealed.extend(chunk?);
+            }
+            Ok::<_, Error>(sealed)
+        })?;
+
+        if sealed.is_empty() {
+            return Ok(vec![]);
+        }
+        let is_final = (sealed.len() as u64) < STORED_SEGMENT_SIZE;
+        open(&self.cipher, &self.file_id, segment, is_final, &sealed)
+    }
+}
+
+impl Write for EncryptedHandler {
+    fn write(&mut self, mut buf: &[u8]) -> Result<usize> {
+        let written = buf.len();
+
+        while !buf.is_empty() {
+            let space = SEGMENT_SIZE as usize - self.pending.len();
+            let (now, rest) = buf.split_at(space.min(buf.len()));
+            self.pending.extend_from_slice(now);
+            self.dirty = true;
+            buf = rest;
+
+            if self.pending.len() == SEGMENT_SIZE as usize {
+                self.write_pending()?;
+                self.segment += 1;
+                self.pending.clear();
+                // the empty final segment after it isn't written yet
+                self.dirty = true;
+            }
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.write_pending()?;
+        self.inner.flush()
+    }
+}
+
+impl Seek for EncryptedHandler {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(p) => p,
+            SeekFrom::Current(offset) => self
+                .position()
+                .checked_add_signed(offset)
+                .ok_or(Error::new(ErrorKind::InvalidInput, "Invalid seek"))?,
+            SeekFrom::End(_) => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "Can't seek from the end of an encrypted file",
+                ));
+            }
+        };
+
+        if target == self.position() {
+            return Ok(target);
+        }
+
+        let (segment, offset) = (target / SEGMENT_SIZE, (target % SEGMENT_SIZE) as usize);
+        if segment == self.segment && offset <= self.pending.len() {
+            self.pending.truncate(offset);
+            self.dirty = true;
+            return Ok(target);
+        }
+
+        // the segments before it were sealed with a file id that isn't known anymore
+        if !self.header_written && target > 0 {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "Can't continue an encrypted file that can't be read back",
+            ));
+        }
+
+        self.write_pending()?;
+        self.segment = segment;
+        self.pending = if offset == 0 {
+            vec![]
+        } else {
+            let mut existing = self.load_segment(segment)?;
+            existing.resize(offset, 0);
+            existing
+        };
+        self.dirty = false;
+
+        Ok(target)
+    }
+}
+
+impl Drop for EncryptedHandler {
+    fn drop(&mut self) {
+        // unflushed data never made it into the file
+        if self.dirty || !self.header_written {
+            return;
+        }
+
+        self.resumable.lock().unwrap().insert(
+            self.path.clone(),
+            Resumable {
+                file_id: self.file_id,
+                segment: self.segment,
+                pending: std::mem::take(&mut self.pending),
+            },
+        );
+    }
+}
+
+/// Where [`decrypt_stream`] is
+struct Decryption {
+    stream: FSStream,
+    cipher: XChaCha20Poly1305,
+    buffer: Vec<u8>,
+    /// Read from the start of the stream if it's not known
+    file_id: Option<FileId>,
+    segment: u64,
+    /// Ciphertext bytes asked for, the stream doesn't have to end with the final segment
+    /// if it stops after exactly that many
+    stored_len: Option<u64>,
+    received: u64,
+    finished: bool,
+    done: bool,
+}
+
+impl Decryption {
+    /// Fills the buffer up to `len` bytes or until the stream ends
+    async fn fill(&mut self, len: usize) -> Result<()> {
+        while self.buffer.len() < len {
+            match self.stream.next().await {
+                Some(chunk) => {
+                    let chunk = chunk?;
+                    self.received += chunk.len() as u64;
+                    self.buffer.extend(chunk);
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    async fn next_segment(&mut self) -> Option<Result<Vec<u8>>> {
+        if self.file_id.is_none() {
+            if let Err(err) = self.fill(FILE_ID_SIZE as usize).await {
+                return Some(Err(err));
+            }
+            if (self.buffer.len() as u64) < FILE_ID_SIZE {
+                return Some(Err(missing_header()));
+            }
+            let header = self.buffer.drain(..FILE_ID_SIZE as usize);
+            self.file_id = Some(header.as_slice().try_into().unwrap());
+        }
+
+        if let Err(err) = self.fill(STORED_SEGMENT_SIZE as usize).await {
+            return Some(Err(err));
+        }
+
+        if self.buffer.is_empty() {
+            let complete = self.stored_len.is_some_and(|len| self.received >= len);
+            return match self.finished || complete {
+                true => None,
+                false => Some(Err(cut_short())),
+            };
+        }
+        if self.finished {
+            return Some(Err(Error::new(
+                ErrorKind::InvalidData,
+                "Encrypted file continues after its final segment",
+            )));
+        }
+
+        let take = self.buffer.len().min(STORED_SEGMENT_SIZE as usize);
+        let sealed = self.buffer.drain(..take).collect::<Vec<u8>>();
+        let is_final = (take as u64) < STORED_SEGMENT_SIZE;
+        let plaintext = open(
+            &self.cipher,
+            &self.file_id.unwrap(),
+            self.segment,
+            is_final,
+            &sealed,
+        );
+
+        self.segment += 1;
+        self.finished = is_final;
+        Some(plaintext)
+    }
+}
+
+/// Decrypts a stream of ciphertext, starting at the segment `first_segment`.
+/// Without a `file_id` the stream has to start at the beginning of the file
+fn decrypt_stream(
+    stream: FSStream,
+    cipher: XChaCha20Poly1305,
+    file_id: Option<FileId>,
+    first_segment: u64,
+    stored_len: Option<u64>,
+) -> FSStream {
+    let state = Decryption {
+        stream,
+        cipher,
+        buffer: Vec::new(),
+        file_id,
+        segment: first_segment,
+        stored_len,
+        received: 0,
+        finished: false,
+        done: false,
+    };
+
+    Box::pin(futures_util::stream::unfold(
+        state,
+        |mut state| async move {
+            if state.done {
+                return None;
+            }
+
+            let segment = state.next_segment().await?;
+            state.done = segment.is_err();
+            Some((segment, state))
+        },
+    ))
+}
+
+fn new_file_id() -> FileId {
+    let mut file_id = [0u8; FILE_ID_SIZE as usize];
+    rand::rng().fill_bytes(&mut file_id);
+    file_id
+}
+
+/// What a segment is bound to, so it can only be decrypted in the same place
+fn associated_data(file_id: &FileId, segment: u64, is_final: bool) -> Vec<u8> {
+    let mut aad = file_id.to_vec();
+    aad.extend(segment.to_le_bytes());
+    aad.push(is_final as u8);
+    aad
+}
+
+fn seal(
+    cipher: &XChaCha20Poly1305,
+    file_id: &FileId,
+    segment: u64,
+    is_final: bool,
+    plaintext: &[u8],
+) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_SIZE as usize];
+    rand::rng().fill_bytes(&mut nonce);
+
+    let ciphertext = cipher
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: &associated_data(file_id, segment, is_final),
+            },
+        )
+        .map_err(|_| Error::other("Failed to encrypt segment"))?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    Ok(sealed)
+}
+
+fn open(
+    cipher: &XChaCha20Poly1305,
+    file_id: &FileId,
+    segment: u64,
+    is_final: bool,
+    sealed: &[u8],
+) -> Result<Vec<u8>> {
+    if (sealed.len() as u64) < OVERHEAD {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Encrypted segment is too short",
+        ));
+    }
+
+    let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE as usize);
+    cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &associated_data(file_id, segment, is_final),
+            },
+        )
+        .map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Encrypted segment {segment} failed authentication"),
+            )
+        })
+}
+
+fn missing_header() -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        "Encrypted file is too short to have a header",
+    )
+}
+
+fn cut_short() -> Error {
+    Error::new(
+        ErrorKind::UnexpectedEof,
+        "Encrypted file ends without its final segment",
+    )
+}
+
+/// How big a file with `stored` bytes of ciphertext is once decrypted
+fn plaintext_size(stored: u64) -> u64 {
+    let stored = stored.saturating_sub(FILE_ID_SIZE);
+    let full = stored / STORED_SEGMENT_SIZE;
+    let rest = stored % STORED_SEGMENT_SIZE;
+    full * SEGMENT_SIZE + rest.saturating_sub(OVERHEAD)
+}
+
+/// How many bytes of ciphertext `plaintext` bytes turn into
+fn stored_size(plaintext: u64) -> u64 {
+    let full = plaintext / SEGMENT_SIZE;
+    let rest = plaintext % SEGMENT_SIZE;
+    FILE_ID_SIZE + full * STORED_SEGMENT_SIZE + rest + OVERHEAD
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_system::Memory;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn encrypted() -> Encrypted {
+        Encrypted::new(Box::new(Memory::new()), KEY).unwrap()
+    }
+
+    fn data(len: u64) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    async fn collect(stream: FSStream) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        let mut stream = stream;
+        while let Some(chunk) = stream.next().await {
+            data.extend(chunk?);
+        }
+        Ok(data)
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let fs = encrypted();
+        for len in [0, 10, SEGMENT_SIZE, SEGMENT_SIZE + 5, 3 * SEGMENT_SIZE] {
+            let data = data(len);
+            fs.write("file", &data).await.unwrap();
+
+            assert_eq!(
+                fs.inner.metadata("file").await.unwrap().size,
+                stored_size(len)
+            );
+            assert_eq!(fs.metadata("file").await.unwrap().size, len);
+            assert_eq!(fs.stored_size("file").await.unwrap(), stored_size(len));
+            assert_eq!(fs.read("file").await.unwrap(), data);
+            assert_eq!(
+                collect(fs.read_stream("file").await.unwrap())
+                    .await
+                    .unwrap(),
+                data
+            );
+
+            let start = len / 3;
+            let range = fs.read_range("file", start, Some(len / 3)).await.unwrap();
+            assert_eq!(
+                collect(range).await.unwrap(),
+                data[start as usize..(2 * start) as usize]
+            );
+            let rest = fs.read_range("file", start, None).await.unwrap();
+            assert_eq!(collect(rest).await.unwrap(), data[start as usize..]);
+        }
+    }
+
+    /// Cuts off the end of the stored file, or replaces its segment `index`
+    async fn tamper(fs: &Encrypted, path: &str, f: impl FnOnce(&mut Vec<u8>)) {
+        let mut stored = fs.inner.read(path).await.unwrap();
+        f(&mut stored);
+        fs.inner.write(path, &stored).await.unwrap();
+    }
+
+    async fn assert_rejected(fs: &Encrypted, path: &str) {
+        assert!(fs.read(path).await.is_err());
+        assert!(collect(fs.read_stream(path).await.unwrap()).await.is_err());
+        let range = fs.read_range(path, 0, None).await.unwrap();
+        assert!(collect(range).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn truncation_is_detected() {
+        let fs = encrypted();
+
+        // cut at a segment boundary
+        fs.write("a", &data(2 * SEGMENT_SIZE + 10)).await.unwrap();
+        tamper(&fs, "a", |stored| {
+            stored.truncate((FILE_ID_SIZE + STORED_SEGMENT_SIZE) as usize)
+        })
+        .await;
+        assert_rejected(&fs, "a").await;
+
+        // without the empty final segment of a file that ends on a boundary
+        fs.write("b", &data(2 * SEGMENT_SIZE)).await.unwrap();
+        tamper(&fs, "b", |stored| {
+            stored.truncate(stored.len() - OVERHEAD as usize)
+        })
+        .await;
+        assert_rejected(&fs, "b").await;
+
+        // without anything but the header
+        fs.write("c", &data(10)).await.unwrap();
+        tamper(&fs, "c", |stored| stored.truncate(FILE_ID_SIZE as usize)).await;
+        assert_rejected(&fs, "c").await;
+    }
+
+    #[tokio::test]
+    async fn splicing_is_detected() {
+        let fs = encrypted();
+        fs.write("a", &data(3 * SEGMENT_SIZE)).await.unwrap();
+        fs.write("b", &vec![1; 3 * SEGMENT_SIZE as usize])
+            .await
+            .unwrap();
+
+        // the same segment of another file under the same key
+        let other = fs.inner.read("b").await.unwrap();
+        let segment = (FILE_ID_SIZE + STORED_SEGMENT_SIZE) as usize
+            ..(FILE_ID_SIZE + 2 * STORED_SEGMENT_SIZE) as usize;
+        tamper(&fs, "a", |stored| {
+            stored[segment.clone()].copy_from_slice(&other[segment])
+        })
+        .await;
+        assert_rejected(&fs, "a").await;
+
+        // the other file's id doesn't help either
+        fs.write("c", &data(10)).await.unwrap();
+        tamper(&fs, "c", |stored| {
+            stored[..FILE_ID_SIZE as usize].copy_from_slice(&other[..FILE_ID_SIZE as usize])
+        })
+        .await;
+        assert_rejected(&fs, "c").await;
+    }
+
+    /// Writes `data[from..to]` with a new handler like a resumed upload does
+    async fn write_with_handler(fs: &Encrypted, data: &[u8], from: usize, to: usize) {
+        let mut handler = fs.get_file_handler("upload").await.unwrap();
+        tokio::task::block_in_place(|| {
+            handler.seek(SeekFrom::Start(from as u64)).unwrap();
+            handler.write_all(&data[from..to]).unwrap();
+            handler.flush().unwrap();
+        });
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn resuming_uploads() {
+        let fs = encrypted();
+        let data = data(3 * SEGMENT_SIZE + 100);
+        let (first, second) = (SEGMENT_SIZE as usize + 123, 2 * SEGMENT_SIZE as usize + 7);
+
+        write_with_handler(&fs, &data, 0, first).await;
+        // where the handler stopped is still known
+        write_with_handler(&fs, &data, first, second).await;
+        // and where it's not, the partial segment is read back
+        fs.forget("upload");
+        write_with_handler(&fs, &data, second, data.len()).await;
+        fs.finish_upload("upload").await.unwrap();
+
+        assert_eq!(fs.read("upload").await.unwrap(), data);
+        assert_eq!(fs.metadata("upload").await.unwrap().size, data.len() as u64);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn handler_ending_on_a_segment_boundary() {
+        let fs = encrypted();
+        let data = data(2 * SEGMENT_SIZE);
+
+        write_with_handler(&fs, &data, 0, data.len()).await;
+        fs.finish_upload("upload").await.unwrap();
+
+        assert_eq!(fs.read("upload").await.unwrap(), data);
+    }
+}
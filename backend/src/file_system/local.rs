@@ -1,7 +1,7 @@
 use async_trait::async_trait;
 use std::{
     fmt::Debug,
-    io::Result,
+    io::{Result, SeekFrom},
     path::PathBuf,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
@@ -46,18 +46,26 @@ impl FileSystem for Local {
 
     #[tracing::instrument]
     async fn read_stream(&self, path: &str) -> Result<FSStream> {
+        self.read_range(path, 0, None).await
+    }
+
+    #[tracing::instrument]
+    async fn read_range(&self, path: &str, start: u64, len: Option<u64>) -> Result<FSStream> {
         let full_path = self.full_path(path);
-        tracing::debug!("Streaming from {:?}", full_path);
+        tracing::debug!("Streaming from {:?} at {}", full_path, start);
 
-        let file = fs::File::open(&full_path).await?;
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+        let mut file = fs::File::open(&full_path).await?;
+        if start > 0 {
+            file.seek(SeekFrom::Start(start)).await?;
+        }
 
         const CHUNK_SIZE: usize = 8192;
-        let mut reader = tokio::io::BufReader::new(file);
+        let mut reader = tokio::io::BufReader::new(file).take(len.unwrap_or(u64::MAX));
 
         let (tx, rx) = tokio::sync::mpsc::channel(16);
 
         tokio::spawn(async move {
-            use tokio::io::AsyncReadExt;
             loop {
                 let mut chunk = vec![0u8; CHUNK_SIZE];
                 match reader.read(&mut chunk).await {
@@ -181,6 +189,18 @@ impl FileSystem for Local {
         fs::rename(from_path, to_path).await
     }
 
+    #[tracing::instrument]
+    async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        let from_path = self.full_path(from);
+        let to_path = self.full_path(to);
+        tracing::debug!("{:?} to {:?}", from_path, to_path);
+        if let Some(parent) = to_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::copy(from_path, to_path).await?;
+        Ok(())
+    }
+
     #[tracing::instrument]
     async fn delete_empty_dir(&self, path: &str) -> Result<()> {
         let full_path = self.full_path(&path);
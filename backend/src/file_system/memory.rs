@@ -0,0 +1,376 @@
+//! A file system that only lives in memory, everything is lost on restart.
+//!
+//! Useful for throwaway instances and for running the whole backend in tests
+
+use async_trait::async_trait;
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    io::{Error, ErrorKind, Result, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::file_system::{FSStream, FileHandler, FileMetadata, FileSystem};
+
+/// How big the chunks sent through [`FileSystem::read_stream`] are
+const CHUNK_SIZE: usize = 8192;
+
+/// Every file and directory keyed by their normalized path (`a/b/c`, root is `""`)
+pub struct Memory {
+    nodes: RwLock<BTreeMap<String, Node>>,
+}
+
+impl Debug for Memory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Memory")
+    }
+}
+
+#[derive(Clone)]
+enum Node {
+    Dir { modified: u64 },
+    File(Arc<MemoryFile>),
+}
+
+struct MemoryFile {
+    data: RwLock<Vec<u8>>,
+    modified: AtomicU64,
+}
+
+impl MemoryFile {
+    fn new(data: Vec<u8>) -> Self {
+        Self {
+            data: RwLock::new(data),
+            modified: AtomicU64::new(now()),
+        }
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(String::new(), Node::Dir { modified: now() });
+
+        Self {
+            nodes: RwLock::new(nodes),
+        }
+    }
+
+    fn get(&self, path: &str) -> Option<Node> {
+        self.nodes.read().unwrap().get(&normalize(path)).cloned()
+    }
+
+    fn get_file(&self, path: &str) -> Result<Arc<MemoryFile>> {
+        match self.get(path) {
+            Some(Node::File(file)) => Ok(file),
+            Some(Node::Dir { .. }) => Err(Error::new(ErrorKind::IsADirectory, path)),
+            None => Err(Error::new(ErrorKind::NotFound, path)),
+        }
+    }
+
+    /// Creates (or replaces) a file, its parent directory has to exist
+    fn insert_file(&self, path: &str, data: Vec<u8>) -> Result<Arc<MemoryFile>> {
+        let path = normalize(path);
+        let mut nodes = self.nodes.write().unwrap();
+
+        if !matches!(nodes.get(parent(&path)), Some(Node::Dir { .. })) {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "Parent directory not found",
+            ));
+        }
+        if let Some(Node::Dir { .. }) = nodes.get(&path) {
+            return Err(Error::new(ErrorKind::IsADirectory, path));
+        }
+
+        let file = Arc::new(MemoryFile::new(data));
+        nodes.insert(path, Node::File(file.clone()));
+        Ok(file)
+    }
+
+    fn metadata_of(path: &str, node: &Node) -> FileMetadata {
+        match node {
+            Node::Dir { modified } => FileMetadata {
+                path: path.to_string(),
+                is_dir: true,
+                size: 0,
+                modified: *modified,
+            },
+            Node::File(file) => FileMetadata {
+                path: path.to_string(),
+                is_dir: false,
+                size: file.data.read().unwrap().len() as u64,
+                modified: file.modified.load(Ordering::Relaxed),
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl FileSystem for Memory {
+    #[tracing::instrument]
+    async fn read(&self, path: &str) -> Result<Vec<u8>> {
+        tracing::debug!("{:?}", path);
+        Ok(self.get_file(path)?.data.read().unwrap().clone())
+    }
+
+    #[tracing::instrument]
+    async fn read_stream(&self, path: &str) -> Result<FSStream> {
+        tracing::debug!("Streaming from {:?}", path);
+        let data = self.get_file(path)?.data.read().unwrap().clone();
+
+        let chunks = data
+            .chunks(CHUNK_SIZE)
+            .map(|c| Ok(c.to_vec()))
+            .collect::<Vec<_>>();
+        Ok(Box::pin(tokio_stream::iter(chunks)))
+    }
+
+    #[tracing::instrument]
+    async fn read_range(&self, path: &str, start: u64, len: Option<u64>) -> Result<FSStream> {
+        tracing::debug!("Streaming from {:?} at {}", path, start);
+        let file = self.get_file(path)?;
+        let data = file.data.read().unwrap();
+
+        let start = (start as usize).min(data.len());
+        let end = match len {
+            Some(len) => start.saturating_add(len as usize).min(data.len()),
+            None => data.len(),
+        };
+        let chunks = data[start..end]
+            .chunks(CHUNK_SIZE)
+            .map(|c| Ok(c.to_vec()))
+            .collect::<Vec<_>>();
+        Ok(Box::pin(tokio_stream::iter(chunks)))
+    }
+
+    #[tracing::instrument(skip(data))]
+    async fn write(&self, path: &str, data: &[u8]) -> Result<()> {
+        tracing::debug!("{:?}", path);
+        self.create_dir_all(parent(&normalize(path))).await?;
+        self.insert_file(path, data.to_vec())?;
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    async fn delete(&self, path: &str) -> Result<()> {
+        tracing::debug!("{:?}", path);
+        let path = normalize(path);
+        let mut nodes = self.nodes.write().unwrap();
+
+        match nodes.get(&path) {
+            Some(Node::File(_)) => {
+                nodes.remove(&path);
+                Ok(())
+            }
+            Some(Node::Dir { .. }) => Err(Error::new(ErrorKind::IsADirectory, path)),
+            None => Err(Error::new(ErrorKind::NotFound, path)),
+        }
+    }
+
+    #[tracing::instrument]
+    async fn exists(&self, path: &str) -> Result<bool> {
+        tracing::debug!("{:?}", path);
+        Ok(self.get(path).is_some())
+    }
+
+    #[tracing::instrument]
+    async fn metadata(&self, path: &str) -> Result<FileMetadata> {
+        tracing::debug!("{:?}", path);
+        match self.get(path) {
+            Some(node) => Ok(Self::metadata_of(&normalize(path), &node)),
+            None => Err(Error::new(ErrorKind::NotFound, path)),
+        }
+    }
+
+    #[tracing::instrument]
+    async fn get_file_handler(&self, path: &str) -> Result<FileHandler> {
+        tracing::debug!("{:?}", path);
+        let file = match self.get_file(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => self.insert_file(path, vec![])?,
+            Err(err) => return Err(err),
+        };
+
+        Ok(Box::new(MemoryHandler { file, position: 0 }))
+    }
+
+    #[tracing::instrument]
+    async fn list_dir(&self, path: &str) -> Result<Vec<FileMetadata>> {
+        tracing::debug!("{:?}", path);
+        let path = normalize(path);
+        let nodes = self.nodes.read().unwrap();
+
+        if !matches!(nodes.get(&path), Some(Node::Dir { .. })) {
+            return Err(Error::new(ErrorKind::NotFound, path));
+        }
+
+        let prefix = if path.is_empty() {
+            path
+        } else {
+            format!("{path}/")
+        };
+
+        Ok(nodes
+            .range(prefix.clone()..)
+            .take_while(|(p, _)| p.starts_with(&prefix))
+            .filter(|(p, _)| !p.is_empty() && !p[prefix.len()..].contains('/'))
+            .map(|(p, node)| Self::metadata_of(&p[prefix.len()..], node))
+            .collect())
+    }
+
+    #[tracing::instrument]
+    async fn create_dir_all(&self, path: &str) -> Result<()> {
+        tracing::debug!("{:?}", path);
+        let path = normalize(path);
+        let mut nodes = self.nodes.write().unwrap();
+
+        let mut current = String::new();
+        for part in path.split('/').filter(|p| !p.is_empty()) {
+            if !current.is_empty() {
+                current.push('/');
+            }
+            current.push_str(part);
+
+            match nodes.get(&current) {
+                Some(Node::File(_)) => {
+                    return Err(Error::new(ErrorKind::NotADirectory, current));
+                }
+                Some(Node::Dir { .. }) => (),
+                None => {
+                    nodes.insert(current.clone(), Node::Dir { modified: now() });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        tracing::debug!("{:?} to {:?}", from, to);
+        let (from, to) = (normalize(from), normalize(to));
+        let mut nodes = self.nodes.write().unwrap();
+
+        if !nodes.contains_key(&from) {
+            return Err(Error::new(ErrorKind::NotFound, from));
+        }
+        if !matches!(nodes.get(parent(&to)), Some(Node::Dir { .. })) {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "Parent directory not found",
+            ));
+        }
+
+        // move the node itself and everything below it if it's a directory
+        let from_prefix = format!("{from}/");
+        let moved = nodes
+            .keys()
+            .filter(|k| **k == from || k.starts_with(&from_prefix))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for key in moved {
+            if let Some(node) = nodes.remove(&key) {
+                nodes.insert(format!("{}{}", to, &key[from.len()..]), node);
+            }
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    async fn delete_empty_dir(&self, path: &str) -> Result<()> {
+        tracing::debug!("{:?}", path);
+        let is_empty = self.list_dir(path).await?.is_empty();
+
+        if is_empty {
+            self.nodes.write().unwrap().remove(&normalize(path));
+            Ok(())
+        } else {
+            Err(Error::other("Tried to delete a non-empty directory"))
+        }
+    }
+
+    async fn root_directory(&self) -> PathBuf {
+        PathBuf::from("")
+    }
+}
+
+/// A handle that writes straight into the file's buffer,
+/// so multiple uploads to different files never block each other
+struct MemoryHandler {
+    file: Arc<MemoryFile>,
+    position: u64,
+}
+
+impl Write for MemoryHandler {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut data = self.file.data.write().unwrap();
+        let start = self.position as usize;
+        let end = start + buf.len();
+
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+
+        self.position = end as u64;
+        self.file.modified.store(now(), Ordering::Relaxed);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryHandler {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let len = self.file.data.read().unwrap().len() as u64;
+        let target = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => len.checked_add_signed(offset),
+        };
+
+        match target {
+            Some(target) => {
+                self.position = target;
+                Ok(target)
+            }
+            None => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Tried to seek before the start of the file",
+            )),
+        }
+    }
+}
+
+fn normalize(path: &str) -> String {
+    path.replace("\\", "/")
+        .split('/')
+        .filter(|p| !p.is_empty() && *p != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map(|(p, _)| p).unwrap_or("")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0))
+        .as_secs()
+}
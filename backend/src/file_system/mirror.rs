@@ -0,0 +1,740 @@
+//! Keeps the same files on multiple file systems at once.
+//!
+//! Everything is written to the primary and every replica, reads are served by
+//! the first file system that doesn't fail. A replica that fails while being written
+//! to is skipped instead of failing the whole operation, it then falls behind until
+//! it's caught up again with [`FileSystem::repair`].
+//! The primary is always required to succeed.
+//!
+//! Which replicas are behind on which paths is kept in [`BEHIND_FILE`] on the primary,
+//! so uploads resumed after a restart still skip them.
+
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    fmt::Debug,
+    io::{Error, ErrorKind, Result, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+use tokio::sync::Mutex;
+
+use crate::file_system::{
+    FSStream, FileHandler, FileMetadata, FileSystem, Tier, block_on, copy_file,
+};
+
+/// Where the paths replicas are behind on are stored, relative to the primary's root
+const BEHIND_FILE: &str = ".mirror_behind";
+
+pub struct Mirror {
+    primary: Arc<dyn FileSystem>,
+    replicas: Vec<Arc<dyn FileSystem>>,
+    behind: Arc<Behind>,
+}
+
+/// Paths (replica index, path) that a replica missed a change of, it isn't read from
+/// until it has been caught up. A path also covers everything below it
+struct Behind {
+    primary: Arc<dyn FileSystem>,
+    entries: Mutex<HashSet<(usize, String)>>,
+}
+
+impl Behind {
+    async fn load(primary: Arc<dyn FileSystem>) -> Self {
+        let entries = match primary.read(BEHIND_FILE).await {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|err| {
+                tracing::warn!("Ignoring the invalid {BEHIND_FILE:?}: {err:?}");
+                HashSet::new()
+            }),
+            Err(err) if err.kind() == ErrorKind::NotFound => HashSet::new(),
+            Err(err) => {
+                tracing::warn!("Failed to read {BEHIND_FILE:?}: {err:?}");
+                HashSet::new()
+            }
+        };
+
+        Self {
+            primary,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    async fn contains(&self, replica: usize, path: &str) -> bool {
+        self.entries.lock().await.iter().any(|(index, behind)| {
+            *index == replica
+                && (behind == path
+                    || path
+                        .strip_prefix(behind.as_str())
+                        .is_some_and(|rest| rest.starts_with('/')))
+        })
+    }
+
+    async fn insert(&self, replica: usize, path: &str) {
+        let mut entries = self.entries.lock().await;
+        if entries.insert((replica, path.to_string())) {
+            self.save(&entries).await;
+        }
+    }
+
+    async fn retain(&self, f: impl Fn(&(usize, String)) -> bool) {
+        let mut entries = self.entries.lock().await;
+        let len = entries.len();
+        entries.retain(f);
+        if entries.len() != len {
+            self.save(&entries).await;
+        }
+    }
+
+    /// Failing to save isn't fatal, the replica is still skipped until a restart
+    async fn save(&self, entries: &HashSet<(usize, String)>) {
+        let result = if entries.is_empty() {
+            match self.primary.delete(BEHIND_FILE).await {
+                Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+                result => result,
+            }
+        } else {
+            match serde_json::to_vec(entries) {
+                Ok(data) => self.primary.write(BEHIND_FILE, &data).await,
+                Err(err) => Err(Error::other(err)),
+            }
+        };
+
+        if let Err(err) = result {
+            tracing::warn!("Failed to save {BEHIND_FILE:?}: {err:?}");
+        }
+    }
+}
+
+impl Debug for Mirror {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Mirror({:?} -> {:?})", self.primary, self.replicas)
+    }
+}
+
+impl Mirror {
+    pub async fn new(primary: Arc<dyn FileSystem>, replicas: Vec<Arc<dyn FileSystem>>) -> Self {
+        Self {
+            behind: Arc::new(Behind::load(primary.clone()).await),
+            primary,
+            replicas,
+        }
+    }
+
+    /// The primary followed by every replica that isn't behind on `path`
+    async fn readable(&self, path: &str) -> Vec<&dyn FileSystem> {
+        let mut readable = vec![self.primary.as_ref()];
+        for (index, replica) in self.replicas.iter().enumerate() {
+            if !self.behind.contains(index, path).await {
+                readable.push(replica.as_ref());
+            }
+        }
+        readable
+    }
+
+    /// Runs `op` on every replica, failures are logged and leave the replica behind on `paths`
+    async fn on_replicas<'a, F, Fut>(&'a self, what: &str, paths: &[&str], op: F)
+    where
+        F: Fn(&'a dyn FileSystem) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        for (index, replica) in self.replicas.iter().enumerate() {
+            match op(replica.as_ref()).await {
+                Ok(_) => (),
+                Err(err) if err.kind() == ErrorKind::NotFound => (),
+                Err(err) => {
+                    tracing::warn!("Replica {replica:?} failed to {what}: {err:?}");
+                    for path in paths {
+                        self.behind.insert(index, path).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Copies whatever is missing or different on the replica from the primary
+    /// and deletes what the primary doesn't have, returns how many files changed
+    /// and if anything failed
+    async fn repair_replica(&self, replica: &dyn FileSystem) -> Result<(u64, bool)> {
+        let (mut repaired, mut failed) = (0, false);
+
+        let mut dirs = vec![String::new()];
+        while let Some(dir) = dirs.pop() {
+            for entry in self.primary.list_dir(&dir).await? {
+                let path = join(&dir, &entry.path);
+                if path == BEHIND_FILE {
+                    continue;
+                }
+
+                if entry.is_dir {
+                    if let Err(err) = replica.create_dir_all(&path).await {
+                        tracing::warn!("Replica {replica:?} failed to create {path:?}: {err:?}");
+                        failed = true;
+                    }
+                    dirs.push(path);
+                    continue;
+                }
+
+                match same_contents(self.primary.as_ref(), replica, &path, entry.size).await {
+                    Ok(true) => continue,
+                    Ok(false) => (),
+                    Err(err) => {
+                        tracing::warn!("Failed to compare {path:?} on {replica:?}: {err:?}");
+                        failed = true;
+                        continue;
+                    }
+                }
+
+                match copy_file(self.primary.as_ref(), replica, &path).await {
+                    Ok(_) => {
+                        tracing::info!("Copied {path:?} to {replica:?}");
+                        repaired += 1;
+                    }
+                    Err(err) => {
+                        tracing::warn!("Failed to copy {path:?} to {replica:?}: {err:?}");
+                        failed = true;
+                    }
+                }
+            }
+        }
+
+        // left behind by deletes & renames the replica missed,
+        // directories are deleted after everything in them
+        let (mut dirs, mut stale_dirs) = (vec![String::new()], vec![]);
+        while let Some(dir) = dirs.pop() {
+            let entries = match replica.list_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(err) => {
+                    tracing::warn!("Replica {replica:?} failed to list {dir:?}: {err:?}");
+                    failed = true;
+                    continue;
+                }
+            };
+            for entry in entries {
+                let path = join(&dir, &entry.path);
+                if entry.is_dir {
+                    if !self.primary.exists(&path).await? {
+                        stale_dirs.push(path.clone());
+                    }
+                    dirs.push(path);
+                    continue;
+                }
+                if path == BEHIND_FILE || self.primary.exists(&path).await? {
+                    continue;
+                }
+
+                match replica.delete(&path).await {
+                    Ok(_) => {
+                        tracing::info!("Deleted {path:?} from {replica:?}");
+                        repaired += 1;
+                    }
+                    Err(err) => {
+                        tracing::warn!("Failed to delete {path:?} from {replica:?}: {err:?}");
+                        failed = true;
+                    }
+                }
+            }
+        }
+        for dir in stale_dirs.iter().rev() {
+            if let Err(err) = replica.delete_empty_dir(dir).await {
+                tracing::warn!("Failed to delete {dir:?} from {replica:?}: {err:?}");
+                failed = true;
+            }
+        }
+
+        Ok((repaired, failed))
+    }
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+/// Compares the SHA-256 digest of what's actually stored, the digests in the
+/// database can't be used as they're taken before encryption & compression
+async fn same_contents(
+    primary: &dyn FileSystem,
+    replica: &dyn FileSystem,
+    path: &str,
+    size: u64,
+) -> Result<bool> {
+    match replica.metadata(path).await {
+        Ok(metadata) if metadata.size != size => return Ok(false),
+        Ok(_) => (),
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    }
+
+    Ok(digest(primary, path).await? == digest(replica, path).await?)
+}
+
+async fn digest(fs: &dyn FileSystem, path: &str) -> Result<Vec<u8>> {
+    let mut stream = fs.read_stream(path).await?;
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = stream.next().await {
+        hasher.update(chunk?);
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+#[async_trait]
+impl FileSystem for Mirror {
+    #[tracing::instrument]
+    async fn read(&self, path: &str) -> Result<Vec<u8>> {
+        tracing::debug!("{:?}", path);
+        let mut last_err = None;
+        for fs in self.readable(path).await {
+            match fs.read(path).await {
+                Ok(data) => return Ok(data),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap())
+    }
+
+    #[tracing::instrument]
+    async fn read_stream(&self, path: &str) -> Result<FSStream> {
+        tracing::debug!("Streaming from {:?}", path);
+        let mut last_err = None;
+        for fs in self.readable(path).await {
+            match fs.read_stream(path).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => {
+                    tracing::warn!("{fs:?} failed to read {path:?}, trying the next one: {err:?}");
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap())
+    }
+
+    #[tracing::instrument]
+    async fn read_range(&self, path: &str, start: u64, len: Option<u64>) -> Result<FSStream> {
+        tracing::debug!("Streaming from {:?} at {}", path, start);
+        let mut last_err = None;
+        for fs in self.readable(path).await {
+            match fs.read_range(path, start, len).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => {
+                    tracing::warn!("{fs:?} failed to read {path:?}, trying the next one: {err:?}");
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap())
+    }
+
+    #[tracing::instrument(skip(data))]
+    async fn write(&self, path: &str, data: &[u8]) -> Result<()> {
+        tracing::debug!("{:?}", path);
+        self.primary.write(path, data).await?;
+        self.on_replicas("write", &[path], |r| r.write(path, data))
+            .await;
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    async fn delete(&self, path: &str) -> Result<()> {
+        tracing::debug!("{:?}", path);
+        let result = self.primary.delete(path).await;
+        self.on_replicas("delete", &[path], |r| r.delete(path))
+            .await;
+
+        // the primary might have lost it while a replica still had it
+        match result {
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    /// A file exists if any of the file systems has it,
+    /// as reads fall back to the replicas too
+    #[tracing::instrument]
+    async fn exists(&self, path: &str) -> Result<bool> {
+        tracing::debug!("{:?}", path);
+        let mut last_err = None;
+        for fs in self.readable(path).await {
+            match fs.exists(path).await {
+                Ok(true) => return Ok(true),
+                Ok(false) => last_err = None,
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        match last_err {
+            Some(err) => Err(err),
+            None => Ok(false),
+        }
+    }
+
+    #[tracing::instrument]
+    async fn metadata(&self, path: &str) -> Result<FileMetadata> {
+        tracing::debug!("{:?}", path);
+        let mut last_err = None;
+        for fs in self.readable(path).await {
+            match fs.metadata(path).await {
+                Ok(metadata) => return Ok(metadata),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap())
+    }
+
+    #[tracing::instrument]
+    async fn get_file_handler(&self, path: &str) -> Result<FileHandler> {
+        tracing::debug!("{:?}", path);
+        let primary = self.primary.get_file_handler(path).await?;
+
+        let mut replicas = vec![];
+        for (index, replica) in self.replicas.iter().enumerate() {
+            // it's copied over in full once the upload is done
+            if self.behind.contains(index, path).await {
+                continue;
+            }
+
+            match replica.get_file_handler(path).await {
+                Ok(handler) => replicas.push((index, handler)),
+                Err(err) => {
+                    tracing::warn!("Replica {replica:?} failed to open {path:?}: {err:?}");
+                    self.behind.insert(index, path).await;
+                }
+            }
+        }
+
+        Ok(Box::new(MirrorHandler {
+            primary,
+            replicas,
+            path: path.to_string(),
+            behind: self.behind.clone(),
+        }))
+    }
+
+    #[tracing::instrument]
+    async fn finish_upload(&self, path: &str) -> Result<()> {
+        tracing::debug!("{:?}", path);
+        self.primary.finish_upload(path).await?;
+
+        for (index, replica) in self.replicas.iter().enumerate() {
+            if !self.behind.contains(index, path).await
+                && let Err(err) = replica.finish_upload(path).await
+            {
+                tracing::warn!("Replica {replica:?} failed to finish {path:?}: {err:?}");
+                self.behind.insert(index, path).await;
+            }
+
+            if self.behind.contains(index, path).await {
+                match copy_file(self.primary.as_ref(), replica.as_ref(), path).await {
+                    Ok(_) => {
+                        self.behind
+                            .retain(|entry| *entry != (index, path.to_string()))
+                            .await;
+                    }
+                    Err(err) => {
+                        tracing::warn!("Failed to catch up {replica:?} on {path:?}: {err:?}")
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    async fn list_dir(&self, path: &str) -> Result<Vec<FileMetadata>> {
+        tracing::debug!("{:?}", path);
+        let mut last_err = None;
+        for fs in self.readable(path).await {
+            match fs.list_dir(path).await {
+                Ok(mut entries) => {
+                    if path.trim_matches('/').is_empty() {
+                        entries.retain(|entry| entry.path != BEHIND_FILE);
+                    }
+                    return Ok(entries);
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap())
+    }
+
+    #[tracing::instrument]
+    async fn create_dir_all(&self, path: &str) -> Result<()> {
+        tracing::debug!("{:?}", path);
+        self.primary.create_dir_all(path).await?;
+        self.on_replicas("create directory", &[path], |r| r.create_dir_all(path))
+            .await;
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        tracing::debug!("{:?} to {:?}", from, to);
+        self.primary.rename(from, to).await?;
+        self.on_replicas("rename", &[from, to], |r| r.rename(from, to))
+            .await;
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    async fn delete_empty_dir(&self, path: &str) -> Result<()> {
+        tracing::debug!("{:?}", path);
+        self.primary.delete_empty_dir(path).await?;
+        self.on_replicas("delete directory", &[path], |r| r.delete_empty_dir(path))
+            .await;
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        tracing::debug!("{:?} to {:?}", from, to);
+        self.primary.copy(from, to).await?;
+        self.on_replicas("copy", &[to], |r| r.copy(from, to)).await;
+        Ok(())
+    }
+
+    /// Makes every replica the same as the primary, by content and not just size.
+    /// Replicas that are completely caught up aren't behind on anything anymore
+    #[tracing::instrument]
+    async fn repair(&self) -> Result<u64> {
+        let mut repaired = 0;
+        for (index, replica) in self.replicas.iter().enumerate() {
+            let (count, failed) = self.repair_replica(replica.as_ref()).await?;
+            repaired += count;
+            if !failed {
+                self.behind.retain(|(i, _)| *i != index).await;
+            }
+        }
+
+        Ok(repaired)
+    }
+
+    #[tracing::instrument]
+    async fn move_to_tier(&self, path: &str, tier: Tier) -> Result<()> {
+        tracing::debug!("{:?} to {:?}", path, tier);
+        self.primary.move_to_tier(path, tier).await?;
+        // replicas without tiers just keep it where it is
+        self.on_replicas("move to another tier", &[path], |r| async move {
+            match r.move_to_tier(path, tier).await {
+                Err(err) if err.kind() == ErrorKind::Unsupported => Ok(()),
+                result => result,
+            }
+        })
+        .await;
+        Ok(())
+    }
+
+    fn contains_mount_point(&self, path: &str) -> bool {
+        self.primary.contains_mount_point(path)
+            || self.replicas.iter().any(|r| r.contains_mount_point(path))
+    }
+
+    async fn root_directory(&self) -> PathBuf {
+        self.primary.root_directory().await
+    }
+}
+
+/// Writes to the primary and every replica at once,
+/// replicas that fail are dropped and marked as behind
+struct MirrorHandler {
+    primary: FileHandler,
+    replicas: Vec<(usize, FileHandler)>,
+    path: String,
+    behind: Arc<Behind>,
+}
+
+impl MirrorHandler {
+    fn on_replicas(&mut self, mut op: impl FnMut(&mut FileHandler) -> Result<()>) {
+        let (path, behind) = (&self.path, &self.behind);
+        self.replicas
+            .retain_mut(|(index, handler)| match op(handler) {
+                Ok(_) => true,
+                Err(err) => {
+                    tracing::warn!("Replica {index} fell behind on {path:?}: {err:?}");
+                    block_on(behind.insert(*index, path));
+                    false
+                }
+            });
+    }
+}
+
+impl Write for MirrorHandler {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let written = self.primary.write(buf)?;
+        self.on_replicas(|r| r.write_all(&buf[..written]));
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.primary.flush()?;
+        self.on_replicas(|r| r.flush());
+        Ok(())
+    }
+}
+
+impl Seek for MirrorHandler {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let position = self.primary.seek(pos)?;
+        self.on_replicas(|r| match r.seek(SeekFrom::Start(position))? {
+            p if p == position => Ok(()),
+            _ => Err(Error::other("Replica seeked to the wrong position")),
+        });
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_system::Memory;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// A replica that fails every change while `failing` is set
+    #[derive(Debug, Default)]
+    struct Flaky {
+        inner: Memory,
+        failing: AtomicBool,
+    }
+
+    impl Flaky {
+        fn check(&self) -> Result<()> {
+            match self.failing.load(Ordering::Relaxed) {
+                true => Err(Error::other("unreachable")),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl FileSystem for Flaky {
+        async fn read(&self, path: &str) -> Result<Vec<u8>> {
+            self.inner.read(path).await
+        }
+        async fn read_stream(&self, path: &str) -> Result<FSStream> {
+            self.inner.read_stream(path).await
+        }
+        async fn write(&self, path: &str, data: &[u8]) -> Result<()> {
+            self.check()?;
+            self.inner.write(path, data).await
+        }
+        async fn delete(&self, path: &str) -> Result<()> {
+            self.check()?;
+            self.inner.delete(path).await
+        }
+        async fn exists(&self, path: &str) -> Result<bool> {
+            self.inner.exists(path).await
+        }
+        async fn metadata(&self, path: &str) -> Result<FileMetadata> {
+            self.inner.metadata(path).await
+        }
+        async fn get_file_handler(&self, path: &str) -> Result<FileHandler> {
+            self.check()?;
+            self.inner.get_file_handler(path).await
+        }
+        async fn list_dir(&self, path: &str) -> Result<Vec<FileMetadata>> {
+            self.inner.list_dir(path).await
+        }
+        async fn create_dir_all(&self, path: &str) -> Result<()> {
+            self.check()?;
+            self.inner.create_dir_all(path).await
+        }
+        async fn rename(&self, from: &str, to: &str) -> Result<()> {
+            self.check()?;
+            self.inner.rename(from, to).await
+        }
+        async fn delete_empty_dir(&self, path: &str) -> Result<()> {
+            self.check()?;
+            self.inner.delete_empty_dir(path).await
+        }
+        async fn root_directory(&self) -> PathBuf {
+            self.inner.root_directory().await
+        }
+    }
+
+    fn file_systems() -> (Arc<Memory>, Arc<Flaky>) {
+        (Arc::new(Memory::new()), Arc::new(Flaky::default()))
+    }
+
+    async fn mirror(primary: &Arc<Memory>, replica: &Arc<Flaky>) -> Mirror {
+        Mirror::new(primary.clone(), vec![replica.clone()]).await
+    }
+
+    /// A replica that missed the start of an upload is still skipped
+    /// when the upload is resumed after a restart
+    #[tokio::test(flavor = "multi_thread")]
+    async fn behind_survives_restarts() {
+        let (primary, replica) = file_systems();
+
+        replica.failing.store(true, Ordering::Relaxed);
+        let mut handler = mirror(&primary, &replica)
+            .await
+            .get_file_handler("file")
+            .await
+            .unwrap();
+        tokio::task::block_in_place(|| handler.write_all(b"first half, ").unwrap());
+        drop(handler);
+        replica.failing.store(false, Ordering::Relaxed);
+
+        let mirror = mirror(&primary, &replica).await;
+        let mut handler = mirror.get_file_handler("file").await.unwrap();
+        tokio::task::block_in_place(|| {
+            handler.seek(SeekFrom::End(0)).unwrap();
+            handler.write_all(b"second half").unwrap();
+        });
+        drop(handler);
+        mirror.finish_upload("file").await.unwrap();
+
+        assert_eq!(
+            replica.read("file").await.unwrap(),
+            b"first half, second half"
+        );
+        assert!(!primary.exists(BEHIND_FILE).await.unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn repair_compares_contents() {
+        let (primary, replica) = file_systems();
+        primary.write("a/file", b"the right one").await.unwrap();
+        replica.create_dir_all("a").await.unwrap();
+        replica.write("a/file", b"the wrong one").await.unwrap();
+
+        let mirror = mirror(&primary, &replica).await;
+        assert_eq!(mirror.repair().await.unwrap(), 1);
+        assert_eq!(replica.read("a/file").await.unwrap(), b"the right one");
+        assert_eq!(mirror.repair().await.unwrap(), 0);
+    }
+
+    /// Paths a replica missed a delete or rename of aren't read from it,
+    /// and are removed from it by a repair
+    #[tokio::test(flavor = "multi_thread")]
+    async fn failed_deletes_and_renames() {
+        let (primary, replica) = file_systems();
+        let mirror = mirror(&primary, &replica).await;
+        mirror.create_dir_all("dir").await.unwrap();
+        mirror.write("dir/a", b"a").await.unwrap();
+        mirror.write("b", b"b").await.unwrap();
+
+        replica.failing.store(true, Ordering::Relaxed);
+        mirror.delete("b").await.unwrap();
+        mirror.rename("dir", "moved").await.unwrap();
+        replica.failing.store(false, Ordering::Relaxed);
+
+        assert!(!mirror.exists("b").await.unwrap());
+        assert!(mirror.metadata("dir/a").await.is_err());
+        assert!(mirror.list_dir("dir").await.is_err());
+        assert_eq!(mirror.read("moved/a").await.unwrap(), b"a");
+        let root = mirror.list_dir("").await.unwrap();
+        assert_eq!(root.len(), 1, "{root:?}");
+
+        // the moved directory is copied, the old one & the deleted file removed
+        assert_eq!(mirror.repair().await.unwrap(), 3);
+        assert!(!replica.exists("b").await.unwrap());
+        assert!(!replica.exists("dir").await.unwrap());
+        assert_eq!(replica.read("moved/a").await.unwrap(), b"a");
+        assert!(!primary.exists(BEHIND_FILE).await.unwrap());
+    }
+}
@@ -1,17 +1,36 @@
+mod compressed;
+mod encrypted;
 mod local;
+mod memory;
+mod mirror;
+mod mounts;
+mod s3;
 mod ssh;
+mod swappable;
+mod tiered;
+mod webdav;
 
 use async_trait::async_trait;
+use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
-use sf_core::FileMetadata;
+use sf_core::{FileMetadata, Tier};
 use std::{
     fmt::Debug,
-    io::{Result, Seek, Write},
-    path::PathBuf,
+    io::{Error, ErrorKind, Result, Seek, Write},
+    path::{Path, PathBuf},
 };
 
+pub use compressed::Compressed;
+pub use encrypted::Encrypted;
 pub use local::Local;
+pub use memory::Memory;
+pub use mirror::Mirror;
+pub use mounts::Mounts;
+pub use s3::S3;
 pub use ssh::SSH;
+pub use swappable::Swappable;
+pub use tiered::Tiered;
+pub use webdav::WebDAV;
 
 pub type FSStream = std::pin::Pin<
     Box<dyn tokio_stream::Stream<Item = std::result::Result<Vec<u8>, std::io::Error>> + Send>,
@@ -21,21 +40,155 @@ pub trait WriteSeek: Write + Seek {}
 impl<T: Write + Seek> WriteSeek for T {}
 pub type FileHandler = Box<dyn WriteSeek + Send + Sync>;
 
+/// Runs a future to completion from synchronous code,
+/// like the [`FileHandler`] of network backends that have to talk to an async client
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(future))
+}
+
+/// Copies a whole file from one file system to another, replacing it if it exists
+pub(crate) async fn copy_file(
+    from: &(impl FileSystem + ?Sized),
+    to: &(impl FileSystem + ?Sized),
+    path: &str,
+) -> Result<()> {
+    copy_file_as(from, path, to, path).await
+}
+
+/// Like [`copy_file`], with the copy at another path
+pub(crate) async fn copy_file_as(
+    from: &(impl FileSystem + ?Sized),
+    from_path: &str,
+    to: &(impl FileSystem + ?Sized),
+    path: &str,
+) -> Result<()> {
+    if let Some(parent) = Path::new(path).parent() {
+        to.create_dir_all(&parent.to_string_lossy()).await?;
+    }
+    // handlers write in place, so anything past the new end would be left behind
+    if to.exists(path).await? {
+        to.delete(path).await?;
+    }
+
+    let mut stream = from.read_stream(from_path).await?;
+    let mut handler = to.get_file_handler(path).await?;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        tokio::task::block_in_place(|| handler.write_all(&chunk))?;
+    }
+    tokio::task::block_in_place(|| {
+        handler.flush()?;
+        drop(handler);
+        Ok::<_, std::io::Error>(())
+    })?;
+
+    to.finish_upload(path).await
+}
+
+/// Cuts `len` bytes starting at `start` out of a stream, or everything after `start`
+/// when `len` is [`None`]. Stops reading from `stream` once it has enough
+pub(crate) fn slice_stream(stream: FSStream, start: u64, len: Option<u64>) -> FSStream {
+    let state = (stream, start, len);
+    Box::pin(futures_util::stream::unfold(
+        state,
+        |(mut stream, mut skip, remaining)| async move {
+            loop {
+                if remaining == Some(0) {
+                    return None;
+                }
+
+                let mut chunk = match stream.next().await? {
+                    Ok(chunk) => chunk,
+                    Err(err) => return Some((Err(err), (stream, skip, Some(0)))),
+                };
+                if skip >= chunk.len() as u64 {
+                    skip -= chunk.len() as u64;
+                    continue;
+                }
+
+                chunk.drain(..skip as usize);
+                if let Some(remaining) = remaining {
+                    chunk.truncate(remaining.min(chunk.len() as u64) as usize);
+                }
+                let remaining = remaining.map(|r| r - chunk.len() as u64);
+                return Some((Ok(chunk), (stream, 0, remaining)));
+            }
+        },
+    ))
+}
+
+/// The HTTP `Range` header asking for what [`FileSystem::read_range`] reads,
+/// `len` can't be zero since that range can't be written down
+pub(crate) fn range_header(start: u64, len: Option<u64>) -> String {
+    match len {
+        Some(len) => format!("bytes={start}-{}", start.saturating_add(len) - 1),
+        None => format!("bytes={start}-"),
+    }
+}
+
 #[allow(unused)]
 #[async_trait]
 pub trait FileSystem: Send + Sync + Debug {
     async fn read(&self, path: &str) -> Result<Vec<u8>>;
     async fn read_stream(&self, path: &str) -> Result<FSStream>;
+    /// Reads `len` bytes starting at `start`, or everything after `start` when `len` is [`None`].
+    /// Like reading a file, a range past the end just returns fewer bytes (or none).
+    /// Skips over the start of [`FileSystem::read_stream`] unless the file system can seek
+    async fn read_range(&self, path: &str, start: u64, len: Option<u64>) -> Result<FSStream> {
+        let stream = self.read_stream(path).await?;
+        Ok(slice_stream(stream, start, len))
+    }
     async fn write(&self, path: &str, data: &[u8]) -> Result<()>;
     async fn delete(&self, path: &str) -> Result<()>;
     async fn exists(&self, path: &str) -> Result<bool>;
     async fn metadata(&self, path: &str) -> Result<FileMetadata>;
+    /// Size of the file as it's read back, which is what [`FileSystem::metadata`] reports
+    /// unless the file system stores files in another size, like compressed ones
+    async fn content_size(&self, path: &str) -> Result<u64> {
+        Ok(self.metadata(path).await?.size)
+    }
+    /// Bytes the file takes up in storage, which is what [`FileSystem::metadata`] reports
+    /// unless the file system reports a size from before it changed the contents, like encrypted ones
+    async fn stored_size(&self, path: &str) -> Result<u64> {
+        Ok(self.metadata(path).await?.size)
+    }
     async fn get_file_handler(&self, path: &str) -> Result<FileHandler>;
+    /// Called once every chunk of an upload has been written via [`FileSystem::get_file_handler`],
+    /// for file systems that need to do something before the file actually exists
+    async fn finish_upload(&self, _path: &str) -> Result<()> {
+        Ok(())
+    }
 
     async fn list_dir(&self, path: &str) -> Result<Vec<FileMetadata>>;
     async fn create_dir_all(&self, path: &str) -> Result<()>;
     async fn rename(&self, from: &str, to: &str) -> Result<()>;
     async fn delete_empty_dir(&self, path: &str) -> Result<()>;
 
+    /// Copies a file to another path, replacing it if it exists.
+    /// Streams it through the server unless the file system can copy on its own
+    async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        copy_file_as(self, from, self, to).await
+    }
+
+    /// Brings copies that fell behind back in sync, returns how many files were copied.
+    /// Only does something for file systems that keep more than one copy of every file
+    async fn repair(&self) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Moves a file to another storage tier, for file systems that have them
+    async fn move_to_tier(&self, _path: &str, _tier: Tier) -> Result<()> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "This file system doesn't have tiers",
+        ))
+    }
+
+    /// Whether another file system is mounted at `path` or anywhere below it,
+    /// those directories can't be moved or deleted as a whole
+    fn contains_mount_point(&self, _path: &str) -> bool {
+        false
+    }
+
     async fn root_directory(&self) -> PathBuf;
 }
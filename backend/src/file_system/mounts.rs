@@ -0,0 +1,397 @@
+//! Several file systems put together into one tree, each mounted at its own path.
+//!
+//! Every path goes to the file system mounted at its longest matching prefix,
+//! with that prefix taken off. There always is a file system mounted at the root
+//! which gets everything else. Mount points show up as directories in the listing
+//! of their parent, even if the file system of the parent has no such directory.
+
+use async_trait::async_trait;
+use sf_core::Tier;
+use std::{
+    fmt::Debug,
+    io::{Error, ErrorKind, Result},
+    path::PathBuf,
+};
+
+use crate::file_system::{FSStream, FileHandler, FileMetadata, FileSystem, copy_file_as};
+
+pub struct Mounts {
+    /// Mount path without leading or trailing slashes and its file system,
+    /// longest paths first so they match before their parents do
+    mounts: Vec<(String, Box<dyn FileSystem>)>,
+}
+
+impl Debug for Mounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mounts = self
+            .mounts
+            .iter()
+            .map(|(path, fs)| format!("/{path}: {fs:?}"))
+            .collect::<Vec<_>>();
+        write!(f, "Mounts({})", mounts.join(", "))
+    }
+}
+
+impl Mounts {
+    /// One of the mounts has to be at the root (`""` or `"/"`)
+    pub fn new(mounts: Vec<(String, Box<dyn FileSystem>)>) -> Result<Self> {
+        let mut mounts = mounts
+            .into_iter()
+            .map(|(path, fs)| (normalize(&path).to_string(), fs))
+            .collect::<Vec<_>>();
+        mounts.sort_by_key(|(path, _)| std::cmp::Reverse(path.len()));
+
+        if !mounts.iter().any(|(path, _)| path.is_empty()) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "A file system has to be mounted at the root",
+            ));
+        }
+        for (index, (path, _)) in mounts.iter().enumerate() {
+            if mounts[index + 1..].iter().any(|(other, _)| other == path) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Two file systems are mounted at {path:?}"),
+                ));
+            }
+        }
+
+        Ok(Self { mounts })
+    }
+
+    /// The file system `path` is on and the path within it
+    fn route<'a>(&self, path: &'a str) -> (&dyn FileSystem, &'a str) {
+        let path = normalize(path);
+        for (mount, fs) in &self.mounts {
+            if mount.is_empty() {
+                return (fs.as_ref(), path);
+            }
+            if path == mount {
+                return (fs.as_ref(), "");
+            }
+            if let Some(rest) = path.strip_prefix(mount.as_str())
+                && let Some(rest) = rest.strip_prefix('/')
+            {
+                return (fs.as_ref(), rest);
+            }
+        }
+        unreachable!("There is always a file system mounted at the root")
+    }
+
+    fn is_mount_point(&self, path: &str) -> bool {
+        let path = normalize(path);
+        self.mounts.iter().any(|(mount, _)| mount == path)
+    }
+
+    /// Moves a directory to another mount file by file, everything is copied
+    /// before anything is deleted so a failed copy leaves the directory as it was
+    async fn move_directory(
+        from_fs: &dyn FileSystem,
+        from: &str,
+        to_fs: &dyn FileSystem,
+        to: &str,
+    ) -> Result<()> {
+        let (mut dirs, mut files) = (vec![String::new()], vec![]);
+        let mut index = 0;
+        while let Some(dir) = dirs.get(index).cloned() {
+            for entry in from_fs.list_dir(&join(from, &dir)).await? {
+                let entry_path = join(&dir, &entry.path);
+                match entry.is_dir {
+                    true => dirs.push(entry_path),
+                    false => files.push(entry_path),
+                }
+            }
+            index += 1;
+        }
+
+        tracing::debug!(
+            "Moving {:?} with {} files to another mount as {:?}",
+            from,
+            files.len(),
+            to
+        );
+        for dir in &dirs {
+            to_fs.create_dir_all(&join(to, dir)).await?;
+        }
+        for file in &files {
+            copy_file_as(from_fs, &join(from, file), to_fs, &join(to, file)).await?;
+        }
+
+        for file in &files {
+            from_fs.delete(&join(from, file)).await?;
+        }
+        // children before their parents
+        for dir in dirs.iter().rev() {
+            from_fs.delete_empty_dir(&join(from, dir)).await?;
+        }
+        Ok(())
+    }
+
+    /// Names of the mount points directly inside `dir`
+    fn mount_points_in<'a>(&'a self, dir: &'a str) -> impl Iterator<Item = &'a str> {
+        let dir = normalize(dir);
+        self.mounts.iter().filter_map(move |(mount, _)| {
+            let rest = match dir.is_empty() {
+                true => mount.as_str(),
+                false => mount.strip_prefix(dir)?.strip_prefix('/')?,
+            };
+            (!rest.is_empty() && !rest.contains('/')).then_some(rest)
+        })
+    }
+}
+
+fn normalize(path: &str) -> &str {
+    path.trim_matches('/')
+}
+
+fn join(dir: &str, name: &str) -> String {
+    match (dir.is_empty(), name.is_empty()) {
+        (true, _) => name.to_string(),
+        (_, true) => dir.to_string(),
+        _ => format!("{dir}/{name}"),
+    }
+}
+
+#[async_trait]
+impl FileSystem for Mounts {
+    #[tracing::instrument]
+    async fn read(&self, path: &str) -> Result<Vec<u8>> {
+        let (fs, path) = self.route(path);
+        fs.read(path).await
+    }
+
+    #[tracing::instrument]
+    async fn read_stream(&self, path: &str) -> Result<FSStream> {
+        let (fs, path) = self.route(path);
+        fs.read_stream(path).await
+    }
+
+    #[tracing::instrument]
+    async fn read_range(&self, path: &str, start: u64, len: Option<u64>) -> Result<FSStream> {
+        let (fs, path) = self.route(path);
+        fs.read_range(path, start, len).await
+    }
+
+    #[tracing::instrument(skip(data))]
+    async fn write(&self, path: &str, data: &[u8]) -> Result<()> {
+        let (fs, path) = self.route(path);
+        fs.write(path, data).await
+    }
+
+    #[tracing::instrument]
+    async fn delete(&self, path: &str) -> Result<()> {
+        let (fs, path) = self.route(path);
+        fs.delete(path).await
+    }
+
+    #[tracing::instrument]
+    async fn exists(&self, path: &str) -> Result<bool> {
+        if self.is_mount_point(path) || self.mount_points_in(path).next().is_some() {
+            return Ok(true);
+        }
+        let (fs, path) = self.route(path);
+        fs.exists(path).await
+    }
+
+    #[tracing::instrument]
+    async fn metadata(&self, path: &str) -> Result<FileMetadata> {
+        let (fs, inner) = self.route(path);
+        match fs.metadata(inner).await {
+            // directories that only exist because something is mounted below them
+            Err(err)
+                if err.kind() == ErrorKind::NotFound
+                    && self.mount_points_in(path).next().is_some() =>
+            {
+                Ok(FileMetadata {
+                    path: normalize(path).to_string(),
+                    is_dir: true,
+                    size: 0,
+                    modified: 0,
+                })
+            }
+            result => result,
+        }
+    }
+
+    #[tracing::instrument]
+    async fn get_file_handler(&self, path: &str) -> Result<FileHandler> {
+        let (fs, path) = self.route(path);
+        fs.get_file_handler(path).await
+    }
+
+    async fn finish_upload(&self, path: &str) -> Result<()> {
+        let (fs, path) = self.route(path);
+        fs.finish_upload(path).await
+    }
+
+    /// The directory on its own file system, along with the mount points inside it
+    #[tracing::instrument]
+    async fn list_dir(&self, path: &str) -> Result<Vec<FileMetadata>> {
+        let (fs, inner) = self.route(path);
+        let mount_points = self.mount_points_in(path).collect::<Vec<_>>();
+
+        let mut entries = match fs.list_dir(inner).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound && !mount_points.is_empty() => vec![],
+            Err(err) => return Err(err),
+        };
+
+        entries.retain(|entry| !mount_points.contains(&entry.path.as_str()));
+        for mount_point in mount_points {
+            entries.push(FileMetadata {
+                path: mount_point.to_string(),
+                is_dir: true,
+                size: 0,
+                modified: 0,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    #[tracing::instrument]
+    async fn create_dir_all(&self, path: &str) -> Result<()> {
+        let (fs, path) = self.route(path);
+        fs.create_dir_all(path).await
+    }
+
+    /// Files moved to another mount are copied over and then deleted,
+    /// directories one file at a time
+    #[tracing::instrument]
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        if self.is_mount_point(from) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "Mount points can't be renamed",
+            ));
+        }
+        // only part of the tree would move, the rest stays on the mount
+        if self.contains_mount_point(from) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "Directories with mount points in them can't be moved",
+            ));
+        }
+
+        let (from_fs, from_inner) = self.route(from);
+        let (to_fs, to_inner) = self.route(to);
+        if std::ptr::addr_eq(from_fs, to_fs) {
+            return from_fs.rename(from_inner, to_inner).await;
+        }
+
+        if from_fs.metadata(from_inner).await?.is_dir {
+            return Self::move_directory(from_fs, from_inner, to_fs, to_inner).await;
+        }
+
+        tracing::debug!("Moving {:?} to another mount as {:?}", from, to);
+        copy_file_as(from_fs, from_inner, to_fs, to_inner).await?;
+        from_fs.delete(from_inner).await
+    }
+
+    /// Files copied to another mount are streamed through the server
+    #[tracing::instrument]
+    async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        let (from_fs, from_inner) = self.route(from);
+        let (to_fs, to_inner) = self.route(to);
+        if std::ptr::addr_eq(from_fs, to_fs) {
+            return from_fs.copy(from_inner, to_inner).await;
+        }
+        copy_file_as(from_fs, from_inner, to_fs, to_inner).await
+    }
+
+    #[tracing::instrument]
+    async fn delete_empty_dir(&self, path: &str) -> Result<()> {
+        if self.is_mount_point(path) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "Mount points can't be deleted",
+            ));
+        }
+        let (fs, path) = self.route(path);
+        fs.delete_empty_dir(path).await
+    }
+
+    async fn repair(&self) -> Result<u64> {
+        let mut copied = 0;
+        for (_, fs) in &self.mounts {
+            copied += fs.repair().await?;
+        }
+        Ok(copied)
+    }
+
+    async fn move_to_tier(&self, path: &str, tier: Tier) -> Result<()> {
+        let (fs, path) = self.route(path);
+        fs.move_to_tier(path, tier).await
+    }
+
+    fn contains_mount_point(&self, path: &str) -> bool {
+        let path = normalize(path);
+        self.mounts.iter().any(|(mount, _)| {
+            !mount.is_empty()
+                && (path.is_empty()
+                    || mount == path
+                    || mount
+                        .strip_prefix(path)
+                        .is_some_and(|rest| rest.starts_with('/')))
+        })
+    }
+
+    async fn root_directory(&self) -> PathBuf {
+        let (fs, _) = self.route("");
+        fs.root_directory().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_system::Memory;
+
+    async fn mounts() -> Mounts {
+        let mounts = Mounts::new(vec![
+            (
+                "/".to_string(),
+                Box::new(Memory::new()) as Box<dyn FileSystem>,
+            ),
+            ("media/archive".to_string(), Box::new(Memory::new())),
+            ("other".to_string(), Box::new(Memory::new())),
+        ])
+        .unwrap();
+
+        mounts.create_dir_all("media/photos/2024").await.unwrap();
+        mounts.write("media/photos/a.jpg", b"a").await.unwrap();
+        mounts.write("media/photos/2024/b.jpg", b"b").await.unwrap();
+        mounts.write("media/archive/c.jpg", b"c").await.unwrap();
+        mounts
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn directories_with_mount_points_stay_in_place() {
+        let mounts = mounts().await;
+
+        assert!(mounts.contains_mount_point(""));
+        assert!(mounts.contains_mount_point("media"));
+        assert!(mounts.contains_mount_point("/media/archive/"));
+        assert!(!mounts.contains_mount_point("media/photos"));
+        assert!(!mounts.contains_mount_point("media/arch"));
+        assert!(!mounts.contains_mount_point("media/archive/old"));
+
+        let err = mounts.rename("media", "moved").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(mounts.read("media/archive/c.jpg").await.unwrap(), b"c");
+        assert_eq!(mounts.read("media/photos/a.jpg").await.unwrap(), b"a");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn directories_move_to_another_mount() {
+        let mounts = mounts().await;
+
+        mounts.rename("media/photos", "other/photos").await.unwrap();
+
+        assert_eq!(mounts.read("other/photos/a.jpg").await.unwrap(), b"a");
+        assert_eq!(mounts.read("other/photos/2024/b.jpg").await.unwrap(), b"b");
+        assert!(!mounts.exists("media/photos").await.unwrap());
+        let (root, _) = mounts.route("");
+        assert!(!root.exists("media/photos").await.unwrap());
+    }
+}
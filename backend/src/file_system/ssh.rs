@@ -1,24 +1,37 @@
 use async_trait::async_trait;
-use ssh2::{OpenFlags, OpenType, Session};
+use ssh2::{ErrorCode, OpenFlags, OpenType, Session, Sftp};
 use std::{
     fmt::Debug,
-    io::{Read, Result, Write},
-    net::TcpStream,
+    io::{Read, Result, Seek, SeekFrom, Write},
+    net::{TcpStream, ToSocketAddrs},
     path::{Path, PathBuf},
-    sync::Arc,
-    time::Duration,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
 };
-use tokio::sync::Mutex;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
 use crate::{
-    config::{SSHPassword, SSHPublicKey},
-    file_system::{FSStream, FileHandler, FileMetadata, FileSystem},
+    config::SSHConfig,
+    file_system::{FSStream, FileHandler, FileMetadata, FileSystem, block_on, copy_file_as},
 };
 
+/// Seconds between keepalives on idle sessions
+const KEEPALIVE_INTERVAL: u32 = 30;
+const DEFAULT_POOL_SIZE: usize = 4;
+/// How long connecting is tried for before giving up, waiting twice as long after every attempt
+const MAX_CONNECT_WAIT: Duration = Duration::from_secs(15);
+/// How long operations fail right away after connecting gave up, instead of waiting again
+const RETRY_AFTER: Duration = Duration::from_secs(30);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a single call on a session can take before the session is given up on,
+/// so a host that stops answering can't hang a thread forever
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+/// Bytes read or written with one session from the pool,
+/// streams and handlers give the session back in between
+const BLOCK_SIZE: usize = 1024 * 1024;
+
 pub struct SSH {
-    #[allow(unused)]
-    session: Arc<Mutex<Session>>,
-    sftp: ssh2::Sftp,
+    pool: Arc<Pool>,
     root: String,
 }
 
@@ -29,35 +42,90 @@ impl Debug for SSH {
 }
 
 impl SSH {
-    pub async fn connect(
-        host: &str,
-        port: u16,
-        username: &str,
-        password_config: &Option<SSHPassword>,
-        public_key_config: &Option<SSHPublicKey>,
-        root: impl Into<String>,
-    ) -> Result<Self> {
+    pub async fn connect(config: &SSHConfig) -> Result<Self> {
+        let pool = Arc::new(Pool::new(
+            config.clone(),
+            config.pool_size.unwrap_or(DEFAULT_POOL_SIZE),
+        ));
+
+        // fail early if the host or credentials are wrong
+        let connection = pool.connect().await?;
+        pool.idle.lock().unwrap().push(connection);
+
+        let ssh = Self {
+            pool,
+            root: config.root.clone(),
+        };
+
+        ssh.start_keepalive().await;
+
+        Ok(ssh)
+    }
+
+    pub async fn start_keepalive(&self) {
+        let pool = Arc::downgrade(&self.pool);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(KEEPALIVE_INTERVAL as u64)).await;
+                let Some(pool) = Weak::upgrade(&pool) else {
+                    break;
+                };
+                if let Err(err) = blocking(move || pool.keepalive()).await {
+                    tracing::error!("SSH keepalive failed: {err:?}");
+                }
+            }
+        });
+        tracing::debug!("Started SSH keepalive loop");
+    }
+
+    fn full_path(&self, path: &str) -> String {
+        if path == "" {
+            return self.root.clone();
+        };
+
+        let path = PathBuf::from(&self.root).join(path);
+
+        path.to_string_lossy().to_string().replace("\\", "/")
+    }
+
+    /// See [`Pool::run`]
+    async fn run<T, F>(&self, op: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: Fn(&Sftp) -> std::result::Result<T, ssh2::Error> + Send + Sync + 'static,
+    {
+        self.pool.run(move |connection| op(&connection.sftp)).await
+    }
+}
+
+/// An authenticated session and its SFTP channel
+struct Connection {
+    session: Session,
+    sftp: Sftp,
+}
+
+impl Connection {
+    fn open(config: &SSHConfig) -> Result<Self> {
         tracing::debug!("Connecting to remote SSH");
-        let tcp = TcpStream::connect((host, port))?;
-        tcp.set_read_timeout(None)?;
-        tcp.set_write_timeout(None)?;
+        let tcp = connect_tcp(config)?;
 
         let mut session = Session::new()?;
+        session.set_timeout(SESSION_TIMEOUT.as_millis() as u32);
         session.set_tcp_stream(tcp);
         tracing::debug!("Started SSH handshake");
         session.handshake()?;
 
         tracing::debug!("Authenticating SSH");
-        if let Some(config) = public_key_config {
+        if let Some(key) = &config.public_key {
             session.userauth_pubkey_file(
-                username,
-                config.public_key.as_deref(),
-                &config.private_key,
-                config.pass_phrase.as_deref(),
+                &config.username,
+                key.public_key.as_deref(),
+                &key.private_key,
+                key.pass_phrase.as_deref(),
             )?;
             tracing::debug!("Authenticated via public_key");
-        } else if let Some(config) = password_config {
-            session.userauth_password(username, &config.password)?;
+        } else if let Some(password) = &config.password {
+            session.userauth_password(&config.username, &password.password)?;
             tracing::debug!("Authenticated via password");
         }
 
@@ -68,96 +136,433 @@ impl SSH {
             ));
         }
 
-        session.set_keepalive(true, 30);
+        session.set_keepalive(true, KEEPALIVE_INTERVAL);
 
         tracing::debug!("Connecting via SFTP");
         let sftp = session.sftp()?;
-        let session = Arc::new(Mutex::new(session));
 
-        let ssh = Self {
-            session,
-            sftp,
-            root: root.into(),
+        Ok(Self { session, sftp })
+    }
+}
+
+/// The first address of the host that accepts a connection within [`CONNECT_TIMEOUT`]
+fn connect_tcp(config: &SSHConfig) -> Result<TcpStream> {
+    let mut last_err = None;
+    for addr in (config.host.as_str(), config.port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(tcp) => return Ok(tcp),
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "The SSH host has no addresses",
+        )
+    }))
+}
+
+/// Opens the sessions of a [`Pool`], an [`SSHConfig`] opens real ones
+trait Connector: Send + Sync + 'static {
+    type Connection: Send + 'static;
+
+    fn open(&self) -> Result<Self::Connection>;
+    /// Fails if the session is dead
+    fn keepalive(&self, connection: &Self::Connection) -> std::result::Result<(), ssh2::Error>;
+}
+
+impl Connector for SSHConfig {
+    type Connection = Connection;
+
+    fn open(&self) -> Result<Connection> {
+        Connection::open(self)
+    }
+
+    fn keepalive(&self, connection: &Connection) -> std::result::Result<(), ssh2::Error> {
+        connection.session.keepalive_send().map(|_| ())
+    }
+}
+
+/// SFTP sessions shared by every operation, each session is only used
+/// by one operation at a time so concurrent transfers don't wait on each other.
+/// Sessions are only checked out for a single call (or block of data),
+/// never for as long as a stream or file handler lives
+struct Pool<C: Connector = SSHConfig> {
+    connector: C,
+    idle: Mutex<Vec<C::Connection>>,
+    /// Limits how many sessions can be open at once
+    permits: Arc<Semaphore>,
+    /// When connecting last gave up, operations fail right away for [`RETRY_AFTER`]
+    unreachable_since: Mutex<Option<Instant>>,
+}
+
+impl<C: Connector> Pool<C> {
+    fn new(connector: C, size: usize) -> Self {
+        Self {
+            connector,
+            idle: Mutex::new(vec![]),
+            permits: Arc::new(Semaphore::new(size.max(1))),
+            unreachable_since: Mutex::new(None),
+        }
+    }
+
+    /// Takes an idle session or opens a new one if there is none
+    async fn get(self: &Arc<Self>) -> Result<PooledConnection<C>> {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(std::io::Error::other)?;
+
+        let idle = self.idle.lock().unwrap().pop();
+        let connection = match idle {
+            Some(connection) => connection,
+            None => self.connect().await?,
         };
 
-        ssh.start_keepalive().await;
+        Ok(PooledConnection {
+            connection: Some(connection),
+            pool: self.clone(),
+            _permit: permit,
+        })
+    }
 
-        Ok(ssh)
+    /// Runs `op` on a session from the pool, if the session turns out
+    /// to be dead it's replaced with a new one and `op` is tried once more.
+    /// The session goes right back into the pool afterwards.
+    ///
+    /// `ssh2` only has blocking calls, so `op` runs on tokio's blocking thread pool
+    async fn run<T, F>(self: &Arc<Self>, op: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: Fn(&C::Connection) -> std::result::Result<T, ssh2::Error> + Send + Sync + 'static,
+    {
+        let op = Arc::new(op);
+        let connection = self.get().await?;
+
+        let (result, mut connection) = connection.run(op.clone()).await?;
+        match result {
+            Err(err) if is_disconnect(&err) => {
+                tracing::warn!("Lost the SSH connection, reconnecting: {err}");
+                connection.reconnect().await?;
+                let (result, _) = connection.run(op).await?;
+                Ok(result?)
+            }
+            result => Ok(result?),
+        }
     }
 
-    pub async fn start_keepalive(&self) {
-        let session = self.session.clone();
-        tokio::spawn(async move {
-            loop {
-                let remaining = {
-                    let guard = session.lock().await;
-                    match guard.keepalive_send() {
-                        Ok(r) => r,
-                        Err(err) => {
-                            tracing::error!("SSH keepalive failed: {err:?}");
-                            break;
-                        }
-                    }
-                };
+    /// Opens a new session, retrying with backoff while the host is unreachable
+    /// for up to [`MAX_CONNECT_WAIT`]. Once that gave up, it fails right away
+    /// for [`RETRY_AFTER`] so operations don't all wait for a host that's down
+    async fn connect(self: &Arc<Self>) -> Result<C::Connection> {
+        if let Some(since) = *self.unreachable_since.lock().unwrap()
+            && since.elapsed() < RETRY_AFTER
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "The SSH host couldn't be reached just now",
+            ));
+        }
+
+        let started = Instant::now();
+        let mut delay = Duration::from_secs(1);
+        let mut attempt = 1;
 
-                let remaining = Duration::from_secs(remaining.max(1) as u64);
-                tokio::time::sleep(remaining).await;
+        loop {
+            let pool = self.clone();
+            match blocking(move || pool.connector.open()).await? {
+                Ok(connection) => {
+                    *self.unreachable_since.lock().unwrap() = None;
+                    return Ok(connection);
+                }
+                Err(err)
+                    if started.elapsed() + delay < MAX_CONNECT_WAIT
+                        && err.kind() != std::io::ErrorKind::PermissionDenied =>
+                {
+                    tracing::warn!(
+                        "Failed to connect to the SSH host (attempt {attempt}), retrying in {delay:?}: {err:?}"
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(err) => {
+                    *self.unreachable_since.lock().unwrap() = Some(Instant::now());
+                    return Err(err);
+                }
             }
-        });
-        tracing::debug!("Started SSH keepalive loop");
+        }
     }
 
-    fn full_path(&self, path: &str) -> String {
-        if path == "" {
-            return self.root.clone();
+    /// Sends a keepalive over every idle session and drops the dead ones,
+    /// they are replaced by new sessions once they're needed again
+    fn keepalive(&self) {
+        self.idle
+            .lock()
+            .unwrap()
+            .retain(|connection| match self.connector.keepalive(connection) {
+                Ok(_) => true,
+                Err(err) => {
+                    tracing::warn!("Dropping a dead SSH session: {err:?}");
+                    false
+                }
+            });
+    }
+}
+
+impl Pool {
+    /// Opens the file at `path` with a session from the pool and runs `op` on it
+    async fn with_file<T, F>(self: &Arc<Self>, path: &str, flags: OpenFlags, op: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: Fn(&mut ssh2::File) -> Result<T> + Send + Sync + 'static,
+    {
+        let path = path.to_string();
+        self.run(move |connection| {
+            let mut file =
+                connection
+                    .sftp
+                    .open_mode(Path::new(&path), flags, 0o666, OpenType::File)?;
+            Ok(op(&mut file))
+        })
+        .await?
+    }
+}
+
+/// A session taken out of the pool, it's put back once this is dropped
+struct PooledConnection<C: Connector = SSHConfig> {
+    connection: Option<C::Connection>,
+    pool: Arc<Pool<C>>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl<C: Connector> PooledConnection<C> {
+    fn connection(&self) -> &C::Connection {
+        self.connection.as_ref().expect("SSH session is gone")
+    }
+
+    /// Runs `op` with the session on the blocking thread pool
+    async fn run<T, F>(self, op: Arc<F>) -> Result<(std::result::Result<T, ssh2::Error>, Self)>
+    where
+        T: Send + 'static,
+        F: Fn(&C::Connection) -> std::result::Result<T, ssh2::Error> + Send + Sync + 'static,
+    {
+        blocking(move || (op(self.connection()), self)).await
+    }
+
+    /// Replaces the session with a new one
+    async fn reconnect(&mut self) -> Result<()> {
+        self.connection = None;
+        self.connection = Some(self.pool.connect().await?);
+        Ok(())
+    }
+}
+
+impl PooledConnection {
+    /// Runs a shell command on the host, returns its exit status
+    fn exec(&self, command: &str) -> std::result::Result<i32, ssh2::Error> {
+        let mut channel = self.connection().session.channel_session()?;
+        channel.exec(command)?;
+
+        let mut output = String::new();
+        let _ = channel.read_to_string(&mut output);
+        channel.wait_close()?;
+        if !output.is_empty() {
+            tracing::debug!("{command:?}: {output}");
+        }
+        channel.exit_status()
+    }
+}
+
+impl<C: Connector> Drop for PooledConnection<C> {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            self.pool.idle.lock().unwrap().push(connection);
+        }
+    }
+}
+
+/// A remote file that only takes a session from the pool while it writes,
+/// so slow uploads don't keep every session checked out.
+///
+/// Writes that follow each other are collected until there's [`BLOCK_SIZE`] of them,
+/// a flush or a write somewhere else, and are then written in one go
+struct SSHFile {
+    pool: Arc<Pool>,
+    path: String,
+    position: u64,
+    /// Where `buffer` goes in the file
+    buffer_start: u64,
+    buffer: Vec<u8>,
+}
+
+impl SSHFile {
+    fn write_buffer(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let (start, data) = (
+            self.buffer_start,
+            Arc::new(std::mem::take(&mut self.buffer)),
+        );
+        let written = data.clone();
+        let result = block_on(self.pool.with_file(
+            &self.path,
+            OpenFlags::WRITE | OpenFlags::CREATE,
+            move |file| {
+                file.seek(SeekFrom::Start(start))?;
+                file.write_all(&written)
+            },
+        ));
+
+        // kept to try again with the next flush
+        if result.is_err() {
+            self.buffer = Arc::unwrap_or_clone(data);
+        }
+        result
+    }
+}
+
+impl Write for SSHFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if !self.buffer.is_empty() && self.position != self.buffer_start + self.buffer.len() as u64
+        {
+            self.write_buffer()?;
+        }
+        if self.buffer.is_empty() {
+            self.buffer_start = self.position;
+        }
+
+        self.buffer.extend_from_slice(buf);
+        self.position += buf.len() as u64;
+        if self.buffer.len() >= BLOCK_SIZE {
+            self.write_buffer()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.write_buffer()
+    }
+}
+
+impl Seek for SSHFile {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => {
+                self.write_buffer()?;
+                let path = self.path.clone();
+                let stat = block_on(
+                    self.pool
+                        .run(move |connection| connection.sftp.stat(Path::new(&path))),
+                )?;
+                stat.size.unwrap_or(0).checked_add_signed(offset)
+            }
         };
 
-        let path = PathBuf::from(&self.root).join(path);
+        self.position = target.ok_or(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Invalid seek",
+        ))?;
+        Ok(self.position)
+    }
+}
 
-        path.to_string_lossy().to_string().replace("\\", "/")
+impl Drop for SSHFile {
+    fn drop(&mut self) {
+        if let Err(err) = self.write_buffer() {
+            tracing::error!("Failed to write to {:?}: {err:?}", self.path);
+        }
     }
 }
 
+/// Runs blocking `ssh2` calls without holding up the async runtime
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(std::io::Error::other)
+}
+
+/// Quotes an argument for a POSIX shell
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// Errors from the session itself rather than the SFTP operation,
+/// which means the connection is gone
+fn is_disconnect(err: &ssh2::Error) -> bool {
+    matches!(err.code(), ErrorCode::Session(_))
+}
+
 #[async_trait]
 impl FileSystem for SSH {
     #[tracing::instrument]
     async fn read(&self, path: &str) -> Result<Vec<u8>> {
         let full_path = self.full_path(path);
         tracing::debug!("{:?}", full_path);
-        let mut file = self.sftp.open(Path::new(&full_path))?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
-        Ok(buffer)
+        self.pool
+            .with_file(&full_path, OpenFlags::READ, |file| {
+                let mut buffer = Vec::new();
+                file.read_to_end(&mut buffer)?;
+                Ok(buffer)
+            })
+            .await
     }
 
     #[tracing::instrument]
     async fn read_stream(&self, path: &str) -> Result<FSStream> {
-        let full_path = self.full_path(path);
-        tracing::debug!("Streaming from {:?}", full_path);
-
-        let file = self.sftp.open(Path::new(&full_path))?;
+        self.read_range(path, 0, None).await
+    }
 
-        let (tx, rx) =
-            tokio::sync::mpsc::channel::<std::result::Result<Vec<u8>, std::io::Error>>(16);
+    #[tracing::instrument]
+    async fn read_range(&self, path: &str, start: u64, len: Option<u64>) -> Result<FSStream> {
+        let full_path = self.full_path(path);
+        tracing::debug!("Streaming from {:?} at {}", full_path, start);
 
-        tokio::task::spawn_blocking(move || {
-            const CHUNK_SIZE: usize = 8192;
-            let mut file = file;
-            let mut buffer = vec![0u8; CHUNK_SIZE];
+        // fail right away if it can't be opened
+        self.pool
+            .with_file(&full_path, OpenFlags::READ, |_| Ok(()))
+            .await?;
 
-            loop {
-                match file.read(&mut buffer) {
-                    Ok(0) => break, // EOF
-                    Ok(bytes_read) => {
-                        let chunk = buffer[..bytes_read].to_vec();
-                        if tx.blocking_send(Ok(chunk)).is_err() {
-                            break; // Channel closed
-                        }
-                    }
-                    Err(e) => {
-                        let _ = tx.blocking_send(Err(e));
+        // every block takes a session from the pool just for reading it,
+        // so clients that download slowly don't hold one up
+        let (tx, rx) = tokio::sync::mpsc::channel::<Result<Vec<u8>>>(2);
+        let pool = self.pool.clone();
+        tokio::spawn(async move {
+            let (mut offset, mut remaining) = (start, len.unwrap_or(u64::MAX));
+
+            while remaining > 0 {
+                let want = remaining.min(BLOCK_SIZE as u64) as usize;
+                let block = pool
+                    .with_file(&full_path, OpenFlags::READ, move |file| {
+                        // only moves the offset the next read request is sent for
+                        file.seek(SeekFrom::Start(offset))?;
+                        let mut block = Vec::with_capacity(want);
+                        file.take(want as u64).read_to_end(&mut block)?;
+                        Ok(block)
+                    })
+                    .await;
+
+                let block = match block {
+                    Ok(block) if block.is_empty() => break, // EOF
+                    Ok(block) => block,
+                    Err(err) => {
+                        let _ = tx.send(Err(err)).await;
                         break;
                     }
+                };
+
+                let at_end = block.len() < want;
+                offset += block.len() as u64;
+                remaining -= block.len() as u64;
+                if tx.send(Ok(block)).await.is_err() || at_end {
+                    break; // channel closed or EOF
                 }
             }
         });
@@ -170,31 +575,43 @@ impl FileSystem for SSH {
     async fn write(&self, path: &str, data: &[u8]) -> Result<()> {
         let full_path = self.full_path(path);
         tracing::debug!("{:?}", full_path);
-        let mut file = self.sftp.create(Path::new(&full_path))?;
-        file.write_all(data)?;
-        Ok(())
+        let data = data.to_vec();
+        self.pool
+            .with_file(
+                &full_path,
+                OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                move |file| file.write_all(&data),
+            )
+            .await
     }
 
     #[tracing::instrument]
     async fn delete(&self, path: &str) -> Result<()> {
         let full_path = self.full_path(path);
         tracing::debug!("{:?}", full_path);
-        self.sftp.unlink(Path::new(&full_path))?;
-        Ok(())
+        self.run(move |sftp| sftp.unlink(Path::new(&full_path)))
+            .await
     }
 
     #[tracing::instrument]
     async fn exists(&self, path: &str) -> Result<bool> {
         let full_path = self.full_path(path);
         tracing::debug!("{:?}", full_path);
-        Ok(self.sftp.stat(Path::new(&full_path)).is_ok())
+        self.run(move |sftp| match sftp.stat(Path::new(&full_path)) {
+            Err(err) if is_disconnect(&err) => Err(err),
+            result => Ok(result.is_ok()),
+        })
+        .await
     }
 
     #[tracing::instrument]
     async fn metadata(&self, path: &str) -> Result<FileMetadata> {
         let full_path = self.full_path(path);
         tracing::debug!("{:?}", full_path);
-        let stat = self.sftp.stat(&Path::new(&full_path))?;
+        let stat_path = full_path.clone();
+        let stat = self
+            .run(move |sftp| sftp.stat(Path::new(&stat_path)))
+            .await?;
         Ok(FileMetadata {
             path: full_path,
             is_dir: stat.is_dir(),
@@ -208,21 +625,31 @@ impl FileSystem for SSH {
         let full_path = self.full_path(path);
         tracing::debug!("{:?}", full_path);
 
-        let file = self.sftp.open_mode(
-            full_path,
-            OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE,
-            0o666,
-            OpenType::File,
-        )?;
-
-        Ok(Box::new(file))
+        // creates it, writes only open it again for as long as they take
+        self.pool
+            .with_file(
+                &full_path,
+                OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE,
+                |_| Ok(()),
+            )
+            .await?;
+
+        Ok(Box::new(SSHFile {
+            pool: self.pool.clone(),
+            path: full_path,
+            position: 0,
+            buffer_start: 0,
+            buffer: vec![],
+        }))
     }
 
     #[tracing::instrument]
     async fn list_dir(&self, path: &str) -> Result<Vec<FileMetadata>> {
         let full_path = self.full_path(path);
         tracing::debug!("{:?}", full_path);
-        let entries = self.sftp.readdir(Path::new(&full_path))?;
+        let entries = self
+            .run(move |sftp| sftp.readdir(Path::new(&full_path)))
+            .await?;
         let mut result = Vec::new();
 
         for (pathbuf, stat) in entries {
@@ -245,11 +672,15 @@ impl FileSystem for SSH {
     async fn create_dir_all(&self, path: &str) -> Result<()> {
         let full_path = self.full_path(path);
         tracing::debug!("{:?}", full_path);
-        let parts = Path::new(&full_path).ancestors().collect::<Vec<_>>();
-        for ancestor in parts.iter().rev() {
-            let _ = self.sftp.mkdir(ancestor, 0o755); // ignore already exists
-        }
-        Ok(())
+        self.run(move |sftp| {
+            let parts = Path::new(&full_path).ancestors().collect::<Vec<_>>();
+            for ancestor in parts.iter().rev() {
+                let _ = sftp.mkdir(ancestor, 0o755); // ignore already exists
+            }
+            // the errors above are ignored, this makes sure the connection is still alive
+            sftp.stat(Path::new(&full_path)).map(|_| ())
+        })
+        .await
     }
 
     #[tracing::instrument]
@@ -257,9 +688,32 @@ impl FileSystem for SSH {
         let from_path = self.full_path(from);
         let to_path = self.full_path(to);
         tracing::debug!("{:?} to {:?}", from_path, to_path);
-        self.sftp
-            .rename(Path::new(&from_path), Path::new(&to_path), None)?;
-        Ok(())
+        self.run(move |sftp| sftp.rename(Path::new(&from_path), Path::new(&to_path), None))
+            .await
+    }
+
+    /// Copies on the host with `cp` if it lets us run commands,
+    /// otherwise the file is streamed through the server
+    #[tracing::instrument]
+    async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        let command = format!(
+            "cp -- {} {}",
+            shell_quote(&self.full_path(from)),
+            shell_quote(&self.full_path(to))
+        );
+        tracing::debug!("{:?}", command);
+        if let Some(parent) = Path::new(to).parent() {
+            self.create_dir_all(&parent.to_string_lossy()).await?;
+        }
+
+        let connection = self.pool.get().await?;
+        match blocking(move || connection.exec(&command)).await? {
+            Ok(0) => Ok(()),
+            result => {
+                tracing::debug!("Remote cp didn't work ({result:?}), copying through the server");
+                copy_file_as(self, from, self, to).await
+            }
+        }
     }
 
     #[tracing::instrument]
@@ -269,8 +723,8 @@ impl FileSystem for SSH {
         let is_empty = self.list_dir(&path).await?.is_empty();
 
         if is_empty {
-            self.sftp.rmdir(Path::new(&full_path))?;
-            Ok(())
+            self.run(move |sftp| sftp.rmdir(Path::new(&full_path)))
+                .await
         } else {
             Err(std::io::Error::new(
                 std::io::ErrorKind::Other,
@@ -283,3 +737,145 @@ impl FileSystem for SSH {
         PathBuf::from(&self.root)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    /// Hands out numbered sessions that all die when it reboots
+    #[derive(Default)]
+    struct FakeHost {
+        opened: AtomicUsize,
+        sessions: Mutex<Vec<Arc<AtomicBool>>>,
+        /// Turns away every connection
+        down: AtomicBool,
+    }
+
+    struct FakeSession {
+        number: usize,
+        alive: Arc<AtomicBool>,
+    }
+
+    impl FakeSession {
+        fn check(&self) -> std::result::Result<usize, ssh2::Error> {
+            match self.alive.load(Ordering::SeqCst) {
+                true => Ok(self.number),
+                false => Err(ssh2::Error::new(
+                    ErrorCode::Session(-7),
+                    "Unable to send data on socket",
+                )),
+            }
+        }
+    }
+
+    impl FakeHost {
+        fn reboot(&self) {
+            for session in self.sessions.lock().unwrap().iter() {
+                session.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+
+    impl Connector for FakeHost {
+        type Connection = FakeSession;
+
+        fn open(&self) -> Result<FakeSession> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "Turned away",
+                ));
+            }
+            let alive = Arc::new(AtomicBool::new(true));
+            self.sessions.lock().unwrap().push(alive.clone());
+            Ok(FakeSession {
+                number: self.opened.fetch_add(1, Ordering::SeqCst),
+                alive,
+            })
+        }
+
+        fn keepalive(&self, session: &FakeSession) -> std::result::Result<(), ssh2::Error> {
+            session.check().map(|_| ())
+        }
+    }
+
+    fn pool(size: usize) -> Arc<Pool<FakeHost>> {
+        Arc::new(Pool::new(FakeHost::default(), size))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reuses_sessions() {
+        let pool = pool(2);
+        for _ in 0..5 {
+            assert_eq!(pool.run(FakeSession::check).await.unwrap(), 0);
+        }
+        assert_eq!(pool.connector.opened.load(Ordering::SeqCst), 1);
+
+        // never more sessions than the pool has room for
+        let slow = || {
+            pool.run(|session| {
+                std::thread::sleep(Duration::from_millis(100));
+                session.check()
+            })
+        };
+        let results = tokio::join!(slow(), slow(), slow(), slow());
+        for result in [results.0, results.1, results.2, results.3] {
+            assert!(result.unwrap() < 2);
+        }
+        assert_eq!(pool.connector.opened.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.idle.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reconnects_dead_sessions() {
+        let pool = pool(2);
+        assert_eq!(pool.run(FakeSession::check).await.unwrap(), 0);
+
+        pool.connector.reboot();
+        assert_eq!(pool.run(FakeSession::check).await.unwrap(), 1);
+        assert_eq!(pool.run(FakeSession::check).await.unwrap(), 1);
+
+        // dead idle sessions are dropped by the keepalive
+        pool.connector.reboot();
+        pool.keepalive();
+        assert!(pool.idle.lock().unwrap().is_empty());
+        assert_eq!(pool.run(FakeSession::check).await.unwrap(), 2);
+    }
+
+    /// With a single worker thread, anything blocking it would hold up the timer too
+    #[tokio::test]
+    async fn runs_off_the_async_workers() {
+        let pool = pool(2);
+        let slow = pool.run(|session| {
+            std::thread::sleep(Duration::from_millis(500));
+            session.check()
+        });
+
+        let started = Instant::now();
+        let ticked = async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            started.elapsed()
+        };
+        let (result, ticked) = tokio::join!(slow, ticked);
+        assert_eq!(result.unwrap(), 0);
+        assert!(ticked < Duration::from_millis(400), "{ticked:?}");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fails_fast_while_unreachable() {
+        let pool = pool(2);
+        pool.connector.down.store(true, Ordering::SeqCst);
+        let err = pool.run(FakeSession::check).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+
+        // it isn't tried again right away, even once the host is back
+        pool.connector.down.store(false, Ordering::SeqCst);
+        let err = pool.run(FakeSession::check).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotConnected);
+        assert_eq!(pool.connector.opened.load(Ordering::SeqCst), 0);
+
+        *pool.unreachable_since.lock().unwrap() = Instant::now().checked_sub(RETRY_AFTER);
+        assert_eq!(pool.run(FakeSession::check).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,190 @@
+//! A file system that can be replaced by another one while the server is running,
+//! which is how `crate::migration` switches over without a restart.
+//!
+//! It sits right below encryption & compression, so everything above it
+//! never notices the switch.
+
+use async_trait::async_trait;
+use sf_core::Tier;
+use std::{
+    fmt::Debug,
+    io::{Result, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    config::WhichFileSystem,
+    file_system::{FSStream, FileHandler, FileMetadata, FileSystem},
+};
+
+/// Cheap to clone, every clone switches together
+#[derive(Clone)]
+pub struct Swappable {
+    active: Arc<RwLock<Active>>,
+}
+
+struct Active {
+    fs: Arc<dyn FileSystem>,
+    which: WhichFileSystem,
+    /// Held by every handler opened on `fs`
+    generation: Arc<()>,
+}
+
+impl Debug for Swappable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Swappable({:?})", self.current())
+    }
+}
+
+impl Swappable {
+    pub fn new(fs: Box<dyn FileSystem>, which: WhichFileSystem) -> Self {
+        Self {
+            active: Arc::new(RwLock::new(Active {
+                fs: Arc::from(fs),
+                which,
+                generation: Arc::new(()),
+            })),
+        }
+    }
+
+    /// The file system everything currently goes to
+    pub fn current(&self) -> Arc<dyn FileSystem> {
+        self.active.read().unwrap().fs.clone()
+    }
+
+    /// Which file system from the config is in use
+    pub fn which(&self) -> WhichFileSystem {
+        self.active.read().unwrap().which.clone()
+    }
+
+    /// Replaces the file system, returns a token that every handler opened on the
+    /// previous file system holds on to. Once it's the last one left, nothing writes
+    /// to the previous file system anymore
+    pub fn swap(&self, fs: Arc<dyn FileSystem>, which: WhichFileSystem) -> Arc<()> {
+        let mut active = self.active.write().unwrap();
+        let previous = std::mem::replace(
+            &mut *active,
+            Active {
+                fs,
+                which,
+                generation: Arc::new(()),
+            },
+        );
+        previous.generation
+    }
+}
+
+#[async_trait]
+impl FileSystem for Swappable {
+    async fn read(&self, path: &str) -> Result<Vec<u8>> {
+        self.current().read(path).await
+    }
+
+    async fn read_stream(&self, path: &str) -> Result<FSStream> {
+        self.current().read_stream(path).await
+    }
+
+    async fn read_range(&self, path: &str, start: u64, len: Option<u64>) -> Result<FSStream> {
+        self.current().read_range(path, start, len).await
+    }
+
+    async fn write(&self, path: &str, data: &[u8]) -> Result<()> {
+        self.current().write(path, data).await
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        self.current().delete(path).await
+    }
+
+    async fn exists(&self, path: &str) -> Result<bool> {
+        self.current().exists(path).await
+    }
+
+    async fn metadata(&self, path: &str) -> Result<FileMetadata> {
+        self.current().metadata(path).await
+    }
+
+    async fn get_file_handler(&self, path: &str) -> Result<FileHandler> {
+        let (fs, generation) = {
+            let active = self.active.read().unwrap();
+            (active.fs.clone(), active.generation.clone())
+        };
+
+        let inner = fs.get_file_handler(path).await?;
+        Ok(Box::new(TrackedHandler {
+            inner,
+            _generation: generation,
+        }))
+    }
+
+    async fn content_size(&self, path: &str) -> Result<u64> {
+        self.current().content_size(path).await
+    }
+
+    async fn stored_size(&self, path: &str) -> Result<u64> {
+        self.current().stored_size(path).await
+    }
+
+    async fn finish_upload(&self, path: &str) -> Result<()> {
+        self.current().finish_upload(path).await
+    }
+
+    async fn list_dir(&self, path: &str) -> Result<Vec<FileMetadata>> {
+        self.current().list_dir(path).await
+    }
+
+    async fn create_dir_all(&self, path: &str) -> Result<()> {
+        self.current().create_dir_all(path).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.current().rename(from, to).await
+    }
+
+    async fn delete_empty_dir(&self, path: &str) -> Result<()> {
+        self.current().delete_empty_dir(path).await
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        self.current().copy(from, to).await
+    }
+
+    async fn repair(&self) -> Result<u64> {
+        self.current().repair().await
+    }
+
+    async fn move_to_tier(&self, path: &str, tier: Tier) -> Result<()> {
+        self.current().move_to_tier(path, tier).await
+    }
+
+    fn contains_mount_point(&self, path: &str) -> bool {
+        self.current().contains_mount_point(path)
+    }
+
+    async fn root_directory(&self) -> PathBuf {
+        self.current().root_directory().await
+    }
+}
+
+/// Keeps the generation it was opened in alive until it's dropped
+struct TrackedHandler {
+    inner: FileHandler,
+    _generation: Arc<()>,
+}
+
+impl Write for TrackedHandler {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for TrackedHandler {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.inner.seek(pos)
+    }
+}
@@ -0,0 +1,284 @@
+//! Hot & cold storage, every file lives on exactly one of them.
+//!
+//! New files are always written to the hot file system and are moved between
+//! the two with [`FileSystem::move_to_tier`], which tier a file is on is tracked
+//! in the database by `crate::tiering`. Reads check the hot file system first and
+//! fall back to the cold one, so files can be read no matter where they are.
+
+use async_trait::async_trait;
+use sf_core::Tier;
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+};
+
+use crate::file_system::{FSStream, FileHandler, FileMetadata, FileSystem, copy_file};
+
+pub struct Tiered {
+    hot: Box<dyn FileSystem>,
+    cold: Box<dyn FileSystem>,
+}
+
+impl Debug for Tiered {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Tiered(hot: {:?}, cold: {:?})", self.hot, self.cold)
+    }
+}
+
+impl Tiered {
+    pub fn new(hot: Box<dyn FileSystem>, cold: Box<dyn FileSystem>) -> Self {
+        Self { hot, cold }
+    }
+
+    /// The tiers that have something at `path`
+    async fn holding(&self, path: &str) -> Result<Vec<&dyn FileSystem>> {
+        let mut tiers = vec![];
+        for fs in [self.hot.as_ref(), self.cold.as_ref()] {
+            if fs.exists(path).await? {
+                tiers.push(fs);
+            }
+        }
+        Ok(tiers)
+    }
+}
+
+#[async_trait]
+impl FileSystem for Tiered {
+    #[tracing::instrument]
+    async fn read(&self, path: &str) -> Result<Vec<u8>> {
+        tracing::debug!("{:?}", path);
+        match self.hot.read(path).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => self.cold.read(path).await,
+            result => result,
+        }
+    }
+
+    #[tracing::instrument]
+    async fn read_stream(&self, path: &str) -> Result<FSStream> {
+        tracing::debug!("Streaming from {:?}", path);
+        match self.hot.read_stream(path).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => self.cold.read_stream(path).await,
+            result => result,
+        }
+    }
+
+    #[tracing::instrument]
+    async fn read_range(&self, path: &str, start: u64, len: Option<u64>) -> Result<FSStream> {
+        tracing::debug!("Streaming from {:?} at {}", path, start);
+        match self.hot.read_range(path, start, len).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                self.cold.read_range(path, start, len).await
+            }
+            result => result,
+        }
+    }
+
+    #[tracing::instrument(skip(data))]
+    async fn write(&self, path: &str, data: &[u8]) -> Result<()> {
+        tracing::debug!("{:?}", path);
+        self.hot.write(path, data).await
+    }
+
+    #[tracing::instrument]
+    async fn delete(&self, path: &str) -> Result<()> {
+        tracing::debug!("{:?}", path);
+        let tiers = self.holding(path).await?;
+        if tiers.is_empty() {
+            return Err(Error::new(ErrorKind::NotFound, path));
+        }
+
+        for fs in tiers {
+            fs.delete(path).await?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    async fn exists(&self, path: &str) -> Result<bool> {
+        tracing::debug!("{:?}", path);
+        Ok(self.hot.exists(path).await? || self.cold.exists(path).await?)
+    }
+
+    #[tracing::instrument]
+    async fn metadata(&self, path: &str) -> Result<FileMetadata> {
+        tracing::debug!("{:?}", path);
+        match self.hot.metadata(path).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => self.cold.metadata(path).await,
+            result => result,
+        }
+    }
+
+    #[tracing::instrument]
+    async fn get_file_handler(&self, path: &str) -> Result<FileHandler> {
+        tracing::debug!("{:?}", path);
+        self.hot.get_file_handler(path).await
+    }
+
+    async fn finish_upload(&self, path: &str) -> Result<()> {
+        self.hot.finish_upload(path).await
+    }
+
+    /// Everything in the directory on both tiers, files that somehow
+    /// ended up on both are only listed once
+    #[tracing::instrument]
+    async fn list_dir(&self, path: &str) -> Result<Vec<FileMetadata>> {
+        tracing::debug!("{:?}", path);
+        let mut entries = BTreeMap::new();
+        let mut found = false;
+
+        // cold first so the hot entries replace them
+        for fs in [self.cold.as_ref(), self.hot.as_ref()] {
+            match fs.list_dir(path).await {
+                Ok(list) => {
+                    found = true;
+                    for entry in list {
+                        entries.insert(entry.path.clone(), entry);
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => (),
+                Err(err) => return Err(err),
+            }
+        }
+
+        if !found {
+            return Err(Error::new(ErrorKind::NotFound, path.to_string()));
+        }
+        Ok(entries.into_values().collect())
+    }
+
+    #[tracing::instrument]
+    async fn create_dir_all(&self, path: &str) -> Result<()> {
+        tracing::debug!("{:?}", path);
+        self.hot.create_dir_all(path).await
+    }
+
+    #[tracing::instrument]
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        tracing::debug!("{:?} to {:?}", from, to);
+        let tiers = self.holding(from).await?;
+        if tiers.is_empty() {
+            return Err(Error::new(ErrorKind::NotFound, from.to_string()));
+        }
+
+        for fs in tiers {
+            if let Some(parent) = Path::new(to).parent() {
+                fs.create_dir_all(&parent.to_string_lossy()).await?;
+            }
+            fs.rename(from, to).await?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    async fn delete_empty_dir(&self, path: &str) -> Result<()> {
+        tracing::debug!("{:?}", path);
+        if !self.list_dir(path).await?.is_empty() {
+            return Err(Error::other("Tried to delete a non-empty directory"));
+        }
+
+        for fs in self.holding(path).await? {
+            fs.delete_empty_dir(path).await?;
+        }
+        Ok(())
+    }
+
+    /// The copy stays on the tier the original is on
+    #[tracing::instrument]
+    async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        tracing::debug!("{:?} to {:?}", from, to);
+        let tiers = self.holding(from).await?;
+        let Some(fs) = tiers.first() else {
+            return Err(Error::new(ErrorKind::NotFound, from.to_string()));
+        };
+        fs.copy(from, to).await
+    }
+
+    #[tracing::instrument]
+    async fn move_to_tier(&self, path: &str, tier: Tier) -> Result<()> {
+        let (from, to) = match tier {
+            Tier::Hot => (self.cold.as_ref(), self.hot.as_ref()),
+            Tier::Cold => (self.hot.as_ref(), self.cold.as_ref()),
+        };
+
+        if !from.exists(path).await? {
+            return match to.exists(path).await? {
+                true => Ok(()),
+                false => Err(Error::new(ErrorKind::NotFound, path.to_string())),
+            };
+        }
+
+        tracing::debug!("Moving {:?} to the {:?} tier", path, tier);
+        copy_file(from, to, path).await?;
+        from.delete(path).await
+    }
+
+    async fn repair(&self) -> Result<u64> {
+        Ok(self.hot.repair().await? + self.cold.repair().await?)
+    }
+
+    fn contains_mount_point(&self, path: &str) -> bool {
+        self.hot.contains_mount_point(path) || self.cold.contains_mount_point(path)
+    }
+
+    async fn root_directory(&self) -> PathBuf {
+        self.hot.root_directory().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_system::Memory;
+
+    fn tiered() -> Tiered {
+        Tiered::new(Box::new(Memory::new()), Box::new(Memory::new()))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn moving_between_tiers() {
+        let fs = tiered();
+        fs.create_dir_all("dir").await.unwrap();
+        fs.write("dir/file", b"contents").await.unwrap();
+        assert!(fs.hot.exists("dir/file").await.unwrap());
+
+        fs.move_to_tier("dir/file", Tier::Cold).await.unwrap();
+        assert!(!fs.hot.exists("dir/file").await.unwrap());
+        assert_eq!(fs.cold.read("dir/file").await.unwrap(), b"contents");
+        // still readable from where it is now
+        assert_eq!(fs.read("dir/file").await.unwrap(), b"contents");
+        assert_eq!(fs.metadata("dir/file").await.unwrap().size, 8);
+        // moving it again is a no-op
+        fs.move_to_tier("dir/file", Tier::Cold).await.unwrap();
+
+        fs.move_to_tier("dir/file", Tier::Hot).await.unwrap();
+        assert!(!fs.cold.exists("dir/file").await.unwrap());
+        assert_eq!(fs.hot.read("dir/file").await.unwrap(), b"contents");
+
+        assert_eq!(
+            fs.move_to_tier("missing", Tier::Cold)
+                .await
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deleting_from_both_tiers() {
+        let fs = tiered();
+        fs.write("file", b"old").await.unwrap();
+        fs.move_to_tier("file", Tier::Cold).await.unwrap();
+        // a new upload to the same path lands on the hot tier
+        fs.write("file", b"new").await.unwrap();
+        assert_eq!(fs.read("file").await.unwrap(), b"new");
+
+        fs.delete("file").await.unwrap();
+        assert!(!fs.exists("file").await.unwrap());
+        assert_eq!(
+            fs.delete("file").await.unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+}
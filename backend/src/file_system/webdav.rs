@@ -0,0 +1,995 @@
+//! A WebDAV client backend (Nextcloud, Apache mod_dav, rclone serve webdav etc.)
+//!
+//! WebDAV has no standard way of writing into the middle of a file,
+//! so resumable uploads use whichever [`PartialUpdate`] method the server supports.
+
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use percent_encoding::{AsciiSet, CONTROLS, percent_decode_str, utf8_percent_encode};
+use quick_xml::events::Event;
+use reqwest::{Method, RequestBuilder, StatusCode, Url};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::Debug,
+    fs::OpenOptions,
+    io::{Error, ErrorKind, Result, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    config::{PartialUpdate, WebDAVConfig},
+    file_system::{
+        FSStream, FileHandler, FileMetadata, FileSystem, block_on, range_header, slice_stream,
+    },
+};
+
+/// How much is buffered in a [`RangedHandler`] before it's sent as one ranged write
+const WRITE_BUFFER: usize = 8 * 1024 * 1024;
+
+/// Characters that can't be used as is in a path segment
+const SEGMENT_ENCODE: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}')
+    .add(b'/');
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:">
+  <d:prop>
+    <d:resourcetype/>
+    <d:getcontentlength/>
+    <d:getlastmodified/>
+  </d:prop>
+</d:propfind>"#;
+
+pub struct WebDAV {
+    client: Arc<Client>,
+    partial_update: PartialUpdate,
+}
+
+impl Debug for WebDAV {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("WebDAV")
+    }
+}
+
+struct Client {
+    http: reqwest::Client,
+    base: Url,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl WebDAV {
+    pub fn new(config: &WebDAVConfig) -> Result<Self> {
+        let mut base =
+            Url::parse(&config.url).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        // so joining paths onto it never replaces the last segment
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+
+        Ok(Self {
+            client: Arc::new(Client {
+                http: reqwest::Client::new(),
+                base,
+                username: config.username.clone(),
+                password: config.password.clone(),
+            }),
+            partial_update: config.partial_update.clone().unwrap_or_default(),
+        })
+    }
+
+    async fn propfind(&self, path: &str, depth: u8) -> Result<Vec<DavEntry>> {
+        let res = self
+            .client
+            .request(Method::from_bytes(b"PROPFIND").unwrap(), path)
+            .header("Depth", depth.to_string())
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(PROPFIND_BODY)
+            .send()
+            .await
+            .map_err(Error::other)?;
+        let res = check_status(res).await?;
+
+        parse_multistatus(&res.text().await.map_err(Error::other)?)
+    }
+}
+
+#[async_trait]
+impl FileSystem for WebDAV {
+    #[tracing::instrument]
+    async fn read(&self, path: &str) -> Result<Vec<u8>> {
+        tracing::debug!("{:?}", path);
+        let res = self.client.send(Method::GET, path).await?;
+        Ok(res.bytes().await.map_err(Error::other)?.to_vec())
+    }
+
+    #[tracing::instrument]
+    async fn read_stream(&self, path: &str) -> Result<FSStream> {
+        tracing::debug!("Streaming from {:?}", path);
+        let res = self.client.send(Method::GET, path).await?;
+
+        let stream = res
+            .bytes_stream()
+            .map(|chunk| chunk.map(|b| b.to_vec()).map_err(Error::other));
+        Ok(Box::pin(stream))
+    }
+
+    #[tracing::instrument]
+    async fn read_range(&self, path: &str, start: u64, len: Option<u64>) -> Result<FSStream> {
+        tracing::debug!("Streaming from {:?} at {}", path, start);
+        if len == Some(0) {
+            return Ok(Box::pin(tokio_stream::empty()));
+        }
+
+        let res = self
+            .client
+            .request(Method::GET, path)
+            .header("Range", range_header(start, len))
+            .send()
+            .await
+            .map_err(Error::other)?;
+        let res = match check_status(res).await {
+            Ok(res) => res,
+            // starts past the end
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                return Ok(Box::pin(tokio_stream::empty()));
+            }
+            Err(err) => return Err(err),
+        };
+
+        let partial = res.status() == StatusCode::PARTIAL_CONTENT;
+        let stream: FSStream = Box::pin(
+            res.bytes_stream()
+                .map(|chunk| chunk.map(|b| b.to_vec()).map_err(Error::other)),
+        );
+        // servers are free to ignore the range and send everything
+        match partial {
+            true => Ok(stream),
+            false => Ok(slice_stream(stream, start, len)),
+        }
+    }
+
+    #[tracing::instrument(skip(data))]
+    async fn write(&self, path: &str, data: &[u8]) -> Result<()> {
+        tracing::debug!("{:?}", path);
+        let res = self
+            .client
+            .request(Method::PUT, path)
+            .body(data.to_vec())
+            .send()
+            .await
+            .map_err(Error::other)?;
+        check_status(res).await?;
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    async fn delete(&self, path: &str) -> Result<()> {
+        tracing::debug!("{:?}", path);
+        self.client.send(Method::DELETE, path).await?;
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    async fn exists(&self, path: &str) -> Result<bool> {
+        tracing::debug!("{:?}", path);
+        match self.propfind(path, 0).await {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    #[tracing::instrument]
+    async fn metadata(&self, path: &str) -> Result<FileMetadata> {
+        tracing::debug!("{:?}", path);
+        match self.propfind(path, 0).await?.into_iter().next() {
+            Some(entry) => Ok(FileMetadata {
+                path: self.client.full_path(path),
+                is_dir: entry.is_dir,
+                size: entry.size,
+                modified: entry.modified,
+            }),
+            None => Err(Error::new(ErrorKind::NotFound, "Empty PROPFIND response")),
+        }
+    }
+
+    #[tracing::instrument]
+    async fn get_file_handler(&self, path: &str) -> Result<FileHandler> {
+        tracing::debug!("{:?}", path);
+        let handler: FileHandler = match self.partial_update {
+            PartialUpdate::ContentRange | PartialUpdate::SabreDAV => Box::new(RangedHandler {
+                client: self.client.clone(),
+                path: path.to_string(),
+                method: self.partial_update.clone(),
+                start: 0,
+                buffer: vec![],
+            }),
+            PartialUpdate::None => {
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(self.client.spool_path(path))?;
+                Box::new(file)
+            }
+        };
+        Ok(handler)
+    }
+
+    #[tracing::instrument]
+    async fn finish_upload(&self, path: &str) -> Result<()> {
+        if self.partial_update != PartialUpdate::None {
+            // ranged writes only create the file once there's something to write
+            if !self.exists(path).await? {
+                self.write(path, &[]).await?;
+            }
+            return Ok(());
+        }
+
+        tracing::debug!("Uploading spooled file for {:?}", path);
+        let spool = self.client.spool_path(path);
+        let file = tokio::fs::File::open(&spool).await?;
+        let res = self
+            .client
+            .request(Method::PUT, path)
+            .body(reqwest::Body::wrap_stream(
+                tokio_util::io::ReaderStream::new(file),
+            ))
+            .send()
+            .await
+            .map_err(Error::other)?;
+        check_status(res).await?;
+
+        tokio::fs::remove_file(spool).await
+    }
+
+    #[tracing::instrument]
+    async fn list_dir(&self, path: &str) -> Result<Vec<FileMetadata>> {
+        tracing::debug!("{:?}", path);
+        let own_path = self.client.full_path(path);
+        let own_path = own_path.trim_end_matches('/');
+
+        Ok(self
+            .propfind(path, 1)
+            .await?
+            .into_iter()
+            .filter(|e| e.href.trim_end_matches('/') != own_path)
+            .map(|e| FileMetadata {
+                path: e
+                    .href
+                    .trim_end_matches('/')
+                    .rsplit('/')
+                    .next()
+                    .unwrap_or_default()
+                    .to_string(),
+                is_dir: e.is_dir,
+                size: e.size,
+                modified: e.modified,
+            })
+            .collect())
+    }
+
+    #[tracing::instrument]
+    async fn create_dir_all(&self, path: &str) -> Result<()> {
+        tracing::debug!("{:?}", path);
+        let mut dir = String::new();
+        for part in path.replace("\\", "/").split('/').filter(|p| !p.is_empty()) {
+            dir.push_str(part);
+            dir.push('/');
+
+            let res = self
+                .client
+                .request(Method::from_bytes(b"MKCOL").unwrap(), &dir)
+                .send()
+                .await
+                .map_err(Error::other)?;
+            // 405 means that it already exists
+            if res.status() != StatusCode::METHOD_NOT_ALLOWED {
+                check_status(res).await?;
+            }
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        tracing::debug!("{:?} to {:?}", from, to);
+        let res = self
+            .client
+            .request(Method::from_bytes(b"MOVE").unwrap(), from)
+            .header("Destination", self.client.url(to).as_str())
+            .header("Overwrite", "T")
+            .send()
+            .await
+            .map_err(Error::other)?;
+        check_status(res).await?;
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        tracing::debug!("{:?} to {:?}", from, to);
+        if let Some(parent) = Path::new(to).parent() {
+            self.create_dir_all(&parent.to_string_lossy()).await?;
+        }
+
+        let res = self
+            .client
+            .request(Method::from_bytes(b"COPY").unwrap(), from)
+            .header("Destination", self.client.url(to).as_str())
+            .header("Overwrite", "T")
+            .send()
+            .await
+            .map_err(Error::other)?;
+        check_status(res).await?;
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    async fn delete_empty_dir(&self, path: &str) -> Result<()> {
+        tracing::debug!("{:?}", path);
+        let is_empty = self.list_dir(path).await?.is_empty();
+
+        if is_empty {
+            self.client
+                .send(Method::DELETE, &format!("{path}/"))
+                .await?;
+            Ok(())
+        } else {
+            Err(Error::other("Tried to delete a non-empty directory"))
+        }
+    }
+
+    async fn root_directory(&self) -> PathBuf {
+        PathBuf::from(self.client.full_path(""))
+    }
+}
+
+impl Client {
+    fn url(&self, path: &str) -> Url {
+        let path = path.replace("\\", "/");
+        let encoded = path
+            .split('/')
+            .map(|p| utf8_percent_encode(p, SEGMENT_ENCODE).to_string())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let mut url = self.base.clone();
+        url.set_path(&format!(
+            "{}{}",
+            self.base.path(),
+            encoded.trim_start_matches('/')
+        ));
+        url
+    }
+
+    /// Decoded path of a file on the server, like the full paths of the other backends
+    fn full_path(&self, path: &str) -> String {
+        percent_decode_str(self.url(path).path())
+            .decode_utf8_lossy()
+            .to_string()
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self.http.request(method, self.url(path));
+        match &self.username {
+            Some(username) => request.basic_auth(username, self.password.as_ref()),
+            None => request,
+        }
+    }
+
+    async fn send(&self, method: Method, path: &str) -> Result<reqwest::Response> {
+        let res = self
+            .request(method, path)
+            .send()
+            .await
+            .map_err(Error::other)?;
+        check_status(res).await
+    }
+
+    /// Where uploads are spooled locally for servers without partial updates
+    fn spool_path(&self, path: &str) -> PathBuf {
+        let name = hex::encode(Sha256::digest(self.url(path).as_str().as_bytes()));
+        std::env::temp_dir().join(format!("simply_files_{name}"))
+    }
+}
+
+/// A file handle that sends ranged writes to the server,
+/// buffering sequential writes so not every chunk is its own request
+struct RangedHandler {
+    client: Arc<Client>,
+    path: String,
+    method: PartialUpdate,
+    /// Where in the file `buffer` starts
+    start: u64,
+    buffer: Vec<u8>,
+}
+
+impl RangedHandler {
+    fn send_buffer(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let end = self.start + self.buffer.len() as u64 - 1;
+        let data = std::mem::take(&mut self.buffer);
+        let request = match self.method {
+            PartialUpdate::SabreDAV => self
+                .client
+                .request(Method::PATCH, &self.path)
+                .header("Content-Type", "application/x-sabredav-partialupdate")
+                .header("X-Update-Range", format!("bytes={}-{}", self.start, end)),
+            _ => self
+                .client
+                .request(Method::PUT, &self.path)
+                .header("Content-Range", format!("bytes {}-{}/*", self.start, end)),
+        };
+
+        block_on(async {
+            let res = request.body(data).send().await.map_err(Error::other)?;
+            check_status(res).await
+        })?;
+
+        self.start = end + 1;
+        Ok(())
+    }
+}
+
+impl Write for RangedHandler {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= WRITE_BUFFER {
+            self.send_buffer()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.send_buffer()
+    }
+}
+
+impl Seek for RangedHandler {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let position = self.start + self.buffer.len() as u64;
+        let target = match pos {
+            SeekFrom::Start(p) => p,
+            SeekFrom::Current(offset) => position.saturating_add_signed(offset),
+            SeekFrom::End(_) => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "Can't seek from the end of a remote WebDAV file",
+                ));
+            }
+        };
+
+        if target != position {
+            self.send_buffer()?;
+            self.start = target;
+        }
+        Ok(target)
+    }
+}
+
+async fn check_status(res: reqwest::Response) -> Result<reqwest::Response> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+
+    let kind = match status {
+        StatusCode::NOT_FOUND => ErrorKind::NotFound,
+        StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED => ErrorKind::PermissionDenied,
+        StatusCode::RANGE_NOT_SATISFIABLE => ErrorKind::UnexpectedEof,
+        _ => ErrorKind::Other,
+    };
+    let body = res.text().await.unwrap_or_default();
+    Err(Error::new(
+        kind,
+        format!("WebDAV server responded with {status}: {body}"),
+    ))
+}
+
+#[derive(Debug, Default)]
+struct DavEntry {
+    /// Decoded path of the resource on the server
+    href: String,
+    is_dir: bool,
+    size: u64,
+    modified: u64,
+}
+
+/// Reads a PROPFIND multistatus response, ignoring namespace prefixes
+/// since every server seems to pick their own
+fn parse_multistatus(xml: &str) -> Result<Vec<DavEntry>> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut entries = vec![];
+    let mut current: Option<DavEntry> = None;
+    let mut element = Vec::new();
+
+    loop {
+        match reader
+            .read_event()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?
+        {
+            Event::Start(e) => {
+                element = e.local_name().as_ref().to_vec();
+                match element.as_slice() {
+                    b"response" => current = Some(DavEntry::default()),
+                    b"collection" => current.iter_mut().for_each(|c| c.is_dir = true),
+                    _ => (),
+                }
+            }
+            Event::Empty(e) if e.local_name().as_ref() == b"collection" => {
+                current.iter_mut().for_each(|c| c.is_dir = true)
+            }
+            Event::Text(text) => {
+                let (Some(entry), Ok(text)) = (current.as_mut(), text.unescape()) else {
+                    continue;
+                };
+
+                match element.as_slice() {
+                    b"href" => {
+                        // some servers send the full url instead of only the path
+                        let href = match Url::parse(&text) {
+                            Ok(url) => url.path().to_string(),
+                            Err(_) => text.to_string(),
+                        };
+                        entry.href = percent_decode_str(&href).decode_utf8_lossy().to_string();
+                    }
+                    b"getcontentlength" => entry.size = text.trim().parse().unwrap_or(0),
+                    b"getlastmodified" => {
+                        entry.modified = httpdate::parse_http_date(text.trim())
+                            .unwrap_or(SystemTime::now())
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap_or(Duration::from_secs(0))
+                            .as_secs()
+                    }
+                    _ => (),
+                }
+            }
+            Event::End(e) => {
+                if e.local_name().as_ref() == b"response" {
+                    entries.extend(current.take());
+                }
+                element.clear();
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        Router,
+        body::Bytes,
+        extract::DefaultBodyLimit,
+        http::{HeaderMap, Uri},
+        response::{IntoResponse, Response},
+    };
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        sync::Mutex,
+    };
+    use tokio::net::TcpListener;
+
+    /// Files & directories of [`fake_dav`] by their decoded path below `/dav/`
+    #[derive(Default)]
+    struct Share {
+        files: BTreeMap<String, Vec<u8>>,
+        dirs: BTreeSet<String>,
+    }
+
+    /// Serves just enough WebDAV (without authentication) for the backend,
+    /// everything below `forbidden` answers with 403
+    async fn fake_dav(partial_update: PartialUpdate) -> WebDAV {
+        let share = Arc::new(Mutex::new(Share::default()));
+        let app =
+            Router::new()
+                .fallback(
+                    move |method: Method, uri: Uri, headers: HeaderMap, body: Bytes| {
+                        let share = share.clone();
+                        async move {
+                            fake_request(&mut share.lock().unwrap(), method, uri, headers, body)
+                        }
+                    },
+                )
+                .layer(DefaultBodyLimit::disable());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/dav", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        WebDAV::new(&WebDAVConfig {
+            url,
+            username: None,
+            password: None,
+            partial_update: Some(partial_update),
+        })
+        .unwrap()
+    }
+
+    fn decode_path(path: &str) -> String {
+        let path = percent_decode_str(path).decode_utf8_lossy();
+        path.trim_start_matches("/dav")
+            .trim_matches('/')
+            .to_string()
+    }
+
+    /// Start & end of a `bytes=s-e` or `bytes s-e/*` header
+    fn byte_range(header: &str) -> (usize, Option<usize>) {
+        let range = header[6..].split('/').next().unwrap();
+        let (start, end) = range.split_once('-').unwrap();
+        (start.parse().unwrap(), end.parse().ok())
+    }
+
+    fn fake_request(
+        share: &mut Share,
+        method: Method,
+        uri: Uri,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Response {
+        let path = decode_path(uri.path());
+        if path.starts_with("forbidden") {
+            return StatusCode::FORBIDDEN.into_response();
+        }
+        let header = |name: &str| headers.get(name).map(|h| h.to_str().unwrap().to_string());
+        let is_dir = path.is_empty() || share.dirs.contains(&path);
+        let is_file = share.files.contains_key(&path);
+        let children = |share: &Share, dir: &str| {
+            let prefix = format!("{dir}/");
+            let below = |p: &String| dir.is_empty() || p.starts_with(&prefix);
+            (
+                share
+                    .files
+                    .keys()
+                    .filter(|p| below(p))
+                    .cloned()
+                    .collect::<Vec<_>>(),
+                share
+                    .dirs
+                    .iter()
+                    .filter(|p| below(p))
+                    .cloned()
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        match method.as_str() {
+            "PROPFIND" => {
+                let response = |path: &str, size: Option<usize>| {
+                    let href = utf8_percent_encode(&format!("/dav/{path}"), SEGMENT_ENCODE)
+                        .to_string()
+                        .replace("%2F", "/");
+                    let properties = match size {
+                        Some(size) => format!(
+                            "<d:resourcetype/><d:getcontentlength>{size}</d:getcontentlength>\
+                             <d:getlastmodified>Sun, 06 Nov 1994 08:49:37 GMT</d:getlastmodified>"
+                        ),
+                        None => "<d:resourcetype><d:collection/></d:resourcetype>".to_string(),
+                    };
+                    format!(
+                        "<d:response><d:href>{href}</d:href><d:propstat>\
+                         <d:prop>{properties}</d:prop>\
+                         <d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>"
+                    )
+                };
+
+                let mut responses = match (is_file, is_dir) {
+                    (true, _) => response(&path, Some(share.files[&path].len())),
+                    (_, true) => response(&path, None),
+                    _ => return StatusCode::NOT_FOUND.into_response(),
+                };
+                if is_dir && header("Depth").as_deref() == Some("1") {
+                    let (files, dirs) = children(share, &path);
+                    let direct =
+                        |p: &String| !p[path.len()..].trim_start_matches('/').contains('/');
+                    for dir in dirs.iter().filter(|p| direct(p)) {
+                        responses.push_str(&response(dir, None));
+                    }
+                    for file in files.iter().filter(|p| direct(p)) {
+                        responses.push_str(&response(file, Some(share.files[file].len())));
+                    }
+                }
+                (
+                    StatusCode::MULTI_STATUS,
+                    format!(r#"<?xml version="1.0"?><d:multistatus xmlns:d="DAV:">{responses}</d:multistatus>"#),
+                )
+                    .into_response()
+            }
+            "GET" => {
+                let Some(data) = share.files.get(&path) else {
+                    return StatusCode::NOT_FOUND.into_response();
+                };
+                match header("Range") {
+                    Some(range) => {
+                        let (start, end) = byte_range(&range);
+                        if start >= data.len() {
+                            return StatusCode::RANGE_NOT_SATISFIABLE.into_response();
+                        }
+                        let end = end.map_or(data.len(), |e| (e + 1).min(data.len()));
+                        (StatusCode::PARTIAL_CONTENT, data[start..end].to_vec()).into_response()
+                    }
+                    None => data.clone().into_response(),
+                }
+            }
+            "PUT" | "PATCH" => {
+                let range = match method.as_str() {
+                    "PUT" => header("Content-Range"),
+                    _ => header("X-Update-Range"),
+                };
+                let file = share.files.entry(path).or_default();
+                match range {
+                    Some(range) => {
+                        let (start, _) = byte_range(&range);
+                        if file.len() < start + body.len() {
+                            file.resize(start + body.len(), 0);
+                        }
+                        file[start..start + body.len()].copy_from_slice(&body);
+                    }
+                    None => *file = body.to_vec(),
+                }
+                StatusCode::CREATED.into_response()
+            }
+            "MKCOL" if is_dir || is_file => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+            "MKCOL" => {
+                share.dirs.insert(path);
+                StatusCode::CREATED.into_response()
+            }
+            "DELETE" | "MOVE" | "COPY" => {
+                if !is_file && !is_dir {
+                    return StatusCode::NOT_FOUND.into_response();
+                }
+                let (files, dirs) = match is_file {
+                    true => (vec![path.clone()], vec![]),
+                    false => {
+                        let (files, mut dirs) = children(share, &path);
+                        dirs.push(path.clone());
+                        (files, dirs)
+                    }
+                };
+                let destination = header("Destination")
+                    .map(|d| decode_path(Url::parse(&d).unwrap().path()))
+                    .unwrap_or_default();
+                let moved = |p: &String| format!("{destination}{}", &p[path.len()..]);
+
+                for file in &files {
+                    let data = match method.as_str() {
+                        "COPY" => share.files[file].clone(),
+                        _ => share.files.remove(file).unwrap(),
+                    };
+                    if method.as_str() != "DELETE" {
+                        share.files.insert(moved(file), data);
+                    }
+                }
+                for dir in &dirs {
+                    if method.as_str() != "COPY" {
+                        share.dirs.remove(dir);
+                    }
+                    if method.as_str() != "DELETE" {
+                        share.dirs.insert(moved(dir));
+                    }
+                }
+                StatusCode::NO_CONTENT.into_response()
+            }
+            _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+        }
+    }
+
+    async fn read_all(mut stream: FSStream) -> Vec<u8> {
+        let mut data = vec![];
+        while let Some(chunk) = stream.next().await {
+            data.extend(chunk.unwrap());
+        }
+        data
+    }
+
+    #[test]
+    fn multistatus() {
+        // roughly what Nextcloud sends, with its own prefix and full urls
+        let xml = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:s="http://sabredav.org/ns" xmlns:oc="http://owncloud.org/ns">
+  <d:response>
+    <d:href>https://cloud.example.com/remote.php/dav/files/me/</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype><d:collection/></d:resourcetype>
+        <d:getlastmodified>Sun, 06 Nov 1994 08:49:37 GMT</d:getlastmodified>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+  <D:response xmlns:D="DAV:">
+    <D:href>/remote.php/dav/files/me/a%20b%C3%A9.txt</D:href>
+    <D:propstat>
+      <D:prop>
+        <D:resourcetype/>
+        <D:getcontentlength>1234</D:getcontentlength>
+        <D:getlastmodified>Sun, 06 Nov 1994 08:49:37 GMT</D:getlastmodified>
+      </D:prop>
+    </D:propstat>
+  </D:response>
+</d:multistatus>"#;
+
+        let entries = parse_multistatus(xml).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].href, "/remote.php/dav/files/me/");
+        assert!(entries[0].is_dir);
+        assert_eq!(entries[0].modified, 784111777);
+        assert_eq!(entries[1].href, "/remote.php/dav/files/me/a bé.txt");
+        assert!(!entries[1].is_dir);
+        assert_eq!(entries[1].size, 1234);
+
+        assert!(parse_multistatus("<d:multistatus><d:response></d:href>").is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn files_and_directories() {
+        let dav = fake_dav(PartialUpdate::ContentRange).await;
+        dav.create_dir_all("dir/sub").await.unwrap();
+        // already existing collections are fine
+        dav.create_dir_all("dir").await.unwrap();
+        dav.write("dir/a b#.txt", b"hello world").await.unwrap();
+
+        assert_eq!(dav.read("dir/a b#.txt").await.unwrap(), b"hello world");
+        assert!(dav.exists("dir/a b#.txt").await.unwrap());
+        assert!(!dav.exists("dir/missing").await.unwrap());
+        assert_eq!(
+            read_all(dav.read_range("dir/a b#.txt", 6, Some(3)).await.unwrap()).await,
+            b"wor"
+        );
+        assert_eq!(
+            read_all(dav.read_range("dir/a b#.txt", 6, None).await.unwrap()).await,
+            b"world"
+        );
+        assert!(
+            read_all(dav.read_range("dir/a b#.txt", 100, None).await.unwrap())
+                .await
+                .is_empty()
+        );
+
+        let metadata = dav.metadata("dir/a b#.txt").await.unwrap();
+        assert_eq!(metadata.path, "/dav/dir/a b#.txt");
+        assert_eq!(metadata.size, 11);
+        assert!(!metadata.is_dir);
+        assert!(dav.metadata("dir").await.unwrap().is_dir);
+        assert_eq!(dav.root_directory().await, PathBuf::from("/dav/"));
+
+        let mut entries = dav
+            .list_dir("dir")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.path, e.is_dir))
+            .collect::<Vec<_>>();
+        entries.sort();
+        assert_eq!(
+            entries,
+            [("a b#.txt".to_string(), false), ("sub".to_string(), true)]
+        );
+
+        dav.copy("dir/a b#.txt", "other/copy.txt").await.unwrap();
+        assert_eq!(dav.read("other/copy.txt").await.unwrap(), b"hello world");
+        dav.rename("dir/a b#.txt", "dir/sub/moved.txt")
+            .await
+            .unwrap();
+        assert!(!dav.exists("dir/a b#.txt").await.unwrap());
+        assert_eq!(dav.read("dir/sub/moved.txt").await.unwrap(), b"hello world");
+        dav.rename("dir/sub", "renamed").await.unwrap();
+        assert_eq!(dav.read("renamed/moved.txt").await.unwrap(), b"hello world");
+
+        assert!(dav.delete_empty_dir("renamed").await.is_err());
+        dav.delete("renamed/moved.txt").await.unwrap();
+        dav.delete_empty_dir("renamed").await.unwrap();
+        assert!(!dav.exists("renamed").await.unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn errors() {
+        let dav = fake_dav(PartialUpdate::ContentRange).await;
+        let kind = |result: Result<Vec<u8>>| result.unwrap_err().kind();
+
+        assert_eq!(kind(dav.read("missing").await), ErrorKind::NotFound);
+        assert_eq!(
+            dav.metadata("missing").await.unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(
+            kind(dav.read("forbidden").await),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            dav.write("forbidden/x", b"").await.unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            dav.rename("missing", "other").await.unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert!(dav.exists("forbidden").await.is_err());
+    }
+
+    /// An upload that's interrupted after `split` bytes and resumed with a new handler
+    async fn resumed_upload(dav: &WebDAV, path: &str, data: &[u8], split: usize) {
+        let mut handler = dav.get_file_handler(path).await.unwrap();
+        tokio::task::block_in_place(|| {
+            handler.write_all(&data[..split]).unwrap();
+            handler.flush().unwrap();
+        });
+        drop(handler);
+
+        let mut handler = dav.get_file_handler(path).await.unwrap();
+        tokio::task::block_in_place(|| {
+            handler.seek(SeekFrom::Start(split as u64)).unwrap();
+            handler.write_all(&data[split..]).unwrap();
+            handler.flush().unwrap();
+        });
+        dav.finish_upload(path).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn resume_uploads() {
+        let data = (0..WRITE_BUFFER + 100_000)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+
+        for method in [
+            PartialUpdate::ContentRange,
+            PartialUpdate::SabreDAV,
+            PartialUpdate::None,
+        ] {
+            let dav = fake_dav(method.clone()).await;
+            resumed_upload(&dav, "big.bin", &data, 100_000).await;
+            assert!(dav.read("big.bin").await.unwrap() == data, "{method:?}");
+            if method == PartialUpdate::None {
+                continue;
+            }
+
+            // rewriting the start of a file keeps the rest
+            let mut handler = dav.get_file_handler("big.bin").await.unwrap();
+            tokio::task::block_in_place(|| {
+                handler.write_all(b"rewritten").unwrap();
+                handler.flush().unwrap();
+            });
+            dav.finish_upload("big.bin").await.unwrap();
+            let read = dav.read("big.bin").await.unwrap();
+            assert_eq!(&read[..9], b"rewritten");
+            assert!(read[9..] == data[9..], "{method:?}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn empty_uploads() {
+        for method in [
+            PartialUpdate::ContentRange,
+            PartialUpdate::SabreDAV,
+            PartialUpdate::None,
+        ] {
+            let dav = fake_dav(method.clone()).await;
+            let mut handler = dav.get_file_handler("empty").await.unwrap();
+            tokio::task::block_in_place(|| handler.flush().unwrap());
+            dav.finish_upload("empty").await.unwrap();
+
+            assert!(dav.exists("empty").await.unwrap(), "{method:?}");
+            assert!(dav.read("empty").await.unwrap().is_empty());
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! The server as a library, so tests can build an [`AppState`] and its [`app`]
+//! without going through `main`. With a `memory` file system and a
+//! `sqlite::memory:` database that touches nothing on disk, see `tests/`
+
+use std::{
+    fs::{File, exists},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    Router,
+    extract::DefaultBodyLimit,
+    routing::{any, get, post},
+};
+use sqlx::{SqlitePool, pool::PoolOptions};
+use tower_http::{cors::CorsLayer, timeout::TimeoutLayer};
+
+use crate::{
+    config::Config,
+    file_system::{FileSystem, Swappable},
+    protected::protected_routes,
+    speed_test::speed_test,
+    upload::PARTIAL_DIR,
+};
+
+pub mod config;
+mod db;
+mod dedup;
+mod download;
+mod download_stream;
+mod error;
+pub mod file_system;
+pub mod migration;
+mod password;
+mod preview;
+mod protected;
+mod range;
+pub mod scrub;
+mod signed;
+mod speed_test;
+pub mod sync;
+pub mod tiering;
+mod upload;
+pub mod watcher;
+mod zip;
+
+#[derive(Debug)]
+pub struct AppState {
+    pub config: Config,
+    pub fs: Box<dyn FileSystem>,
+    /// The file system below encryption & compression, see [`migration`]
+    backend: Swappable,
+    pub db: SqlitePool,
+}
+
+impl AppState {
+    /// Connects to the file system and database specified in the config
+    ///
+    /// With a `memory` file system and a `sqlite::memory:` database
+    /// this touches nothing on disk, which is handy for tests & demo instances
+    pub async fn new(config: Config) -> Self {
+        let backend = Swappable::new(
+            config.get_file_system(&config.file_system).await,
+            config.file_system.clone(),
+        );
+        let fs = config.wrap_file_system(Box::new(backend.clone()));
+
+        let in_memory = config.in_memory_db();
+        if !in_memory && !exists(&config.db).expect("Failed to check if the database file exists") {
+            File::create(&config.db).expect("Failed to create missing database file");
+        }
+
+        if let Err(err) = init_folders(fs.as_ref()).await {
+            panic!("Failed to create base folders, can't continue: {err:?}");
+        }
+
+        let mut pool_options = PoolOptions::new();
+        if in_memory {
+            // every connection would otherwise get its own empty database
+            pool_options = pool_options
+                .max_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None);
+        }
+
+        let db = pool_options
+            .connect(&config.db)
+            .await
+            .expect("Failed to connect to database");
+        db::init(&db).await.expect("Failed to init database tables");
+
+        AppState {
+            config,
+            fs,
+            backend,
+            db,
+        }
+    }
+}
+
+#[cfg(test)]
+impl AppState {
+    /// A `memory` file system and an in-memory database
+    pub(crate) async fn in_memory() -> Arc<Self> {
+        let config = toml::from_str(
+            r#"
+            file_system = "memory"
+            addr = "127.0.0.1:0"
+            db = "sqlite::memory:"
+            token = "token"
+            upload_limit = 1000000
+            storage_limit = 1000000
+            upload_timeout = 60
+            "#,
+        )
+        .unwrap();
+        Arc::new(Self::new(config).await)
+    }
+}
+
+/// Every route the server has
+pub fn app(state: Arc<AppState>) -> Router {
+    let (upload_limit, upload_timeout) = (state.config.upload_limit, state.config.upload_timeout);
+
+    Router::new()
+        .route("/", get(root))
+        .route("/d/{*id}", get(download::download))
+        .route("/zip", get(download::download_zip))
+        .route("/qr/file/{*id}", get(download::qr_code))
+        .route("/qr/link/{*id}", get(protected::link::qr_code))
+        .route("/preview_data/{*id}", get(preview::get_preview_data))
+        .route("/unlock/{*id}", post(password::unlock))
+        .route("/o/upload/{*name}", any(upload::public::upload))
+        .route("/verify_link/{*id}", post(protected::link::verify_link))
+        .route(
+            "/translate_path/{*path}",
+            get(protected::path_to_id::path_to_id),
+        )
+        .route(
+            "/translate_id/{*id}",
+            get(protected::path_to_id::id_to_path),
+        )
+        .with_state(state.clone())
+        .nest("/speed_test", speed_test())
+        .nest("/m", protected_routes(state.clone()))
+        .layer(CorsLayer::very_permissive())
+        .layer(TimeoutLayer::new(Duration::from_secs(upload_timeout)))
+        .layer(DefaultBodyLimit::max(upload_limit))
+}
+
+async fn root() -> &'static str {
+    "What will today's adventure be?"
+}
+
+/// Generates a random id with A-Z, a-z & 0-9
+pub fn generate_id(len: Option<usize>) -> String {
+    use rand::Rng;
+    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz1234567890";
+
+    let len = len.unwrap_or(10);
+
+    let mut rng = rand::rng();
+    let hash: String = (0..len)
+        .map(|_| {
+            let idx = rng.random_range(0..CHARSET.len());
+            CHARSET[idx] as char
+        })
+        .collect();
+
+    hash
+}
+
+async fn init_folders(fs: &dyn FileSystem) -> std::io::Result<()> {
+    let root = fs.root_directory().await.to_string_lossy().to_string();
+
+    if !fs.exists(&root).await? {
+        tracing::debug!("Creating /data: {root:?}");
+        fs.create_dir_all("").await?;
+    }
+
+    let public_uploads = PathBuf::from("")
+        .join(".public_uploads")
+        .to_string_lossy()
+        .to_string();
+    if !fs.exists(&public_uploads).await? {
+        fs.create_dir_all(&public_uploads).await?;
+    }
+
+    if !fs.exists(PARTIAL_DIR).await? {
+        fs.create_dir_all(PARTIAL_DIR).await?;
+    }
+
+    Ok(())
+}
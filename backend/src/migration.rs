@@ -0,0 +1,471 @@
+//! Moves every file to another file system from the config while the server keeps running.
+//!
+//! For as long as a migration runs everything written goes to both file systems
+//! (see [`Mirror`]), while the files that were already there are copied over one by one
+//! and checked by size and SHA-256 digest. Copied files are recorded in the database,
+//! so an interrupted migration picks up where it left off when started again.
+//! Once every file is copied the server switches to the new file system and
+//! `file_system` is updated in the config file. The old file system is left as it is.
+//!
+//! Files are copied as they are stored, so encryption, compression and
+//! deduplication carry over unchanged, as do file ids and share links.
+
+use futures_util::StreamExt;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeSet,
+    io::{self, ErrorKind, Write},
+    path::Path,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    AppState,
+    config::{Config, WhichFileSystem},
+    db,
+    dedup::{hash, storage_path},
+    file_system::{FileSystem, Mirror},
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Progress {
+    pub from: WhichFileSystem,
+    pub to: WhichFileSystem,
+    pub total_files: u64,
+    pub copied_files: u64,
+    /// Files copied by an earlier migration that was interrupted
+    pub skipped_files: u64,
+    pub copied_bytes: u64,
+    /// Files that failed to copy, starting the migration again retries them
+    pub failed: Vec<String>,
+    pub running: bool,
+    pub error: Option<String>,
+}
+
+/// The current (or last) migration
+static PROGRESS: LazyLock<Mutex<Option<Progress>>> = LazyLock::new(|| Mutex::new(None));
+
+pub fn progress() -> Option<Progress> {
+    PROGRESS.lock().unwrap().clone()
+}
+
+fn update(f: impl FnOnce(&mut Progress)) {
+    if let Some(progress) = PROGRESS.lock().unwrap().as_mut() {
+        f(progress);
+    }
+}
+
+/// Checks that a migration to `to` can start and marks it as running,
+/// so only one migration can run at a time
+pub fn prepare(state: &AppState, to: &WhichFileSystem) -> Result<(), MigrationError> {
+    let from = state.backend.which();
+    if from == *to {
+        return Err(MigrationError::Invalid("Already using this file system"));
+    }
+    // copying a file onto itself would delete it
+    let used = state.config.members(&from);
+    if state.config.members(to).iter().any(|m| used.contains(m)) {
+        return Err(MigrationError::Invalid(
+            "Can't migrate to a file system that's already in use",
+        ));
+    }
+    if !state.config.is_configured(to) {
+        return Err(MigrationError::Invalid(
+            "The file system isn't configured in the config",
+        ));
+    }
+
+    let mut progress = PROGRESS.lock().unwrap();
+    if progress.as_ref().is_some_and(|p| p.running) {
+        return Err(MigrationError::Invalid("A migration is already running"));
+    }
+
+    *progress = Some(Progress {
+        from,
+        to: to.clone(),
+        total_files: 0,
+        copied_files: 0,
+        skipped_files: 0,
+        copied_bytes: 0,
+        failed: vec![],
+        running: true,
+        error: None,
+    });
+    Ok(())
+}
+
+/// Runs a migration started with [`prepare`] until it's done
+#[tracing::instrument(skip(state))]
+pub async fn migrate(state: &AppState, to: WhichFileSystem) -> Result<(), MigrationError> {
+    let result = run(state, &to).await;
+    update(|progress| {
+        progress.running = false;
+        if let Err(err) = &result {
+            progress.error = Some(format!("{err:?}"));
+        }
+    });
+    result
+}
+
+async fn run(state: &AppState, to: &WhichFileSystem) -> Result<(), MigrationError> {
+    let from = state.backend.which();
+    let source = state.backend.current();
+    let target: Arc<dyn FileSystem> = Arc::from(state.config.get_file_system(to).await);
+    let name = to.to_string();
+    tracing::info!("Migrating every file from {} to {name}", from.to_string());
+
+    // from here on everything is written to both
+    let mirror = Arc::new(Mirror::new(source.clone(), vec![target.clone()]).await);
+    wait_for_handlers(state.backend.swap(mirror, from.clone())).await;
+
+    if let Err(err) = copy_all(state, source.as_ref(), target.as_ref(), &name).await {
+        // no reason to keep writing to both until it's tried again
+        state.backend.swap(source, from);
+        return Err(err);
+    }
+
+    tracing::info!("Every file was copied, switching to {name}");
+    wait_for_handlers(state.backend.swap(target.clone(), to.clone())).await;
+
+    // uploads that ran during the switch wrote to both file systems,
+    // anything that changed on the old one meanwhile is copied again
+    for path in storage_paths(state).await? {
+        let Ok(old) = source.metadata(&path).await else {
+            continue;
+        };
+        if target
+            .metadata(&path)
+            .await
+            .is_ok_and(|new| new.size == old.size)
+        {
+            continue;
+        }
+        copy_logged(state, source.as_ref(), target.as_ref(), &path, &name).await;
+    }
+
+    db::file::reset_tiers(&state.db).await?;
+    db::migration::clear(&state.db).await?;
+
+    if let Err(err) = Config::save_file_system(to) {
+        tracing::error!(
+            "Failed to update the config file, set `file_system` to {name} by hand: {err:?}"
+        );
+    }
+    tracing::info!("Migration to {name} is done");
+
+    match progress().map(|p| p.failed.len()).unwrap_or(0) {
+        0 => Ok(()),
+        failed => Err(MigrationError::Incomplete(failed)),
+    }
+}
+
+/// Copies every directory and every file in the database
+async fn copy_all(
+    state: &AppState,
+    source: &dyn FileSystem,
+    target: &dyn FileSystem,
+    name: &str,
+) -> Result<(), MigrationError> {
+    copy_directories(source, target).await?;
+
+    let paths = storage_paths(state).await?;
+    update(|progress| progress.total_files = paths.len() as u64);
+
+    for (index, path) in paths.iter().enumerate() {
+        copy_logged(state, source, target, path, name).await;
+
+        if (index + 1) % 100 == 0 {
+            tracing::info!("Migrated {}/{} files", index + 1, paths.len());
+        }
+    }
+
+    match progress().map(|p| p.failed.len()).unwrap_or(0) {
+        0 => Ok(()),
+        failed => Err(MigrationError::Incomplete(failed)),
+    }
+}
+
+/// [`copy_verified`], keeping track of the progress
+async fn copy_logged(
+    state: &AppState,
+    source: &dyn FileSystem,
+    target: &dyn FileSystem,
+    path: &str,
+    name: &str,
+) {
+    match copy_verified(state, source, target, path, name).await {
+        Ok(Some(bytes)) => update(|progress| {
+            progress.copied_files += 1;
+            progress.copied_bytes += bytes;
+        }),
+        Ok(None) => update(|progress| progress.skipped_files += 1),
+        Err(MigrationError::IO(err)) if err.kind() == ErrorKind::NotFound => {
+            // deleted or renamed meanwhile, or never fully uploaded
+            tracing::warn!("{path:?} doesn't exist anymore, skipping it");
+            update(|progress| progress.skipped_files += 1);
+        }
+        Err(err) => {
+            tracing::error!("Failed to migrate {path:?}: {err:?}");
+            update(|progress| progress.failed.push(path.to_string()));
+        }
+    }
+}
+
+/// Copies a file and checks that the copy matches, returns how many bytes were copied
+/// or nothing if an earlier migration already copied it
+async fn copy_verified(
+    state: &AppState,
+    source: &dyn FileSystem,
+    target: &dyn FileSystem,
+    path: &str,
+    name: &str,
+) -> Result<Option<u64>, MigrationError> {
+    let size = source.metadata(path).await?.size;
+
+    let migrated = db::migration::get_migrated_size(&state.db, path, name).await?;
+    if migrated == Some(size as i64) && target.metadata(path).await.is_ok_and(|m| m.size == size) {
+        return Ok(None);
+    }
+
+    let digest = copy(source, target, path).await?;
+    if target.metadata(path).await?.size != size || hash(target, path).await? != digest {
+        return Err(MigrationError::Mismatch(path.to_string()));
+    }
+
+    db::migration::set_migrated(&state.db, path, name, size as i64, &digest).await?;
+    Ok(Some(size))
+}
+
+/// Like [`crate::file_system::copy_file`], returns the digest of everything copied
+async fn copy(source: &dyn FileSystem, target: &dyn FileSystem, path: &str) -> io::Result<String> {
+    if let Some(parent) = Path::new(path).parent() {
+        target.create_dir_all(&parent.to_string_lossy()).await?;
+    }
+    if target.exists(path).await? {
+        target.delete(path).await?;
+    }
+
+    let mut stream = source.read_stream(path).await?;
+    let mut handler = target.get_file_handler(path).await?;
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        hasher.update(&chunk);
+        tokio::task::block_in_place(|| handler.write_all(&chunk))?;
+    }
+    tokio::task::block_in_place(|| {
+        handler.flush()?;
+        drop(handler);
+        Ok::<_, io::Error>(())
+    })?;
+
+    target.finish_upload(path).await?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Creates every directory of `source` in `target`, empty ones included
+async fn copy_directories(source: &dyn FileSystem, target: &dyn FileSystem) -> io::Result<()> {
+    let mut dirs = vec![String::new()];
+    while let Some(dir) = dirs.pop() {
+        target.create_dir_all(&dir).await?;
+
+        for entry in source.list_dir(&dir).await? {
+            if entry.is_dir {
+                dirs.push(match dir.is_empty() {
+                    true => entry.path,
+                    false => format!("{dir}/{}", entry.path),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Where every file in the database is stored, files sharing a blob only once
+async fn storage_paths(state: &AppState) -> sqlx::Result<BTreeSet<String>> {
+    Ok(db::file::get_all_files(&state.db)
+        .await?
+        .iter()
+        .map(storage_path)
+        .collect())
+}
+
+/// Waits until every handler opened on a file system that was swapped out is closed,
+/// meaning no upload is writing to it anymore
+async fn wait_for_handlers(generation: Arc<()>) {
+    let mut logged = false;
+    while Arc::strong_count(&generation) > 1 {
+        if !logged {
+            tracing::info!(
+                "Waiting for {} running uploads to finish",
+                Arc::strong_count(&generation) - 1
+            );
+            logged = true;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum MigrationError {
+    IO(io::Error),
+    DB(sqlx::Error),
+    /// The migration can't be started
+    Invalid(&'static str),
+    /// The copy of a file doesn't match the original
+    Mismatch(String),
+    /// How many files failed to copy
+    Incomplete(usize),
+}
+
+impl From<io::Error> for MigrationError {
+    fn from(value: io::Error) -> Self {
+        Self::IO(value)
+    }
+}
+
+impl From<sqlx::Error> for MigrationError {
+    fn from(value: sqlx::Error) -> Self {
+        Self::DB(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_system::{FSStream, FileHandler, Memory};
+    use async_trait::async_trait;
+    use sf_core::FileMetadata;
+    use std::path::PathBuf;
+
+    /// Fails opening `path` once, like a migration that was interrupted halfway through
+    #[derive(Debug, Default)]
+    struct FailOnce {
+        inner: Memory,
+        path: Mutex<Option<String>>,
+    }
+
+    #[async_trait]
+    impl FileSystem for FailOnce {
+        async fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+            self.inner.read(path).await
+        }
+        async fn read_stream(&self, path: &str) -> io::Result<FSStream> {
+            self.inner.read_stream(path).await
+        }
+        async fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+            self.inner.write(path, data).await
+        }
+        async fn delete(&self, path: &str) -> io::Result<()> {
+            self.inner.delete(path).await
+        }
+        async fn exists(&self, path: &str) -> io::Result<bool> {
+            self.inner.exists(path).await
+        }
+        async fn metadata(&self, path: &str) -> io::Result<FileMetadata> {
+            self.inner.metadata(path).await
+        }
+        async fn get_file_handler(&self, path: &str) -> io::Result<FileHandler> {
+            let failing = {
+                let mut failing = self.path.lock().unwrap();
+                failing.take_if(|failing| failing == path).is_some()
+            };
+            if failing {
+                return Err(io::Error::other("Interrupted"));
+            }
+            self.inner.get_file_handler(path).await
+        }
+        async fn list_dir(&self, path: &str) -> io::Result<Vec<FileMetadata>> {
+            self.inner.list_dir(path).await
+        }
+        async fn create_dir_all(&self, path: &str) -> io::Result<()> {
+            self.inner.create_dir_all(path).await
+        }
+        async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+            self.inner.rename(from, to).await
+        }
+        async fn delete_empty_dir(&self, path: &str) -> io::Result<()> {
+            self.inner.delete_empty_dir(path).await
+        }
+        async fn root_directory(&self) -> PathBuf {
+            self.inner.root_directory().await
+        }
+    }
+
+    fn start_progress() {
+        *PROGRESS.lock().unwrap() = Some(Progress {
+            from: WhichFileSystem::Memory,
+            to: WhichFileSystem::Memory,
+            total_files: 0,
+            copied_files: 0,
+            skipped_files: 0,
+            copied_bytes: 0,
+            failed: vec![],
+            running: true,
+            error: None,
+        });
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn resume_interrupted_migration() {
+        let state = AppState::in_memory().await;
+        state.fs.create_dir_all("dir").await.unwrap();
+        state.fs.create_dir_all("empty").await.unwrap();
+        for (id, path, data) in [
+            ("a", "a.txt", b"first".as_slice()),
+            ("b", "dir/b.txt", b"second"),
+            ("c", "dir/c.txt", b"third"),
+        ] {
+            let mut file = db::file::new(&state.db, id, path, -1).await.unwrap();
+            db::file::successful_upload(&mut file, &state.db, data.len() as i64)
+                .await
+                .unwrap();
+            state.fs.write(path, data).await.unwrap();
+        }
+
+        let target = FailOnce::default();
+        *target.path.lock().unwrap() = Some("dir/b.txt".into());
+
+        start_progress();
+        let result = copy_all(&state, state.fs.as_ref(), &target, "memory").await;
+        assert!(matches!(result, Err(MigrationError::Incomplete(1))));
+        let first = progress().unwrap();
+        assert_eq!((first.total_files, first.copied_files), (3, 2));
+        assert_eq!(first.failed, ["dir/b.txt"]);
+        assert!(target.exists("empty").await.unwrap());
+        assert!(!target.exists("dir/b.txt").await.unwrap());
+        assert_eq!(
+            db::migration::get_migrated_size(&state.db, "a.txt", "memory")
+                .await
+                .unwrap(),
+            Some(5)
+        );
+
+        // only what's missing is copied when it's started again
+        start_progress();
+        copy_all(&state, state.fs.as_ref(), &target, "memory")
+            .await
+            .unwrap();
+        let second = progress().unwrap();
+        assert_eq!((second.copied_files, second.skipped_files), (1, 2));
+        assert_eq!(second.copied_bytes, 6);
+        for path in ["a.txt", "dir/b.txt", "dir/c.txt"] {
+            assert_eq!(
+                target.read(path).await.unwrap(),
+                state.fs.read(path).await.unwrap()
+            );
+        }
+
+        // a file changed since it was copied is copied again
+        state.fs.write("a.txt", b"changed").await.unwrap();
+        start_progress();
+        copy_all(&state, state.fs.as_ref(), &target, "memory")
+            .await
+            .unwrap();
+        assert_eq!(progress().unwrap().copied_files, 1);
+        assert_eq!(target.read("a.txt").await.unwrap(), b"changed");
+    }
+}
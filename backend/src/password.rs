@@ -0,0 +1,302 @@
+//! Files anyone with their password can access, see [`FileAccess::Protected`].
+//!
+//! The password can be sent with every request in the `X-File-Password` header
+//! or the `password` query, or exchanged once at `POST /unlock/{id}` for a cookie
+//! and a [`crate::signed`] link that work for an hour, or until its password or access changes.
+//! Checking a password is slow on purpose, so that's what video players asking for
+//! range after range should use. Only a few passwords of a client are checked at once,
+//! and clients that keep getting the password of a file wrong have to wait longer and longer.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use argon2::{
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{SaltString, rand_core::OsRng},
+};
+use axum::{
+    Json,
+    extract::{ConnectInfo, Path, State},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::{
+    CookieJar,
+    cookie::{Cookie, SameSite},
+};
+use serde::Deserialize;
+use sf_core::{File, FileAccess};
+use time::OffsetDateTime;
+use tokio::sync::Semaphore;
+
+use crate::{
+    AppState, db,
+    error::{SimplyError, err},
+    protected::standalone_auth,
+    signed::{self, Signature},
+};
+
+pub const HEADER: &str = "x-file-password";
+/// How long unlocking a file lasts
+const UNLOCK_DURATION: u64 = 60 * 60;
+/// Every check takes 19 MiB and a core for a while, so a client only gets a few at once
+const CONCURRENT_CHECKS: usize = 2;
+/// Attempts at the password of a file a client gets before it has to wait
+const FREE_ATTEMPTS: u32 = 5;
+/// The longest a client has to wait, and how long until its attempts are forgotten
+const MAX_BACKOFF: Duration = Duration::from_secs(15 * 60);
+
+/// Checks running or waiting by client, removed once it has none left
+static CHECKS: LazyLock<Mutex<HashMap<IpAddr, Arc<Semaphore>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+static ATTEMPTS: LazyLock<Mutex<Attempts>> = LazyLock::new(|| {
+    Mutex::new(Attempts {
+        wrong: HashMap::new(),
+        pruned: Instant::now(),
+    })
+});
+
+struct Attempts {
+    /// Recent wrong attempts by file id & client, until the password was right
+    wrong: HashMap<(String, IpAddr), WrongAttempts>,
+    /// Forgotten attempts are only removed every [`MAX_BACKOFF`]
+    pruned: Instant,
+}
+
+struct WrongAttempts {
+    count: u32,
+    last: Instant,
+}
+
+impl Attempts {
+    /// If the client has to wait before trying the password again
+    fn backing_off(&mut self, key: &(String, IpAddr)) -> bool {
+        if self.pruned.elapsed() >= MAX_BACKOFF {
+            self.wrong
+                .retain(|_, attempts| attempts.last.elapsed() < MAX_BACKOFF);
+            self.pruned = Instant::now();
+        }
+
+        self.wrong.get(key).is_some_and(|attempts| {
+            let elapsed = attempts.last.elapsed();
+            attempts.count >= FREE_ATTEMPTS
+                && elapsed < MAX_BACKOFF
+                && elapsed < backoff(attempts.count)
+        })
+    }
+
+    fn count(&mut self, key: (String, IpAddr)) {
+        let attempts = self.wrong.entry(key).or_insert(WrongAttempts {
+            count: 0,
+            last: Instant::now(),
+        });
+        if attempts.last.elapsed() >= MAX_BACKOFF {
+            attempts.count = 0;
+        }
+        attempts.count += 1;
+        attempts.last = Instant::now();
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordQuery {
+    password: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Access {
+    Granted,
+    /// The file is protected, but the request had no (or the wrong) password
+    PasswordRequired,
+    /// The client got the password wrong too often lately
+    TooManyAttempts,
+    Denied,
+}
+
+/// If the request can access the file, with the token,
+/// a signed link or the password if the file is protected
+pub async fn check(
+    state: &AppState,
+    jar: &CookieJar,
+    headers: &HeaderMap,
+    ip: IpAddr,
+    file: &File,
+    signature: &Signature,
+    query: &PasswordQuery,
+) -> Access {
+    let token = &state.config.token;
+    match file.get_access() {
+        FileAccess::Public => return Access::Granted,
+        _ if standalone_auth(jar, headers, token) || signature.is_valid(token, &file.id) => {
+            return Access::Granted;
+        }
+        FileAccess::Private => return Access::Denied,
+        FileAccess::Protected => (),
+    }
+
+    let unlocked = signature.unlocks(token, file)
+        || jar
+            .get(&cookie_name(&file.id))
+            .is_some_and(|cookie| Signature::from_query(cookie.value()).unlocks(token, file));
+    if unlocked {
+        return Access::Granted;
+    }
+
+    let password = headers
+        .get(HEADER)
+        .and_then(|header| header.to_str().ok())
+        .or(query.password.as_deref());
+    match password {
+        Some(password) => attempt(file, ip, password).await,
+        None => Access::PasswordRequired,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnlockBody {
+    password: String,
+}
+
+/// Gives a cookie & a signed link to a protected file for its password
+pub async fn unlock(
+    jar: CookieJar,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<UnlockBody>,
+) -> Result<Response, SimplyError> {
+    let file = match db::file::get_via_id(&state.db, &id).await {
+        Ok(f) => f,
+        Err(err) => match err {
+            sqlx::Error::RowNotFound => err!("No file with this id found", NOT_FOUND),
+            _ => return Err(SimplyError::from(err)),
+        },
+    };
+
+    if file.get_access() != FileAccess::Protected {
+        err!("This file isn't protected by a password", BAD_REQUEST);
+    }
+    match attempt(&file, addr.ip(), &body.password).await {
+        Access::Granted => (),
+        Access::TooManyAttempts => err!(
+            "Too many wrong passwords, try again later",
+            TOO_MANY_REQUESTS
+        ),
+        _ => err!("Wrong password", UNAUTHORIZED),
+    }
+
+    let expires = OffsetDateTime::now_utc() + Duration::from_secs(UNLOCK_DURATION);
+    let link = signed::unlock_link(&state, &file, expires.unix_timestamp());
+
+    let mut cookie = Cookie::new(cookie_name(&file.id), link.query.clone());
+    cookie.set_expires(expires);
+    cookie.set_path("/");
+    cookie.set_secure(true);
+    if let Some(domain) = &state.config.cookie_domain {
+        cookie.set_domain(domain.clone());
+    }
+    cookie.set_same_site(SameSite::None);
+    cookie.set_http_only(true);
+
+    Ok((jar.add(cookie), Json(link)).into_response())
+}
+
+fn cookie_name(id: &str) -> String {
+    format!("unlock_{id}")
+}
+
+/// The Argon2 hash of the password to store
+pub async fn hash(password: String) -> Result<String, SimplyError> {
+    let hash = tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+    })
+    .await;
+
+    match hash {
+        Ok(Ok(hash)) => Ok(hash),
+        Ok(Err(err)) => err!(format!("Failed to hash password: {err}")),
+        Err(err) => err!("Failed to hash password", INTERNAL_SERVER_ERROR, err),
+    }
+}
+
+/// Checks the password, unless the client has to wait before trying again
+async fn attempt(file: &File, ip: IpAddr, password: &str) -> Access {
+    let key = (file.id.clone(), ip);
+    {
+        let mut attempts = ATTEMPTS.lock().unwrap();
+        if attempts.backing_off(&key) {
+            return Access::TooManyAttempts;
+        }
+        // counted before it's checked, so attempts at the same time count too
+        attempts.count(key.clone());
+    }
+
+    if verify(file, ip, password).await {
+        ATTEMPTS.lock().unwrap().wrong.remove(&key);
+        Access::Granted
+    } else {
+        Access::PasswordRequired
+    }
+}
+
+/// How long to wait after the last of `count` attempts, doubling with every attempt
+fn backoff(count: u32) -> Duration {
+    let exponent = count.saturating_sub(FREE_ATTEMPTS).min(16);
+    Duration::from_secs(1 << exponent).min(MAX_BACKOFF)
+}
+
+/// A protected file without a password can't be unlocked
+async fn verify(file: &File, ip: IpAddr, password: &str) -> bool {
+    let (Some(hash), password) = (file.password.clone(), password.to_string()) else {
+        return false;
+    };
+
+    let checks = ClientChecks::new(ip);
+    let Ok(_permit) = checks.semaphore.acquire().await else {
+        return false;
+    };
+    tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// The checks of a client, removed from [`CHECKS`] once the last one is dropped
+struct ClientChecks {
+    ip: IpAddr,
+    semaphore: Arc<Semaphore>,
+}
+
+impl ClientChecks {
+    fn new(ip: IpAddr) -> Self {
+        let semaphore = CHECKS
+            .lock()
+            .unwrap()
+            .entry(ip)
+            .or_insert_with(|| Arc::new(Semaphore::new(CONCURRENT_CHECKS)))
+            .clone();
+        Self { ip, semaphore }
+    }
+}
+
+impl Drop for ClientChecks {
+    fn drop(&mut self) {
+        let mut checks = CHECKS.lock().unwrap();
+        // only the map & this one hold it, so nothing else is running or waiting
+        if Arc::strong_count(&self.semaphore) == 2 {
+            checks.remove(&self.ip);
+        }
+    }
+}
@@ -1,26 +1,38 @@
-use std::{ffi::OsString, path::PathBuf, sync::Arc};
+use std::{ffi::OsString, net::SocketAddr, path::PathBuf, sync::Arc};
 
 use axum::{
     Json,
-    extract::{Path, State},
+    extract::{ConnectInfo, Path, Query, State},
     http::HeaderMap,
     response::Result,
 };
 use axum_extra::extract::CookieJar;
-use sf_core::{FileAccess, PreviewData};
+use sf_core::PreviewData;
 
 use crate::{
     AppState, db,
+    dedup::storage_path,
     error::{SimplyError, err},
+    password::{self, Access, PasswordQuery},
     protected::standalone_auth,
+    signed::Signature,
 };
 
 pub const PREVIEW_FILE_LIMIT: i64 = 512_000_000; // 512 MB
 
+/// Videos & audio are streamed in ranges by the browser, so they can be
+/// previewed no matter how big they are
+pub fn can_preview(size: i64, mime_type: &str) -> bool {
+    size <= PREVIEW_FILE_LIMIT || mime_type.starts_with("video/") || mime_type.starts_with("audio/")
+}
+
 pub async fn get_preview_data(
     jar: CookieJar,
     headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Path(id): Path<String>,
+    Query(signature): Query<Signature>,
+    Query(password): Query<PasswordQuery>,
     State(state): State<Arc<AppState>>,
 ) -> Result<Json<PreviewData>, SimplyError> {
     let file = match db::file::get_via_id(&state.db, &id).await {
@@ -31,16 +43,29 @@ pub async fn get_preview_data(
         },
     };
 
-    if !state.fs.exists(&file.path).await.unwrap_or(false) {
+    if !state.fs.exists(&storage_path(&file)).await.unwrap_or(false) {
         err!("No actual file found", NOT_FOUND);
     }
 
-    if file.get_access() == FileAccess::Private
-        && !standalone_auth(&jar, &headers, &state.config.token)
-    {
-        err!("You can't access this file", UNAUTHORIZED);
-    }
+    // the name, size & type are still sent so it's clear what's being unlocked
+    let ip = addr.ip();
+    let password_required =
+        match password::check(&state, &jar, &headers, ip, &file, &signature, &password).await {
+            Access::Granted => false,
+            Access::PasswordRequired => true,
+            Access::TooManyAttempts => {
+                err!(
+                    "Too many wrong passwords, try again later",
+                    TOO_MANY_REQUESTS
+                )
+            }
+            Access::Denied => err!("You can't access this file", UNAUTHORIZED),
+        };
 
+    let mime_type = mime_guess::from_path(&file.path)
+        .first()
+        .unwrap_or(mime_guess::mime::APPLICATION_OCTET_STREAM)
+        .to_string();
     let data = PreviewData {
         size: file.size,
         file_name: PathBuf::from(&file.path)
@@ -50,18 +75,17 @@ pub async fn get_preview_data(
             .to_string(),
         id,
         created_at: file.created_at.clone(),
-        mime_type: mime_guess::from_path(&file.path)
-            .first()
-            .unwrap_or(mime_guess::mime::APPLICATION_OCTET_STREAM)
-            .to_string(),
+        cant_preview: !can_preview(file.size, &mime_type),
+        mime_type,
         access: file.get_access() as i64,
+        hash: file.hash.clone().filter(|_| !password_required),
         // only send the path if its an authorized user no matter
         path: if standalone_auth(&jar, &headers, &state.config.token) {
             Some(file.path)
         } else {
             None
         },
-        cant_preview: file.size > PREVIEW_FILE_LIMIT,
+        password_required,
     };
 
     Ok(Json(data))
@@ -0,0 +1,139 @@
+use std::{path::PathBuf, sync::Arc};
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    response::Result,
+};
+use serde::Deserialize;
+use sf_core::File;
+
+use crate::{
+    AppState, db, dedup,
+    error::{SimplyError, err},
+    generate_id,
+};
+
+use super::directory::{is_reserved, walk};
+
+#[derive(Debug, Deserialize)]
+pub struct CopyQuery {
+    pub to: String,
+    /// Gives the copies the access level of the originals, otherwise they're private
+    pub keep_access: Option<bool>,
+}
+
+/// Copies a file, or a directory with everything inside it, without it leaving the server.
+/// Every copy is a new file with its own id
+pub async fn copy(
+    Path(path): Path<String>,
+    Query(query): Query<CopyQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<File>>, SimplyError> {
+    let (from, to) = (path.trim_matches('/'), query.to.trim_matches('/'));
+    let keep_access = query.keep_access.unwrap_or(false);
+    if is_reserved(from) || is_reserved(to) {
+        err!("This path can't be copied", FORBIDDEN);
+    }
+    if PathBuf::from(to).starts_with(from) {
+        err!("Can't copy a directory into itself", BAD_REQUEST);
+    }
+    if state.fs.exists(to).await?
+        || !db::file::get_files_below(&state.db, to).await?.is_empty()
+        || db::file::get_via_path(&state.db, to).await.is_ok()
+    {
+        err!("Something already exists at the new path", CONFLICT);
+    }
+
+    if let Ok(file) = db::file::get_via_path(&state.db, from).await {
+        if !dedup::is_uploaded(&file) {
+            err!("The file isn't fully uploaded yet", CONFLICT);
+        }
+        check_storage(&state, std::slice::from_ref(&file)).await?;
+        return Ok(Json(vec![copy_file(&state, &file, to, keep_access).await?]));
+    }
+
+    let files = db::file::get_files_below(&state.db, from)
+        .await?
+        .into_iter()
+        .filter(dedup::is_uploaded)
+        .collect::<Vec<_>>();
+    if files.is_empty() && !state.fs.exists(from).await? {
+        err!("Nothing to copy found", NOT_FOUND);
+    }
+    check_storage(&state, &files).await?;
+
+    let (directories, fs_files) = walk(state.fs.as_ref(), from).await?;
+    let copied_path = |path: &str| format!("{to}{}", &path[from.len()..]);
+
+    for dir in &directories {
+        state.fs.create_dir_all(&copied_path(dir)).await?;
+    }
+
+    let mut copies = vec![];
+    for file in &files {
+        copies.push(copy_file(&state, file, &copied_path(&file.path), keep_access).await?);
+    }
+
+    // files that aren't in the database, they're added by the next sync like the originals.
+    // Unfinished uploads are staged elsewhere so they aren't in here
+    for path in fs_files {
+        if !files.iter().any(|f| f.path == path) {
+            state.fs.copy(&path, &copied_path(&path)).await?;
+        }
+    }
+
+    tracing::info!("Copied {from:?} with {} files to {to:?}", copies.len());
+    Ok(Json(copies))
+}
+
+/// Copies one file and gives it a database entry of its own,
+/// deduplicated files only get another reference to their blob
+async fn copy_file(
+    state: &AppState,
+    file: &File,
+    to: &str,
+    keep_access: bool,
+) -> Result<File, SimplyError> {
+    if file.blob.is_none() {
+        state.fs.copy(&file.path, to).await?;
+    }
+
+    let result = async {
+        let stored_size = match &file.blob {
+            Some(_) => file.stored_size.unwrap_or(file.size),
+            None => state.fs.stored_size(to).await? as i64,
+        };
+        let id = generate_id(None);
+        Ok::<_, SimplyError>(
+            db::file::insert_copy(&state.db, file, &id, to, stored_size, keep_access).await?,
+        )
+    }
+    .await;
+
+    // otherwise it would be a copy the database doesn't know about
+    if result.is_err()
+        && file.blob.is_none()
+        && let Err(err) = state.fs.delete(to).await
+    {
+        tracing::error!("Failed to delete the copy at {to:?}: {err:?}");
+    }
+    result
+}
+
+/// Deduplicated files don't take up any more space when they're copied
+async fn check_storage(state: &AppState, files: &[File]) -> Result<(), SimplyError> {
+    let needed = files
+        .iter()
+        .filter(|f| f.blob.is_none())
+        .map(|f| f.stored_size.unwrap_or(f.size) as u64)
+        .sum::<u64>();
+
+    let bytes_stored = db::file::get_bytes_stored(&state.db).await?;
+    let remaining_storage =
+        (state.config.storage_limit as u64).saturating_sub(bytes_stored.physical as u64);
+    if needed > remaining_storage {
+        err!("Not enough storage left for the copy", INSUFFICIENT_STORAGE);
+    }
+    Ok(())
+}
@@ -1,14 +1,25 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{io, path::PathBuf, sync::Arc};
 
 use axum::{
     Json,
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
-    response::Result,
+    response::{IntoResponse, Response, Result},
 };
+use serde::{Deserialize, Serialize};
 use sf_core::ClientFile;
 
-use crate::{AppState, error::SimplyError};
+use crate::{
+    AppState,
+    db::{self, links::FileLink},
+    dedup::{self, BLOB_DIR},
+    error::{SimplyError, err},
+    file_system::FileSystem,
+    upload::PARTIAL_DIR,
+    zip::{self, Zip},
+};
+
+use super::file::RenameQuery;
 
 pub async fn get_files(
     Path(path): Path<String>,
@@ -35,14 +46,60 @@ async fn get(
 
     let files = files
         .iter()
-        // hide .public_uploads directory
-        .filter(|f| !f.path.starts_with(".public_uploads"))
+        // hide .public_uploads, .blobs & .partial directory
+        .filter(|f| {
+            !f.path.starts_with(".public_uploads")
+                && !f.path.starts_with(BLOB_DIR)
+                && !f.path.starts_with(PARTIAL_DIR)
+        })
         .map(|f| f.clone())
         .collect();
 
     Ok(Json(files))
 }
 
+/// Everything in the directory as one ZIP archive
+pub async fn download_directory(
+    Path(path): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Response, SimplyError> {
+    download(state, Some(path.trim_end_matches('/'))).await
+}
+
+pub async fn download_root(State(state): State<Arc<AppState>>) -> Result<Response, SimplyError> {
+    download(state, None).await
+}
+
+async fn download(state: Arc<AppState>, path: Option<&str>) -> Result<Response, SimplyError> {
+    let mut files = match path {
+        Some(path) => db::file::get_files_below(&state.db, path).await?,
+        None => db::file::get_all_files(&state.db).await?,
+    };
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let mut zip = Zip::default();
+    for file in files {
+        let name = match path {
+            Some(path) => file.path[path.len() + 1..].to_string(),
+            // hidden in the root too
+            None if file.path.starts_with(".public_uploads/") => continue,
+            None => file.path.clone(),
+        };
+        if dedup::is_uploaded(&file) {
+            zip.add(&name, file);
+        }
+    }
+
+    if zip.is_empty() {
+        err!("No files in this directory", NOT_FOUND);
+    }
+
+    let name = path
+        .and_then(|path| path.rsplit('/').next())
+        .unwrap_or("files");
+    zip::response(state, zip, &format!("{name}.zip"))
+}
+
 pub async fn add_directory(
     Path(path): Path<String>,
     State(state): State<Arc<AppState>>,
@@ -51,10 +108,201 @@ pub async fn add_directory(
     Ok(StatusCode::OK)
 }
 
+#[derive(Debug, Deserialize)]
+pub struct DeleteDirectoryQuery {
+    /// Also deletes everything inside the directory
+    pub recursive: Option<bool>,
+    /// Only lists what a recursive delete would delete
+    pub dry_run: Option<bool>,
+}
+
+/// Everything a recursive delete deleted, or would delete in a dry run
+#[derive(Debug, Serialize)]
+pub struct DeletedDirectory {
+    pub files: Vec<String>,
+    /// Nested directories first
+    pub directories: Vec<String>,
+    /// Ids of the links the files were uploaded with
+    pub links: Vec<String>,
+    pub dry_run: bool,
+}
+
 pub async fn delete_directory(
     Path(path): Path<String>,
+    Query(query): Query<DeleteDirectoryQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Response, SimplyError> {
+    let path = path.trim_matches('/');
+    if query.recursive.unwrap_or(false) {
+        let deleted = delete_recursive(&state, path, query.dry_run.unwrap_or(false)).await?;
+        return Ok(Json(deleted).into_response());
+    }
+
+    // deduplicated files aren't in the directory on the file system
+    if !crate::db::file::get_files_in_directory(&state.db, path)
+        .await?
+        .is_empty()
+    {
+        err!("Directory isn't empty", CONFLICT);
+    }
+
+    state.fs.delete_empty_dir(path).await?;
+    Ok(StatusCode::OK.into_response())
+}
+
+/// Deletes the directory with every file and directory inside it,
+/// along with their database entries and the links they were uploaded with
+async fn delete_recursive(
+    state: &AppState,
+    path: &str,
+    dry_run: bool,
+) -> Result<DeletedDirectory, SimplyError> {
+    if is_reserved(path) {
+        err!("This directory can't be deleted", FORBIDDEN);
+    }
+    // the files on the mount would be deleted, but not the mount point
+    if state.fs.contains_mount_point(path) {
+        err!(
+            "Directories with mount points in them can't be deleted",
+            FORBIDDEN
+        );
+    }
+
+    let db_files = db::file::get_files_below(&state.db, path).await?;
+    if db_files.is_empty() && !state.fs.exists(path).await? {
+        err!("No directory found", NOT_FOUND);
+    }
+    let (directories, fs_files) = walk(state.fs.as_ref(), path).await?;
+    // files that aren't in the database, deduplicated & unfinished ones are stored elsewhere
+    let stray_files = fs_files
+        .into_iter()
+        .filter(|f| !db_files.iter().any(|db| db.path == *f))
+        .collect::<Vec<_>>();
+
+    let mut links = vec![];
+    for file in &db_files {
+        links.extend(FileLink::get_via_uploaded_file(&state.db, &file.id).await?);
+    }
+
+    let mut files = db_files
+        .iter()
+        .map(|f| f.path.clone())
+        .chain(stray_files.iter().cloned())
+        .collect::<Vec<_>>();
+    files.sort();
+    let deleted = DeletedDirectory {
+        files,
+        directories: directories.iter().rev().cloned().collect(),
+        links: links.iter().map(|l| l.id.clone()).collect(),
+        dry_run,
+    };
+    if dry_run {
+        return Ok(deleted);
+    }
+
+    tracing::info!(
+        "Deleting {path:?} with {} files and {} directories",
+        deleted.files.len(),
+        deleted.directories.len()
+    );
+    for file in &db_files {
+        dedup::delete_file(state, file).await?;
+    }
+    for link in &links {
+        FileLink::delete(&state.db, &link.id).await?;
+    }
+    for file in &stray_files {
+        state.fs.delete(file).await?;
+    }
+    for dir in &deleted.directories {
+        state.fs.delete_empty_dir(dir).await?;
+    }
+
+    Ok(deleted)
+}
+
+/// Moves a directory and everything inside it, the files keep their ids (and links)
+pub async fn rename_directory(
+    Path(path): Path<String>,
+    Query(query): Query<RenameQuery>,
     State(state): State<Arc<AppState>>,
 ) -> Result<StatusCode, SimplyError> {
-    state.fs.delete_empty_dir(&path).await?;
+    let (from, to) = (path.trim_matches('/'), query.to.trim_matches('/'));
+    if is_reserved(from) || is_reserved(to) {
+        err!("This directory can't be moved", FORBIDDEN);
+    }
+    if PathBuf::from(to).starts_with(from) {
+        err!("Can't move a directory into itself", BAD_REQUEST);
+    }
+    if state.fs.contains_mount_point(from) {
+        err!(
+            "Directories with mount points in them can't be moved",
+            FORBIDDEN
+        );
+    }
+    if state.fs.exists(to).await?
+        || !db::file::get_files_below(&state.db, to).await?.is_empty()
+        || db::file::get_via_path(&state.db, to).await.is_ok()
+    {
+        err!("Something already exists at the new path", CONFLICT);
+    }
+
+    if state.fs.exists(from).await? {
+        if let Some(parent) = PathBuf::from(to).parent() {
+            state.fs.create_dir_all(&parent.to_string_lossy()).await?;
+        }
+        state.fs.rename(from, to).await?;
+    } else if db::file::get_files_below(&state.db, from).await?.is_empty() {
+        err!("No directory found", NOT_FOUND);
+    } else {
+        // only deduplicated files are left, those aren't on the file system
+        state.fs.create_dir_all(to).await?;
+    }
+
+    match db::file::rename_directory(&state.db, from, to).await {
+        Ok(moved) => tracing::info!("Moved {from:?} with {moved} files to {to:?}"),
+        Err(err) => {
+            // the files would be at another path than the database says
+            if let Err(err) = state.fs.rename(to, from).await {
+                tracing::error!("Failed to move {to:?} back to {from:?}: {err:?}");
+            }
+            return Err(err.into());
+        }
+    }
+
     Ok(StatusCode::OK)
 }
+
+/// The root and the directories the server keeps its own files in
+pub(super) fn is_reserved(path: &str) -> bool {
+    let reserved = [".public_uploads", BLOB_DIR, PARTIAL_DIR];
+    path.is_empty() || reserved.iter().any(|d| PathBuf::from(path).starts_with(d))
+}
+
+/// Every directory from `path` on down (parents before their children)
+/// and every file the file system has in them
+pub(super) async fn walk(
+    fs: &dyn FileSystem,
+    path: &str,
+) -> io::Result<(Vec<String>, Vec<String>)> {
+    let (mut dirs, mut files) = (vec![path.to_string()], vec![]);
+    let mut index = 0;
+    while let Some(dir) = dirs.get(index).cloned() {
+        let entries = match fs.list_dir(&dir).await {
+            Ok(entries) => entries,
+            // only its deduplicated files are left, those aren't on the file system
+            Err(err) if err.kind() == io::ErrorKind::NotFound && index == 0 => vec![],
+            Err(err) => return Err(err),
+        };
+
+        for entry in entries {
+            let entry_path = format!("{dir}/{}", entry.path);
+            match entry.is_dir {
+                true => dirs.push(entry_path),
+                false => files.push(entry_path),
+            }
+        }
+        index += 1;
+    }
+    Ok((dirs, files))
+}
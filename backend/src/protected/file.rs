@@ -1,14 +1,21 @@
 use std::sync::Arc;
 
 use axum::{
+    Json,
     extract::{Path, Query, State},
     http::StatusCode,
     response::Result,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
 
-use crate::{AppState, db, error::SimplyError};
-use sf_core::FileAccess;
+use crate::{
+    AppState, db, dedup,
+    error::{SimplyError, err},
+    password,
+    signed::{self, DEFAULT_EXPIRY, MAX_EXPIRY},
+};
+use sf_core::{FileAccess, SignedLink};
 
 pub async fn remove_file(
     Path(path): Path<String>,
@@ -16,8 +23,7 @@ pub async fn remove_file(
 ) -> Result<StatusCode, SimplyError> {
     let db_file = db::file::get_via_path(&state.db, &path).await?;
 
-    db::file::delete(&state.db, &db_file.id).await?;
-    state.fs.delete(&path).await?;
+    dedup::delete_file(&state, &db_file).await?;
 
     Ok(StatusCode::OK)
 }
@@ -33,9 +39,19 @@ pub async fn rename_file(
     State(state): State<Arc<AppState>>,
 ) -> Result<StatusCode, SimplyError> {
     let mut db_file = db::file::get_via_path(&state.db, &path).await?;
+    let staged = dedup::storage_path(&db_file);
 
     db::file::rename(&mut db_file, &state.db, &query.to).await?;
-    state.fs.rename(&path, &query.to).await?;
+    // deduplicated files are only a path in the db,
+    // and uploads that aren't done yet are staged under their id (and extension)
+    if db_file.blob.is_none() && dedup::is_uploaded(&db_file) {
+        state.fs.rename(&path, &query.to).await?;
+    } else if !dedup::is_uploaded(&db_file) {
+        let to = dedup::storage_path(&db_file);
+        if staged != to && state.fs.exists(&staged).await? {
+            state.fs.rename(&staged, &to).await?;
+        }
+    }
 
     Ok(StatusCode::OK)
 }
@@ -59,7 +75,101 @@ pub async fn change_access(
         db::file::get_via_path(&state.db, &path).await?
     };
 
+    if access == FileAccess::Protected && file.password.is_none() {
+        err!("Set a password to protect the file with first", BAD_REQUEST);
+    }
     db::file::change_access(&mut file, &state.db, access).await?;
 
     Ok(StatusCode::OK)
 }
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordQuery {
+    pub id: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordBody {
+    pub password: String,
+}
+
+/// Protects the file with the password, replacing the one it had
+pub async fn set_password(
+    Path(path): Path<String>,
+    Query(query): Query<PasswordQuery>,
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<PasswordBody>,
+) -> Result<StatusCode, SimplyError> {
+    if body.password.is_empty() {
+        err!("The password can't be empty", BAD_REQUEST);
+    }
+
+    let mut file = if query.id.unwrap_or(false) {
+        db::file::get_via_id(&state.db, &path).await?
+    } else {
+        db::file::get_via_path(&state.db, &path).await?
+    };
+
+    let hash = password::hash(body.password).await?;
+    db::file::set_password(&mut file, &state.db, &hash).await?;
+    db::file::change_access(&mut file, &state.db, FileAccess::Protected).await?;
+
+    Ok(StatusCode::OK)
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileHash {
+    id: String,
+    path: String,
+    /// Hex encoded
+    sha256: String,
+}
+
+/// The SHA-256 digest of a file's contents, worked out now if it isn't known yet
+pub async fn get_hash(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<FileHash>, SimplyError> {
+    let mut file = db::file::get_via_id(&state.db, &id).await?;
+    if !dedup::is_uploaded(&file) {
+        err!("The file isn't fully uploaded yet", CONFLICT);
+    }
+
+    let sha256 = match file.hash.clone() {
+        Some(hash) => hash,
+        None => {
+            let digest = dedup::hash(state.fs.as_ref(), &dedup::storage_path(&file)).await?;
+            db::file::set_hash(&mut file, &state.db, &digest).await?;
+            digest
+        }
+    };
+
+    Ok(Json(FileHash {
+        id: file.id,
+        path: file.path,
+        sha256,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SignQuery {
+    /// Seconds until the link stops working
+    expires_in: Option<u64>,
+}
+
+/// A link to a file that works without the token until it expires
+pub async fn sign_link(
+    Path(id): Path<String>,
+    Query(query): Query<SignQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<SignedLink>, SimplyError> {
+    let file = db::file::get_via_id(&state.db, &id).await?;
+
+    let expires_in = query.expires_in.unwrap_or(DEFAULT_EXPIRY);
+    if expires_in == 0 || expires_in > MAX_EXPIRY {
+        err!("Links have to expire within a year", BAD_REQUEST);
+    }
+
+    let expires_at = OffsetDateTime::now_utc().unix_timestamp() + expires_in as i64;
+    Ok(Json(signed::link(&state, &file.id, expires_at)))
+}
@@ -1,9 +1,21 @@
 use std::sync::Arc;
 
-use axum::{Json, extract::State};
-use serde::Serialize;
+use axum::{
+    Json,
+    extract::{Query, State},
+    response::Result,
+};
+use serde::{Deserialize, Serialize};
+use sf_core::SyncReport;
 
-use crate::{AppState, config::WhichFileSystem};
+use crate::{
+    AppState,
+    config::{Config, WhichFileSystem},
+    error::{SimplyError, err},
+    migration::{self, Progress},
+    scrub::{self, ScrubReport},
+    sync,
+};
 
 #[derive(Debug, Serialize)]
 pub struct FileSystemInfo {
@@ -12,16 +24,87 @@ pub struct FileSystemInfo {
 }
 
 pub async fn get_file_system(State(state): State<Arc<AppState>>) -> Json<FileSystemInfo> {
-    let info = match state.config.file_system {
+    let mut info = match state.backend.which() {
+        WhichFileSystem::Mounts => {
+            let config = state.config.mounts.as_ref().expect("Invalid config");
+            let mounts = config
+                .iter()
+                .map(|mount| {
+                    let info = file_system_info(&state.config, &mount.file_system);
+                    format!(
+                        "/{}: {} ({})",
+                        mount.path.trim_matches('/'),
+                        info.which,
+                        info.about
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            FileSystemInfo {
+                which: "Mounts".into(),
+                about: mounts.join("\n"),
+            }
+        }
+        ref which => file_system_info(&state.config, which),
+    };
+
+    if state.config.compression.is_some() {
+        info.about += " | compressed";
+    }
+    if state.config.encryption.is_some() {
+        info.about += " | encrypted at rest";
+    }
+
+    Json(info)
+}
+
+fn file_system_info(config: &Config, which: &WhichFileSystem) -> FileSystemInfo {
+    match which {
+        WhichFileSystem::Mirror => {
+            let mirror = config.mirror.as_ref().expect("Invalid config");
+            let members = std::iter::once(&mirror.primary)
+                .chain(mirror.replicas.iter())
+                .map(|which| {
+                    let info = backend_info(config, which);
+                    format!("{} ({})", info.which, info.about)
+                })
+                .collect::<Vec<_>>();
+
+            FileSystemInfo {
+                which: "Mirror".into(),
+                about: members.join(" -> "),
+            }
+        }
+        WhichFileSystem::Tiered => {
+            let tiered = config.tiered.as_ref().expect("Invalid config");
+            let (hot, cold) = (
+                backend_info(config, &tiered.hot),
+                backend_info(config, &tiered.cold),
+            );
+
+            FileSystemInfo {
+                which: "Tiered".into(),
+                about: format!(
+                    "hot: {} ({}) | cold: {} ({})",
+                    hot.which, hot.about, cold.which, cold.about
+                ),
+            }
+        }
+        which => backend_info(config, which),
+    }
+}
+
+fn backend_info(config: &Config, which: &WhichFileSystem) -> FileSystemInfo {
+    match which {
         WhichFileSystem::Local => {
-            let config = state.config.local.as_ref().expect("Invalid config");
+            let config = config.local.as_ref().expect("Invalid config");
             FileSystemInfo {
                 which: "Local".into(),
                 about: format!("{}", config.root),
             }
         }
         WhichFileSystem::SSH => {
-            let config = state.config.ssh.as_ref().expect("Invalid config");
+            let config = config.ssh.as_ref().expect("Invalid config");
             FileSystemInfo {
                 which: "SSH".into(),
                 about: format!(
@@ -30,7 +113,108 @@ pub async fn get_file_system(State(state): State<Arc<AppState>>) -> Json<FileSys
                 ),
             }
         }
-    };
+        WhichFileSystem::S3 => {
+            let config = config.s3.as_ref().expect("Invalid config");
+            FileSystemInfo {
+                which: "S3".into(),
+                about: format!(
+                    "{}/{} | {}",
+                    config.endpoint,
+                    config.bucket,
+                    config.prefix.as_deref().unwrap_or("")
+                ),
+            }
+        }
+        WhichFileSystem::WebDAV => {
+            let config = config.webdav.as_ref().expect("Invalid config");
+            FileSystemInfo {
+                which: "WebDAV".into(),
+                about: match &config.username {
+                    Some(username) => format!("{}:*****@{}", username, config.url),
+                    None => config.url.clone(),
+                },
+            }
+        }
+        WhichFileSystem::Memory => FileSystemInfo {
+            which: "Memory".into(),
+            about: "In-memory, every file is lost on restart".into(),
+        },
+        WhichFileSystem::Mirror | WhichFileSystem::Tiered | WhichFileSystem::Mounts => {
+            unreachable!("Mirrored & tiered file systems only contain regular file systems")
+        }
+    }
+}
 
-    Json(info)
+#[derive(Debug, Serialize)]
+pub struct RepairResult {
+    copied: u64,
+}
+
+/// Copies files to replicas that fell behind, does nothing if the file system isn't mirrored
+pub async fn repair(State(state): State<Arc<AppState>>) -> Result<Json<RepairResult>, SimplyError> {
+    let copied = state.fs.repair().await?;
+    Ok(Json(RepairResult { copied }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SyncQuery {
+    /// Only reports what would change
+    pub dry_run: Option<bool>,
+}
+
+/// Adds files that are in the file system but not in the database and removes
+/// the ones that aren't anymore, like on start
+pub async fn sync(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<SyncQuery>,
+) -> Result<Json<SyncReport>, SimplyError> {
+    let report = sync::sync_files(&state, query.dry_run.unwrap_or(false)).await?;
+    Ok(Json(report))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MigrationRequest {
+    to: WhichFileSystem,
+}
+
+/// Starts moving every file to another file system from the config in the background,
+/// see [`migration`] for how it works
+pub async fn start_migration(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<MigrationRequest>,
+) -> Result<Json<Option<Progress>>, SimplyError> {
+    migration::prepare(&state, &request.to)?;
+
+    let migration_state = state.clone();
+    tokio::spawn(async move {
+        if let Err(err) = migration::migrate(&migration_state, request.to).await {
+            tracing::error!("Migration failed: {err:?}");
+        }
+    });
+
+    Ok(Json(migration::progress()))
+}
+
+/// Progress of the running (or last) migration, if there has been one since starting
+pub async fn get_migration() -> Json<Option<Progress>> {
+    Json(migration::progress())
+}
+
+/// Starts checking every file for corruption in the background, see [`scrub`]
+pub async fn start_scrub(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Option<ScrubReport>>, SimplyError> {
+    if !scrub::prepare() {
+        err!("A scrub is already running", CONFLICT);
+    }
+
+    let scrub_state = state.clone();
+    tokio::spawn(async move { scrub::scrub(&scrub_state).await });
+
+    Ok(Json(scrub::report()))
+}
+
+/// Progress of the running (or last) scrub, if there has been one since starting
+pub async fn get_scrub() -> Json<Option<ScrubReport>> {
+    Json(scrub::report())
 }
@@ -17,6 +17,7 @@ use crate::{
 };
 
 mod authenticate;
+mod copy;
 mod directory;
 mod file;
 mod file_system;
@@ -35,14 +36,30 @@ pub fn protected_routes(state: Arc<AppState>) -> Router {
         .route("/links", get(link::get_unused_links))
         .route("/link/{*id}", delete(link::delete_link))
         .route("/file_system", get(file_system::get_file_system))
+        .route("/repair", post(file_system::repair))
+        .route("/sync", post(file_system::sync))
+        .route("/scrub", get(file_system::get_scrub))
+        .route("/scrub", post(file_system::start_scrub))
+        .route("/migrate", get(file_system::get_migration))
+        .route("/migrate", post(file_system::start_migration))
         .route("/storage_limit", get(storage_limit::get_used_storage_space))
         .route("/directory/{*path}", get(directory::get_files))
         .route("/directory", get(directory::get_root))
         .route("/directory/{*path}", post(directory::add_directory))
         .route("/directory/{*path}", delete(directory::delete_directory))
+        .route("/zip/{*path}", get(directory::download_directory))
+        .route("/zip", get(directory::download_root))
+        .route(
+            "/rename_directory/{*path}",
+            post(directory::rename_directory),
+        )
         .route("/delete_file/{*path}", delete(file::remove_file))
         .route("/rename_file/{*path}", post(file::rename_file))
+        .route("/copy/{*path}", post(copy::copy))
         .route("/access/{*path}", post(file::change_access))
+        .route("/password/{*path}", post(file::set_password))
+        .route("/hash/{*id}", get(file::get_hash))
+        .route("/sign/{*id}", post(file::sign_link))
         .route_layer(from_fn_with_state(state.clone(), token_auth))
         .route("/authenticate", post(authenticate::authenticate))
         .with_state(state.clone())
@@ -23,7 +23,7 @@ pub async fn path_to_id(
 ) -> Result<Json<File>, SimplyError> {
     let file = db::file::get_via_path(&state.db, &path).await?;
 
-    if file.get_access() == FileAccess::Private
+    if file.get_access() != FileAccess::Public
         && !standalone_auth(&jar, &headers, &state.config.token)
     {
         err!("You can't access this file", UNAUTHORIZED);
@@ -40,7 +40,7 @@ pub async fn id_to_path(
 ) -> Result<Json<File>, SimplyError> {
     let file = db::file::get_via_id(&state.db, &id).await?;
 
-    if file.get_access() == FileAccess::Private
+    if file.get_access() != FileAccess::Public
         && !standalone_auth(&jar, &headers, &state.config.token)
     {
         err!("You can't access this file", UNAUTHORIZED);
@@ -7,7 +7,10 @@ use crate::{AppState, db, error::SimplyError};
 
 #[derive(Debug, Serialize)]
 pub struct StorageLimit {
+    /// Bytes actually taken up in the file system
     used: u64,
+    /// Bytes of all files before any compression
+    logical: u64,
     max: u64,
 }
 
@@ -15,10 +18,11 @@ pub async fn get_used_storage_space(
     State(state): State<Arc<AppState>>,
 ) -> Result<Json<StorageLimit>, SimplyError> {
     let max_bytes = state.config.storage_limit as u64;
-    let used_bytes = db::file::get_bytes_stored(&state.db).await?;
+    let stored = db::file::get_bytes_stored(&state.db).await?;
 
     Ok(Json(StorageLimit {
-        used: used_bytes,
+        used: stored.physical as u64,
+        logical: stored.logical as u64,
         max: max_bytes,
     }))
 }
@@ -0,0 +1,248 @@
+//! `Range` requests for downloads, so players can seek in videos without
+//! downloading everything before it and interrupted downloads can continue
+//! where they stopped, see RFC 9110 section 14.
+
+use axum::http::HeaderValue;
+use futures_util::{StreamExt, stream};
+use std::{io, sync::Arc};
+
+use crate::{AppState, file_system::FSStream};
+
+/// More ranges than this in one request are ignored and the whole file is sent,
+/// so a request can't make the server read the same bytes over and over
+const MAX_RANGES: usize = 16;
+
+/// Bytes `start..=end` of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// The `Content-Range` of this range in a file of `size` bytes
+    pub fn content_range(&self, size: u64) -> String {
+        format!("bytes {}-{}/{size}", self.start, self.end)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Ranges {
+    /// No (usable) `Range` header, the whole file is sent
+    Full,
+    Partial(Vec<ByteRange>),
+    /// None of the ranges are inside the file
+    Unsatisfiable,
+}
+
+/// The ranges asked for in a `Range` header for a file of `size` bytes.
+/// Headers that can't be parsed are ignored, like the RFC says
+pub fn parse(header: Option<&HeaderValue>, size: u64) -> Ranges {
+    let Some(specs) = header
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.trim().strip_prefix("bytes="))
+    else {
+        return Ranges::Full;
+    };
+    // there's nothing to take a range of
+    if size == 0 {
+        return Ranges::Full;
+    }
+
+    let mut ranges = vec![];
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let Some((start, end)) = spec.split_once('-') else {
+            return Ranges::Full;
+        };
+
+        let range = match (start.trim(), end.trim()) {
+            // the last `end` bytes
+            ("", end) => match end.parse::<u64>() {
+                Ok(0) => None,
+                Ok(len) => Some(ByteRange {
+                    start: size.saturating_sub(len),
+                    end: size - 1,
+                }),
+                Err(_) => return Ranges::Full,
+            },
+            (start, "") => match start.parse::<u64>() {
+                Ok(start) => (start < size).then_some(ByteRange {
+                    start,
+                    end: size - 1,
+                }),
+                Err(_) => return Ranges::Full,
+            },
+            (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+                (Ok(start), Ok(end)) if start <= end => (start < size).then_some(ByteRange {
+                    start,
+                    end: end.min(size - 1),
+                }),
+                _ => return Ranges::Full,
+            },
+        };
+        ranges.extend(range);
+    }
+
+    match ranges.len() {
+        0 => Ranges::Unsatisfiable,
+        len if len > MAX_RANGES => Ranges::Full,
+        _ => Ranges::Partial(ranges),
+    }
+}
+
+/// A `multipart/byteranges` body of every range of the file
+pub struct Multipart {
+    pub ranges: Vec<ByteRange>,
+    pub size: u64,
+    pub content_type: String,
+    pub boundary: String,
+}
+
+impl Multipart {
+    fn part_header(&self, range: &ByteRange) -> String {
+        format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            self.boundary,
+            self.content_type,
+            range.content_range(self.size)
+        )
+    }
+
+    fn closing(&self) -> String {
+        format!("\r\n--{}--\r\n", self.boundary)
+    }
+
+    /// Length of the whole body
+    pub fn len(&self) -> u64 {
+        let parts = self
+            .ranges
+            .iter()
+            .map(|range| self.part_header(range).len() as u64 + range.len())
+            .sum::<u64>();
+        parts + self.closing().len() as u64
+    }
+
+    /// Each range is only read once the one before it has been sent
+    pub fn stream(self, state: Arc<AppState>, path: String) -> FSStream {
+        let closing = self.closing().into_bytes();
+        let parts = self
+            .ranges
+            .iter()
+            .map(|range| (*range, self.part_header(range)))
+            .collect::<Vec<_>>();
+
+        let parts = stream::iter(parts).then(move |(range, header)| {
+            let (state, path) = (state.clone(), path.clone());
+
+            async move {
+                let header = stream::once(async move { Ok::<_, io::Error>(header.into_bytes()) });
+                match state
+                    .fs
+                    .read_range(&path, range.start, Some(range.len()))
+                    .await
+                {
+                    Ok(body) => header.chain(body).boxed(),
+                    Err(err) => header.chain(stream::once(async move { Err(err) })).boxed(),
+                }
+            }
+        });
+
+        Box::pin(
+            parts
+                .flatten()
+                .chain(stream::once(async move { Ok(closing) })),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    #[test]
+    fn parsing() {
+        let many = (0..=MAX_RANGES)
+            .map(|i| format!("{i}-{i}"))
+            .collect::<Vec<_>>();
+        let many = format!("bytes={}", many.join(","));
+        let cases = [
+            // suffixes
+            ("bytes=-100", Ranges::Partial(vec![range(900, 999)])),
+            ("bytes=-5000", Ranges::Partial(vec![range(0, 999)])),
+            ("bytes=-0", Ranges::Unsatisfiable),
+            // open ended
+            ("bytes=100-", Ranges::Partial(vec![range(100, 999)])),
+            ("bytes=999-", Ranges::Partial(vec![range(999, 999)])),
+            ("bytes=1000-", Ranges::Unsatisfiable),
+            // clamped to the end
+            ("bytes=0-0", Ranges::Partial(vec![range(0, 0)])),
+            ("bytes=500-5000", Ranges::Partial(vec![range(500, 999)])),
+            ("bytes=1000-2000", Ranges::Unsatisfiable),
+            // several, the ones outside the file are left out
+            (
+                "bytes=0-9, 2000-3000, -10",
+                Ranges::Partial(vec![range(0, 9), range(990, 999)]),
+            ),
+            ("bytes=2000-3000,-0", Ranges::Unsatisfiable),
+            (&many, Ranges::Full),
+            // can't be parsed, so they're ignored
+            ("bytes=10-5", Ranges::Full),
+            ("bytes=a-b", Ranges::Full),
+            ("bytes=10", Ranges::Full),
+            ("items=0-10", Ranges::Full),
+        ];
+
+        for (header, expected) in cases {
+            let header = HeaderValue::from_str(header).unwrap();
+            assert_eq!(parse(Some(&header), 1000), expected, "{header:?}");
+        }
+
+        let max = (0..MAX_RANGES)
+            .map(|i| format!("{i}-{i}"))
+            .collect::<Vec<_>>();
+        let max = HeaderValue::from_str(&format!("bytes={}", max.join(","))).unwrap();
+        assert!(matches!(parse(Some(&max), 1000), Ranges::Partial(r) if r.len() == MAX_RANGES));
+
+        assert_eq!(parse(None, 1000), Ranges::Full);
+        let header = HeaderValue::from_static("bytes=0-10");
+        assert_eq!(parse(Some(&header), 0), Ranges::Full);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn multipart_length() {
+        let state = AppState::in_memory().await;
+        let data = (0..1000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        state.fs.write("file.bin", &data).await.unwrap();
+
+        let multipart = Multipart {
+            ranges: vec![range(0, 9), range(500, 749), range(999, 999)],
+            size: data.len() as u64,
+            content_type: "application/octet-stream".to_string(),
+            boundary: "boundary".to_string(),
+        };
+        let len = multipart.len();
+
+        let mut body = vec![];
+        let mut stream = multipart.stream(state, "file.bin".to_string());
+        while let Some(chunk) = stream.next().await {
+            body.extend(chunk.unwrap());
+        }
+
+        assert_eq!(body.len() as u64, len);
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.starts_with(
+            "\r\n--boundary\r\nContent-Type: application/octet-stream\r\n\
+             Content-Range: bytes 0-9/1000\r\n\r\n"
+        ));
+        assert!(body.contains("Content-Range: bytes 500-749/1000\r\n"));
+        assert!(body.ends_with("\r\n--boundary--\r\n"));
+    }
+}
@@ -0,0 +1,195 @@
+//! Reads every file again now and then to notice contents that changed without
+//! anyone changing them, like bit rot on an old disk.
+//!
+//! The SHA-256 digest of each file is stored once it's uploaded (or imported by a sync),
+//! a scrub reads everything again through
+//! [`crate::file_system::FileSystem::read_stream`] and compares. Files without a
+//! digest yet get one. Corrupted and missing files are logged and listed in the
+//! report of the last scrub (`GET /m/scrub`), nothing is changed or deleted.
+
+use serde::Serialize;
+use sf_core::File;
+use std::{
+    collections::BTreeMap,
+    io::ErrorKind,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    AppState, db,
+    dedup::{self, is_uploaded, storage_path},
+};
+
+const DEFAULT_INTERVAL: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScrubReport {
+    pub total_files: u64,
+    pub checked_files: u64,
+    pub checked_bytes: u64,
+    /// Files that had no digest yet, they have one now
+    pub hashed_files: u64,
+    /// Files whose contents don't match their digest anymore
+    pub corrupted: Vec<String>,
+    /// Files that are in the database but not in the file system
+    pub missing: Vec<String>,
+    /// Files that couldn't be read for another reason
+    pub failed: Vec<String>,
+    pub running: bool,
+    pub error: Option<String>,
+}
+
+/// The current (or last) scrub
+static REPORT: LazyLock<Mutex<Option<ScrubReport>>> = LazyLock::new(|| Mutex::new(None));
+
+pub fn report() -> Option<ScrubReport> {
+    REPORT.lock().unwrap().clone()
+}
+
+fn update(f: impl FnOnce(&mut ScrubReport)) {
+    if let Some(report) = REPORT.lock().unwrap().as_mut() {
+        f(report);
+    }
+}
+
+/// Marks a scrub as running, returns false if one already is
+pub fn prepare() -> bool {
+    let mut report = REPORT.lock().unwrap();
+    if report.as_ref().is_some_and(|r| r.running) {
+        return false;
+    }
+
+    *report = Some(ScrubReport {
+        running: true,
+        ..Default::default()
+    });
+    true
+}
+
+/// Runs forever, scrubbing every `interval` seconds if it's enabled in the config
+pub async fn run_scrubber(state: Arc<AppState>) {
+    let Some(config) = state.config.scrub.as_ref() else {
+        return;
+    };
+
+    let mut interval = tokio::time::interval(Duration::from_secs(
+        config.interval.unwrap_or(DEFAULT_INTERVAL),
+    ));
+    // no need to read everything again on every start
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+
+        if !prepare() {
+            continue;
+        }
+        scrub(&state).await;
+    }
+}
+
+/// Runs a scrub started with [`prepare`] until it's done
+#[tracing::instrument(skip(state))]
+pub async fn scrub(state: &AppState) {
+    tracing::info!("Checking every file for corruption");
+    let result = check_all(state).await;
+
+    update(|report| {
+        report.running = false;
+        if let Err(err) = &result {
+            report.error = Some(format!("{err:?}"));
+        }
+    });
+
+    match (result, report()) {
+        (Err(err), _) => tracing::error!("Scrub failed: {err:?}"),
+        (Ok(_), Some(report)) if report.corrupted.is_empty() && report.missing.is_empty() => {
+            tracing::info!("Checked {} files, none are corrupted", report.checked_files)
+        }
+        (Ok(_), Some(report)) => tracing::error!(
+            "Checked {} files, {} are corrupted and {} are missing",
+            report.checked_files,
+            report.corrupted.len(),
+            report.missing.len()
+        ),
+        (Ok(_), None) => (),
+    }
+}
+
+async fn check_all(state: &AppState) -> sqlx::Result<()> {
+    // files sharing a blob are only read once
+    let mut stored: BTreeMap<String, Vec<File>> = BTreeMap::new();
+    for file in db::file::get_all_files(&state.db).await? {
+        if is_uploaded(&file) {
+            stored.entry(storage_path(&file)).or_default().push(file);
+        }
+    }
+    let total_files = stored.values().map(|files| files.len() as u64).sum();
+    update(|report| report.total_files = total_files);
+
+    for (path, files) in stored {
+        check(state, &path, files).await?;
+    }
+    Ok(())
+}
+
+/// Checks the contents stored at `path` against the digest of the files stored there
+async fn check(state: &AppState, path: &str, mut files: Vec<File>) -> sqlx::Result<()> {
+    let digest = match dedup::hash(state.fs.as_ref(), path).await {
+        Ok(digest) => digest,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            for file in files {
+                // deleted or renamed since the scrub started
+                if db::file::get_via_id(&state.db, &file.id).await.is_err() {
+                    continue;
+                }
+                tracing::error!("{:?} is missing, it's not at {path:?}", file.path);
+                update(|report| report.missing.push(file.path));
+            }
+            return Ok(());
+        }
+        Err(err) => {
+            tracing::error!("Failed to read {path:?} to check it: {err:?}");
+            update(|report| {
+                report
+                    .failed
+                    .extend(files.into_iter().map(|file| file.path))
+            });
+            return Ok(());
+        }
+    };
+
+    for file in &mut files {
+        match &file.hash {
+            Some(hash) if *hash == digest => (),
+            Some(_) if changed_since(state, file).await => (),
+            Some(hash) => {
+                tracing::error!(
+                    "{:?} is corrupted, its contents should have the digest {hash} but have {digest}",
+                    file.path
+                );
+                let path = file.path.clone();
+                update(|report| report.corrupted.push(path));
+            }
+            None => {
+                db::file::set_hash(file, &state.db, &digest).await?;
+                update(|report| report.hashed_files += 1);
+            }
+        }
+    }
+
+    let size = files.first().map(|file| file.size as u64).unwrap_or(0);
+    update(|report| {
+        report.checked_files += files.len() as u64;
+        report.checked_bytes += size;
+    });
+    Ok(())
+}
+
+/// If the file was overwritten or deleted since the scrub started
+async fn changed_since(state: &AppState, file: &File) -> bool {
+    match db::file::get_via_id(&state.db, &file.id).await {
+        Ok(current) => current.hash != file.hash,
+        Err(_) => true,
+    }
+}
@@ -0,0 +1,192 @@
+//! Links that grant access to a private file until they expire, so it can be
+//! shared for a while without making it public.
+//!
+//! The file id & expiry are signed with HMAC-SHA256 keyed by the token,
+//! so changing the token revokes every link at once. Links from unlocking a
+//! protected file also sign its password hash & unlock nonce, so they stop working
+//! once the password or access changes and never work for private files.
+
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sf_core::{File, SignedLink};
+use sha2::Sha256;
+use time::OffsetDateTime;
+
+use crate::AppState;
+
+/// 48 hours
+pub const DEFAULT_EXPIRY: u64 = 48 * 60 * 60;
+/// A year, anything longer should just be public
+pub const MAX_EXPIRY: u64 = 365 * 24 * 60 * 60;
+
+/// The query of a signed link
+#[derive(Debug, Deserialize)]
+pub struct Signature {
+    /// Unix timestamp
+    exp: Option<i64>,
+    /// Hex encoded
+    sig: Option<String>,
+}
+
+impl Signature {
+    /// From `exp=..&sig=..`
+    pub fn from_query(query: &str) -> Self {
+        let mut signature = Self {
+            exp: None,
+            sig: None,
+        };
+        for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            match key {
+                "exp" => signature.exp = value.parse().ok(),
+                "sig" => signature.sig = Some(value.to_string()),
+                _ => (),
+            }
+        }
+        signature
+    }
+
+    /// If it was signed for the file and hasn't expired yet
+    pub fn is_valid(&self, token: &str, id: &str) -> bool {
+        self.verify(token, id, None)
+    }
+
+    /// If it was signed when the file was unlocked with its current password,
+    /// its password & access haven't changed since and it hasn't expired yet
+    pub fn unlocks(&self, token: &str, file: &File) -> bool {
+        match &file.password {
+            Some(password) => self.verify(token, &file.id, Some(unlocked(file, password))),
+            None => false,
+        }
+    }
+
+    fn verify(&self, token: &str, id: &str, unlocked: Option<Unlocked>) -> bool {
+        let (Some(exp), Some(sig)) = (self.exp, &self.sig) else {
+            return false;
+        };
+        if exp < OffsetDateTime::now_utc().unix_timestamp() {
+            return false;
+        }
+
+        match hex::decode(sig) {
+            Ok(sig) => mac(token, id, exp, unlocked).verify_slice(&sig).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// The query of a link to the file that works until `exp`
+pub fn sign(token: &str, id: &str, exp: i64) -> String {
+    query(token, id, exp, None)
+}
+
+fn query(token: &str, id: &str, exp: i64, unlocked: Option<Unlocked>) -> String {
+    let sig = hex::encode(mac(token, id, exp, unlocked).finalize().into_bytes());
+    format!("exp={exp}&sig={sig}")
+}
+
+/// A link to the file that works until `expires_at`
+pub fn link(state: &AppState, id: &str, expires_at: i64) -> SignedLink {
+    build_link(
+        state,
+        id,
+        expires_at,
+        sign(&state.config.token, id, expires_at),
+    )
+}
+
+/// A link to the protected file that works until `expires_at`
+/// or until its password or access changes, see [`Signature::unlocks`]
+pub fn unlock_link(state: &AppState, file: &File, expires_at: i64) -> SignedLink {
+    let password = file.password.as_deref().unwrap_or_default();
+    let query = query(
+        &state.config.token,
+        &file.id,
+        expires_at,
+        Some(unlocked(file, password)),
+    );
+    build_link(state, &file.id, expires_at, query)
+}
+
+fn build_link(state: &AppState, id: &str, expires_at: i64, query: String) -> SignedLink {
+    SignedLink {
+        download: state
+            .config
+            .backend_url
+            .as_ref()
+            .map(|url| format!("{url}/d/{id}?{query}")),
+        preview: state
+            .config
+            .web_url
+            .as_ref()
+            .map(|url| format!("{url}/d/{id}?{query}")),
+        id: id.to_string(),
+        expires_at,
+        query,
+    }
+}
+
+/// The password hash & unlock nonce, which changes every time the access does,
+/// so protecting it again doesn't revive old links
+type Unlocked<'a> = (&'a str, &'a str);
+
+fn unlocked<'a>(file: &'a File, password: &'a str) -> Unlocked<'a> {
+    (password, file.unlock_nonce.as_deref().unwrap_or_default())
+}
+
+fn mac(token: &str, id: &str, exp: i64, unlocked: Option<Unlocked>) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(token.as_bytes()).expect("HMAC can take a key of any size");
+    match unlocked {
+        Some((password, nonce)) => {
+            mac.update(format!("unlock\n{id}\n{exp}\n{password}\n{nonce}").as_bytes())
+        }
+        None => mac.update(format!("{id}\n{exp}").as_bytes()),
+    }
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "token";
+
+    fn in_an_hour() -> i64 {
+        OffsetDateTime::now_utc().unix_timestamp() + 60 * 60
+    }
+
+    #[test]
+    fn valid_links() {
+        let exp = in_an_hour();
+        let signature = Signature::from_query(&sign(TOKEN, "abc", exp));
+        assert!(signature.is_valid(TOKEN, "abc"));
+        assert!(!signature.is_valid(TOKEN, "abd"));
+        assert!(!signature.is_valid("other token", "abc"));
+    }
+
+    #[test]
+    fn expired_links() {
+        let exp = OffsetDateTime::now_utc().unix_timestamp() - 1;
+        let signature = Signature::from_query(&sign(TOKEN, "abc", exp));
+        assert!(!signature.is_valid(TOKEN, "abc"));
+    }
+
+    #[test]
+    fn tampered_links() {
+        let exp = in_an_hour();
+        let query = sign(TOKEN, "abc", exp);
+
+        // pushing the expiry back breaks the signature
+        let later = query.replace(&exp.to_string(), &(exp + 1).to_string());
+        assert!(!Signature::from_query(&later).is_valid(TOKEN, "abc"));
+
+        let (_, sig) = query.split_once("sig=").unwrap();
+        let mut flipped = hex::decode(sig).unwrap();
+        flipped[0] ^= 1;
+        let flipped = format!("exp={exp}&sig={}", hex::encode(flipped));
+        assert!(!Signature::from_query(&flipped).is_valid(TOKEN, "abc"));
+
+        assert!(!Signature::from_query(&format!("exp={exp}&sig=zz")).is_valid(TOKEN, "abc"));
+        assert!(!Signature::from_query(&format!("exp={exp}")).is_valid(TOKEN, "abc"));
+    }
+}
@@ -1,156 +1,243 @@
-use std::{io, path::PathBuf, pin::Pin, sync::Arc};
+//! Brings the database in line with what's actually in the file system,
+//! once on start and whenever `POST /m/sync` is called.
+//!
+//! Files are only ever found through [`FileSystem::list_dir`],
+//! so it works the same no matter where the files are stored.
 
-use crate::{AppState, db, generate_id};
+use sf_core::{File, SyncEntry, SyncReport};
+use std::{
+    collections::{BTreeMap, HashSet},
+    io,
+};
 
-pub async fn sync_files(state: Arc<AppState>) -> Result<(), SyncError> {
-    sync_from_db(&state).await?;
-    sync_from_files(&state).await?;
-    Ok(())
-}
+use crate::{
+    AppState, db,
+    dedup::{self, BLOB_DIR, DedupError},
+    file_system::FileSystem,
+    generate_id,
+    upload::PARTIAL_DIR,
+};
+
+/// With `dry_run` nothing is changed, the report only says what would be
+#[tracing::instrument(skip(state))]
+pub async fn sync_files(state: &AppState, dry_run: bool) -> Result<SyncReport, SyncError> {
+    let mut report = SyncReport {
+        dry_run,
+        ..Default::default()
+    };
 
-async fn sync_from_db(state: &AppState) -> Result<(), SyncError> {
+    let stored = list_files(state.fs.as_ref()).await?;
     let files = db::file::get_all_files(&state.db).await?;
 
-    let mut count = 0;
+    sync_from_db(state, &stored, &files, &mut report).await?;
+    sync_from_files(state, &stored, &files, &mut report).await?;
+    sync_blobs(state, &mut report).await?;
+
+    Ok(report)
+}
+
+/// Removes files that aren't in the file system anymore and
+/// updates the size of files that were changed by another program
+async fn sync_from_db(
+    state: &AppState,
+    stored: &BTreeMap<String, u64>,
+    files: &[File],
+    report: &mut SyncReport,
+) -> Result<(), SyncError> {
     for file in files {
-        // if any of the db files doesnt exist on the actual system
-        // remove it from the database
-        if !state.fs.exists(&file.path).await? {
+        let path = dedup::storage_path(file);
+
+        if let Some(&size) = stored.get(&path) {
+            if file.blob.is_none() && dedup::is_uploaded(file) && stored_size(file) != size as i64 {
+                report.changed.push(SyncEntry {
+                    path: file.path.clone(),
+                    size,
+                    previous_size: Some(stored_size(file) as u64),
+                });
+                if !report.dry_run {
+                    let mut file = file.clone();
+                    set_changed_size(state, &mut file, size as i64).await?;
+                }
+            }
+            continue;
+        }
+
+        // blobs & unfinished uploads aren't listed,
+        // and the file might've been uploaded since listing
+        if state.fs.exists(&path).await? {
+            continue;
+        }
+
+        report.removed.push(SyncEntry {
+            path: file.path.clone(),
+            size: stored_size(file) as u64,
+            previous_size: None,
+        });
+        if !report.dry_run {
             db::file::delete(&state.db, &file.id).await?;
-            tracing::info!(
-                "Deleted '{:?}' from database to sync with file system",
-                PathBuf::from(file.path).file_name()
-            );
-            count += 1;
         }
     }
 
-    if count > 0 {
-        tracing::info!("Deleted {count} files from the database");
+    if !report.dry_run && !report.removed.is_empty() {
+        tracing::info!(
+            "Deleted {} files from the database to sync with the file system",
+            report.removed.len()
+        );
     }
     Ok(())
 }
 
-async fn sync_from_files(state: &Arc<AppState>) -> Result<(), SyncError> {
-    // iterate over all directories from state.fs.root_directory()
-    // and transform the path for database query (full_current - root) (/ NOT \)
-    // then insert the files with a new ID, access = 0 etc.
-    let root_path = state
-        .fs
-        .root_directory()
-        .await
-        .to_string_lossy()
-        .to_string();
-
-    let root_files = state.fs.list_dir("").await?;
-
-    let cb: Arc<AsyncFn> =
-        Arc::new(|state, path, root, size| Box::pin(handle_entry(state.clone(), path, root, size)));
-
-    let old_file_count = db::file::get_total_amount_of_files(&state.db).await?;
+/// Adds every file that isn't in the database yet
+async fn sync_from_files(
+    state: &AppState,
+    stored: &BTreeMap<String, u64>,
+    files: &[File],
+    report: &mut SyncReport,
+) -> Result<(), SyncError> {
+    let known = files
+        .iter()
+        .map(|file| file.path.as_str())
+        .collect::<HashSet<_>>();
 
-    for file in root_files {
-        let full_path = PathBuf::from(&root_path).join(&file.path);
-        if file.is_dir {
-            visit_dirs(full_path, state.clone(), root_path.to_string(), cb.clone()).await?;
+    for (path, &size) in stored {
+        if known.contains(path.as_str()) {
             continue;
         }
 
-        handle_entry(
-            state.clone(),
-            full_path.to_string_lossy().to_string(),
-            root_path.to_string(),
-            file.size as i64,
-        )
-        .await?;
+        report.added.push(SyncEntry {
+            path: path.clone(),
+            size,
+            previous_size: None,
+        });
+        if !report.dry_run {
+            add_file(state, path, size as i64).await?;
+        }
     }
 
-    let new_file_count = db::file::get_total_amount_of_files(&state.db).await?;
-    if new_file_count > old_file_count {
+    if !report.dry_run && !report.added.is_empty() {
         tracing::info!(
-            "Added {:?} new file entries into the database",
-            new_file_count - old_file_count
+            "Added {} new file entries into the database",
+            report.added.len()
         );
     }
-
     Ok(())
 }
 
-async fn handle_entry(
-    state: Arc<AppState>,
-    path: String,
-    root: String,
-    size: i64,
-) -> Result<(), SyncError> {
-    let path = path.replace(r#"\\"#, "/").replace(r#"\"#, "/");
-    let path = PathBuf::from(&path);
-
-    let db_path = match path.strip_prefix(&root) {
-        Ok(p) => p.to_string_lossy().to_string(),
-        Err(_) => {
-            tracing::debug!("failed to strip root: {path:?}");
-            return Ok(());
+/// Moves every file that isn't deduplicated yet into its blob if dedup is enabled,
+/// and removes blobs that no file uses anymore
+async fn sync_blobs(state: &AppState, report: &mut SyncReport) -> Result<(), SyncError> {
+    if state.config.dedup() {
+        for mut file in db::file::get_all_files(&state.db).await? {
+            if file.blob.is_some() || !dedup::is_uploaded(&file) {
+                continue;
+            }
+            if report.dry_run {
+                // unless it would've been removed
+                if !report.removed.iter().any(|entry| entry.path == file.path) {
+                    report.deduplicated += 1;
+                }
+                continue;
+            }
+
+            dedup::store(state, &mut file).await?;
+            report.deduplicated += 1;
+        }
+        // those would be in the database by now
+        if report.dry_run {
+            report.deduplicated += report.added.len() as u64;
         }
-    };
-    match db::file::get_via_path(&state.db, &db_path).await {
-        Ok(_) => return Ok(()), // exists so we can skip doing anything
-        Err(_) => {
-            // doesnt exist, so we add. we can give it a -1 total chunks since its from syncing
-            let mut file = db::file::new(&state.db, &generate_id(None), &db_path, -1).await?;
-            db::file::successful_upload(&mut file, &state.db, size).await?;
 
+        if !report.dry_run && report.deduplicated > 0 {
             tracing::info!(
-                "Added '{:?}' in database to sync with file system",
-                &db_path
+                "Moved {} files into deduplicated storage",
+                report.deduplicated
             );
         }
-    };
+    }
+
+    report.orphaned_blobs = dedup::remove_orphaned_blobs(state, report.dry_run).await?;
+    if !report.dry_run && report.orphaned_blobs > 0 {
+        tracing::info!("Deleted {} orphaned blobs", report.orphaned_blobs);
+    }
 
     Ok(())
 }
 
-type AsyncFn = dyn Fn(
-        &Arc<AppState>,
-        String,
-        String,
-        i64,
-    ) -> Pin<Box<dyn Future<Output = Result<(), SyncError>> + Send + '_>>
-    + Send
-    + Sync;
-
-fn visit_dirs(
-    dir: PathBuf,
-    state: Arc<AppState>,
-    root: String,
-    cb: Arc<AsyncFn>,
-) -> Pin<Box<dyn Future<Output = Result<(), SyncError>> + Send>> {
-    Box::pin(visit_dirs_inner(dir, state, root, cb))
+/// Adds a file that was put into the file system by something else than an upload,
+/// `stored_size` is what it takes up in the file system
+pub async fn add_file(state: &AppState, path: &str, stored_size: i64) -> Result<File, SyncError> {
+    let size = state.fs.content_size(path).await? as i64;
+    // no chunks since it wasn't uploaded
+    let mut file = db::file::new(&state.db, &generate_id(None), path, -1).await?;
+    db::file::successful_upload(&mut file, &state.db, size).await?;
+    db::file::set_stored_size(&mut file, &state.db, stored_size).await?;
+    let digest = dedup::hash(state.fs.as_ref(), path).await?;
+    db::file::set_hash(&mut file, &state.db, &digest).await?;
+
+    tracing::info!("Added {path:?} in database to sync with file system");
+    Ok(file)
 }
 
-async fn visit_dirs_inner(
-    dir: PathBuf,
-    state: Arc<AppState>,
-    root: String,
-    cb: Arc<AsyncFn>,
+/// Bytes the file takes up in the file system according to the database
+pub fn stored_size(file: &File) -> i64 {
+    file.stored_size.unwrap_or(file.size)
+}
+
+/// Updates the sizes & digest of a file that was overwritten by another program
+pub async fn set_changed_size(
+    state: &AppState,
+    file: &mut File,
+    stored_size: i64,
 ) -> Result<(), SyncError> {
-    if dir.is_dir() {
-        let mut entries = tokio::fs::read_dir(&dir).await?;
-        while let Some(entry) = entries.next_entry().await? {
-            let path = entry.path();
-            if path.is_dir() {
-                visit_dirs(path, state.clone(), root.clone(), cb.clone()).await?;
-            } else {
-                cb(
-                    &state,
-                    path.to_string_lossy().to_string(),
-                    root.clone(),
-                    entry.metadata().await?.len() as i64,
-                )
-                .await?;
+    let size = state.fs.content_size(&file.path).await? as i64;
+    db::file::successful_upload(file, &state.db, size).await?;
+    db::file::set_stored_size(file, &state.db, stored_size).await?;
+    let digest = dedup::hash(state.fs.as_ref(), &file.path).await?;
+    db::file::set_hash(file, &state.db, &digest).await?;
+
+    tracing::info!(
+        "Updated the size of {:?} to sync with file system",
+        file.path
+    );
+    Ok(())
+}
+
+/// Every file in the file system and how many bytes it takes up,
+/// except for blobs and uploads that aren't done yet
+async fn list_files(fs: &dyn FileSystem) -> io::Result<BTreeMap<String, u64>> {
+    let mut files = BTreeMap::new();
+    let mut dirs = vec![String::new()];
+
+    while let Some(dir) = dirs.pop() {
+        for entry in fs.list_dir(&dir).await? {
+            let path = match dir.is_empty() {
+                true if entry.path == BLOB_DIR || entry.path == PARTIAL_DIR => continue,
+                true => entry.path,
+                false => format!("{dir}/{}", entry.path),
+            };
+
+            match entry.is_dir {
+                true => dirs.push(path),
+                false => {
+                    if let Some(size) = stored_size_at(fs, &path).await? {
+                        files.insert(path, size);
+                    }
+                }
             }
         }
     }
-    Ok(())
+
+    Ok(files)
+}
+
+/// What the file takes up in the file system, listings have the size it's read back in
+/// for encrypted files. `None` if it was removed since it was listed
+pub(crate) async fn stored_size_at(fs: &dyn FileSystem, path: &str) -> io::Result<Option<u64>> {
+    match fs.stored_size(path).await {
+        Ok(size) => Ok(Some(size)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
 }
 
 #[derive(Debug)]
@@ -171,3 +258,12 @@ impl From<sqlx::Error> for SyncError {
         Self::DB(value)
     }
 }
+
+impl From<DedupError> for SyncError {
+    fn from(value: DedupError) -> Self {
+        match value {
+            DedupError::IO(err) => Self::IO(err),
+            DedupError::DB(err) => Self::DB(err),
+        }
+    }
+}
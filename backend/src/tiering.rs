@@ -0,0 +1,204 @@
+//! Moves files between the hot & cold tier of a tiered file system.
+//!
+//! Files that haven't been downloaded in a while are periodically moved to the cold
+//! tier, and downloading a cold file moves it back to the hot tier in the background.
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use sf_core::{File, Tier};
+
+use crate::{AppState, config::WhichFileSystem, db, dedup::storage_path};
+
+const DEFAULT_INTERVAL: u64 = 60 * 60;
+
+/// Storage paths currently being moved back to the hot tier
+static PROMOTING: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+
+fn is_tiered(state: &AppState) -> bool {
+    match state.backend.which() {
+        WhichFileSystem::Tiered => true,
+        WhichFileSystem::Mounts => state
+            .config
+            .mounts
+            .iter()
+            .flatten()
+            .any(|mount| mount.file_system == WhichFileSystem::Tiered),
+        _ => false,
+    }
+}
+
+/// Runs forever, moving old files to the cold tier every `interval` seconds
+pub async fn run_mover(state: Arc<AppState>) {
+    let Some(config) = state.config.tiered.as_ref().filter(|_| is_tiered(&state)) else {
+        return;
+    };
+
+    let mut interval = tokio::time::interval(Duration::from_secs(
+        config.interval.unwrap_or(DEFAULT_INTERVAL),
+    ));
+    loop {
+        interval.tick().await;
+
+        match demote_old_files(&state, config.max_age).await {
+            Ok(0) => (),
+            Ok(count) => tracing::info!("Moved {count} files to the cold tier"),
+            Err(err) => {
+                tracing::error!("Failed to look for files to move to the cold tier: {err:?}")
+            }
+        }
+    }
+}
+
+async fn demote_old_files(state: &AppState, max_age: u64) -> sqlx::Result<u64> {
+    let mut files = db::file::get_files_to_demote(&state.db, max_age).await?;
+    // files sharing a blob are moved together
+    let mut seen = HashSet::new();
+    files.retain(|file| seen.insert(storage_path(file)));
+
+    let mut count = 0;
+    for mut file in files {
+        match state
+            .fs
+            .move_to_tier(&storage_path(&file), Tier::Cold)
+            .await
+        {
+            Ok(_) => {
+                db::file::set_tier(&mut file, &state.db, Tier::Cold).await?;
+                count += 1;
+            }
+            Err(err) => tracing::error!("Failed to move {:?} to the cold tier: {err:?}", file.path),
+        }
+    }
+
+    Ok(count)
+}
+
+/// Moves a cold file back to the hot tier in the background,
+/// the download that triggered it just reads from the cold tier meanwhile
+pub fn promote(state: Arc<AppState>, mut file: File) {
+    if !is_tiered(&state) || file.get_tier() != Tier::Cold {
+        return;
+    }
+    // files sharing a blob are moved together
+    let path = storage_path(&file);
+    if !PROMOTING.lock().unwrap().insert(path.clone()) {
+        return;
+    }
+
+    tokio::spawn(async move {
+        match state.fs.move_to_tier(&path, Tier::Hot).await {
+            Ok(_) => match db::file::set_tier(&mut file, &state.db, Tier::Hot).await {
+                Ok(_) => tracing::info!("Moved {:?} back to the hot tier", file.path),
+                Err(err) => tracing::error!("{err:?}"),
+            },
+            Err(err) => tracing::error!("Failed to move {:?} to the hot tier: {err:?}", file.path),
+        }
+
+        PROMOTING.lock().unwrap().remove(&path);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    /// Two `memory` tiers, anything older than a second is cold
+    async fn tiered_state() -> Arc<AppState> {
+        let config: Config = toml::from_str(
+            r#"
+            file_system = "tiered"
+            addr = "127.0.0.1:0"
+            db = "sqlite::memory:"
+            token = "token"
+            upload_limit = 1000000
+            storage_limit = 1000000
+            upload_timeout = 60
+
+            [tiered]
+            hot = "memory"
+            cold = "memory"
+            max_age = 1
+            "#,
+        )
+        .unwrap();
+        Arc::new(AppState::new(config).await)
+    }
+
+    async fn tier(state: &AppState, id: &str) -> Tier {
+        db::file::get_via_id(&state.db, id)
+            .await
+            .unwrap()
+            .get_tier()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn demote_and_promote() {
+        let state = tiered_state().await;
+        assert!(is_tiered(&state));
+
+        let mut file = db::file::new(&state.db, "id", "old.txt", -1).await.unwrap();
+        db::file::successful_upload(&mut file, &state.db, 3)
+            .await
+            .unwrap();
+        state.fs.write("old.txt", b"old").await.unwrap();
+
+        // two files sharing a blob
+        state.fs.write("blob", b"shared").await.unwrap();
+        for id in ["first", "second"] {
+            let mut file = db::file::new(&state.db, id, &format!("{id}.txt"), -1)
+                .await
+                .unwrap();
+            db::file::successful_upload(&mut file, &state.db, 6)
+                .await
+                .unwrap();
+            sqlx::query("UPDATE files SET blob = 'digest' WHERE id = ?")
+                .bind(id)
+                .execute(&state.db)
+                .await
+                .unwrap();
+        }
+        let blob = db::file::get_via_id(&state.db, "first").await.unwrap();
+        let blob_path = storage_path(&blob);
+        state.fs.rename("blob", &blob_path).await.unwrap();
+
+        // too new to be moved
+        assert_eq!(demote_old_files(&state, 1).await.unwrap(), 0);
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        // the blob only once
+        assert_eq!(demote_old_files(&state, 1).await.unwrap(), 2);
+        // already cold
+        assert_eq!(demote_old_files(&state, 1).await.unwrap(), 0);
+
+        for id in ["id", "first", "second"] {
+            assert_eq!(tier(&state, id).await, Tier::Cold);
+        }
+        assert_eq!(state.fs.read("old.txt").await.unwrap(), b"old");
+
+        // downloading either file moves the blob back for both
+        let file = db::file::get_via_id(&state.db, "id").await.unwrap();
+        promote(state.clone(), file);
+        let second = db::file::get_via_id(&state.db, "second").await.unwrap();
+        promote(state.clone(), second);
+        for _ in 0..50 {
+            if PROMOTING.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        for id in ["id", "first", "second"] {
+            assert_eq!(tier(&state, id).await, Tier::Hot);
+        }
+        assert_eq!(state.fs.read("old.txt").await.unwrap(), b"old");
+        assert_eq!(state.fs.read(&blob_path).await.unwrap(), b"shared");
+
+        // hot files aren't promoted again
+        let file = db::file::get_via_id(&state.db, "first").await.unwrap();
+        promote(state.clone(), file);
+        assert!(PROMOTING.lock().unwrap().is_empty());
+    }
+}
@@ -2,6 +2,20 @@ pub mod private;
 pub mod public;
 pub mod websocket;
 
+/// Where uploads are written to until every chunk has arrived,
+/// relative to the file system root
+pub const PARTIAL_DIR: &str = ".partial";
+
+/// `.partial/<id>.<extension of path>`, the staging file of an upload that isn't done yet.
+/// It keeps the extension so file systems that go by it (like compression) treat it
+/// the same as the finished file
+pub fn partial_path(id: &str, path: &str) -> String {
+    match std::path::Path::new(path).extension() {
+        Some(extension) => format!("{PARTIAL_DIR}/{id}.{}", extension.to_string_lossy()),
+        None => format!("{PARTIAL_DIR}/{id}"),
+    }
+}
+
 /// A path cannot be root or go back or anything foul
 fn path_is_valid(path: impl AsRef<std::path::Path>) -> bool {
     let mut components = path.as_ref().components().peekable();
@@ -2,6 +2,7 @@ use std::{
     fmt::Debug,
     io::{Seek, SeekFrom, Write},
     net::SocketAddr,
+    path::Path,
     sync::Arc,
 };
 
@@ -14,11 +15,13 @@ use axum::{
     response::Response,
 };
 use futures_util::{SinkExt, StreamExt};
+use sha2::{Digest, Sha256};
 
 use crate::{
     AppState,
     db::{self, links::FileLink},
-    upload::path_is_valid,
+    dedup::{self, DedupError},
+    upload::{partial_path, path_is_valid},
 };
 use sf_core::{
     FileAccess, packet,
@@ -86,8 +89,10 @@ async fn handle_socket(mut socket: WebSocket, mut data: WebsocketData) {
             return Err(UploadError::InvalidPath(data.path));
         }
 
+        // the limit is on what's actually stored, this file can only take up less (if compressed)
         let bytes_stored = db::file::get_bytes_stored(&data.state.db).await.unwrap();
-        let remaining_storage = data.state.config.storage_limit as u64 - bytes_stored;
+        let remaining_storage =
+            (data.state.config.storage_limit as u64).saturating_sub(bytes_stored.physical as u64);
         if file.size > remaining_storage {
             return Err(UploadError::InsufficientStorage);
         }
@@ -102,29 +107,15 @@ async fn handle_socket(mut socket: WebSocket, mut data: WebsocketData) {
                 // and thus ""must"" discard the old one and begin from the start.
                 // so we clear the file in db and on disk & prepare it
                 // to make a new entry etc with the new data
-                if total_chunks as i64 != f.total_chunks {
+                // deduplicated files have already been moved into their blob
+                // so theres nothing to resume, those are also started over
+                if total_chunks as i64 != f.total_chunks || f.blob.is_some() {
                     tracing::error!(
                         "Mismatched total_chunks, uploading file({}) from the start",
                         &data.id
                     );
 
-                    db::file::delete(&data.state.db, &data.id)
-                        .await
-                        .map_err(|e| UploadError::DBError(e))?;
-
-                    if data
-                        .state
-                        .fs
-                        .exists(&data.path)
-                        .await
-                        .map_err(|e| UploadError::FailedIO(e))?
-                    {
-                        data.state
-                            .fs
-                            .delete(&data.path)
-                            .await
-                            .map_err(|e| UploadError::FailedIO(e))?;
-                    }
+                    dedup::delete_file(&data.state, &f).await?;
 
                     None
                 } else {
@@ -145,14 +136,23 @@ async fn handle_socket(mut socket: WebSocket, mut data: WebsocketData) {
                 .map_err(|e| UploadError::DBError(e))?,
         };
 
+        // the file only shows up at its path once it's complete
+        let staging_path = partial_path(&db_file.id, &db_file.path);
+        if db_file.chunk_index > 0 && !dedup::is_uploaded(&db_file) {
+            stage_legacy_upload(&data.state, &db_file.path, &staging_path).await?;
+        }
+
         let file_handler = data
             .state
             .fs
-            .get_file_handler(&data.path)
+            .get_file_handler(&staging_path)
             .await
             .map_err(|e| UploadError::FailedIO(e))?;
         let mut writer = std::io::BufWriter::new(file_handler);
         chunk_index = db_file.chunk_index as u64; // start at whatever chunk_index is from db, if new it defaults to 0
+        // the chunks are hashed as they're written, so the file doesn't have to be read again.
+        // resumed uploads lost what was hashed before, those are read once they're done
+        let mut hasher = (chunk_index == 0).then(|| (Sha256::new(), 0u64));
 
         tracing::trace!(
             "Chunk metdata: total: {}, bytes per: {}, starting_index: {}",
@@ -194,12 +194,18 @@ async fn handle_socket(mut socket: WebSocket, mut data: WebsocketData) {
                                         .map_err(|e| UploadError::FailedToSend(e))?;
                                 }
 
-                                writer
-                                    .seek(SeekFrom::Start(chunk_index * file.chunk_size))
-                                    .map_err(|e| UploadError::FailedIO(e))?;
-                                writer
-                                    .write_all(chunk.data)
-                                    .map_err(|e| UploadError::FailedIO(e))?;
+                                // handlers of remote file systems block on network I/O,
+                                // which would stall every other task on this worker
+                                tokio::task::block_in_place(|| {
+                                    writer.seek(SeekFrom::Start(chunk_index * file.chunk_size))?;
+                                    writer.write_all(chunk.data)?;
+                                    if let Some((hasher, hashed)) = &mut hasher {
+                                        hasher.update(chunk.data);
+                                        *hashed += chunk.data.len() as u64;
+                                    }
+                                    Ok::<_, std::io::Error>(())
+                                })
+                                .map_err(UploadError::FailedIO)?;
 
                                 chunk_index += 1;
                                 if chunk_index % 1000 == 0 {
@@ -248,7 +254,29 @@ async fn handle_socket(mut socket: WebSocket, mut data: WebsocketData) {
             Ok(())
         };
         // if the core upload fails or succeds it will always run code here
-        writer.flush().map_err(|e| UploadError::FailedIO(e))?;
+        tokio::task::block_in_place(|| {
+            writer.flush()?;
+            drop(writer);
+            Ok::<_, std::io::Error>(())
+        })
+        .map_err(UploadError::FailedIO)?;
+
+        let upload_result = match upload_result {
+            Ok(_) => match finish(&data.state, &staging_path, &data.path, file.size).await {
+                Ok(_) => Ok(()),
+                Err(err) => {
+                    chunk_index = match err {
+                        // the staging file is gone, so it's uploaded again from the start
+                        UploadError::SizeMismatch { .. } => 0,
+                        // resending the last chunk tries finishing it again
+                        _ => chunk_index.saturating_sub(1),
+                    };
+                    Err(err)
+                }
+            },
+            Err(err) => Err(err),
+        };
+
         // always, even if it fails or not. update the databases chunk index
         // this is so we can resume uploading AND this code is 100%
         // always gonna run even if the chunked upload part fails or not
@@ -262,6 +290,38 @@ async fn handle_socket(mut socket: WebSocket, mut data: WebsocketData) {
                     .await
                     .unwrap();
 
+                let digest = hasher
+                    .filter(|(_, hashed)| *hashed == file.size)
+                    .map(|(hasher, _)| hex::encode(hasher.finalize()));
+
+                if data.state.config.dedup() {
+                    match digest {
+                        Some(digest) => dedup::store_as(&data.state, &mut db_file, &digest).await?,
+                        None => dedup::store(&data.state, &mut db_file).await?,
+                    }
+                } else {
+                    let stored_size = data
+                        .state
+                        .fs
+                        .stored_size(&data.path)
+                        .await
+                        .map_err(|e| UploadError::FailedIO(e))?;
+                    db::file::set_stored_size(&mut db_file, &data.state.db, stored_size as i64)
+                        .await
+                        .map_err(|e| UploadError::DBError(e))?;
+
+                    // deduplicated files already got theirs from the blob
+                    let digest = match digest {
+                        Some(digest) => digest,
+                        None => dedup::hash(data.state.fs.as_ref(), &data.path)
+                            .await
+                            .map_err(UploadError::FailedIO)?,
+                    };
+                    db::file::set_hash(&mut db_file, &data.state.db, &digest)
+                        .await
+                        .map_err(UploadError::DBError)?;
+                }
+
                 // one-time link handling
                 if let Some(link) = data.link {
                     link.uploaded_with(&data.state.db, &data.id)
@@ -324,6 +384,73 @@ async fn handle_socket(mut socket: WebSocket, mut data: WebsocketData) {
     tracing::trace!("Closing websocket connection: {:?}", data.addr.ip());
 }
 
+/// Moves a fully received upload from its staging file to its actual path
+async fn finish(
+    state: &AppState,
+    staging_path: &str,
+    path: &str,
+    size: u64,
+) -> Result<(), UploadError> {
+    state
+        .fs
+        .finish_upload(staging_path)
+        .await
+        .map_err(UploadError::FailedIO)?;
+
+    // what's read back, compressed files take up less than that
+    let stored = state
+        .fs
+        .content_size(staging_path)
+        .await
+        .map_err(UploadError::FailedIO)?;
+    if stored != size {
+        if let Err(err) = state.fs.delete(staging_path).await {
+            tracing::error!("Failed to delete {staging_path:?}: {err:?}");
+        }
+        return Err(UploadError::SizeMismatch {
+            expected: size,
+            actual: stored,
+        });
+    }
+
+    if let Some(parent) = Path::new(path).parent() {
+        state
+            .fs
+            .create_dir_all(&parent.to_string_lossy())
+            .await
+            .map_err(UploadError::FailedIO)?;
+    }
+    state
+        .fs
+        .rename(staging_path, path)
+        .await
+        .map_err(UploadError::FailedIO)
+}
+
+/// Uploads started before they were staged were written to their actual path,
+/// those are moved to their staging file so they can be resumed
+async fn stage_legacy_upload(
+    state: &AppState,
+    path: &str,
+    staging_path: &str,
+) -> Result<(), UploadError> {
+    let staged = state
+        .fs
+        .exists(staging_path)
+        .await
+        .map_err(UploadError::FailedIO)?;
+    if staged || !state.fs.exists(path).await.map_err(UploadError::FailedIO)? {
+        return Ok(());
+    }
+
+    tracing::debug!("Moving unfinished upload {path:?} to {staging_path:?}");
+    state
+        .fs
+        .rename(path, staging_path)
+        .await
+        .map_err(UploadError::FailedIO)
+}
+
 macro_rules! message {
     ($($input:tt)*) => {{
         use axum::{extract::ws::{Message}, body::Bytes};
@@ -342,6 +469,11 @@ enum UploadError {
     ClientDisconnected,
     InsufficientStorage,
     InvalidPath(String),
+    /// The staged file doesn't have the size the client announced
+    SizeMismatch {
+        expected: u64,
+        actual: u64,
+    },
     MessageIsNotOk(axum::Error),
     FailedToSend(axum::Error),
     FailedIO(std::io::Error),
@@ -355,3 +487,12 @@ impl std::fmt::Display for UploadError {
     }
 }
 impl std::error::Error for UploadError {}
+
+impl From<DedupError> for UploadError {
+    fn from(value: DedupError) -> Self {
+        match value {
+            DedupError::IO(err) => Self::FailedIO(err),
+            DedupError::DB(err) => Self::DBError(err),
+        }
+    }
+}
@@ -0,0 +1,450 @@
+//! Keeps the database up to date with the `local` file system while the server runs.
+//!
+//! [`crate::sync`] only looks at the file system on start, so files other programs
+//! put into (or take out of) the root would otherwise only show up after a restart.
+//! Changes are picked up with inotify and handled once a path hasn't changed for
+//! [`DEBOUNCE`], so files that are still being written aren't imported halfway.
+//! Uploads that aren't done yet and deduplicated blobs are ignored, and changes
+//! the server makes itself are already in the database by then, so they're skipped.
+
+use notify::{
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+    event::{ModifyKind, RenameMode},
+};
+use sf_core::File;
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    AppState,
+    config::WhichFileSystem,
+    db,
+    dedup::{self, BLOB_DIR, is_uploaded},
+    file_system::FileSystem,
+    sync::{self, SyncError},
+    upload::PARTIAL_DIR,
+};
+
+/// How long a path has to stay unchanged before it's handled
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// The root being watched, with every change since it started
+pub struct Watching {
+    root: PathBuf,
+    events: mpsc::UnboundedReceiver<notify::Result<Event>>,
+    _watcher: RecommendedWatcher,
+}
+
+/// Starts watching the root of the `local` file system if it's used,
+/// changes are collected until [`run_watcher`] handles them. Start it before
+/// syncing so nothing that changes while syncing is missed
+pub fn watch(state: &AppState) -> Option<Watching> {
+    let config = state
+        .config
+        .local
+        .as_ref()
+        .filter(|config| config.watch.unwrap_or(true))?;
+    if state.backend.which() != WhichFileSystem::Local {
+        return None;
+    }
+    if state.config.encryption.is_some() {
+        tracing::warn!("Not watching the local file system for changes since it's encrypted");
+        return None;
+    }
+
+    let root = match std::fs::canonicalize(&config.root) {
+        Ok(root) => root,
+        Err(err) => {
+            tracing::error!("Failed to watch {:?} for changes: {err:?}", config.root);
+            return None;
+        }
+    };
+
+    let (tx, events) = mpsc::unbounded_channel();
+    let mut watcher = match notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    }) {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            tracing::error!("Failed to create the file system watcher: {err:?}");
+            return None;
+        }
+    };
+    if let Err(err) = watcher.watch(&root, RecursiveMode::Recursive) {
+        tracing::error!("Failed to watch {root:?} for changes: {err:?}");
+        return None;
+    }
+    tracing::info!("Watching {root:?} for changes");
+
+    Some(Watching {
+        root,
+        events,
+        _watcher: watcher,
+    })
+}
+
+/// Runs until the server switches away from the `local` file system
+pub async fn run_watcher(state: Arc<AppState>, watching: Watching) {
+    let Watching {
+        root,
+        mut events,
+        _watcher,
+    } = watching;
+
+    let mut pending = Pending::default();
+    let mut interval = tokio::time::interval(DEBOUNCE / 4);
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(Ok(event)) => pending.add(&root, event),
+                Some(Err(err)) => tracing::error!("File system watcher failed: {err:?}"),
+                None => return,
+            },
+            _ = interval.tick() => {
+                // migrated to another file system
+                if state.backend.which() != WhichFileSystem::Local {
+                    tracing::info!("Stopped watching {root:?} for changes");
+                    return;
+                }
+                handle(&state, pending.take_ready()).await;
+            }
+        }
+    }
+}
+
+/// Changes that haven't been handled yet, paths are relative to the root
+#[derive(Default)]
+struct Pending {
+    /// When each path last changed
+    paths: HashMap<String, Instant>,
+    renames: Vec<(String, String, Instant)>,
+    /// Events were dropped, so everything has to be looked at again
+    rescan: Option<Instant>,
+}
+
+/// Changes that haven't changed again for [`DEBOUNCE`]
+struct Ready {
+    paths: Vec<String>,
+    renames: Vec<(String, String)>,
+    rescan: bool,
+}
+
+impl Pending {
+    fn add(&mut self, root: &Path, event: Event) {
+        let now = Instant::now();
+        if event.need_rescan() {
+            self.rescan = Some(now);
+            return;
+        }
+        if let EventKind::Access(_) = event.kind {
+            return;
+        }
+
+        let paths = event
+            .paths
+            .iter()
+            .map(|path| relative(root, path))
+            .collect::<Vec<_>>();
+        if let EventKind::Modify(ModifyKind::Name(RenameMode::Both)) = event.kind
+            && let [Some(from), Some(to)] = paths.as_slice()
+        {
+            self.renames.push((from.clone(), to.clone(), now));
+        }
+        for path in paths.into_iter().flatten() {
+            self.paths.insert(path, now);
+        }
+    }
+
+    fn take_ready(&mut self) -> Ready {
+        let now = Instant::now();
+        let is_ready = |changed: &Instant| now.duration_since(*changed) >= DEBOUNCE;
+
+        let mut paths = vec![];
+        self.paths.retain(|path, changed| match is_ready(changed) {
+            true => {
+                paths.push(path.clone());
+                false
+            }
+            false => true,
+        });
+        // parents before their children
+        paths.sort();
+
+        let mut renames = vec![];
+        self.renames
+            .retain(|(from, to, changed)| match is_ready(changed) {
+                true => {
+                    renames.push((from.clone(), to.clone()));
+                    false
+                }
+                false => true,
+            });
+
+        let rescan = self.rescan.as_ref().is_some_and(is_ready);
+        if rescan {
+            self.rescan = None;
+        }
+
+        Ready {
+            paths,
+            renames,
+            rescan,
+        }
+    }
+}
+
+/// The path as it's stored in the database, nothing if it's the root itself
+/// or somewhere the server keeps things that aren't files of their own
+fn relative(root: &Path, path: &Path) -> Option<String> {
+    let parts = path
+        .strip_prefix(root)
+        .ok()?
+        .components()
+        .map(|part| part.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>();
+
+    match parts.first() {
+        None => None,
+        Some(first) if first == BLOB_DIR || first == PARTIAL_DIR => None,
+        Some(_) => Some(parts.join("/")),
+    }
+}
+
+async fn handle(state: &AppState, ready: Ready) {
+    if ready.rescan {
+        tracing::warn!("Missed some file system changes, syncing everything again");
+        if let Err(err) = sync::sync_files(state, false).await {
+            tracing::error!("Failed syncing database with the file system: {err:?}");
+        }
+    }
+
+    // renames first, so their paths aren't seen as a deleted & a new file
+    for (from, to) in ready.renames {
+        if let Err(err) = rename(state, &from, &to).await {
+            tracing::error!("Failed to move {from:?} to {to:?} in the database: {err:?}");
+        }
+    }
+
+    for path in ready.paths {
+        if let Err(err) = update(state, &path).await {
+            tracing::error!("Failed to update {path:?} in the database: {err:?}");
+        }
+    }
+}
+
+/// Moves the file or every file in the directory to its new path, keeping their ids
+async fn rename(state: &AppState, from: &str, to: &str) -> Result<(), SyncError> {
+    // the server renamed it itself
+    if file_at(state, to).await?.is_some() {
+        return Ok(());
+    }
+
+    match file_at(state, from).await? {
+        Some(mut file) => {
+            if file.blob.is_none() && is_uploaded(&file) {
+                db::file::rename(&mut file, &state.db, to).await?;
+                tracing::info!("Moved {from:?} to {to:?} in the database");
+            }
+        }
+        None => {
+            let moved = db::file::rename_directory(&state.db, from, to).await?;
+            if moved > 0 {
+                tracing::info!("Moved {moved} files from {from:?} to {to:?} in the database");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds whatever is at `path` to the database, or removes it if it's gone
+async fn update(state: &AppState, path: &str) -> Result<(), SyncError> {
+    match state.fs.metadata(path).await {
+        Ok(metadata) if metadata.is_dir => {
+            // anything already in it when it was created or moved here
+            for (path, size) in files_in(state.fs.as_ref(), path).await? {
+                add(state, &path, size).await?;
+            }
+        }
+        Ok(_) => match sync::stored_size_at(state.fs.as_ref(), path).await? {
+            Some(size) => add(state, path, size).await?,
+            None => remove(state, path).await?,
+        },
+        Err(err) if err.kind() == ErrorKind::NotFound => remove(state, path).await?,
+        Err(err) => return Err(err.into()),
+    }
+
+    Ok(())
+}
+
+/// `stored_size` is what the file takes up in the file system
+async fn add(state: &AppState, path: &str, stored_size: u64) -> Result<(), SyncError> {
+    let stored_size = stored_size as i64;
+
+    if let Some(mut file) = file_at(state, path).await? {
+        // overwritten by another program
+        if file.blob.is_none() && is_uploaded(&file) && sync::stored_size(&file) != stored_size {
+            sync::set_changed_size(state, &mut file, stored_size).await?;
+        }
+        return Ok(());
+    }
+
+    let mut file = sync::add_file(state, path, stored_size).await?;
+    if state.config.dedup() {
+        dedup::store(state, &mut file).await?;
+    }
+
+    Ok(())
+}
+
+/// Removes the file or every file in the directory from the database
+async fn remove(state: &AppState, path: &str) -> Result<(), SyncError> {
+    let mut files = db::file::get_files_below(&state.db, path).await?;
+    files.extend(file_at(state, path).await?);
+
+    for file in files {
+        // those aren't stored at their path
+        if file.blob.is_some() || !is_uploaded(&file) {
+            continue;
+        }
+        if state.fs.exists(&file.path).await? {
+            continue;
+        }
+
+        db::file::delete(&state.db, &file.id).await?;
+        tracing::info!("Deleted {:?} from the database", file.path);
+    }
+
+    Ok(())
+}
+
+async fn file_at(state: &AppState, path: &str) -> sqlx::Result<Option<File>> {
+    match db::file::get_via_path(&state.db, path).await {
+        Ok(file) => Ok(Some(file)),
+        Err(sqlx::Error::RowNotFound) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Every file anywhere below the directory and what it takes up in the file system
+async fn files_in(fs: &dyn FileSystem, dir: &str) -> io::Result<Vec<(String, u64)>> {
+    let mut files = vec![];
+    let mut dirs = vec![dir.to_string()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs.list_dir(&dir).await? {
+            let path = format!("{dir}/{}", entry.path);
+            match entry.is_dir {
+                true => dirs.push(path),
+                false => {
+                    if let Some(size) = sync::stored_size_at(fs, &path).await? {
+                        files.push((path, size));
+                    }
+                }
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{AccessKind, CreateKind, Flag};
+
+    fn event(kind: EventKind, paths: &[&str]) -> Event {
+        paths.iter().fold(Event::new(kind), |event, path| {
+            event.add_path(Path::new("/root").join(path))
+        })
+    }
+
+    /// Pretends every pending change happened [`DEBOUNCE`] ago
+    fn age(pending: &mut Pending) {
+        let aged = |changed: &mut Instant| *changed = changed.checked_sub(DEBOUNCE).unwrap();
+        pending.paths.values_mut().for_each(aged);
+        pending
+            .renames
+            .iter_mut()
+            .for_each(|(_, _, changed)| aged(changed));
+        pending.rescan.iter_mut().for_each(aged);
+    }
+
+    #[test]
+    fn relative_paths() {
+        let root = Path::new("/root");
+        let relative = |path: &str| relative(root, Path::new(path));
+
+        assert_eq!(relative("/root/a/b.txt"), Some("a/b.txt".into()));
+        assert_eq!(relative("/root/.blobsx"), Some(".blobsx".into()));
+        assert_eq!(relative("/root"), None);
+        assert_eq!(relative("/elsewhere/a"), None);
+        assert_eq!(relative(&format!("/root/{BLOB_DIR}/ab/cd")), None);
+        assert_eq!(relative(&format!("/root/{PARTIAL_DIR}/upload")), None);
+    }
+
+    #[test]
+    fn debounce() {
+        let mut pending = Pending::default();
+        pending.add(
+            Path::new("/root"),
+            event(EventKind::Create(CreateKind::File), &["dir/b", "dir"]),
+        );
+        // reading doesn't change anything
+        pending.add(
+            Path::new("/root"),
+            event(EventKind::Access(AccessKind::Any), &["other"]),
+        );
+
+        assert!(pending.take_ready().paths.is_empty());
+        age(&mut pending);
+        // still being written to
+        pending.add(
+            Path::new("/root"),
+            event(EventKind::Modify(ModifyKind::Any), &["dir/b"]),
+        );
+
+        let ready = pending.take_ready();
+        assert_eq!(ready.paths, ["dir"]);
+        assert!(!ready.rescan);
+        age(&mut pending);
+        assert_eq!(pending.take_ready().paths, ["dir/b"]);
+        assert!(pending.take_ready().paths.is_empty());
+    }
+
+    #[test]
+    fn renames() {
+        let mut pending = Pending::default();
+        let renamed = EventKind::Modify(ModifyKind::Name(RenameMode::Both));
+        pending.add(Path::new("/root"), event(renamed, &["b", "a/b"]));
+        // finished uploads are moved out of the staging directory
+        pending.add(
+            Path::new("/root"),
+            event(renamed, &[&format!("{PARTIAL_DIR}/x"), "c"]),
+        );
+        age(&mut pending);
+
+        let ready = pending.take_ready();
+        assert_eq!(ready.renames, [("b".to_string(), "a/b".to_string())]);
+        // parents before their children
+        assert_eq!(ready.paths, ["a/b", "b", "c"]);
+        assert!(pending.renames.is_empty());
+    }
+
+    #[test]
+    fn rescan() {
+        let mut pending = Pending::default();
+        pending.add(
+            Path::new("/root"),
+            Event::new(EventKind::Other).set_flag(Flag::Rescan),
+        );
+        assert!(!pending.take_ready().rescan);
+        age(&mut pending);
+        assert!(pending.take_ready().rescan);
+        assert!(!pending.take_ready().rescan);
+    }
+}
@@ -0,0 +1,610 @@
+//! ZIP archives of many files at once, streamed straight from
+//! [`crate::file_system::FileSystem::read_stream`] without building them
+//! in memory or on disk.
+//!
+//! Files are only stored, not compressed (most big files are already compressed anyway),
+//! so how long the archive is is known before anything is read. The CRC-32 of a file
+//! is only known once it was sent, so it follows the file in a data descriptor.
+//! Zip64 is used wherever a file, offset or count doesn't fit the original format.
+
+use axum::{
+    body::Body,
+    http::header::{CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE},
+    response::Response,
+};
+use futures_util::{StreamExt, stream};
+use sf_core::File;
+use std::{
+    collections::HashSet,
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use time::OffsetDateTime;
+
+use crate::{
+    AppState, db, dedup::storage_path, download::content_disposition, error::SimplyError,
+    file_system::FSStream,
+};
+
+const LOCAL_HEADER: u32 = 0x04034b50;
+const DATA_DESCRIPTOR: u32 = 0x08074b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const ZIP64_END: u32 = 0x06064b50;
+const ZIP64_LOCATOR: u32 = 0x07064b50;
+const END: u32 = 0x06054b50;
+
+/// Sizes, offsets & counts at least this big are stored in zip64 extra fields
+const ZIP64_LIMIT: u64 = u32::MAX as u64;
+/// 4.5, the first version with zip64
+const VERSION: u16 = 45;
+/// The sizes & CRC-32 follow the data, names are UTF-8
+const FLAGS: u16 = 1 << 3 | 1 << 11;
+
+#[derive(Default)]
+pub struct Zip {
+    entries: Vec<Entry>,
+    names: HashSet<String>,
+}
+
+struct Entry {
+    name: String,
+    file: File,
+}
+
+impl Entry {
+    fn size(&self) -> u64 {
+        self.file.size as u64
+    }
+
+    fn zip64(&self) -> bool {
+        self.size() >= ZIP64_LIMIT
+    }
+
+    /// Bytes from the start of the local header to the end of the data descriptor
+    fn len(&self) -> u64 {
+        self.local_header().len() as u64 + self.size() + self.data_descriptor(0).len() as u64
+    }
+
+    fn local_header(&self) -> Vec<u8> {
+        let (time, date) = dos_time(self.file.updated_at);
+        let mut header = vec![];
+        put_u32(&mut header, LOCAL_HEADER);
+        put_u16(&mut header, VERSION);
+        put_u16(&mut header, FLAGS);
+        // stored
+        put_u16(&mut header, 0);
+        put_u16(&mut header, time);
+        put_u16(&mut header, date);
+        // CRC-32, compressed & uncompressed size are in the data descriptor
+        put_u32(&mut header, 0);
+        let size = if self.zip64() { u32::MAX } else { 0 };
+        put_u32(&mut header, size);
+        put_u32(&mut header, size);
+        put_u16(&mut header, self.name.len() as u16);
+        put_u16(&mut header, if self.zip64() { 20 } else { 0 });
+        header.extend(self.name.as_bytes());
+
+        if self.zip64() {
+            put_u16(&mut header, 0x0001);
+            put_u16(&mut header, 16);
+            put_u64(&mut header, 0);
+            put_u64(&mut header, 0);
+        }
+        header
+    }
+
+    fn data_descriptor(&self, crc: u32) -> Vec<u8> {
+        let mut descriptor = vec![];
+        put_u32(&mut descriptor, DATA_DESCRIPTOR);
+        put_u32(&mut descriptor, crc);
+        if self.zip64() {
+            put_u64(&mut descriptor, self.size());
+            put_u64(&mut descriptor, self.size());
+        } else {
+            put_u32(&mut descriptor, self.size() as u32);
+            put_u32(&mut descriptor, self.size() as u32);
+        }
+        descriptor
+    }
+
+    fn central_header(&self, crc: u32, offset: u64) -> Vec<u8> {
+        let mut extra = vec![];
+        if self.zip64() {
+            put_u64(&mut extra, self.size());
+            put_u64(&mut extra, self.size());
+        }
+        if offset >= ZIP64_LIMIT {
+            put_u64(&mut extra, offset);
+        }
+
+        let (time, date) = dos_time(self.file.updated_at);
+        let mut header = vec![];
+        put_u32(&mut header, CENTRAL_HEADER);
+        // made by unix, so the permissions are used
+        put_u16(&mut header, 3 << 8 | VERSION);
+        put_u16(&mut header, VERSION);
+        put_u16(&mut header, FLAGS);
+        put_u16(&mut header, 0);
+        put_u16(&mut header, time);
+        put_u16(&mut header, date);
+        put_u32(&mut header, crc);
+        let size = self.size().min(ZIP64_LIMIT) as u32;
+        put_u32(&mut header, size);
+        put_u32(&mut header, size);
+        put_u16(&mut header, self.name.len() as u16);
+        put_u16(
+            &mut header,
+            if extra.is_empty() {
+                0
+            } else {
+                4 + extra.len() as u16
+            },
+        );
+        // comment, disk & internal attributes
+        put_u16(&mut header, 0);
+        put_u16(&mut header, 0);
+        put_u16(&mut header, 0);
+        // -rw-r--r--
+        put_u32(&mut header, 0o100644 << 16);
+        put_u32(&mut header, offset.min(ZIP64_LIMIT) as u32);
+        header.extend(self.name.as_bytes());
+
+        if !extra.is_empty() {
+            put_u16(&mut header, 0x0001);
+            put_u16(&mut header, extra.len() as u16);
+            header.extend(extra);
+        }
+        header
+    }
+}
+
+impl Zip {
+    /// Adds the file as `name`, which gets a number if it's taken already
+    pub fn add(&mut self, name: &str, file: File) {
+        let mut unique = name.to_string();
+        let path = Path::new(name);
+        let mut n = 1;
+        while self.names.contains(&unique) {
+            n += 1;
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            unique = match path.extension() {
+                Some(extension) => format!("{stem} ({n}).{}", extension.to_string_lossy()),
+                None => format!("{stem} ({n})"),
+            };
+            if let Some(parent) = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+            {
+                unique = format!("{}/{unique}", parent.to_string_lossy());
+            }
+        }
+
+        self.names.insert(unique.clone());
+        self.entries.push(Entry { name: unique, file });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Where each entry's local header starts
+    fn offsets(&self) -> Vec<u64> {
+        let mut offset = 0;
+        self.entries
+            .iter()
+            .map(|entry| {
+                let start = offset;
+                offset += entry.len();
+                start
+            })
+            .collect()
+    }
+
+    /// The central directory & the end of the archive, which starts at `start`
+    fn central_directory(&self, crcs: &[u32], start: u64) -> Vec<u8> {
+        let mut directory = vec![];
+        for ((entry, crc), offset) in self.entries.iter().zip(crcs).zip(self.offsets()) {
+            directory.extend(entry.central_header(*crc, offset));
+        }
+
+        let (count, size) = (self.entries.len() as u64, directory.len() as u64);
+        if count >= u16::MAX as u64 || size >= ZIP64_LIMIT || start >= ZIP64_LIMIT {
+            let zip64_end = start + size;
+            put_u32(&mut directory, ZIP64_END);
+            // size of the rest of the record
+            put_u64(&mut directory, 44);
+            put_u16(&mut directory, 3 << 8 | VERSION);
+            put_u16(&mut directory, VERSION);
+            put_u32(&mut directory, 0);
+            put_u32(&mut directory, 0);
+            put_u64(&mut directory, count);
+            put_u64(&mut directory, count);
+            put_u64(&mut directory, size);
+            put_u64(&mut directory, start);
+
+            put_u32(&mut directory, ZIP64_LOCATOR);
+            put_u32(&mut directory, 0);
+            put_u64(&mut directory, zip64_end);
+            put_u32(&mut directory, 1);
+        }
+
+        put_u32(&mut directory, END);
+        put_u16(&mut directory, 0);
+        put_u16(&mut directory, 0);
+        put_u16(&mut directory, count.min(u16::MAX as u64) as u16);
+        put_u16(&mut directory, count.min(u16::MAX as u64) as u16);
+        put_u32(&mut directory, size.min(ZIP64_LIMIT) as u32);
+        put_u32(&mut directory, start.min(ZIP64_LIMIT) as u32);
+        // comment
+        put_u16(&mut directory, 0);
+        directory
+    }
+
+    /// Where the central directory starts, after every file
+    fn files_len(&self) -> u64 {
+        self.entries.iter().map(Entry::len).sum()
+    }
+
+    /// Length of the whole archive
+    pub fn len(&self) -> u64 {
+        let start = self.files_len();
+        start
+            + self
+                .central_directory(&vec![0; self.entries.len()], start)
+                .len() as u64
+    }
+
+    /// Each file is only opened once the one before it has been sent.
+    /// Every file counts as downloaded once all of them were sent
+    pub fn stream(self, state: Arc<AppState>) -> FSStream {
+        let zip = Arc::new(self);
+        let crcs = Arc::new(Mutex::new(vec![0; zip.entries.len()]));
+
+        let files = {
+            let (state, zip, crcs) = (state.clone(), zip.clone(), crcs.clone());
+            stream::iter(0..zip.entries.len()).then(move |i| {
+                let (state, zip, crcs) = (state.clone(), zip.clone(), crcs.clone());
+                async move { entry_stream(&state, zip, i, crcs).await }
+            })
+        };
+
+        let end = stream::once(async move {
+            let directory = zip.central_directory(&crcs.lock().unwrap(), zip.files_len());
+            count_downloads(state, &zip);
+            Ok(directory)
+        });
+
+        Box::pin(files.flatten().chain(end))
+    }
+}
+
+/// The local header, contents & data descriptor of the `i`th entry
+async fn entry_stream(
+    state: &AppState,
+    zip: Arc<Zip>,
+    i: usize,
+    crcs: Arc<Mutex<Vec<u32>>>,
+) -> FSStream {
+    let (header, path) = (
+        zip.entries[i].local_header(),
+        storage_path(&zip.entries[i].file),
+    );
+    let header = stream::once(async move { Ok(header) });
+
+    let data = match state.fs.read_stream(&path).await {
+        Ok(data) => data,
+        Err(err) => return Box::pin(header.chain(stream::once(async move { Err(err) }))),
+    };
+
+    let hasher = Arc::new(Mutex::new((crc32fast::Hasher::new(), 0u64)));
+    let data = {
+        let hasher = hasher.clone();
+        data.inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                let mut hasher = hasher.lock().unwrap();
+                hasher.0.update(chunk);
+                hasher.1 += chunk.len() as u64;
+            }
+        })
+    };
+
+    let descriptor = stream::once(async move {
+        let entry = &zip.entries[i];
+        let (crc, read) = {
+            let hasher = hasher.lock().unwrap();
+            (hasher.0.clone().finalize(), hasher.1)
+        };
+        // the offsets of everything after it would be wrong
+        if read != entry.size() {
+            return Err(io::Error::other(format!(
+                "{:?} changed while it was being zipped, read {read} of {} bytes",
+                entry.file.path,
+                entry.size()
+            )));
+        }
+
+        crcs.lock().unwrap()[i] = crc;
+        Ok(entry.data_descriptor(crc))
+    });
+
+    Box::pin(header.chain(data).chain(descriptor))
+}
+
+fn count_downloads(state: Arc<AppState>, zip: &Zip) {
+    let files = zip
+        .entries
+        .iter()
+        .map(|entry| entry.file.clone())
+        .collect::<Vec<_>>();
+
+    tokio::spawn(async move {
+        for file in files {
+            if let Err(err) = db::file::increment_download_count(&file, &state.db, Some(1)).await {
+                tracing::error!("{err:?}");
+            }
+        }
+    });
+}
+
+/// A response with the whole archive as `name`
+pub fn response(state: Arc<AppState>, zip: Zip, name: &str) -> Result<Response, SimplyError> {
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/zip")
+        .header(CONTENT_LENGTH, zip.len())
+        .header(CONTENT_DISPOSITION, content_disposition(name))
+        .body(Body::from_stream(zip.stream(state)))?)
+}
+
+/// The time & date in MS-DOS format, which ZIP uses
+fn dos_time(time: OffsetDateTime) -> (u16, u16) {
+    // the earliest it can represent
+    if time.year() < 1980 {
+        return (0, 1 << 5 | 1);
+    }
+
+    let date = ((time.year() - 1980) as u16) << 9 | (time.month() as u16) << 5 | time.day() as u16;
+    let time =
+        (time.hour() as u16) << 11 | (time.minute() as u16) << 5 | (time.second() as u16 / 2);
+    (time, date)
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend(value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend(value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend(value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    /// Contents of a file in a [`Archive`]
+    enum Contents {
+        Bytes(Vec<u8>),
+        /// Zeros, made up while they're read so huge files don't take up any memory
+        Zeros(u64),
+    }
+
+    impl Contents {
+        fn len(&self) -> u64 {
+            match self {
+                Contents::Bytes(bytes) => bytes.len() as u64,
+                Contents::Zeros(len) => *len,
+            }
+        }
+
+        fn crc(&self) -> u32 {
+            match self {
+                Contents::Bytes(bytes) => crc32fast::hash(bytes),
+                Contents::Zeros(len) => {
+                    let block = vec![0; 1024 * 1024];
+                    let mut block_hasher = crc32fast::Hasher::new();
+                    block_hasher.update(&block);
+
+                    let mut hasher = crc32fast::Hasher::new();
+                    for _ in 0..len / block.len() as u64 {
+                        hasher.combine(&block_hasher);
+                    }
+                    hasher.update(&block[..(len % block.len() as u64) as usize]);
+                    hasher.finalize()
+                }
+            }
+        }
+    }
+
+    /// The archive [`Zip::stream`] would send, put together from the same pieces
+    /// without reading any files
+    struct Archive {
+        /// Where each piece starts, and the piece
+        pieces: Vec<(u64, Contents)>,
+        len: u64,
+        position: u64,
+    }
+
+    impl Archive {
+        fn new(zip: &Zip, contents: impl Fn(&Entry) -> Contents) -> Self {
+            let mut archive = Archive {
+                pieces: vec![],
+                len: 0,
+                position: 0,
+            };
+            let mut crcs = vec![];
+            for entry in &zip.entries {
+                let contents = contents(entry);
+                assert_eq!(contents.len(), entry.size());
+                crcs.push(contents.crc());
+
+                archive.push(Contents::Bytes(entry.local_header()));
+                archive.push(contents);
+                archive.push(Contents::Bytes(
+                    entry.data_descriptor(*crcs.last().unwrap()),
+                ));
+            }
+            assert_eq!(archive.len, zip.files_len());
+            archive.push(Contents::Bytes(zip.central_directory(&crcs, archive.len)));
+            archive
+        }
+
+        fn push(&mut self, contents: Contents) {
+            let len = contents.len();
+            self.pieces.push((self.len, contents));
+            self.len += len;
+        }
+    }
+
+    impl Read for Archive {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let index = self
+                .pieces
+                .partition_point(|(start, _)| *start <= self.position);
+            let Some((start, contents)) = index.checked_sub(1).map(|i| &self.pieces[i]) else {
+                return Ok(0);
+            };
+            let offset = self.position - start;
+            let len = (contents.len() - offset).min(buf.len() as u64) as usize;
+
+            match contents {
+                Contents::Bytes(bytes) => {
+                    buf[..len].copy_from_slice(&bytes[offset as usize..offset as usize + len])
+                }
+                Contents::Zeros(_) => buf[..len].fill(0),
+            }
+            self.position += len as u64;
+            Ok(len)
+        }
+    }
+
+    impl Seek for Archive {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.position = match pos {
+                SeekFrom::Start(position) => Some(position),
+                SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+                SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            }
+            .ok_or(io::Error::other("Invalid seek"))?;
+            Ok(self.position)
+        }
+    }
+
+    async fn file(state: &AppState, path: &str, size: u64) -> File {
+        let mut file = db::file::new(&state.db, path, path, 0).await.unwrap();
+        file.size = size as i64;
+        file
+    }
+
+    fn read(archive: &mut ::zip::ZipArchive<Archive>, name: &str) -> Vec<u8> {
+        let mut bytes = vec![];
+        archive
+            .by_name(name)
+            .unwrap()
+            .read_to_end(&mut bytes)
+            .unwrap();
+        bytes
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn huge_files() {
+        let state = AppState::in_memory().await;
+        let mut zip = Zip::default();
+        zip.add("huge.bin", file(&state, "huge.bin", 5 * GIB).await);
+        zip.add("limit.bin", file(&state, "limit.bin", ZIP64_LIMIT).await);
+        zip.add(
+            "small.bin",
+            file(&state, "small.bin", ZIP64_LIMIT - 1).await,
+        );
+        zip.add("after.txt", file(&state, "after.txt", 11).await);
+
+        let archive = Archive::new(&zip, |entry| match entry.name.as_str() {
+            "after.txt" => Contents::Bytes(b"after those".to_vec()),
+            _ => Contents::Zeros(entry.size()),
+        });
+        assert_eq!(archive.len, zip.len());
+        let mut archive = ::zip::ZipArchive::new(archive).unwrap();
+        assert_eq!(archive.len(), 4);
+
+        let huge = archive.by_name("huge.bin").unwrap();
+        assert_eq!((huge.size(), huge.header_start()), (5 * GIB, 0));
+        drop(huge);
+        assert_eq!(archive.by_name("limit.bin").unwrap().size(), ZIP64_LIMIT);
+        assert_eq!(
+            archive.by_name("small.bin").unwrap().size(),
+            ZIP64_LIMIT - 1
+        );
+
+        // starts way past 4 GiB
+        let after = archive.by_name("after.txt").unwrap();
+        assert!(after.header_start() > 3 * ZIP64_LIMIT);
+        drop(after);
+        assert_eq!(read(&mut archive, "after.txt"), b"after those");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn many_files() {
+        let state = AppState::in_memory().await;
+        let template = file(&state, "file", 0).await;
+        let count = u16::MAX as usize + 1000;
+
+        let mut zip = Zip::default();
+        for i in 0..count {
+            let mut file = template.clone();
+            file.size = i.to_string().len() as i64;
+            zip.add(&format!("{i}.txt"), file);
+        }
+
+        let archive = Archive::new(&zip, |entry| {
+            Contents::Bytes(entry.name.trim_end_matches(".txt").as_bytes().to_vec())
+        });
+        assert_eq!(archive.len, zip.len());
+        let mut archive = ::zip::ZipArchive::new(archive).unwrap();
+        assert_eq!(archive.len(), count);
+
+        for i in [0, u16::MAX as usize - 1, u16::MAX as usize, count - 1] {
+            assert_eq!(
+                read(&mut archive, &format!("{i}.txt")),
+                i.to_string().as_bytes()
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn streamed_archive() {
+        let state = AppState::in_memory().await;
+        let mut zip = Zip::default();
+        for (path, contents) in [("a.txt", "first"), ("dir/a.txt", "second"), ("b", "")] {
+            state.fs.create_dir_all("dir").await.unwrap();
+            state.fs.write(path, contents.as_bytes()).await.unwrap();
+            let file = file(&state, path, contents.len() as u64).await;
+            // the second one gets a number
+            zip.add(path.rsplit('/').next().unwrap(), file);
+        }
+        let len = zip.len();
+
+        let mut bytes = vec![];
+        let mut stream = zip.stream(state);
+        while let Some(chunk) = stream.next().await {
+            bytes.extend(chunk.unwrap());
+        }
+        assert_eq!(bytes.len() as u64, len);
+
+        let mut archive = ::zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let mut read = |name: &str| {
+            let mut contents = String::new();
+            let file = archive.by_name(name);
+            file.unwrap().read_to_string(&mut contents).unwrap();
+            contents
+        };
+        assert_eq!(read("a.txt"), "first");
+        assert_eq!(read("a (2).txt"), "second");
+        assert_eq!(read("b"), "");
+    }
+}
@@ -0,0 +1,926 @@
+//! Runs the whole server on a `memory` file system and an in-memory database:
+//! uploads a file through the websocket, downloads it and syncs the database
+//! with files that were changed behind the server's back.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use backend::{AppState, app, config::Config};
+use futures_util::{SinkExt, StreamExt};
+use sf_core::{
+    File, FileAccess, SignedLink, SyncReport,
+    simply_packet::{ByteConversion, Chunk, JsonData, JsonInitializeUpload, Packet},
+};
+use tokio_tungstenite::tungstenite::{Message, client::IntoClientRequest};
+
+const TOKEN: &str = "test-token";
+
+/// `extra` is added to the end of the config
+async fn start(extra: &str) -> (Arc<AppState>, SocketAddr) {
+    start_with("memory", extra).await
+}
+
+/// Like [`start`] on another file system, configured in `extra`
+async fn start_with(file_system: &str, extra: &str) -> (Arc<AppState>, SocketAddr) {
+    let config: Config = toml::from_str(&format!(
+        r#"
+        file_system = "{file_system}"
+        addr = "127.0.0.1:0"
+        db = "sqlite::memory:"
+        token = "{TOKEN}"
+        upload_limit = 1000000
+        storage_limit = 1000000
+        upload_timeout = 60
+        {extra}
+        "#
+    ))
+    .unwrap();
+    let state = Arc::new(AppState::new(config).await);
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = app(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    (state, addr)
+}
+
+fn packet(mut packet: Packet) -> Message {
+    Message::Binary(packet.to_bytes().unwrap().into())
+}
+
+/// The next packet from the server, [`None`] for [`Packet::Next`]
+async fn receive<S>(socket: &mut S) -> Option<JsonData>
+where
+    S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    try_receive(socket)
+        .await
+        .expect("The server closed the socket")
+}
+
+/// Like [`receive`], but [`None`] once the server closed the socket
+async fn try_receive<S>(socket: &mut S) -> Option<Option<JsonData>>
+where
+    S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    let Some(Ok(Message::Binary(bytes))) = socket.next().await else {
+        return None;
+    };
+    match Packet::from_bytes(&bytes).unwrap() {
+        Packet::Next => Some(None),
+        Packet::Json(data) => Some(Some(data)),
+        Packet::Binary(_) => panic!("The server doesn't send chunks"),
+    }
+}
+
+/// Uploads `data` to `path` in chunks of `chunk_size` bytes, like the CLI does
+async fn upload(addr: SocketAddr, path: &str, data: &[u8], chunk_size: u64) -> File {
+    try_upload(addr, path, data, data.len() as u64, chunk_size)
+        .await
+        .expect("The upload failed")
+}
+
+/// Uploads `data` as a file of `size` bytes, [`None`] if the server gave up on it
+async fn try_upload(
+    addr: SocketAddr,
+    path: &str,
+    data: &[u8],
+    size: u64,
+    chunk_size: u64,
+) -> Option<File> {
+    let mut request = format!("ws://{addr}/m/upload/{path}")
+        .into_client_request()
+        .unwrap();
+    request
+        .headers_mut()
+        .insert("Authorization", format!("Bearer {TOKEN}").parse().unwrap());
+    send_upload(request, path, data, size, chunk_size).await
+}
+
+/// Uploads `data` into `.public_uploads` with a one-time link
+async fn public_upload(addr: SocketAddr, link: &str, name: &str, data: &[u8]) -> File {
+    let request = format!("ws://{addr}/o/upload/{name}?id={link}")
+        .into_client_request()
+        .unwrap();
+    send_upload(request, name, data, data.len() as u64, 1024)
+        .await
+        .expect("The upload failed")
+}
+
+async fn send_upload(
+    request: tokio_tungstenite::tungstenite::handshake::client::Request,
+    path: &str,
+    data: &[u8],
+    size: u64,
+    chunk_size: u64,
+) -> Option<File> {
+    let (mut socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+
+    assert_eq!(
+        receive(&mut socket).await,
+        Some(JsonData::ConnectionAccepted)
+    );
+
+    socket
+        .send(packet(Packet::Json(JsonData::InitializeUpload(
+            JsonInitializeUpload {
+                name: path.rsplit('/').next().unwrap().to_string(),
+                size,
+                chunk_size,
+            },
+        ))))
+        .await
+        .unwrap();
+    let mut idx = match receive(&mut socket).await {
+        Some(JsonData::ReadyForUpload(ready)) => ready.chunk_index,
+        packet => panic!("Expected ReadyForUpload, got {packet:?}"),
+    };
+
+    loop {
+        let start = ((idx * chunk_size) as usize).min(data.len());
+        let chunk = &data[start..(start + chunk_size as usize).min(data.len())];
+        socket
+            .send(packet(Packet::Binary(Chunk {
+                size: chunk.len() as u64,
+                idx,
+                data: chunk,
+            })))
+            .await
+            .unwrap();
+        idx += 1;
+
+        match try_receive(&mut socket).await? {
+            None => (),
+            Some(JsonData::SetChunkIndex(set)) => idx = set.chunk_index,
+            Some(JsonData::UploadComplete(file)) => return Some(file),
+            packet => panic!("Unexpected {packet:?}"),
+        }
+    }
+}
+
+/// Starts uploading `data` in chunks of 1 KiB, but gives up after `chunks` of them
+async fn half_upload(state: &AppState, addr: SocketAddr, path: &str, data: &[u8], chunks: u64) {
+    let mut request = format!("ws://{addr}/m/upload/{path}")
+        .into_client_request()
+        .unwrap();
+    request
+        .headers_mut()
+        .insert("Authorization", format!("Bearer {TOKEN}").parse().unwrap());
+    let (mut socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+    receive(&mut socket).await;
+
+    socket
+        .send(packet(Packet::Json(JsonData::InitializeUpload(
+            JsonInitializeUpload {
+                name: path.rsplit('/').next().unwrap().to_string(),
+                size: data.len() as u64,
+                chunk_size: 1024,
+            },
+        ))))
+        .await
+        .unwrap();
+    receive(&mut socket).await;
+    for idx in 0..chunks {
+        let chunk = &data[(idx * 1024) as usize..((idx + 1) * 1024) as usize];
+        socket
+            .send(packet(Packet::Binary(Chunk {
+                size: chunk.len() as u64,
+                idx,
+                data: chunk,
+            })))
+            .await
+            .unwrap();
+    }
+    socket.close(None).await.unwrap();
+
+    // the server only saves how far it got once it noticed
+    for _ in 0..100 {
+        let chunk_index =
+            sqlx::query_scalar::<_, i64>("SELECT chunk_index FROM files WHERE path = ?")
+                .bind(path)
+                .fetch_optional(&state.db)
+                .await
+                .unwrap();
+        if chunk_index == Some(chunks as i64) {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!("The server never saved the chunks of {path:?}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn upload_download_sync() {
+    let (state, addr) = start("").await;
+    let client = reqwest::Client::new();
+    let data = (0..10_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+    let file = upload(addr, "dir/numbers.bin", &data, 1024).await;
+    assert_eq!(file.path, "dir/numbers.bin");
+    assert_eq!(file.size, data.len() as i64);
+    // nothing is left behind in the staging directory
+    assert!(state.fs.list_dir(".partial").await.unwrap().is_empty());
+
+    // private, so it needs the token
+    let url = format!("http://{addr}/d/{}", file.id);
+    let res = client.get(&url).send().await.unwrap();
+    assert_eq!(res.status(), 401);
+
+    let res = client.get(&url).bearer_auth(TOKEN).send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.content_length(), Some(data.len() as u64));
+    assert_eq!(res.bytes().await.unwrap(), data);
+
+    let res = client
+        .get(&url)
+        .bearer_auth(TOKEN)
+        .header("Range", "bytes=100-199")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 206);
+    assert_eq!(res.bytes().await.unwrap(), data[100..200]);
+
+    // another program adds one file and removes the uploaded one
+    state.fs.write("added.txt", b"hello").await.unwrap();
+    state.fs.delete("dir/numbers.bin").await.unwrap();
+
+    let res = client
+        .post(format!("http://{addr}/m/sync"))
+        .bearer_auth(TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    let report: SyncReport = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_eq!(
+        report.added.iter().map(|e| &e.path).collect::<Vec<_>>(),
+        ["added.txt"]
+    );
+    assert_eq!(
+        report.removed.iter().map(|e| &e.path).collect::<Vec<_>>(),
+        ["dir/numbers.bin"]
+    );
+
+    let res = client.get(&url).bearer_auth(TOKEN).send().await.unwrap();
+    assert_eq!(res.status(), 404);
+
+    let res = client
+        .get(format!("http://{addr}/translate_path/added.txt"))
+        .bearer_auth(TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn compressed_uploads() {
+    let (state, addr) = start("[compression]").await;
+    let client = reqwest::Client::new();
+    let data = b"compresses well ".repeat(1000);
+
+    let file = upload(addr, "text.txt", &data, 1024).await;
+    assert!(state.fs.metadata("text.txt").await.unwrap().size < data.len() as u64);
+    let res = client
+        .get(format!("http://{addr}/d/{}", file.id))
+        .bearer_auth(TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.content_length(), Some(data.len() as u64));
+    assert_eq!(res.bytes().await.unwrap(), data);
+
+    // staged under the same extension, so it's stored as is
+    upload(addr, "photo.jpg", &data, 1024).await;
+    assert_eq!(
+        state.fs.metadata("photo.jpg").await.unwrap().size,
+        data.len() as u64
+    );
+
+    // the compressed size can't be compared, but what's read back can
+    let uploaded = try_upload(addr, "short.txt", &data, data.len() as u64 + 100, 1024).await;
+    assert!(uploaded.is_none());
+    assert!(!state.fs.exists("short.txt").await.unwrap());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sync_compressed_and_encrypted_files() {
+    let key = "ab".repeat(32);
+    let (state, addr) = start(&format!("[compression]\n[encryption]\nkey = \"{key}\"")).await;
+    let client = reqwest::Client::new();
+    let data = b"compresses well ".repeat(1000);
+
+    // sizes come from what's read back, the file system has what it takes up
+    let check = async |path: &str, data: &[u8]| {
+        let file = sqlx::query_as::<_, File>("SELECT * FROM files WHERE path = ?")
+            .bind(path)
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(file.size, data.len() as i64);
+        assert_eq!(
+            file.stored_size,
+            Some(state.fs.stored_size(path).await.unwrap() as i64)
+        );
+        assert_ne!(file.stored_size, Some(file.size));
+
+        let res = client
+            .get(format!("http://{addr}/d/{}", file.id))
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.content_length(), Some(data.len() as u64));
+        assert_eq!(res.bytes().await.unwrap(), data);
+    };
+    let sync = async || {
+        let res = client
+            .post(format!("http://{addr}/m/sync"))
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        serde_json::from_str::<SyncReport>(&res.text().await.unwrap()).unwrap()
+    };
+
+    state.fs.write("added.txt", &data).await.unwrap();
+    let report = sync().await;
+    assert_eq!(report.added.len(), 1);
+    check("added.txt", &data).await;
+
+    // nothing changed, even though the sizes differ
+    let report = sync().await;
+    assert!(report.added.is_empty() && report.changed.is_empty());
+
+    let data = b"and so does this ".repeat(2000);
+    state.fs.write("added.txt", &data).await.unwrap();
+    let report = sync().await;
+    assert_eq!(report.changed.len(), 1);
+    check("added.txt", &data).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn protected_files() {
+    let (_, addr) = start("").await;
+    let client = reqwest::Client::new();
+    let file = upload(addr, "secret.txt", b"secret", 1024).await;
+    let url = format!("http://{addr}/d/{}", file.id);
+
+    let set_password = async |password: &str| {
+        let res = client
+            .post(format!("http://{addr}/m/password/{}?id=true", file.id))
+            .bearer_auth(TOKEN)
+            .header("Content-Type", "application/json")
+            .body(format!(r#"{{"password":"{password}"}}"#))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+    };
+    let unlock = async |password: &str| {
+        let res = client
+            .post(format!("http://{addr}/unlock/{}", file.id))
+            .header("Content-Type", "application/json")
+            .body(format!(r#"{{"password":"{password}"}}"#))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        serde_json::from_str::<SignedLink>(&res.text().await.unwrap())
+            .unwrap()
+            .query
+    };
+    let status = async |url: String| client.get(url).send().await.unwrap().status();
+
+    set_password("first").await;
+    assert_eq!(status(url.clone()).await, 401);
+    let query = unlock("first").await;
+    assert_eq!(status(format!("{url}?{query}")).await, 200);
+
+    // a new password locks it again
+    set_password("second").await;
+    assert_eq!(status(format!("{url}?{query}")).await, 401);
+    let query = unlock("second").await;
+    assert_eq!(status(format!("{url}?{query}")).await, 200);
+
+    // and so does making it private
+    let change_access = async |access: u8| {
+        let res = client
+            .post(format!(
+                "http://{addr}/m/access/{}?id=true&access={access}",
+                file.id
+            ))
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+    };
+    change_access(0).await;
+    assert_eq!(status(format!("{url}?{query}")).await, 401);
+
+    // protecting it again doesn't bring old links back, even right away
+    change_access(2).await;
+    assert_eq!(status(format!("{url}?{query}")).await, 401);
+    let query = unlock("second").await;
+    assert_eq!(status(format!("{url}?{query}")).await, 200);
+    set_password("second").await;
+    assert_eq!(status(format!("{url}?{query}")).await, 401);
+
+    // clients that keep guessing have to wait, even with the right password
+    for _ in 0..5 {
+        assert_eq!(status(format!("{url}?password=wrong")).await, 401);
+    }
+    assert_eq!(status(format!("{url}?password=wrong")).await, 429);
+    assert_eq!(status(format!("{url}?password=second")).await, 429);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn signed_links() {
+    let (_, addr) = start("").await;
+    let client = reqwest::Client::new();
+    let file = upload(addr, "private.txt", b"private", 1024).await;
+    let url = format!("http://{addr}/d/{}", file.id);
+
+    let sign = async |expires_in: u64| {
+        client
+            .post(format!(
+                "http://{addr}/m/sign/{}?expires_in={expires_in}",
+                file.id
+            ))
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .unwrap()
+    };
+    let status = async |url: String| client.get(url).send().await.unwrap().status();
+
+    let res = sign(60).await;
+    assert_eq!(res.status(), 200);
+    let link: SignedLink = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_eq!(status(url.clone()).await, 401);
+    assert_eq!(status(format!("{url}?{}", link.query)).await, 200);
+
+    // anything longer than a year should just be public
+    assert_eq!(sign(365 * 24 * 60 * 60 + 1).await.status(), 400);
+    assert_eq!(sign(0).await.status(), 400);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn delete_unfinished_uploads() {
+    let (state, addr) = start("").await;
+    let client = reqwest::Client::new();
+    let data = (0..8192).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+    half_upload(&state, addr, "half.bin", &data, 2).await;
+    assert_eq!(state.fs.list_dir(".partial").await.unwrap().len(), 1);
+    let res = client
+        .delete(format!("http://{addr}/m/delete_file/half.bin"))
+        .bearer_auth(TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    assert!(state.fs.list_dir(".partial").await.unwrap().is_empty());
+
+    // a smaller file at the same path starts over instead of resuming
+    half_upload(&state, addr, "half.bin", &data, 4).await;
+    let file = upload(addr, "half.bin", &data[..3000], 1024).await;
+    assert_eq!(file.size, 3000);
+    assert!(state.fs.list_dir(".partial").await.unwrap().is_empty());
+    assert_eq!(state.fs.read("half.bin").await.unwrap(), data[..3000]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn uploads_are_hashed() {
+    use sha2::{Digest, Sha256};
+
+    for extra in ["", "dedup = true"] {
+        let (state, addr) = start(extra).await;
+        let data = (0..8192).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let digest = hex::encode(Sha256::digest(&data));
+
+        // hashed while it came in
+        let file = upload(addr, "whole.bin", &data, 1024).await;
+        assert_eq!(file.hash.as_ref(), Some(&digest));
+
+        // what was hashed before it was resumed is gone, so it's read again
+        half_upload(&state, addr, "resumed.bin", &data, 3).await;
+        let file = upload(addr, "resumed.bin", &data, 1024).await;
+        assert_eq!(file.hash.as_ref(), Some(&digest));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn delete_directories() {
+    let (state, addr) = start("").await;
+    let client = reqwest::Client::new();
+    let a = upload(addr, "dir/a.txt", b"a", 1024).await;
+    upload(addr, "dir/sub/b.txt", b"b", 1024).await;
+    state.fs.create_dir_all("dir/empty").await.unwrap();
+    // put there by another program, so not in the database
+    state.fs.write("dir/stray.txt", b"stray").await.unwrap();
+
+    // a file uploaded with a link and moved into the directory afterwards
+    let res = client
+        .post(format!("http://{addr}/m/new_link"))
+        .bearer_auth(TOKEN)
+        .send()
+        .await
+        .unwrap();
+    let link = serde_json::from_str::<serde_json::Value>(&res.text().await.unwrap()).unwrap();
+    let link = link["id"].as_str().unwrap().to_string();
+    public_upload(addr, &link, "linked.txt", b"linked").await;
+    let res = client
+        .post(format!(
+            "http://{addr}/m/rename_file/.public_uploads/linked.txt?to=dir/linked.txt"
+        ))
+        .bearer_auth(TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+
+    let delete = async |path: &str, query: &str| {
+        client
+            .delete(format!("http://{addr}/m/directory/{path}?{query}"))
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .unwrap()
+    };
+
+    assert_eq!(delete("dir", "").await.status(), 409);
+    assert_eq!(
+        delete(".public_uploads", "recursive=true").await.status(),
+        403
+    );
+    assert_eq!(delete(".blobs/dir", "recursive=true").await.status(), 403);
+    assert_eq!(delete(".partial", "recursive=true").await.status(), 403);
+
+    let expected = serde_json::json!({
+        "files": ["dir/a.txt", "dir/linked.txt", "dir/stray.txt", "dir/sub/b.txt"],
+        "directories": ["dir/empty", "dir/sub", "dir"],
+        "links": [link],
+        "dry_run": true,
+    });
+    let res = delete("dir", "recursive=true&dry_run=true").await;
+    assert_eq!(res.status(), 200);
+    let mut report: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    // directories on the same level come in whatever order the file system lists them,
+    // only nested ones have to come before their parents
+    report["directories"]
+        .as_array_mut()
+        .unwrap()
+        .sort_by_key(|d| std::cmp::Reverse(d.as_str().unwrap().len()));
+    assert_eq!(report, expected);
+    // nothing was deleted
+    for path in ["dir/a.txt", "dir/stray.txt", "dir/sub/b.txt", "dir/empty"] {
+        assert!(state.fs.exists(path).await.unwrap(), "{path}");
+    }
+
+    let res = delete("dir", "recursive=true").await;
+    assert_eq!(res.status(), 200);
+    let report: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_eq!(report["files"], expected["files"]);
+    assert_eq!(report["dry_run"], false);
+
+    assert!(!state.fs.exists("dir").await.unwrap());
+    let res = client
+        .get(format!("http://{addr}/d/{}", a.id))
+        .bearer_auth(TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 404);
+    let res = client
+        .get(format!("http://{addr}/translate_path/dir/sub/b.txt"))
+        .bearer_auth(TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 404);
+    // the link is gone too, instead of just being used up
+    let res = client
+        .get(format!("http://{addr}/o/upload/again.txt?id={link}"))
+        .header("Connection", "upgrade")
+        .header("Upgrade", "websocket")
+        .header("Sec-WebSocket-Version", "13")
+        .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 404);
+
+    assert_eq!(delete("dir", "recursive=true").await.status(), 404);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn directories_with_mount_points() {
+    let root = std::env::temp_dir().join(format!("simply_files_mount_{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let (state, addr) = start_with(
+        "mounts",
+        &format!(
+            r#"
+        [local]
+        root = "{}"
+
+        [[mounts]]
+        path = ""
+        file_system = "memory"
+
+        [[mounts]]
+        path = "dir/mnt"
+        file_system = "local"
+        "#,
+            root.to_string_lossy()
+        ),
+    )
+    .await;
+    let client = reqwest::Client::new();
+    state.fs.create_dir_all("dir/mnt").await.unwrap();
+    state
+        .fs
+        .write("dir/mnt/file", b"on the mount")
+        .await
+        .unwrap();
+
+    for path in ["dir", "dir/mnt"] {
+        let res = client
+            .delete(format!("http://{addr}/m/directory/{path}?recursive=true"))
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 403, "{path}");
+    }
+    assert_eq!(
+        state.fs.read("dir/mnt/file").await.unwrap(),
+        b"on the mount"
+    );
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn move_directories() {
+    let (state, addr) = start("").await;
+    let client = reqwest::Client::new();
+    let x = upload(addr, "a/x.txt", b"x", 1024).await;
+    let y = upload(addr, "a/deep/er/y.txt", b"y", 1024).await;
+    // only shares a prefix with the moved directory
+    let sibling = upload(addr, "ab/x.txt", b"sibling", 1024).await;
+
+    let rename = async |from: &str, to: &str| {
+        client
+            .post(format!("http://{addr}/m/rename_directory/{from}?to={to}"))
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .unwrap()
+            .status()
+    };
+    let path_of = async |id: &str| {
+        let res = client
+            .get(format!("http://{addr}/translate_id/{id}"))
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        serde_json::from_str::<File>(&res.text().await.unwrap())
+            .unwrap()
+            .path
+    };
+
+    assert_eq!(rename("a", "a/inside").await, 400);
+    assert_eq!(rename("a", "ab").await, 409);
+    assert_eq!(rename("a", ".public_uploads/a").await, 403);
+    assert_eq!(rename("missing", "elsewhere").await, 404);
+
+    assert_eq!(rename("a", "moved/a").await, 200);
+    assert_eq!(path_of(&x.id).await, "moved/a/x.txt");
+    assert_eq!(path_of(&y.id).await, "moved/a/deep/er/y.txt");
+    assert_eq!(path_of(&sibling.id).await, "ab/x.txt");
+    assert!(!state.fs.exists("a").await.unwrap());
+    assert_eq!(state.fs.read("ab/x.txt").await.unwrap(), b"sibling");
+
+    // links keep working since the ids stay the same
+    for (file, data) in [(&x, b"x"), (&y, b"y")] {
+        let res = client
+            .get(format!("http://{addr}/d/{}", file.id))
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.bytes().await.unwrap(), data.as_slice());
+    }
+
+    // and back to where it was
+    assert_eq!(rename("moved/a", "a").await, 200);
+    assert_eq!(path_of(&y.id).await, "a/deep/er/y.txt");
+    assert_eq!(path_of(&sibling.id).await, "ab/x.txt");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn copy_files() {
+    let (state, addr) = start("").await;
+    let client = reqwest::Client::new();
+    let a = upload(addr, "src/a.txt", b"a", 1024).await;
+    let b = upload(addr, "src/sub/b.txt", b"b", 1024).await;
+    state.fs.create_dir_all("src/empty").await.unwrap();
+
+    let res = client
+        .post(format!("http://{addr}/m/access/src/a.txt?access=1"))
+        .bearer_auth(TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+
+    let copy = async |from: &str, query: &str| {
+        let res = client
+            .post(format!("http://{addr}/m/copy/{from}?{query}"))
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .unwrap();
+        let status = res.status();
+        let copies = match status.is_success() {
+            true => serde_json::from_str::<Vec<File>>(&res.text().await.unwrap()).unwrap(),
+            false => vec![],
+        };
+        (status, copies)
+    };
+    let download = async |id: &str| {
+        client
+            .get(format!("http://{addr}/d/{id}"))
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap()
+    };
+
+    assert_eq!(copy("src", "to=src/inner").await.0, 400);
+    assert_eq!(copy("src/a.txt", "to=src/sub/b.txt").await.0, 409);
+    assert_eq!(copy("src/sub", "to=src/a.txt").await.0, 409);
+    assert_eq!(copy("missing", "to=elsewhere").await.0, 404);
+
+    let (status, mut copies) = copy("src", "to=dst").await;
+    assert_eq!(status, 200);
+    copies.sort_by(|a, b| a.path.cmp(&b.path));
+    assert_eq!(
+        copies.iter().map(|c| c.path.as_str()).collect::<Vec<_>>(),
+        ["dst/a.txt", "dst/sub/b.txt"]
+    );
+    for (copy, original) in copies.iter().zip([&a, &b]) {
+        assert_ne!(copy.id, original.id);
+        assert_eq!(copy.hash, original.hash);
+        assert_eq!(download(&copy.id).await, download(&original.id).await);
+        // private unless asked otherwise
+        assert_eq!(copy.get_access(), FileAccess::Private);
+    }
+    assert!(state.fs.exists("dst/empty").await.unwrap());
+
+    let (_, copies) = copy("src/a.txt", "to=public.txt&keep_access=true").await;
+    assert_eq!(copies[0].get_access(), FileAccess::Public);
+    let res = client
+        .get(format!("http://{addr}/d/{}", copies[0].id))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn copy_deduplicated_files() {
+    let (state, addr) = start("dedup = true").await;
+    let client = reqwest::Client::new();
+    let file = upload(addr, "original.txt", b"shared contents", 1024).await;
+    let ref_count = async || {
+        sqlx::query_scalar::<_, i64>("SELECT ref_count FROM blobs WHERE digest = ?")
+            .bind(file.hash.as_ref().unwrap())
+            .fetch_one(&state.db)
+            .await
+            .unwrap()
+    };
+    assert_eq!(ref_count().await, 1);
+
+    let res = client
+        .post(format!("http://{addr}/m/copy/original.txt?to=copy.txt"))
+        .bearer_auth(TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    let copy = serde_json::from_str::<Vec<File>>(&res.text().await.unwrap()).unwrap();
+    assert_eq!(copy[0].blob, file.blob);
+    assert_eq!(ref_count().await, 2);
+    // no second copy of the contents
+    assert!(!state.fs.exists("copy.txt").await.unwrap());
+
+    // the blob stays for the copy when the original is deleted
+    let res = client
+        .delete(format!("http://{addr}/m/delete_file/original.txt"))
+        .bearer_auth(TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(ref_count().await, 1);
+    let res = client
+        .get(format!("http://{addr}/d/{}", copy[0].id))
+        .bearer_auth(TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.bytes().await.unwrap(), b"shared contents".as_slice());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sync_blobs_dry_run() {
+    let (state, addr) = start("dedup = true").await;
+    let client = reqwest::Client::new();
+    upload(addr, "uploaded.txt", b"uploaded", 1024).await;
+    // put there by another program, and a blob nothing uses anymore
+    state.fs.write("added.txt", b"added").await.unwrap();
+    let orphan = format!(".blobs/ab/ab{}", "0".repeat(62));
+    state.fs.create_dir_all(".blobs/ab").await.unwrap();
+    state.fs.write(&orphan, b"orphaned").await.unwrap();
+
+    let sync = async |dry_run: bool| {
+        let res = client
+            .post(format!("http://{addr}/m/sync?dry_run={dry_run}"))
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        serde_json::from_str::<SyncReport>(&res.text().await.unwrap()).unwrap()
+    };
+
+    let report = sync(true).await;
+    assert_eq!(report.added.len(), 1);
+    assert_eq!((report.deduplicated, report.orphaned_blobs), (1, 1));
+    assert!(state.fs.exists("added.txt").await.unwrap());
+    assert!(state.fs.exists(&orphan).await.unwrap());
+
+    let report = sync(false).await;
+    assert_eq!((report.deduplicated, report.orphaned_blobs), (1, 1));
+    assert!(!state.fs.exists("added.txt").await.unwrap());
+    assert!(!state.fs.exists(&orphan).await.unwrap());
+
+    let report = sync(true).await;
+    assert_eq!((report.deduplicated, report.orphaned_blobs), (0, 0));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn scrub_finds_corrupted_and_missing_files() {
+    let (state, addr) = start("").await;
+    let client = reqwest::Client::new();
+    upload(addr, "fine.txt", b"fine", 1024).await;
+    upload(addr, "rotten.txt", b"rotten", 1024).await;
+    upload(addr, "gone.txt", b"gone", 1024).await;
+    // the same size, so only the contents tell
+    state.fs.write("rotten.txt", b"rotted").await.unwrap();
+    state.fs.delete("gone.txt").await.unwrap();
+
+    let res = client
+        .post(format!("http://{addr}/m/scrub"))
+        .bearer_auth(TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+
+    let mut report = serde_json::Value::Null;
+    for _ in 0..100 {
+        let res = client
+            .get(format!("http://{addr}/m/scrub"))
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .unwrap();
+        report = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        if report["running"] == false {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+
+    assert_eq!(report["running"], false);
+    assert_eq!(report["total_files"], 3);
+    assert_eq!(report["checked_files"], 2);
+    assert_eq!(report["corrupted"], serde_json::json!(["rotten.txt"]));
+    assert_eq!(report["missing"], serde_json::json!(["gone.txt"]));
+    assert_eq!(report["failed"], serde_json::json!([]));
+    // nothing is changed
+    assert_eq!(state.fs.read("rotten.txt").await.unwrap(), b"rotted");
+}
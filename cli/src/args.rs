@@ -59,6 +59,14 @@ pub enum Command {
         metadata: bool,
         #[arg(short, long)]
         link: bool,
+        #[arg(
+            short,
+            long,
+            requires = "link",
+            value_parser = clap::value_parser!(u64).range(1..=365 * 24),
+            help = "Prints links that work for this many hours (at most a year) without making the file public"
+        )]
+        expires: Option<u64>,
     },
     Rm {
         file: FileIdentifier,
@@ -90,7 +98,18 @@ pub enum Command {
     )]
     Auth(AuthCommands),
 
-    Sync,
+    #[clap(
+        about = "Syncs the server database with its file system",
+        long_about = "Adds files that are in the server's file system but not in its database,\nand removes the ones that aren't in the file system anymore.\nPrints every added, removed and changed file"
+    )]
+    Sync {
+        #[arg(
+            short,
+            long,
+            help = "Only shows what would change, without changing anything"
+        )]
+        dry_run: bool,
+    },
     Log,
     Config,
     Stats,
@@ -1,23 +1,22 @@
 use std::{env::current_dir, fs::exists, path::PathBuf, time::Instant};
 
 use human_bytes::human_bytes;
-use sf_core::{FileAccess, PreviewData};
+use sf_core::{FileAccess, PreviewData, SignedLink};
 
 use crate::{app::App, args::FileIdentifier};
 
-pub fn get(app: App, file: FileIdentifier, local: Option<PathBuf>, metadata: bool, link: bool) {
+pub fn get(
+    app: App,
+    file: FileIdentifier,
+    local: Option<PathBuf>,
+    metadata: bool,
+    link: bool,
+    expires: Option<u64>,
+) {
     let id = file.id(&app);
 
-    let data = get_metadata(&app, &id);
-    // if local is provided, use it only as the outpath, but if not we combine current_dir + file_name
-    let local = if let Some(local) = local {
-        local
-    } else {
-        current_dir().unwrap().join(&data.file_name)
-    };
-
-    if exists(&local).unwrap() {
-        return tracing::error!("{} already exists", data.file_name);
+    if let Some(hours) = expires {
+        return signed_link(&app, &id, hours);
     }
 
     if link {
@@ -29,6 +28,8 @@ pub fn get(app: App, file: FileIdentifier, local: Option<PathBuf>, metadata: boo
         return;
     }
 
+    let data = get_metadata(&app, &id);
+
     if metadata {
         tracing::info!("Metadata   {id} / {}", data.file_name);
         tracing::info!("Access     {:?}", FileAccess::from(data.access));
@@ -39,19 +40,31 @@ pub fn get(app: App, file: FileIdentifier, local: Option<PathBuf>, metadata: boo
             tracing::info!("Path       {path}");
         }
     } else {
+        // if local is provided, use it only as the outpath, but if not we combine current_dir + file_name
+        let local = if let Some(local) = local {
+            local
+        } else {
+            current_dir().unwrap().join(&data.file_name)
+        };
+
+        if exists(&local).unwrap() {
+            return tracing::error!("{} already exists", data.file_name);
+        }
+
         let mut request = ureq::get(app.get_url(format!("/d/{id}")));
         request = request.query("r", "t");
         request = app.add_auth_to_req(request);
         request = app.add_agent_to_req(request);
 
+        let instant = Instant::now();
+        let mut response = request.call().unwrap();
+
+        let size = response.body().content_length().unwrap_or(data.size as u64);
         tracing::info!(
             "Downloading {} ({})",
             data.file_name,
-            human_bytes(data.size as f64)
+            human_bytes(size as f64)
         );
-        let instant = Instant::now();
-
-        let mut response = request.call().unwrap();
         let mut reader = response.body_mut().as_reader();
 
         tracing::debug!("Streaming to {local:?}");
@@ -79,3 +92,34 @@ fn get_metadata(app: &App, id: &str) -> PreviewData {
 
     data
 }
+
+fn signed_link(app: &App, id: &str, hours: u64) {
+    let mut request = ureq::post(app.get_url(format!("/m/sign/{id}")));
+    request = app.add_auth_to_req(request);
+    request = app.add_agent_to_req(request);
+    request = request.query("expires_in", (hours * 60 * 60).to_string());
+    // read the error message instead of failing on 4xx/5xx
+    let request = request.config().http_status_as_error(false).build();
+    let mut response = request.send_empty().unwrap();
+
+    if response.status().as_u16() != 200 {
+        return tracing::error!(
+            "Failed to create link: {:?}",
+            response.body_mut().read_to_string()
+        );
+    }
+    let link: SignedLink = response.body_mut().read_json().unwrap();
+
+    tracing::info!("Links expire in {hours} hours");
+    tracing::info!(
+        "Download link:    {}",
+        app.get_url(format!("/d/{id}?{}", link.query))
+    );
+    tracing::info!(
+        "Raw preview link: {}",
+        app.get_url(format!("/d/{id}?r=t&{}", link.query))
+    );
+    if let Some(web_url) = app.get_host().web_url {
+        tracing::info!("Web preview link: {web_url}/d/{id}?{}", link.query);
+    }
+}
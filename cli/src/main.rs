@@ -16,6 +16,7 @@ mod config;
 mod get;
 mod ls;
 mod rm;
+mod sync;
 mod upload;
 
 // TODO: Beautify all the output, switch from tracing to like a custom logging that
@@ -42,10 +43,12 @@ fn main() {
             local,
             metadata,
             link,
-        } => get::get(app, file, local, metadata, link),
+            expires,
+        } => get::get(app, file, local, metadata, link, expires),
         Command::Rm { file } => rm::rm(&app, file),
         Command::Ls { directory } => ls::ls(app, directory),
         Command::Access { file, access } => access::access(app, file, access),
+        Command::Sync { dry_run } => sync::sync(app, dry_run),
         Command::Auth(auth_command) => match auth_command {
             AuthCommands::Add {
                 name,
@@ -0,0 +1,83 @@
+use comfy_table::{Cell, Color, Table, presets::UTF8_FULL_CONDENSED};
+use human_bytes::human_bytes;
+use owo_colors::OwoColorize;
+use sf_core::SyncReport;
+
+use crate::app::App;
+
+pub fn sync(app: App, dry_run: bool) {
+    let mut request = ureq::post(app.get_url("/m/sync"));
+    request = app.add_auth_to_req(request);
+    request = app.add_agent_to_req(request);
+    if dry_run {
+        request = request.query("dry_run", "true");
+    }
+    let mut response = request.send_empty().unwrap();
+
+    if response.status().as_u16() != 200 {
+        return tracing::error!("Failed to sync: {:?}", response.body_mut().read_to_string());
+    }
+
+    let report: SyncReport = response.body_mut().read_json().unwrap();
+
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL_CONDENSED)
+        .set_content_arrangement(comfy_table::ContentArrangement::Dynamic)
+        .set_header(vec!["Change", "Path", "Size"]);
+
+    let changes = [
+        ("Added", Color::DarkGreen, &report.added),
+        ("Removed", Color::DarkRed, &report.removed),
+        ("Changed", Color::DarkYellow, &report.changed),
+    ];
+    for (change, color, entries) in changes {
+        for entry in entries {
+            let size = match entry.previous_size {
+                Some(previous) => format!(
+                    "{} -> {}",
+                    human_bytes(previous as f64),
+                    human_bytes(entry.size as f64)
+                ),
+                None => human_bytes(entry.size as f64),
+            };
+
+            table.add_row(vec![
+                Cell::new(change).fg(color),
+                Cell::new(&entry.path),
+                Cell::new(size),
+            ]);
+        }
+    }
+
+    println!(
+        "{}",
+        format!(
+            "{} added, {} removed, {} changed{}",
+            report.added.len().to_string().bright_green(),
+            report.removed.len().to_string().bright_red(),
+            report.changed.len().to_string().bright_yellow(),
+            if report.dry_run {
+                " (dry run, nothing was changed)"
+            } else {
+                ""
+            }
+        )
+        .bold()
+    );
+    if report.deduplicated > 0 || report.orphaned_blobs > 0 {
+        match report.dry_run {
+            true => println!(
+                "{} files would be deduplicated, {} orphaned blobs deleted",
+                report.deduplicated, report.orphaned_blobs
+            ),
+            false => println!(
+                "{} files deduplicated, {} orphaned blobs deleted",
+                report.deduplicated, report.orphaned_blobs
+            ),
+        }
+    }
+    if !table.is_empty() {
+        println!("{table}");
+    }
+}
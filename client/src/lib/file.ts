@@ -15,12 +15,14 @@ export type DBFile = {
     created_at: Date,
     updated_at: Date,
     access: number,
+    stored_size?: number,
 }
 
 export type FileMetadata = {
     path: string,
     is_dir: boolean,
     size: number,
+    stored_size?: number,
     modified: number,
     id: string,
     access: number,
@@ -35,6 +37,8 @@ export type FilePreviewData = {
     access: number,
     path?: string
     cant_preview: boolean,
+    hash?: string,
+    password_required?: boolean,
 }
 
 export type UploadEndpoint = "/m/upload" | "/o/upload";
@@ -98,7 +102,9 @@ export async function rename_file(file: FileMetadata, new_name: string): Promise
     let new_path = cleaned.split('/').slice(0, -1).join('/') + '/' + new_name;
     if (new_path.startsWith('/')) new_path = new_path.slice(1); // remove leading slash if exists
 
-    const response = await fetch(`${PUBLIC_BACKEND}/m/rename_file/${cleaned}?to=${new_path}`, {
+    // directories are moved with everything inside them
+    const endpoint = file.is_dir ? 'rename_directory' : 'rename_file';
+    const response = await fetch(`${PUBLIC_BACKEND}/m/${endpoint}/${cleaned}?to=${new_path}`, {
         method: 'POST',
         credentials: 'include',
     });
@@ -132,10 +138,69 @@ export async function change_access_with_id(id: string, access: number): Promise
     }
 }
 
+/** A preview link that works without making the file public, until it expires */
+export async function create_signed_link(id: string, expires_in: number): Promise<string | undefined> {
+    const response = await fetch(`${PUBLIC_BACKEND}/m/sign/${id}?expires_in=${expires_in}`, {
+        method: 'POST',
+        credentials: 'include',
+    });
+
+    if (!response.ok) {
+        notification.error(`Failed to create link: ${response.statusText}`);
+        return;
+    }
+
+    const link: { query: string } = await response.json();
+    return `${get_preview_link(id)}?${link.query}`;
+}
+
+/** Protects the file with the password, anyone with it can access the file */
+export async function set_password(id: string, password: string): Promise<void> {
+    const response = await fetch(`${PUBLIC_BACKEND}/m/password/${id}?id=true`, {
+        method: 'POST',
+        credentials: 'include',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify({ password }),
+    });
+
+    if (!response.ok) {
+        notification.error(`Failed to set password: ${response.statusText}`);
+    }
+}
+
+/** The query of a link that works without the password for an hour */
+export async function unlock_file(id: string, password: string): Promise<string | undefined> {
+    const response = await fetch(`${PUBLIC_BACKEND}/unlock/${id}`, {
+        method: 'POST',
+        credentials: 'include',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify({ password }),
+    });
+
+    if (!response.ok) {
+        if (response.status == 401) {
+            notification.error('Wrong password');
+        } else if (response.status == 429) {
+            notification.error('Too many wrong passwords, try again later');
+        } else {
+            notification.error(`Failed to unlock file: ${response.statusText}`);
+        }
+        return;
+    }
+
+    const link: { query: string } = await response.json();
+    return link.query;
+}
+
 export function get_download_link(file_id: string): string {
     return `${PUBLIC_BACKEND}/d/${file_id}`;
 }
 
+/** Everything in the directory as one ZIP archive */
+export function get_zip_link(file: FileMetadata): string {
+    return `${PUBLIC_BACKEND}/m/zip/${get_good_path(file.path)}`;
+}
+
 export function get_preview_link(file_id: string): string {
     return `${location.origin}/d/${file_id}`;
 }
@@ -35,6 +35,7 @@ export async function get_storage_limit(token: string): Promise<StorageLimit> {
 
 export type StorageLimit = {
     used: number;
+    logical: number;
     max: number;
 };
 
@@ -2,14 +2,19 @@ import { PUBLIC_BACKEND } from '$env/static/public';
 import { get_download_link, type FilePreviewData } from '$lib/file.js';
 import { error } from '@sveltejs/kit';
 
-export async function load({ params, cookies }) {
+export async function load({ params, cookies, url }) {
     const { id } = params;
     const token = cookies.get('token');
     if (!id) {
         throw error(400, 'ID parameter is required');
     }
 
-    const response = await fetch(`${PUBLIC_BACKEND}/preview_data/${id}`, {
+    // opened through a link that expires, it has to be passed on
+    const exp = url.searchParams.get('exp');
+    const sig = url.searchParams.get('sig');
+    const signature = exp && sig ? `exp=${exp}&sig=${sig}` : undefined;
+
+    const response = await fetch(`${PUBLIC_BACKEND}/preview_data/${id}${signature ? `?${signature}` : ''}`, {
         headers: token ? {
             Authorization: `Bearer ${token}`
         } : {}
@@ -25,8 +30,9 @@ export async function load({ params, cookies }) {
     return {
         id,
         meta: data,
-        url: get_download_link(id) + "?r=t&p=t",
-        raw_url: get_download_link(id),
+        url: get_download_link(id) + "?r=t&p=t" + (signature ? `&${signature}` : ''),
+        raw_url: get_download_link(id) + (signature ? `?${signature}` : ''),
+        signature,
         has_token: token ? true : false
     }
 }
\ No newline at end of file
@@ -21,6 +21,23 @@ pub struct File {
     access: i64,
     pub chunk_index: i64,
     pub total_chunks: i64,
+    /// Bytes the file takes up in the file system, if it differs from `size`
+    #[serde(default)]
+    pub stored_size: Option<i64>,
+    /// SHA-256 digest of the blob holding the contents, if deduplicated
+    #[serde(default)]
+    pub blob: Option<String>,
+    #[serde(default)]
+    tier: i64,
+    /// Hex encoded SHA-256 digest of the contents, once it's fully uploaded
+    #[serde(default)]
+    pub hash: Option<String>,
+    /// Argon2 hash of the password of a protected file, never sent anywhere
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+    /// Changes whenever the password or access does, so unlocking it again is needed
+    #[serde(default, skip_serializing)]
+    pub unlock_nonce: Option<String>,
 }
 
 #[derive(Debug, Type, Clone, Serialize_repr, PartialEq, Eq, Default)]
@@ -29,6 +46,8 @@ pub enum FileAccess {
     #[default]
     Private = 0,
     Public = 1,
+    /// Anyone with the password
+    Protected = 2,
 }
 
 impl From<i64> for FileAccess {
@@ -36,6 +55,8 @@ impl From<i64> for FileAccess {
         match value {
             0 => Self::Private,
             1 => Self::Public,
+            2 => Self::Protected,
+            // unknown levels are never less strict
             _ => Self::Private,
         }
     }
@@ -46,6 +67,7 @@ impl From<FileAccess> for i64 {
         match value {
             FileAccess::Private => 0,
             FileAccess::Public => 1,
+            FileAccess::Protected => 2,
         }
     }
 }
@@ -63,6 +85,7 @@ impl FromStr for FileAccess {
             Err(err) => match s.to_lowercase().as_str() {
                 "private" => 0,
                 "public" => 1,
+                "protected" => 2,
                 _ => return Err(format!("Failed to convert input to FileAccess: {err:?}")),
             },
         };
@@ -75,10 +98,29 @@ impl ToString for FileAccess {
         String::from(match self {
             FileAccess::Private => "Private",
             FileAccess::Public => "Public",
+            FileAccess::Protected => "Protected",
         })
     }
 }
 
+/// Which storage tier holds a file, when using a tiered file system
+#[derive(Debug, Type, Clone, Copy, Serialize_repr, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum Tier {
+    #[default]
+    Hot = 0,
+    Cold = 1,
+}
+
+impl From<i64> for Tier {
+    fn from(value: i64) -> Self {
+        match value {
+            1 => Self::Cold,
+            _ => Self::Hot,
+        }
+    }
+}
+
 impl File {
     pub fn get_access(&self) -> FileAccess {
         self.access.into()
@@ -87,6 +129,14 @@ impl File {
     pub fn set_access(&mut self, access: FileAccess) {
         self.access = access as i64;
     }
+
+    pub fn get_tier(&self) -> Tier {
+        self.tier.into()
+    }
+
+    pub fn set_tier(&mut self, tier: Tier) {
+        self.tier = tier as i64;
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -99,6 +149,27 @@ pub struct PreviewData {
     pub access: i64,
     pub path: Option<String>,
     pub cant_preview: bool,
+    /// Hex encoded SHA-256 digest of the contents, if it's known
+    #[serde(default)]
+    pub hash: Option<String>,
+    /// The file is protected and the request didn't have the password,
+    /// nothing about its contents is sent
+    #[serde(default)]
+    pub password_required: bool,
+}
+
+/// Access to a private file until it expires, without making it public
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedLink {
+    pub id: String,
+    /// Unix timestamp
+    pub expires_at: i64,
+    /// `exp=..&sig=..`, works on both `/d/{id}` & `/preview_data/{id}`
+    pub query: String,
+    /// If the server knows its `backend_url`
+    pub download: Option<String>,
+    /// If the server knows the `web_url`
+    pub preview: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -106,6 +177,9 @@ pub struct ClientFile {
     pub path: String,
     pub is_dir: bool,
     pub size: u64,
+    /// Bytes the file takes up in the file system, only known for files in the database
+    #[serde(default)]
+    pub stored_size: Option<u64>,
     pub modified: u64,
     pub id: Option<String>,
     pub access: Option<i64>,
@@ -134,6 +208,7 @@ impl ClientFile {
                         path: real.path,
                         is_dir: real.is_dir,
                         size: real.size,
+                        stored_size: None,
                         modified: real.modified,
                         id: None,
                         access: None,
@@ -149,13 +224,36 @@ impl ClientFile {
             files.push(ClientFile {
                 path: real.path.clone(),
                 is_dir: real.is_dir,
-                size: real.size,
+                // the file system only knows the stored size, the original is in the db
+                size: db.size as u64,
+                stored_size: Some(real.size),
                 modified: real.modified,
                 id: Some(db.id.clone()),
                 access: Some(db.get_access() as i64),
             });
         }
 
+        // deduplicated files only exist in the db, their contents are stored elsewhere
+        for db in db_files.iter().filter(|f| f.blob.is_some()) {
+            if files.iter().any(|f| f.id.as_ref() == Some(&db.id)) {
+                continue;
+            }
+
+            let name = match std::path::Path::new(&db.path).file_name() {
+                Some(name) => name.to_string_lossy().to_string(),
+                None => continue,
+            };
+            files.push(ClientFile {
+                path: name,
+                is_dir: false,
+                size: db.size as u64,
+                stored_size: db.stored_size.map(|s| s as u64),
+                modified: db.updated_at.unix_timestamp() as u64,
+                id: Some(db.id.clone()),
+                access: Some(db.get_access() as i64),
+            });
+        }
+
         files
     }
 }
@@ -167,3 +265,29 @@ pub struct FileMetadata {
     pub size: u64,
     pub modified: u64,
 }
+
+/// What syncing the database with the file system changed,
+/// or would change if it was a dry run
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SyncReport {
+    /// Files in the file system that weren't in the database
+    pub added: Vec<SyncEntry>,
+    /// Files in the database that weren't in the file system anymore
+    pub removed: Vec<SyncEntry>,
+    /// Files that were changed in the file system since they were added
+    pub changed: Vec<SyncEntry>,
+    /// Files moved into deduplicated storage (or that would be in a dry run)
+    pub deduplicated: u64,
+    /// Blobs deleted since no file used them anymore (or that would be in a dry run)
+    pub orphaned_blobs: u64,
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncEntry {
+    pub path: String,
+    /// Bytes it takes up in the file system, or took up if it was removed
+    pub size: u64,
+    /// What the database had for changed files
+    pub previous_size: Option<u64>,
+}